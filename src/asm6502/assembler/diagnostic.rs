@@ -0,0 +1,191 @@
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found while assembling. Lines refer to the assembler input; the build maps them
+/// back to source files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 1-based input line, or 0 when the problem is not tied to a single line.
+    pub line: usize,
+    /// Byte range of the offending text within the line.
+    pub span: Option<Range<usize>>,
+    pub message: String,
+    /// Suggested fix, e.g. "did you mean LDA?".
+    pub hint: Option<String>,
+    /// Macro name and body line for each expansion level the line came from, outermost first.
+    pub macro_trace: Vec<(String, usize)>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if self.line > 0 {
+            write!(f, " on line {}", self.line)?;
+        }
+        if !self.macro_trace.is_empty() {
+            let trace: Vec<String> = self
+                .macro_trace
+                .iter()
+                .rev()
+                .map(|(name, line)| format!("in macro '{}' at line {}", name, line))
+                .collect();
+            write!(f, " ({})", trace.join(", "))?;
+        }
+        if let Some(hint) = &self.hint {
+            write!(f, "; {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error or warning raised while processing a line, before it is placed on that line.
+#[derive(Debug)]
+pub(super) struct Issue {
+    severity: Severity,
+    line: usize,
+    message: String,
+    hint: Option<String>,
+    /// Text to point at once the line is known.
+    needle: Option<String>,
+}
+
+impl Issue {
+    pub(super) fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            line: 0,
+            message: message.into(),
+            hint: None,
+            needle: None,
+        }
+    }
+
+    pub(super) fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message)
+        }
+    }
+
+    pub(super) fn at_line(mut self, line: usize) -> Self {
+        self.line = line;
+        self
+    }
+
+    pub(super) fn pointing_at(mut self, needle: impl Into<String>) -> Self {
+        self.needle = Some(needle.into());
+        self
+    }
+
+    pub(super) fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    /// Place the issue on `line`, whose text as written is `text`. Points at `fallback` when
+    /// nothing more specific was named.
+    pub(super) fn locate(self, line: usize, text: &str, fallback: &str) -> Diagnostic {
+        let needle = self.needle.as_deref().unwrap_or(fallback).trim();
+        let span = (!needle.is_empty())
+            .then(|| text.find(needle))
+            .flatten()
+            .map(|start| start..start + needle.len());
+        Diagnostic {
+            line,
+            span,
+            ..self.into_diagnostic()
+        }
+    }
+
+    /// Convert without a span, keeping the line set with `at_line`.
+    pub(super) fn into_diagnostic(self) -> Diagnostic {
+        Diagnostic {
+            severity: self.severity,
+            line: self.line,
+            span: None,
+            message: self.message,
+            hint: self.hint,
+            macro_trace: Vec::new(),
+        }
+    }
+}
+
+impl From<String> for Issue {
+    fn from(message: String) -> Self {
+        Issue::error(message)
+    }
+}
+
+/// Suggest the candidate closest to `word` (case-insensitive), if any is near enough to be a typo.
+pub(super) fn closest<'a>(
+    word: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> Option<&'a str> {
+    closest_preferring(word, candidates, std::iter::empty())
+}
+
+/// Like [`closest`], but a candidate from `preferred` wins over one from `others` that is just as
+/// close.
+pub(super) fn closest_preferring<'a>(
+    word: &str,
+    preferred: impl Iterator<Item = &'a str>,
+    others: impl Iterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let word = word.to_ascii_lowercase();
+    let limit = if word.len() <= 4 { 1 } else { 2 };
+    preferred
+        .map(|candidate| (0, candidate))
+        .chain(others.map(|candidate| (1, candidate)))
+        .map(|(rank, candidate)| {
+            (
+                edit_distance(&word, &candidate.to_ascii_lowercase()),
+                rank,
+                candidate,
+            )
+        })
+        .filter(|(distance, ..)| *distance <= limit)
+        .min()
+        .map(|(.., candidate)| candidate)
+}
+
+/// Levenshtein distance, counting a swap of adjacent characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
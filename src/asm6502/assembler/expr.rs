@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+
+use super::object::Target;
+
+/// Why an expression could not be evaluated.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum ExprError {
+    /// A symbol that is not (yet) defined.
+    Undefined(String),
+    /// Malformed input, division by zero, or `*` where no PC is known.
+    Invalid(String),
+}
+
+impl ExprError {
+    /// Describe the error for `expr`; callers append the line number.
+    pub(super) fn describe(&self, expr: &str) -> String {
+        match self {
+            ExprError::Undefined(name) => {
+                format!("Undefined symbol '{}' in '{}'", name, expr.trim())
+            }
+            ExprError::Invalid(reason) => {
+                format!("Invalid expression '{}' ({})", expr.trim(), reason)
+            }
+        }
+    }
+}
+
+/// Which part of a relocatable value an expression selects with a leading `<` or `>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Part {
+    Word,
+    Low,
+    High,
+}
+
+/// The result of an expression: a constant, or an offset from an address the linker decides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Value {
+    pub(super) offset: i64,
+    pub(super) target: Option<Target>,
+    pub(super) part: Part,
+}
+
+impl Value {
+    pub(super) fn constant(value: i64) -> Self {
+        Self {
+            offset: value,
+            target: None,
+            part: Part::Word,
+        }
+    }
+
+    pub(super) fn relocatable(offset: i64, target: Target) -> Self {
+        Self {
+            offset,
+            target: Some(target),
+            part: Part::Word,
+        }
+    }
+
+    fn into_constant(self) -> Result<i64, ExprError> {
+        match self.target {
+            None => Ok(self.offset),
+            Some(_) => Err(ExprError::Invalid(NOT_RELOCATABLE.to_string())),
+        }
+    }
+}
+
+/// Evaluate an assembler expression.
+///
+/// Precedence, loosest first: `||`, `&&`, `|`, `^`, `&`, `==`/`!=`, `<`/`<=`/`>`/`>=`,
+/// `<<`/`>>`, `+`/`-`, `*`/`/`/`%`, then unary `-`, `+`, `~` and `!`. Comparisons and logical
+/// operators give 1 or 0. A leading `<` or `>` takes the low/high byte of everything after it.
+/// Operands are numbers (`$FF`, `%1010`, `0b1010`, `0x1F`, `42`), character literals (`'A'`),
+/// symbols, `*` for the current PC and parenthesised sub-expressions.
+pub(super) fn evaluate(
+    expr: &str,
+    labels: &HashMap<String, u16>,
+    pc: Option<u16>,
+) -> Result<i64, ExprError> {
+    let value = evaluate_value(expr, labels, None, pc.map(|pc| Value::constant(pc.into())))?;
+    Ok(value.offset)
+}
+
+/// Evaluate to a 16-bit value; negative results wrap like 6502 address arithmetic.
+pub(super) fn evaluate_word(
+    expr: &str,
+    labels: &HashMap<String, u16>,
+    pc: Option<u16>,
+) -> Result<u16, ExprError> {
+    fit_word(evaluate(expr, labels, pc)?)
+}
+
+pub(super) fn fit_word(value: i64) -> Result<u16, ExprError> {
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(ExprError::Invalid(format!(
+            "value {} does not fit in 16 bits",
+            value
+        )));
+    }
+    Ok(value as u16)
+}
+
+/// Evaluate an expression that may refer to addresses only the linker knows. With `pieces`,
+/// labels listed there are offsets into that segment piece and unknown symbols are imports;
+/// such values allow adding and subtracting constants, differences within one piece and a
+/// final `<`/`>` byte selection.
+pub(super) fn evaluate_value(
+    expr: &str,
+    labels: &HashMap<String, u16>,
+    pieces: Option<&HashMap<String, usize>>,
+    pc: Option<Value>,
+) -> Result<Value, ExprError> {
+    let mut parser = Parser {
+        src: expr.as_bytes(),
+        pos: 0,
+        labels,
+        pieces,
+        pc,
+    };
+    let value = parser.expr()?;
+    parser.skip_ws();
+    if parser.pos < parser.src.len() {
+        return Err(ExprError::Invalid(format!(
+            "unexpected '{}'",
+            &expr[parser.pos..]
+        )));
+    }
+    Ok(value)
+}
+
+/// Reason given when an operator cannot be applied to a relocatable value.
+const NOT_RELOCATABLE: &str =
+    "only constants can be added to or subtracted from an address the linker places";
+
+/// Apply `op` where either side may be relocatable.
+fn combine(
+    op: &str,
+    left: Value,
+    right: Value,
+    apply: fn(&str, i64, i64) -> Result<i64, ExprError>,
+) -> Result<Value, ExprError> {
+    if left.target.is_none() && right.target.is_none() {
+        return Ok(Value::constant(apply(op, left.offset, right.offset)?));
+    }
+    if left.part != Part::Word || right.part != Part::Word {
+        return Err(ExprError::Invalid(NOT_RELOCATABLE.to_string()));
+    }
+    match (op, left.target, right.target) {
+        ("+", Some(target), None) | ("+", None, Some(target)) => Ok(Value::relocatable(
+            checked(left.offset.checked_add(right.offset))?,
+            target,
+        )),
+        ("-", Some(target), None) => Ok(Value::relocatable(
+            checked(left.offset.checked_sub(right.offset))?,
+            target,
+        )),
+        ("-", Some(a), Some(b)) if a == b => Ok(Value::constant(checked(
+            left.offset.checked_sub(right.offset),
+        )?)),
+        _ => Err(ExprError::Invalid(NOT_RELOCATABLE.to_string())),
+    }
+}
+
+/// The result of a checked operation, or an error when it overflowed.
+fn checked(result: Option<i64>) -> Result<i64, ExprError> {
+    result.ok_or_else(|| ExprError::Invalid("overflow".to_string()))
+}
+
+/// Take the low or high byte of `value`, or mark a relocatable value for the linker to do so.
+fn select(value: Value, part: Part) -> Result<Value, ExprError> {
+    match (&value.target, value.part) {
+        (None, _) => Ok(Value::constant(match part {
+            Part::High => (value.offset >> 8) & 0xFF,
+            _ => value.offset & 0xFF,
+        })),
+        (Some(_), Part::Word) => Ok(Value { part, ..value }),
+        (Some(_), _) => Err(ExprError::Invalid(NOT_RELOCATABLE.to_string())),
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    labels: &'a HashMap<String, u16>,
+    pieces: Option<&'a HashMap<String, usize>>,
+    pc: Option<Value>,
+}
+
+impl Parser<'_> {
+    fn skip_ws(&mut self) {
+        while self.src.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `op` if it is next, making sure a one-character operator does not match the
+    /// start of a longer one (`<` in `<<` or `<=`, `&` in `&&`, `!` in `!=`).
+    fn eat(&mut self, op: &str) -> bool {
+        self.skip_ws();
+        let rest = &self.src[self.pos..];
+        if !rest.starts_with(op.as_bytes()) {
+            return false;
+        }
+        let next = rest.get(1).copied();
+        let longer = match op {
+            "<" | ">" => next == Some(rest[0]) || next == Some(b'='),
+            "&" | "|" => next == Some(rest[0]),
+            "!" => next == Some(b'='),
+            _ => false,
+        };
+        if longer {
+            return false;
+        }
+        self.pos += op.len();
+        true
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[&str],
+        next: fn(&mut Self) -> Result<Value, ExprError>,
+        apply: fn(&str, i64, i64) -> Result<i64, ExprError>,
+    ) -> Result<Value, ExprError> {
+        let mut left = next(self)?;
+        'outer: loop {
+            for op in ops {
+                if self.eat(op) {
+                    let right = next(self)?;
+                    left = combine(op, left, right, apply)?;
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn expr(&mut self) -> Result<Value, ExprError> {
+        if self.eat("<") {
+            return select(self.expr()?, Part::Low);
+        }
+        if self.eat(">") {
+            return select(self.expr()?, Part::High);
+        }
+        self.logical_or()
+    }
+
+    fn logical_or(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["||"], Parser::logical_and, |_, a, b| {
+            Ok(i64::from(a != 0 || b != 0))
+        })
+    }
+
+    fn logical_and(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["&&"], Parser::or, |_, a, b| {
+            Ok(i64::from(a != 0 && b != 0))
+        })
+    }
+
+    fn or(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["|"], Parser::xor, |_, a, b| Ok(a | b))
+    }
+
+    fn xor(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["^"], Parser::and, |_, a, b| Ok(a ^ b))
+    }
+
+    fn and(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["&"], Parser::equality, |_, a, b| Ok(a & b))
+    }
+
+    fn equality(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["==", "!="], Parser::relational, |op, a, b| {
+            Ok(i64::from((a == b) == (op == "==")))
+        })
+    }
+
+    fn relational(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["<=", ">=", "<", ">"], Parser::shift, |op, a, b| {
+            Ok(i64::from(match op {
+                "<=" => a <= b,
+                ">=" => a >= b,
+                "<" => a < b,
+                _ => a > b,
+            }))
+        })
+    }
+
+    fn shift(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["<<", ">>"], Parser::sum, |op, a, b| {
+            let b = u32::try_from(b)
+                .ok()
+                .filter(|b| *b < 32)
+                .ok_or_else(|| ExprError::Invalid(format!("invalid shift amount {}", b)))?;
+            if op == "<<" {
+                checked(a.checked_mul(1 << b))
+            } else {
+                Ok(a >> b)
+            }
+        })
+    }
+
+    fn sum(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["+", "-"], Parser::product, |op, a, b| {
+            checked(if op == "+" {
+                a.checked_add(b)
+            } else {
+                a.checked_sub(b)
+            })
+        })
+    }
+
+    fn product(&mut self) -> Result<Value, ExprError> {
+        self.binary(&["*", "/", "%"], Parser::unary, |op, a, b| match op {
+            "*" => checked(a.checked_mul(b)),
+            _ if b == 0 => Err(ExprError::Invalid("division by zero".to_string())),
+            "/" => checked(a.checked_div(b)),
+            _ => checked(a.checked_rem(b)),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Value, ExprError> {
+        if self.eat("-") {
+            let value = self.unary()?.into_constant()?;
+            return Ok(Value::constant(checked(value.checked_neg())?));
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        if self.eat("~") {
+            let value = self.unary()?.into_constant()?;
+            return Ok(Value::constant(!value & 0xFFFF));
+        }
+        if self.eat("!") {
+            let value = self.unary()?.into_constant()?;
+            return Ok(Value::constant(i64::from(value == 0)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Value, ExprError> {
+        self.skip_ws();
+        let Some(&c) = self.src.get(self.pos) else {
+            return Err(ExprError::Invalid("missing operand".to_string()));
+        };
+        match c {
+            b'(' => {
+                self.pos += 1;
+                let value = self.expr()?;
+                if !self.eat(")") {
+                    return Err(ExprError::Invalid("missing ')'".to_string()));
+                }
+                Ok(value)
+            }
+            b'*' => {
+                self.pos += 1;
+                self.pc.clone().ok_or_else(|| {
+                    ExprError::Invalid("'*' (current PC) is not available here".to_string())
+                })
+            }
+            b'\'' => {
+                let ch = self.src.get(self.pos + 1).copied();
+                if self.src.get(self.pos + 2) != Some(&b'\'') {
+                    return Err(ExprError::Invalid(
+                        "malformed character literal".to_string(),
+                    ));
+                }
+                self.pos += 3;
+                Ok(Value::constant(i64::from(ch.unwrap_or(0))))
+            }
+            b'$' => {
+                self.pos += 1;
+                self.number(16)
+            }
+            b'%' => {
+                self.pos += 1;
+                self.number(2)
+            }
+            b'0' if matches!(self.src.get(self.pos + 1), Some(b'x' | b'X')) => {
+                self.pos += 2;
+                self.number(16)
+            }
+            b'0' if matches!(self.src.get(self.pos + 1), Some(b'b' | b'B')) => {
+                self.pos += 2;
+                self.number(2)
+            }
+            b'0'..=b'9' => self.number(10),
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                let start = self.pos;
+                while self
+                    .src
+                    .get(self.pos)
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_' || *b == b'.')
+                {
+                    self.pos += 1;
+                }
+                let name = String::from_utf8_lossy(&self.src[start..self.pos]).into_owned();
+                let piece = self.pieces.map(|pieces| pieces.get(&name));
+                match (self.labels.get(&name), piece) {
+                    (Some(&offset), Some(Some(&piece))) => {
+                        Ok(Value::relocatable(offset.into(), Target::Section(piece)))
+                    }
+                    (Some(&value), _) => Ok(Value::constant(value.into())),
+                    (None, Some(_)) => Ok(Value::relocatable(0, Target::Symbol(name))),
+                    (None, None) => Err(ExprError::Undefined(name)),
+                }
+            }
+            _ => Err(ExprError::Invalid(format!(
+                "unexpected '{}'",
+                String::from_utf8_lossy(&self.src[self.pos..])
+            ))),
+        }
+    }
+
+    fn number(&mut self, radix: u32) -> Result<Value, ExprError> {
+        let start = self.pos;
+        while self
+            .src
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_alphanumeric)
+        {
+            self.pos += 1;
+        }
+        let digits = String::from_utf8_lossy(&self.src[start..self.pos]);
+        i64::from_str_radix(&digits, radix)
+            .ok()
+            .filter(|v| *v <= 0xFFFF)
+            .map(Value::constant)
+            .ok_or_else(|| ExprError::Invalid(format!("invalid number '{}'", digits)))
+    }
+}
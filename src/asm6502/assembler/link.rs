@@ -0,0 +1,231 @@
+use std::collections::{HashMap, HashSet};
+
+use super::diagnostic::{Issue, closest};
+use super::object::{Object, RelocKind, Target};
+use super::{
+    AssembleOutput, DEFAULT_SEGMENT, Diagnostic, SegmentLayout, SegmentOutput, SegmentPiece,
+};
+
+/// Combine objects into a program.
+///
+/// `CODE` sections are placed one after another from `origin` and sections of segments listed
+/// in `segment_bases` from their base. Other segments follow the `CODE` block, and `.org`
+/// sections keep their address. Global symbols resolve imports across objects. Two objects may
+/// define the same `.const` only with the same value.
+///
+/// Line numbers in the output and in diagnostics count through the objects' `source` lines in
+/// order, as if the sources were one file. Every undefined symbol and other error is returned.
+pub fn link(
+    objects: &[Object],
+    origin: u16,
+    segment_bases: &[(&str, u16)],
+) -> Result<AssembleOutput, Vec<Diagnostic>> {
+    let line_offsets: Vec<usize> = objects
+        .iter()
+        .scan(0, |total, object| {
+            let offset = *total;
+            *total += object.source.len();
+            Some(offset)
+        })
+        .collect();
+    let locate = |issue: Issue, object: usize, line: usize, needle: &str| {
+        let text = objects[object].source.get(line.wrapping_sub(1));
+        issue.locate(
+            line_offsets[object] + line,
+            text.map_or("", String::as_str),
+            needle,
+        )
+    };
+
+    let blocks = place_sections(objects, origin, segment_bases)
+        .map_err(|e| vec![Issue::error(e).into_diagnostic()])?;
+    let mut section_addrs: Vec<Vec<u16>> = objects
+        .iter()
+        .map(|object| vec![0; object.sections.len()])
+        .collect();
+    for block in &blocks {
+        let mut addr = block.start;
+        for &(object, section) in &block.sections {
+            section_addrs[object][section] = addr;
+            addr = addr.wrapping_add(objects[object].sections[section].bytes.len() as u16);
+        }
+    }
+
+    let mut diagnostics = Vec::new();
+    let mut globals: HashMap<&str, (u16, bool)> = HashMap::new();
+    let mut labels = HashMap::new();
+    let mut constants = HashSet::new();
+    for (idx, object) in objects.iter().enumerate() {
+        for symbol in &object.symbols {
+            let value = match symbol.section {
+                Some(section) => section_addrs[idx][section].wrapping_add(symbol.value),
+                None => symbol.value,
+            };
+            if symbol.global {
+                match globals.get(symbol.name.as_str()) {
+                    Some(&(first, constant)) if constant && symbol.constant && first == value => {}
+                    Some(_) => {
+                        let issue = Issue::error(format!(
+                            "Symbol '{}' is already defined by another object",
+                            symbol.name
+                        ));
+                        diagnostics.push(locate(issue, idx, symbol.line, &symbol.name));
+                        continue;
+                    }
+                    None => {
+                        globals.insert(&symbol.name, (value, symbol.constant));
+                    }
+                }
+            }
+            if !labels.contains_key(&symbol.name) {
+                labels.insert(symbol.name.clone(), value);
+                if symbol.constant {
+                    constants.insert(symbol.name.clone());
+                }
+            }
+        }
+    }
+
+    let mut images: Vec<Vec<Vec<u8>>> = objects
+        .iter()
+        .map(|object| object.sections.iter().map(|s| s.bytes.clone()).collect())
+        .collect();
+    for (idx, object) in objects.iter().enumerate() {
+        for (section_idx, section) in object.sections.iter().enumerate() {
+            for relocation in &section.relocations {
+                let at = relocation.offset as usize;
+                let line = section.lines.get(at).copied().unwrap_or(0);
+                let (base, name) = match &relocation.target {
+                    Target::Section(target) => (section_addrs[idx][*target], ""),
+                    Target::Symbol(name) => match globals.get(name.as_str()) {
+                        Some(&(value, _)) => (value, name.as_str()),
+                        None => {
+                            let issue = Issue::error(format!("Undefined symbol '{}'", name));
+                            let issue = match closest(name, globals.keys().copied()) {
+                                Some(known) => {
+                                    issue.with_hint(format!("did you mean '{}'?", known))
+                                }
+                                None => issue,
+                            };
+                            diagnostics.push(locate(issue, idx, line, name));
+                            continue;
+                        }
+                    },
+                };
+                let value = i64::from(base) + i64::from(relocation.addend);
+                let bytes = &mut images[idx][section_idx];
+                match relocation.kind {
+                    RelocKind::Word => {
+                        bytes[at..at + 2].copy_from_slice(&(value as u16).to_le_bytes())
+                    }
+                    RelocKind::Byte if !(-128..=255).contains(&value) => {
+                        let issue = Issue::error(format!(
+                            "Value ${:04X} does not fit in a byte",
+                            value as u16
+                        ));
+                        diagnostics.push(locate(issue, idx, line, name));
+                    }
+                    RelocKind::Byte | RelocKind::Low => bytes[at] = value as u8,
+                    RelocKind::High => bytes[at] = (value >> 8) as u8,
+                }
+            }
+        }
+    }
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|d| d.line);
+        return Err(diagnostics);
+    }
+
+    let mut main = (Vec::new(), Vec::new());
+    let mut segments = Vec::new();
+    for (idx, block) in blocks.into_iter().enumerate() {
+        let mut bytes = Vec::new();
+        let mut pc_line = Vec::new();
+        for (object, section) in block.sections {
+            bytes.extend_from_slice(&images[object][section]);
+            let lines = &objects[object].sections[section].lines;
+            pc_line.extend(lines.iter().map(|line| line + line_offsets[object]));
+        }
+        if idx == 0 {
+            main = (bytes, pc_line);
+        } else if !bytes.is_empty() {
+            segments.push(SegmentOutput {
+                name: block.segment,
+                start: block.start,
+                bytes,
+                pc_line,
+            });
+        }
+    }
+
+    Ok(AssembleOutput {
+        bytes: main.0,
+        labels,
+        constants,
+        pc_line: main.1,
+        segments,
+        warnings: Vec::new(),
+    })
+}
+
+/// Sections laid out back to back from `start`, as (object, section) indices.
+struct Block {
+    segment: String,
+    start: u16,
+    sections: Vec<(usize, usize)>,
+}
+
+/// Group sections into blocks and give each block its address. The first block is `CODE`.
+fn place_sections(
+    objects: &[Object],
+    origin: u16,
+    segment_bases: &[(&str, u16)],
+) -> Result<Vec<Block>, String> {
+    let bases: HashMap<String, u16> = segment_bases
+        .iter()
+        .map(|(name, base)| (name.to_ascii_uppercase(), *base))
+        .collect();
+    let mut layout = SegmentLayout::new(Some(origin));
+    let mut members = vec![Vec::new()];
+    let mut floating = HashMap::from([(DEFAULT_SEGMENT.to_string(), 0)]);
+    for (idx, object) in objects.iter().enumerate() {
+        for (section_idx, section) in object.sections.iter().enumerate() {
+            let block = match section.start {
+                Some(_) => None,
+                None => floating.get(&section.segment).copied(),
+            };
+            let block = block.unwrap_or_else(|| {
+                let start = section
+                    .start
+                    .or_else(|| bases.get(&section.segment).copied());
+                layout.pieces.push(SegmentPiece {
+                    segment: section.segment.clone(),
+                    start,
+                    size: 0,
+                });
+                members.push(Vec::new());
+                if section.start.is_none() {
+                    floating.insert(section.segment.clone(), members.len() - 1);
+                }
+                members.len() - 1
+            });
+            let piece = &mut layout.pieces[block];
+            piece.size = u16::try_from(piece.size as usize + section.bytes.len())
+                .map_err(|_| format!("Segment '{}' exceeds 64 KB", piece.segment))?;
+            members[block].push((idx, section_idx));
+        }
+    }
+
+    let starts = layout.resolve_starts()?;
+    Ok(layout
+        .pieces
+        .into_iter()
+        .zip(starts)
+        .zip(members)
+        .map(|((piece, start), sections)| Block {
+            segment: piece.segment,
+            start,
+            sections,
+        })
+        .collect())
+}
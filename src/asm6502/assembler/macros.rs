@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use super::diagnostic::Issue;
+use super::{local_label_name, split_label_and_instr, strip_directive};
+
+/// Invocations may nest (a macro body calling another macro) up to this depth.
+const MAX_MACRO_DEPTH: usize = 16;
+
+/// A line of assembler input after macro expansion.
+pub(super) struct SourceLine {
+    pub text: String,
+    /// Line in the original input; for expanded lines this is the outermost invocation.
+    pub line: usize,
+    /// Macro name and body line for each expansion level, outermost first.
+    pub macro_trace: Vec<(String, usize)>,
+}
+
+struct Macro {
+    params: Vec<String>,
+    /// Body lines with their line numbers in the original input.
+    body: Vec<(usize, String)>,
+    /// Local labels (`.name`/`@name`) defined in the body; renamed per expansion.
+    locals: Vec<String>,
+}
+
+/// Collect `.macro NAME args ... .endm` definitions and expand every invocation in place.
+pub(super) fn expand_macros(buf: &[u8]) -> Result<Vec<SourceLine>, Issue> {
+    let lines: Vec<String> = buf
+        .split(|&b| b == b'\n')
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect();
+
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut plain: Vec<(usize, &str)> = Vec::new();
+    let mut idx = 0;
+    while idx < lines.len() {
+        let line_no = idx + 1;
+        let trimmed = lines[idx].trim();
+        let Some(header) = strip_directive(trimmed, ".macro") else {
+            if is_endm(trimmed) {
+                return Err(Issue::error(".endm without .macro").at_line(line_no));
+            }
+            plain.push((line_no, lines[idx].as_str()));
+            idx += 1;
+            continue;
+        };
+        let (name, params) =
+            parse_macro_header(header).map_err(|e| Issue::error(e).at_line(line_no))?;
+        if macros.contains_key(&name) {
+            return Err(Issue::error(format!("Duplicate macro '{}'", name)).at_line(line_no));
+        }
+        let mut body = Vec::new();
+        let mut locals = Vec::new();
+        idx += 1;
+        loop {
+            let Some(body_line) = lines.get(idx) else {
+                return Err(
+                    Issue::error(format!("macro '{}' is missing .endm", name)).at_line(line_no)
+                );
+            };
+            let trimmed = body_line.trim();
+            if is_endm(trimmed) {
+                break;
+            }
+            if strip_directive(trimmed, ".macro").is_some() {
+                return Err(
+                    Issue::error("nested .macro definitions are not supported").at_line(idx + 1)
+                );
+            }
+            if let (Some(label), _) = split_label_and_instr(body_line)
+                && let Some(local) = local_label_name(&label)
+            {
+                locals.push(local.to_string());
+            }
+            body.push((idx + 1, body_line.clone()));
+            idx += 1;
+        }
+        macros.insert(
+            name,
+            Macro {
+                params,
+                body,
+                locals,
+            },
+        );
+        idx += 1;
+    }
+
+    let mut out = Vec::with_capacity(plain.len());
+    let mut expansions = 0;
+    for (line_no, text) in plain {
+        expand_line(text, line_no, &[], &macros, &mut expansions, &mut out)?;
+    }
+    Ok(out)
+}
+
+fn expand_line(
+    text: &str,
+    line: usize,
+    trace: &[(String, usize)],
+    macros: &HashMap<String, Macro>,
+    expansions: &mut usize,
+    out: &mut Vec<SourceLine>,
+) -> Result<(), Issue> {
+    let (label, instr) = split_label_and_instr(text);
+    let instr = instr.trim();
+    let (head, rest) = instr.split_once(char::is_whitespace).unwrap_or((instr, ""));
+    let Some(mac) = macros.get(head) else {
+        out.push(SourceLine {
+            text: text.to_string(),
+            line,
+            macro_trace: trace.to_vec(),
+        });
+        return Ok(());
+    };
+
+    let at_line = trace
+        .last()
+        .map(|(_, body_line)| *body_line)
+        .unwrap_or(line);
+    if trace.len() >= MAX_MACRO_DEPTH {
+        return Err(Issue::error(format!("macro '{}' nested too deeply", head)).at_line(at_line));
+    }
+    let args = split_macro_args(rest).map_err(|e| Issue::error(e).at_line(at_line))?;
+    if args.len() != mac.params.len() {
+        return Err(Issue::error(format!(
+            "macro '{}' expects {} argument(s), got {}",
+            head,
+            mac.params.len(),
+            args.len()
+        ))
+        .at_line(at_line));
+    }
+    if let Some(label) = label {
+        out.push(SourceLine {
+            text: format!("{}:", label),
+            line,
+            macro_trace: trace.to_vec(),
+        });
+    }
+
+    *expansions += 1;
+    let expansion = *expansions;
+    for (body_line, body_text) in &mac.body {
+        let substituted = substitute_tokens(body_text, |prefix, ident| match prefix {
+            Some(_) if mac.locals.iter().any(|l| l == ident) => {
+                Some(format!("{}.{}.{}", head, expansion, ident))
+            }
+            None => mac
+                .params
+                .iter()
+                .position(|p| p == ident)
+                .map(|i| args[i].clone()),
+            _ => None,
+        });
+        let mut nested = trace.to_vec();
+        nested.push((head.to_string(), *body_line));
+        expand_line(&substituted, line, &nested, macros, expansions, out)?;
+    }
+    Ok(())
+}
+
+fn is_endm(trimmed: &str) -> bool {
+    trimmed.eq_ignore_ascii_case(".endm") || trimmed.eq_ignore_ascii_case(".endmacro")
+}
+
+fn parse_macro_header(header: &str) -> Result<(String, Vec<String>), String> {
+    let header = header.trim();
+    let (name, rest) = header
+        .split_once(char::is_whitespace)
+        .unwrap_or((header, ""));
+    if !is_identifier(name) {
+        return Err(format!("invalid macro name '{}'", name));
+    }
+    let mut params = Vec::new();
+    if !rest.trim().is_empty() {
+        for param in rest.split(',').map(str::trim) {
+            if !is_identifier(param) {
+                return Err(format!("invalid macro parameter '{}'", param));
+            }
+            if params.iter().any(|p| p == param) {
+                return Err(format!("duplicate macro parameter '{}'", param));
+            }
+            params.push(param.to_string());
+        }
+    }
+    Ok((name.to_string(), params))
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split invocation arguments on top-level commas, so `($10),Y` or `"a,b"` stay whole.
+fn split_macro_args(rest: &str) -> Result<Vec<String>, String> {
+    let rest = rest.trim();
+    if rest.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut args = Vec::new();
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                args.push(rest[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(rest[start..].trim().to_string());
+    if args.iter().any(|a| a.is_empty()) {
+        return Err("empty macro argument".to_string());
+    }
+    Ok(args)
+}
+
+/// Replace identifiers outside string/char literals. `replace` receives the optional `.`/`@`
+/// prefix and the bare identifier; returning `None` leaves the token untouched.
+fn substitute_tokens(text: &str, replace: impl Fn(Option<u8>, &str) -> Option<String>) -> String {
+    let bytes = text.as_bytes();
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut quote: Option<u8> = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if let Some(q) = quote {
+            if b == b'\\' {
+                i += 1;
+            } else if b == q {
+                quote = None;
+            }
+            i += 1;
+            continue;
+        }
+        if b == b'"' || b == b'\'' {
+            quote = Some(b);
+            i += 1;
+            continue;
+        }
+        if !(b.is_ascii_alphabetic() || b == b'_') || (i > 0 && is_ident(bytes[i - 1])) {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < bytes.len() && is_ident(bytes[end]) {
+            end += 1;
+        }
+        let prev = i.checked_sub(1).map(|p| bytes[p]);
+        let prefix = prev.filter(|p| *p == b'.' || *p == b'@');
+        // Leave hex digits (`$FF`) and qualified tails (`Global.name`) alone.
+        let qualified = prefix.is_some() && i >= 2 && is_ident(bytes[i - 2]);
+        if prev != Some(b'$')
+            && !qualified
+            && let Some(replacement) = replace(prefix, &text[i..end])
+        {
+            let token_start = if prefix.is_some() { i - 1 } else { i };
+            out.push_str(&text[copied..token_start]);
+            out.push_str(&replacement);
+            copied = end;
+        }
+        i = end;
+    }
+    out.push_str(&text[copied..]);
+    out
+}
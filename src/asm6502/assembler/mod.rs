@@ -1,26 +1,89 @@
+mod diagnostic;
+mod expr;
+mod link;
+mod macros;
+mod object;
 #[cfg(test)]
 mod tests;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
 
-use super::parser::parse_opcode_line;
+pub use self::diagnostic::Diagnostic;
+pub use self::link::link;
+pub use self::object::{Object, RelocKind, Relocation, Section, Symbol, Target};
+
+use self::diagnostic::{Issue, closest, closest_preferring};
+use self::expr::{ExprError, Part, Value, evaluate, evaluate_value, evaluate_word, fit_word};
+use self::macros::{SourceLine, expand_macros};
+use super::opcodes::{
+    CpuKind, Mode, extension_opcode, is_documented_mnemonic, is_extension_mnemonic,
+};
+use super::parser::{mnemonic_name, mnemonic_names, parse_opcode_line};
 use super::tokens::*;
 
 type AssembleResult = Result<(), String>;
 
+/// Bytes, source line per byte and relocations for one segment piece.
+#[derive(Clone, Default)]
+struct PieceOutput {
+    bytes: Vec<u8>,
+    lines: Vec<usize>,
+    relocations: Vec<Relocation>,
+}
+
+/// Segment the assembler starts in; its first block is placed at the assembly origin.
+pub const DEFAULT_SEGMENT: &str = "CODE";
+
+/// Segment whose labels are addressed with zero-page operands.
+const ZERO_PAGE_SEGMENT: &str = "ZP";
+
+/// Opcode the machine appends after every program to stop execution.
+const HALT_OPCODE: u8 = 0xFF;
+
+/// Prefix of the generated names for anonymous `+`/`-` labels.
+pub const ANON_LABEL_PREFIX: &str = "__anon_";
+
+/// Directives offered as suggestions for misspelled ones.
+const DIRECTIVES: [&str; 16] = [
+    ".byte", ".word", ".text", ".fill", ".const", ".include", ".incbin", ".org", ".segment",
+    ".macro", ".endm", ".if", ".ifdef", ".ifndef", ".else", ".endif",
+];
+
+/// Reads the file an `.incbin` names, given the input line it appears on and the name as
+/// written. Returns the file's bytes, or why it could not be read.
+pub type IncbinReader<'a> = dyn Fn(usize, &str) -> Result<Vec<u8>, String> + 'a;
+
+/// An assembled object and its warnings, or every error found.
+pub type ObjectResult = Result<(Object, Vec<Diagnostic>), Vec<Diagnostic>>;
+
 /// Output of an assembly pass, including the generated bytes and label table.
 pub struct AssembleOutput {
+    /// Main program block: the start of the `CODE` segment at the origin.
     pub bytes: Vec<u8>,
     pub labels: HashMap<String, u16>,
+    /// Names in `labels` defined with `.const` rather than as code or data addresses.
+    pub constants: HashSet<String>,
+    pub pc_line: Vec<usize>,
+    /// Blocks placed elsewhere via `.segment` or `.org`.
+    pub segments: Vec<SegmentOutput>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// A block of bytes placed at a fixed address outside the main program block.
+pub struct SegmentOutput {
+    pub name: String,
+    pub start: u16,
+    pub bytes: Vec<u8>,
     pub pc_line: Vec<usize>,
 }
 
 fn strip_comments(input: &[u8]) -> Vec<u8> {
     // Support ';' (typical 6502) as line comment marker.
     // Strip from the first ';' to EOL, keep blank lines to preserve line numbers.
+    // A ';' inside a string or character literal is kept.
     let mut output = Vec::with_capacity(input.len());
     for line in input.split(|&b| b == b'\n') {
-        let comment_cut = line.iter().position(|&b| b == b';').unwrap_or(line.len());
+        let comment_cut = find_unquoted(line, b';').unwrap_or(line.len());
         let trimmed = &line[..comment_cut];
         // Trim trailing whitespace
         let keep_len = trimmed
@@ -36,6 +99,30 @@ fn strip_comments(input: &[u8]) -> Vec<u8> {
     output
 }
 
+/// Find the first `needle` that is not inside a `"..."` string or `'x'` character literal.
+fn find_unquoted(line: &[u8], needle: u8) -> Option<usize> {
+    let mut in_string = false;
+    let mut i = 0;
+    while i < line.len() {
+        let b = line[i];
+        if in_string {
+            if b == b'\\' {
+                i += 1;
+            } else if b == b'"' {
+                in_string = false;
+            }
+        } else if b == b'"' {
+            in_string = true;
+        } else if b == b'\'' && line.get(i + 2) == Some(&b'\'') {
+            i += 2;
+        } else if b == needle {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
 /// Translate 6502 assembly into machine code.
 ///
 /// # Examples
@@ -56,7 +143,13 @@ fn strip_comments(input: &[u8]) -> Vec<u8> {
 /// file.
 #[allow(dead_code)]
 pub fn assemble<R: Read, W: Write>(input: R, output: &mut W) -> AssembleResult {
-    let assembled = assemble_with_labels(input)?;
+    let assembled = assemble_with_labels(input).map_err(|diagnostics| {
+        diagnostics
+            .iter()
+            .map(Diagnostic::to_string)
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
 
     output
         .write_all(&assembled.bytes)
@@ -64,365 +157,1484 @@ pub fn assemble<R: Read, W: Write>(input: R, output: &mut W) -> AssembleResult {
 }
 
 /// Assemble and return both the program bytes and the discovered label table.
-pub fn assemble_with_labels<R: Read>(input: R) -> Result<AssembleOutput, String> {
+pub fn assemble_with_labels<R: Read>(input: R) -> Result<AssembleOutput, Vec<Diagnostic>> {
     assemble_with_labels_at(input, 0)
 }
 
 /// Assemble with a custom origin (initial program counter). Labels are emitted as absolute
 /// addresses starting at `origin`.
 pub fn assemble_with_labels_at<R: Read>(
-    mut input: R,
+    input: R,
     origin: u16,
-) -> Result<AssembleOutput, String> {
+) -> Result<AssembleOutput, Vec<Diagnostic>> {
+    assemble_with_segments_at(input, origin, &[], CpuKind::default(), &no_incbin_files)
+}
+
+/// [`IncbinReader`] for input that is not part of a project, so has no files to include.
+fn no_incbin_files(_line: usize, _name: &str) -> Result<Vec<u8>, String> {
+    Err("only project sources can include files".to_string())
+}
+
+/// Assemble with a custom origin for the `CODE` segment plus base addresses for additional named
+/// segments. Segments selected with `.segment NAME` that have no base here are placed right after
+/// the main `CODE` block unless they start with an `.org`.
+///
+/// `cpu` selects the instruction set: the 65C02 additions or the undocumented NMOS opcodes, and
+/// `files` reads the files named by `.incbin`.
+///
+/// Line errors do not stop assembly: every error found is returned, sorted by line.
+pub fn assemble_with_segments_at<R: Read>(
+    input: R,
+    origin: u16,
+    segment_bases: &[(&str, u16)],
+    cpu: CpuKind,
+    files: &IncbinReader,
+) -> Result<AssembleOutput, Vec<Diagnostic>> {
+    let (_, source) = prepare(input)?;
+    let texts: Vec<&str> = source.iter().map(|l| l.text.as_str()).collect();
+    let files = |line: usize, name: &str| files(source[line - 1].line, name);
+
+    let mut output = assemble_expanded(&texts, origin, segment_bases, cpu, &files)
+        .map_err(|diagnostics| locate_expanded(diagnostics, &source))?;
+    output.warnings = locate_expanded(std::mem::take(&mut output.warnings), &source);
+    let to_source = |pc_line: &mut Vec<usize>| {
+        for line_no in pc_line.iter_mut() {
+            *line_no = source[*line_no - 1].line;
+        }
+    };
+    to_source(&mut output.pc_line);
+    for segment in &mut output.segments {
+        to_source(&mut segment.pc_line);
+    }
+    Ok(output)
+}
+
+/// Assemble into a relocatable [`Object`] for [`link`]. The linker places the `CODE` block and
+/// every named segment; only blocks after an `.org` keep their address, so `*` is unavailable
+/// in `.fill` counts elsewhere. Symbols the input does not define become imports, and `files`
+/// reads the files named by `.incbin`.
+///
+/// Returns the object and any warnings, or every error found.
+#[allow(dead_code)]
+pub fn assemble_object<R: Read>(input: R, cpu: CpuKind, files: &IncbinReader) -> ObjectResult {
+    assemble_unit(input, cpu, files).result
+}
+
+/// Assemble each unit of a program into an [`Object`], as [`assemble_object`] does. A symbol
+/// that no unit and none of the `linked` objects define is reported as an error of the unit
+/// using it, so a misspelled name shows up with the other errors instead of at [`link`].
+///
+/// Returns the object and warnings, or every error found, of each unit in order.
+pub fn assemble_objects(
+    units: &[(&[u8], &IncbinReader)],
+    cpu: CpuKind,
+    linked: &[&Object],
+) -> Vec<ObjectResult> {
+    let assembled: Vec<UnitOutput> = units
+        .iter()
+        .map(|(input, files)| assemble_unit(*input, cpu, files))
+        .collect();
+    let mut defined: HashSet<String> = linked
+        .iter()
+        .flat_map(|object| &object.symbols)
+        .filter(|symbol| symbol.global)
+        .map(|symbol| symbol.name.clone())
+        .collect();
+    for unit in &assembled {
+        // A unit that stopped before its labels were known may define any name.
+        let Some(globals) = &unit.globals else {
+            return assembled.into_iter().map(|unit| unit.result).collect();
+        };
+        defined.extend(globals.iter().cloned());
+    }
+    assembled
+        .into_iter()
+        .map(|unit| {
+            let (object, warnings) = unit.result?;
+            let errors = undefined_symbols(&object, &defined);
+            if errors.is_empty() {
+                Ok((object, warnings))
+            } else {
+                Err(errors)
+            }
+        })
+        .collect()
+}
+
+/// An assembled unit and the global names it defines, which are known even when an error
+/// stops assembly, unless it stops before labels are gathered.
+struct UnitOutput {
+    result: ObjectResult,
+    globals: Option<HashSet<String>>,
+}
+
+fn assemble_unit<R: Read>(input: R, cpu: CpuKind, files: &IncbinReader) -> UnitOutput {
+    let (input, source) = match prepare(input) {
+        Ok(prepared) => prepared,
+        Err(diagnostics) => {
+            return UnitOutput {
+                result: Err(diagnostics),
+                globals: None,
+            };
+        }
+    };
+    let texts: Vec<&str> = source.iter().map(|l| l.text.as_str()).collect();
+    let files = |line: usize, name: &str| files(source[line - 1].line, name);
+
+    let mut globals = HashSet::new();
+    let result = assemble_expanded_object(&texts, cpu, &files, &mut globals)
+        .map(|(mut object, warnings)| {
+            for section in &mut object.sections {
+                for line_no in &mut section.lines {
+                    *line_no = source[*line_no - 1].line;
+                }
+            }
+            for symbol in &mut object.symbols {
+                symbol.line = source[symbol.line - 1].line;
+            }
+            object.source = String::from_utf8_lossy(&input)
+                .lines()
+                .map(str::to_string)
+                .collect();
+            (object, locate_expanded(warnings, &source))
+        })
+        .map_err(|diagnostics| locate_expanded(diagnostics, &source));
+    UnitOutput {
+        result,
+        globals: Some(globals),
+    }
+}
+
+/// An error for each use of a symbol `object` imports that is not in `defined`.
+fn undefined_symbols(object: &Object, defined: &HashSet<String>) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    for section in &object.sections {
+        for relocation in &section.relocations {
+            let Target::Symbol(name) = &relocation.target else {
+                continue;
+            };
+            if defined.contains(name) {
+                continue;
+            }
+            let issue = Issue::error(format!("Undefined symbol '{}'", name));
+            let issue = match closest(name, defined.iter().map(String::as_str)) {
+                Some(known) => issue.with_hint(format!("did you mean '{}'?", known)),
+                None => issue,
+            };
+            let line = section.lines[relocation.offset as usize];
+            let text = object
+                .source
+                .get(line.wrapping_sub(1))
+                .map_or("", String::as_str);
+            errors.push(issue.locate(line, text, name));
+        }
+    }
+    errors.sort_by_key(|d| d.line);
+    errors
+}
+
+/// Read the input and return it with its comment-stripped, macro-expanded and conditionally
+/// assembled lines.
+fn prepare<R: Read>(mut input: R) -> Result<(Vec<u8>, Vec<SourceLine>), Vec<Diagnostic>> {
     let mut buf = Vec::<u8>::new();
     input
         .read_to_end(&mut buf)
-        .map_err(|_| "Error reading input".to_owned())?;
-    let buf = strip_comments(&buf);
+        .map_err(|_| vec![Issue::error("Error reading input").into_diagnostic()])?;
+    let stripped = strip_comments(&buf);
+    let mut source = expand_macros(&stripped).map_err(|issue| vec![issue.into_diagnostic()])?;
+    apply_conditionals(&mut source).map_err(|diagnostics| locate_expanded(diagnostics, &source))?;
+    Ok((buf, source))
+}
 
-    let mut labels: HashMap<String, u16> = HashMap::new();
-    let mut instructions: Vec<(usize, String)> = Vec::new();
+/// Map diagnostics from expanded-line numbering back to input lines, recording the macro body
+/// lines involved when a line came from a macro expansion.
+fn locate_expanded(mut diagnostics: Vec<Diagnostic>, source: &[SourceLine]) -> Vec<Diagnostic> {
+    for diagnostic in &mut diagnostics {
+        let Some(line) = source.get(diagnostic.line.wrapping_sub(1)) else {
+            continue;
+        };
+        diagnostic.line = line.line;
+        if !line.macro_trace.is_empty() {
+            // The span points into the expansion, not the invocation the line maps back to.
+            diagnostic.span = None;
+            diagnostic.macro_trace = line.macro_trace.clone();
+        }
+    }
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
+}
+
+/// Run both passes over macro-expanded lines. Line numbers in diagnostics and `pc_line` refer to
+/// positions in `lines`.
+fn assemble_expanded(
+    lines: &[&str],
+    origin: u16,
+    segment_bases: &[(&str, u16)],
+    cpu: CpuKind,
+    files: &IncbinReader,
+) -> Result<AssembleOutput, Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let pass = first_pass(
+        lines,
+        Some(origin),
+        segment_bases,
+        cpu,
+        files,
+        &mut diagnostics,
+    );
+    let FirstPass {
+        mut labels,
+        constants,
+        floating_labels,
+        instructions,
+        blobs,
+        layout,
+        zero_page,
+        ..
+    } = pass;
+    let starts = match layout.resolve_starts() {
+        Ok(starts) => starts,
+        Err(e) => {
+            diagnostics.push(Issue::error(e).into_diagnostic());
+            return Err(diagnostics);
+        }
+    };
+    for (name, piece, offset) in floating_labels {
+        labels.insert(name, starts[piece].wrapping_add(offset));
+    }
+
+    let symbols = Symbols {
+        labels: &labels,
+        pieces: None,
+        zero_page: &zero_page,
+    };
+    let fixed: Vec<Option<u16>> = starts.iter().copied().map(Some).collect();
+    let (pieces, warnings) =
+        assemble_second_pass(instructions, &blobs, &symbols, &fixed, lines, cpu).map_err(
+            |errors| {
+                diagnostics.extend(errors);
+                std::mem::take(&mut diagnostics)
+            },
+        )?;
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    let mut segments = Vec::new();
+    let mut main = None;
+    for (idx, piece) in pieces.into_iter().enumerate() {
+        if idx == 0 {
+            main = Some((piece.bytes, piece.lines));
+        } else if !piece.bytes.is_empty() {
+            segments.push(SegmentOutput {
+                name: layout.pieces[idx].segment.clone(),
+                start: starts[idx],
+                bytes: piece.bytes,
+                pc_line: piece.lines,
+            });
+        }
+    }
+    let (program, pc_line) = main.unwrap_or_default();
+
+    Ok(AssembleOutput {
+        bytes: program,
+        labels,
+        constants,
+        pc_line,
+        segments,
+        warnings,
+    })
+}
+
+/// Run both passes for an object: every piece without an `.org` stays unplaced, its labels
+/// become offsets and the operands that use them relocations. The global names the first pass
+/// defines are added to `globals`.
+fn assemble_expanded_object(
+    lines: &[&str],
+    cpu: CpuKind,
+    files: &IncbinReader,
+    globals: &mut HashSet<String>,
+) -> Result<(Object, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let pass = first_pass(lines, None, &[], cpu, files, &mut diagnostics);
+    let names = pass
+        .labels
+        .keys()
+        .chain(pass.floating_labels.iter().map(|(name, ..)| name));
+    globals.extend(names.filter(|name| is_global(name)).cloned());
+    let FirstPass {
+        mut labels,
+        constants,
+        floating_labels,
+        instructions,
+        blobs,
+        layout,
+        defined_at,
+        zero_page,
+        ..
+    } = pass;
+    let mut pieces = HashMap::new();
+    for (name, piece, offset) in floating_labels {
+        labels.insert(name.clone(), offset);
+        pieces.insert(name, piece);
+    }
+
+    let symbols = Symbols {
+        labels: &labels,
+        pieces: Some(&pieces),
+        zero_page: &zero_page,
+    };
+    let starts: Vec<Option<u16>> = layout.pieces.iter().map(|piece| piece.start).collect();
+    let (outputs, warnings) =
+        assemble_second_pass(instructions, &blobs, &symbols, &starts, lines, cpu).map_err(
+            |errors| {
+                diagnostics.extend(errors);
+                std::mem::take(&mut diagnostics)
+            },
+        )?;
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let sections = outputs
+        .into_iter()
+        .zip(layout.pieces)
+        .map(|(output, piece)| Section {
+            segment: piece.segment,
+            start: piece.start,
+            bytes: output.bytes,
+            lines: output.lines,
+            relocations: output.relocations,
+        })
+        .collect();
+    let mut symbols: Vec<Symbol> = labels
+        .iter()
+        .map(|(name, value)| Symbol {
+            name: name.clone(),
+            value: *value,
+            section: pieces.get(name).copied(),
+            constant: constants.contains(name),
+            global: is_global(name),
+            line: defined_at[name],
+        })
+        .collect();
+    symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let object = Object {
+        cpu,
+        sections,
+        symbols,
+        source: Vec::new(),
+    };
+    Ok((object, warnings))
+}
+
+/// Whether a label or constant is visible to other objects; local and anonymous labels are not.
+fn is_global(name: &str) -> bool {
+    !name.contains('.') && !name.starts_with(ANON_LABEL_PREFIX)
+}
+
+/// Gather labels and measure every line. The `CODE` block starts at `origin`, or is left for the
+/// linker to place when there is none.
+fn first_pass<'a>(
+    lines: &[&str],
+    origin: Option<u16>,
+    segment_bases: &[(&str, u16)],
+    cpu: CpuKind,
+    files: &'a IncbinReader,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> FirstPass<'a> {
+    let qualified = qualify_labels(lines, diagnostics);
+    let mut pass = FirstPass {
+        cpu,
+        files,
+        zero_page: zero_page_labels(&qualified),
+        bases: segment_bases
+            .iter()
+            .map(|(name, base)| (name.to_ascii_uppercase(), *base))
+            .collect(),
+        labels: HashMap::new(),
+        constants: HashSet::new(),
+        floating_labels: Vec::new(),
+        defined_at: HashMap::new(),
+        instructions: Vec::new(),
+        blobs: HashMap::new(),
+        layout: SegmentLayout::new(origin),
+    };
+    for (idx, raw) in qualified.iter().enumerate() {
+        if let Err(diagnostic) = pass.line(idx + 1, raw) {
+            let instr = split_label_and_instr(raw).1;
+            diagnostics.push(diagnostic.locate(idx + 1, lines[idx], &instr));
+        }
+    }
+    pass
+}
 
-    // First pass: gather labels and measure instruction sizes
-    let mut pc: u16 = origin;
-    for (idx, line) in buf.split(|&b| b == b'\n').enumerate() {
-        let raw = String::from_utf8_lossy(line);
-        let (label_opt, instr_raw) = split_label_and_instr(raw.as_ref());
+/// State gathered by the first pass: labels, segment layout and the lines left to encode.
+struct FirstPass<'a> {
+    cpu: CpuKind,
+    files: &'a IncbinReader<'a>,
+    /// Labels defined in the `ZP` segment, including those further down.
+    zero_page: HashSet<String>,
+    bases: HashMap<String, u16>,
+    labels: HashMap<String, u16>,
+    constants: HashSet<String>,
+    floating_labels: Vec<(String, usize, u16)>,
+    /// Line defining each label and constant.
+    defined_at: HashMap<String, usize>,
+    instructions: Vec<(usize, String, usize)>,
+    /// Bytes read by the `.incbin` on each line.
+    blobs: HashMap<usize, Vec<u8>>,
+    layout: SegmentLayout,
+}
+
+impl FirstPass<'_> {
+    fn line(&mut self, line_no: usize, raw: &str) -> Result<(), Issue> {
+        let (label_opt, instr_raw) = split_label_and_instr(raw);
         let instr = instr_raw.trim().to_string();
         if let Some(label) = label_opt {
-            if labels.insert(label.clone(), pc).is_some() {
-                return Err(format!("Duplicate label '{}' on line {}", label, idx + 1));
+            if self.labels.contains_key(&label)
+                || self.floating_labels.iter().any(|(n, _, _)| *n == label)
+            {
+                return Err(Issue::error(format!("Duplicate label '{}'", label)).pointing_at(label));
+            }
+            self.defined_at.insert(label.clone(), line_no);
+            let piece = self.layout.current;
+            match self.layout.pieces[piece].start {
+                Some(start) => {
+                    let addr = start.wrapping_add(self.layout.pieces[piece].size);
+                    self.labels.insert(label, addr);
+                }
+                None => {
+                    let offset = self.layout.pieces[piece].size;
+                    self.floating_labels.push((label, piece, offset));
+                }
             }
         }
-        if instr.trim().is_empty() {
-            continue;
+        if instr.is_empty() {
+            return Ok(());
         }
         if let Some((name, value)) = parse_const(&instr) {
-            if labels.insert(name.clone(), value).is_some() {
-                return Err(format!(
-                    "Duplicate label/const '{}' on line {}",
-                    name,
-                    idx + 1
-                ));
+            if self.labels.insert(name.clone(), value).is_some() {
+                return Err(
+                    Issue::error(format!("Duplicate label/const '{}'", name)).pointing_at(name)
+                );
             }
-            continue;
+            self.defined_at.insert(name.clone(), line_no);
+            self.constants.insert(name);
+            return Ok(());
         }
-        let mnemonic = instr
-            .split_whitespace()
-            .next()
-            .map(|s| s.to_ascii_uppercase())
-            .unwrap_or_default();
-        let placeholder_repl =
-            if let Some((start, _, _)) = first_symbol_or_expr_after_mnemonic(&instr) {
-                let bytes = instr.as_bytes();
-                let has_immediate = bytes[..start].iter().any(|b| *b == b'#');
-                if is_branch(&mnemonic) {
-                    "0"
-                } else if has_immediate {
-                    "0"
-                } else if start > 0 && (bytes[start - 1] == b'>' || bytes[start - 1] == b'<') {
-                    "$00"
-                } else {
-                    "$0000"
-                }
-            } else if is_branch(&mnemonic) {
-                "0"
-            } else {
-                "$0000"
-            };
-        let placeholder = replace_first_symbol_or_expr(&instr, placeholder_repl);
-        let opcode = match parse_opcode_line(placeholder.as_bytes()) {
-            Ok((rem, opcode)) => {
-                if rem.iter().all(|b| b.is_ascii_whitespace()) {
-                    opcode
-                } else {
-                    return Err(format!("Parse error on line {}: {}", idx + 1, instr.trim()));
-                }
+        if let Some(name) = parse_segment(&instr)? {
+            let base = self.bases.get(&name).copied();
+            self.layout.select(&name, base);
+            return Ok(());
+        }
+        if let Some(expr) = parse_org(&instr) {
+            let addr =
+                evaluate_word(&expr, &self.labels, self.layout.pc()).map_err(|e| match e {
+                    ExprError::Undefined(_) => {
+                        Issue::error(format!("Origin '{}' must be defined before use", expr))
+                            .pointing_at(&expr)
+                    }
+                    e => expr_diagnostic(e, &expr, &self.labels),
+                })?;
+            self.layout.org(addr);
+            return Ok(());
+        }
+        if let Some(incbin) = parse_incbin(&instr)? {
+            let bytes = self.incbin(line_no, &incbin)?;
+            self.layout.advance(bytes.len())?;
+            self.blobs.insert(line_no, bytes);
+            self.instructions
+                .push((line_no, instr, self.layout.current));
+            return Ok(());
+        }
+        if let Some(directive) = parse_data_directive(&instr)? {
+            let size = directive.size(&self.labels, self.layout.pc())?;
+            self.layout.advance(size)?;
+            self.instructions
+                .push((line_no, instr, self.layout.current));
+            return Ok(());
+        }
+        let placeholder = match find_operand(&instr, self.cpu, &self.zero_page) {
+            Some(operand) => {
+                let mut out = instr.clone();
+                out.replace_range(operand.start..operand.end, operand.kind.placeholder());
+                out
             }
-            _ => return Err(format!("Parse error on line {}: {}", idx + 1, instr.trim())),
+            None => instr.clone(),
+        };
+        let opcode = match parse_opcode_line(placeholder.as_bytes()) {
+            Ok((rem, opcode)) if rem.iter().all(|b| b.is_ascii_whitespace()) => opcode,
+            _ => return Err(instruction_error(&instr, self.cpu)),
         };
         let mut scratch = Vec::new();
-        emit_opcode(opcode, &mut scratch)?;
-        pc = pc
-            .checked_add(scratch.len() as u16)
-            .ok_or_else(|| "Program too large".to_owned())?;
-        instructions.push((idx + 1, instr.trim().to_string()));
+        emit_opcode(opcode, self.cpu, &mut scratch)?;
+        self.layout.advance(scratch.len())?;
+        self.instructions
+            .push((line_no, instr, self.layout.current));
+        Ok(())
     }
 
-    let (program, pc_line) = assemble_second_pass(instructions, &labels, origin)?;
+    /// Read the part of the file an `.incbin` selects. The offset and length must resolve with
+    /// the labels known so far, like `.fill` counts.
+    fn incbin(&self, line_no: usize, incbin: &Incbin) -> Result<Vec<u8>, Issue> {
+        let data = (self.files)(line_no, &incbin.file).map_err(|e| {
+            Issue::error(format!("Cannot read '{}': {}", incbin.file, e)).pointing_at(&incbin.file)
+        })?;
+        let argument = |what: &str, expr: &str| match evaluate(expr, &self.labels, self.layout.pc())
+        {
+            Ok(value) => usize::try_from(value).map_err(|_| {
+                Issue::error(format!("Incbin {} '{}' must not be negative", what, expr))
+                    .pointing_at(expr)
+            }),
+            Err(ExprError::Undefined(_)) => Err(Issue::error(format!(
+                "Incbin {} '{}' must be defined before use",
+                what, expr
+            ))
+            .pointing_at(expr)),
+            Err(e) => Err(expr_diagnostic(e, expr, &self.labels)),
+        };
+        let offset = match &incbin.offset {
+            Some(expr) => argument("offset", expr)?,
+            None => 0,
+        };
+        if offset > data.len() {
+            return Err(Issue::error(format!(
+                "Offset {} is past the end of '{}' ({} bytes)",
+                offset,
+                incbin.file,
+                data.len()
+            ))
+            .pointing_at(incbin.offset.as_deref().unwrap_or_default()));
+        }
+        let end = match &incbin.length {
+            Some(expr) => {
+                let length = argument("length", expr)?;
+                offset
+                    .checked_add(length)
+                    .filter(|end| *end <= data.len())
+                    .ok_or_else(|| {
+                        Issue::error(format!(
+                            "Length {} at offset {} runs past the end of '{}' ({} bytes)",
+                            length,
+                            offset,
+                            incbin.file,
+                            data.len()
+                        ))
+                        .pointing_at(expr)
+                    })?
+            }
+            None => data.len(),
+        };
+        Ok(data[offset..end].to_vec())
+    }
+}
 
-    Ok(AssembleOutput {
-        bytes: program,
-        labels,
-        pc_line,
+/// Explain why a line is not a valid instruction, suggesting a mnemonic or directive for typos.
+/// Only mnemonics `cpu` accepts are suggested, and documented ones win ties.
+fn instruction_error(instr: &str, cpu: CpuKind) -> Issue {
+    let word = instr.split_whitespace().next().unwrap_or(instr);
+    if word.starts_with('.') {
+        let diagnostic = Issue::error(format!("Unknown directive '{}'", word)).pointing_at(word);
+        return match closest(word, DIRECTIVES.iter().copied()) {
+            Some(directive) => diagnostic.with_hint(format!("did you mean {}?", directive)),
+            None => diagnostic,
+        };
+    }
+    if word.chars().all(|c| c.is_ascii_alphabetic())
+        && !mnemonic_names().any(|m| m.eq_ignore_ascii_case(word))
+    {
+        let diagnostic = Issue::error(format!("Unknown instruction '{}'", word)).pointing_at(word);
+        let suggestion = closest_preferring(
+            word,
+            mnemonic_names().filter(|m| is_documented_mnemonic(m)),
+            mnemonic_names()
+                .filter(|m| is_extension_mnemonic(cpu, m) && !is_documented_mnemonic(m)),
+        );
+        return match suggestion {
+            Some(mnemonic) => diagnostic.with_hint(format!("did you mean {}?", mnemonic)),
+            None => diagnostic,
+        };
+    }
+    Issue::error(format!("Invalid operand in '{}'", instr))
+}
+
+/// Report an expression error, pointing at an undefined symbol and suggesting a close match.
+fn expr_diagnostic(e: ExprError, expr: &str, labels: &HashMap<String, u16>) -> Issue {
+    let diagnostic = Issue::error(e.describe(expr));
+    match e {
+        ExprError::Undefined(name) => {
+            let known = labels
+                .keys()
+                .map(String::as_str)
+                .filter(|l| !l.starts_with(ANON_LABEL_PREFIX));
+            let diagnostic = match closest(&name, known) {
+                Some(label) => diagnostic.with_hint(format!("did you mean '{}'?", label)),
+                None => diagnostic,
+            };
+            diagnostic.pointing_at(name)
+        }
+        ExprError::Invalid(_) => diagnostic.pointing_at(expr),
+    }
+}
+
+/// Symbol values seen by the second pass. For an object, `pieces` names the labels whose
+/// values are offsets into a piece the linker places, and unknown symbols are imports.
+struct Symbols<'a> {
+    labels: &'a HashMap<String, u16>,
+    pieces: Option<&'a HashMap<String, usize>>,
+    zero_page: &'a HashSet<String>,
+}
+
+impl Symbols<'_> {
+    fn evaluate(&self, expr: &str, pc: Value) -> Result<Value, Issue> {
+        evaluate_value(expr, self.labels, self.pieces, Some(pc))
+            .map_err(|e| expr_diagnostic(e, expr, self.labels))
+    }
+}
+
+/// The piece the second pass is emitting into and its address, unless the linker decides it.
+#[derive(Clone, Copy)]
+struct Location {
+    piece: usize,
+    start: Option<u16>,
+}
+
+impl Location {
+    /// Address `offset` bytes into the piece, if fixed.
+    fn address(self, offset: usize) -> Option<u16> {
+        self.start.map(|start| start.wrapping_add(offset as u16))
+    }
+
+    /// Value of `*` at `offset` bytes into the piece.
+    fn pc(self, offset: usize) -> Value {
+        match self.address(offset) {
+            Some(addr) => Value::constant(addr.into()),
+            None => Value::relocatable(offset as i64, Target::Section(self.piece)),
+        }
+    }
+}
+
+/// Relocation patching `value` in as `kind`, or as the byte chosen with `<`/`>`. `None` for
+/// constants.
+fn relocation(value: Value, kind: RelocKind, offset: usize) -> Option<Relocation> {
+    let kind = match value.part {
+        Part::Word => kind,
+        Part::Low => RelocKind::Low,
+        Part::High => RelocKind::High,
+    };
+    Some(Relocation {
+        offset: offset as u16,
+        kind,
+        target: value.target?,
+        addend: value.offset as i32,
     })
 }
 
+/// Second pass: resolve labels and emit final bytes into pieces starting at `starts`, where
+/// `None` leaves the address to the linker. Returns the output of each piece and any warnings,
+/// or every line error found.
 fn assemble_second_pass(
-    instructions: Vec<(usize, String)>,
-    labels: &HashMap<String, u16>,
-    origin: u16,
-) -> Result<(Vec<u8>, Vec<usize>), String> {
-    // Second pass: resolve labels and emit final bytes
-    let mut program: Vec<u8> = Vec::new();
-    let mut pc_line: Vec<usize> = Vec::new();
-    let mut pc: u16 = origin;
-    for (line_no, instr) in instructions {
+    instructions: Vec<(usize, String, usize)>,
+    blobs: &HashMap<usize, Vec<u8>>,
+    symbols: &Symbols,
+    starts: &[Option<u16>],
+    lines: &[&str],
+    cpu: CpuKind,
+) -> Result<(Vec<PieceOutput>, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let mut pieces = vec![PieceOutput::default(); starts.len()];
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    for (line_no, instr, piece) in instructions {
         if instr.is_empty() {
             continue;
         }
-        let resolved = if let Some((start, end, expr)) = first_symbol_or_expr_after_mnemonic(&instr)
-        {
-            let target = evaluate_expression(&expr, labels).ok_or_else(|| {
-                format!(
-                    "Unknown label or invalid expression '{}' on line {}",
-                    expr, line_no
-                )
-            })?;
-            let mnemonic = instr
-                .split_whitespace()
-                .next()
-                .map(|s| s.to_ascii_uppercase())
-                .unwrap_or_default();
-            let bytes = instr.as_bytes();
-            let mut replace_start = start;
-            let has_immediate = bytes[..start].iter().any(|b| *b == b'#');
-            let replacement = if is_branch(&mnemonic) {
-                let offset = target as i32 - (pc as i32 + 2);
-                if !(i8::MIN as i32..=i8::MAX as i32).contains(&offset) {
+        let at = Location {
+            piece,
+            start: starts[piece],
+        };
+        let output = &mut pieces[piece];
+        let before = output.bytes.len();
+        let relocations_before = output.relocations.len();
+        let mut line_warnings = Vec::new();
+        let result = match blobs.get(&line_no) {
+            Some(blob) => {
+                output.bytes.extend_from_slice(blob);
+                Ok(())
+            }
+            None => encode_line(
+                &instr,
+                symbols,
+                at,
+                cpu,
+                &mut output.bytes,
+                &mut output.relocations,
+                &mut line_warnings,
+            ),
+        };
+        let text = lines[line_no - 1];
+        warnings.extend(
+            line_warnings
+                .into_iter()
+                .map(|w| w.locate(line_no, text, &instr)),
+        );
+        match result {
+            Ok(()) => output
+                .lines
+                .extend(std::iter::repeat_n(line_no, output.bytes.len() - before)),
+            Err(diagnostic) => {
+                output.bytes.truncate(before);
+                output.relocations.truncate(relocations_before);
+                errors.push(diagnostic.locate(line_no, text, &instr));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((pieces, warnings))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Encode one line into `program`, the bytes of the piece at `at`.
+fn encode_line(
+    instr: &str,
+    symbols: &Symbols,
+    at: Location,
+    cpu: CpuKind,
+    program: &mut Vec<u8>,
+    relocations: &mut Vec<Relocation>,
+    warnings: &mut Vec<Issue>,
+) -> Result<(), Issue> {
+    if let Some(directive) = parse_data_directive(instr)? {
+        return directive.emit(symbols, at, program, relocations);
+    }
+    let before = program.len();
+    let (resolved, relocation) = match find_operand(instr, cpu, symbols.zero_page) {
+        Some(operand) => {
+            let (replacement, relocation) = operand.resolve(symbols, at.pc(before), warnings)?;
+            let mut out = instr.to_string();
+            out.replace_range(operand.start..operand.end, &replacement);
+            (out, relocation)
+        }
+        None => (instr.to_string(), None),
+    };
+
+    match parse_opcode_line(resolved.as_bytes()) {
+        Ok((rem, opcode)) if rem.iter().all(|b| b.is_ascii_whitespace()) => {
+            emit_opcode(opcode, cpu, program)?;
+            // The operand always follows the opcode byte, even when a zero-page form falls
+            // back to an absolute one.
+            if let Some(relocation) = relocation {
+                relocations.push(Relocation {
+                    offset: (before + 1) as u16,
+                    ..relocation
+                });
+            }
+            Ok(())
+        }
+        _ => Err(instruction_error(instr, cpu)),
+    }
+}
+
+/// A contiguous run of output within a segment. A new piece starts whenever a segment is first
+/// selected or moved with `.org`.
+struct SegmentPiece {
+    segment: String,
+    start: Option<u16>,
+    size: u16,
+}
+
+/// Tracks the active segment and its location counter across the first pass.
+struct SegmentLayout {
+    pieces: Vec<SegmentPiece>,
+    active_piece: HashMap<String, usize>,
+    current: usize,
+}
+
+impl SegmentLayout {
+    fn new(origin: Option<u16>) -> Self {
+        let mut active_piece = HashMap::new();
+        active_piece.insert(DEFAULT_SEGMENT.to_string(), 0);
+        Self {
+            pieces: vec![SegmentPiece {
+                segment: DEFAULT_SEGMENT.to_string(),
+                start: origin,
+                size: 0,
+            }],
+            active_piece,
+            current: 0,
+        }
+    }
+
+    fn select(&mut self, name: &str, base: Option<u16>) {
+        if let Some(&piece) = self.active_piece.get(name) {
+            self.current = piece;
+            return;
+        }
+        self.push_piece(name.to_string(), base);
+    }
+
+    fn org(&mut self, addr: u16) {
+        let segment = self.pieces[self.current].segment.clone();
+        self.push_piece(segment, Some(addr));
+    }
+
+    fn push_piece(&mut self, segment: String, start: Option<u16>) {
+        self.pieces.push(SegmentPiece {
+            segment: segment.clone(),
+            start,
+            size: 0,
+        });
+        self.current = self.pieces.len() - 1;
+        self.active_piece.insert(segment, self.current);
+    }
+
+    /// Current location counter, if the active piece has a fixed address yet.
+    fn pc(&self) -> Option<u16> {
+        let piece = &self.pieces[self.current];
+        piece.start.map(|start| start.wrapping_add(piece.size))
+    }
+
+    fn advance(&mut self, len: usize) -> AssembleResult {
+        let piece = &mut self.pieces[self.current];
+        let end = piece.start.unwrap_or(0) as usize + piece.size as usize + len;
+        if end > 0x10000 {
+            return Err(format!("Segment '{}' exceeds 64 KB", piece.segment));
+        }
+        piece.size += len as u16;
+        Ok(())
+    }
+
+    /// Place floating pieces after the main `CODE` block and reject overlapping pieces. The byte
+    /// after `CODE` stays free for the halt opcode the machine appends to the program.
+    fn resolve_starts(&self) -> Result<Vec<u16>, String> {
+        let mut cursor =
+            self.pieces[0].start.unwrap_or(0) as usize + self.pieces[0].size as usize + 1;
+        let mut starts = Vec::with_capacity(self.pieces.len());
+        for piece in &self.pieces {
+            let start = match piece.start {
+                Some(start) => start as usize,
+                None => {
+                    let start = cursor;
+                    cursor += piece.size as usize;
+                    start
+                }
+            };
+            if start + piece.size as usize > 0x10000 {
+                return Err(format!("Segment '{}' exceeds 64 KB", piece.segment));
+            }
+            starts.push(start as u16);
+        }
+        for (i, a) in self.pieces.iter().enumerate() {
+            for (j, b) in self.pieces.iter().enumerate().skip(i + 1) {
+                let (a_start, b_start) = (starts[i] as usize, starts[j] as usize);
+                let (a_end, b_end) = (a_start + a.size as usize, b_start + b.size as usize);
+                if a.size > 0 && b.size > 0 && a_start < b_end && b_start < a_end {
                     return Err(format!(
-                        "Branch out of range to '{}' on line {}",
-                        expr, line_no
+                        "Segment '{}' at ${:04X}-${:04X} overlaps segment '{}' at ${:04X}-${:04X}",
+                        b.segment,
+                        b_start,
+                        b_end - 1,
+                        a.segment,
+                        a_start,
+                        a_end - 1
                     ));
                 }
-                offset.to_string()
-            } else if start > 0 && bytes[start - 1] == b'>' {
-                replace_start -= 1;
-                format!("${:02X}", (target >> 8) as u8)
-            } else if start > 0 && bytes[start - 1] == b'<' {
-                replace_start -= 1;
-                format!("${:02X}", target as u8)
-            } else if has_immediate {
-                format!("${:02X}", target as u8)
-            } else {
-                format!("${:04X}", target)
-            };
+            }
+        }
+        Ok(starts)
+    }
+}
+
+fn parse_segment(line: &str) -> Result<Option<String>, String> {
+    let trimmed = line.trim();
+    let Some(rest) = strip_directive(trimmed, ".segment") else {
+        return Ok(None);
+    };
+    let name = rest.trim().trim_matches('"');
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("invalid segment name '{}'", rest.trim()));
+    }
+    Ok(Some(name.to_ascii_uppercase()))
+}
+
+fn parse_org(line: &str) -> Option<String> {
+    strip_directive(line.trim(), ".org").map(|rest| rest.trim().to_string())
+}
 
-            let mut out = instr.clone();
-            out.replace_range(replace_start..end, &replacement);
-            out
+/// Strip a case-insensitive directive keyword followed by whitespace.
+fn strip_directive<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let head = line.get(..keyword.len())?;
+    let rest = &line[keyword.len()..];
+    if head.eq_ignore_ascii_case(keyword) && rest.starts_with(char::is_whitespace) {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Blank out lines excluded by `.if`/`.ifdef`/`.ifndef` ... `.else` ... `.endif`. Conditions are
+/// evaluated top to bottom against the `.const` values defined so far in active code, so a
+/// condition can use constants from `chipcade.inc` or build defines but not labels.
+fn apply_conditionals(lines: &mut [SourceLine]) -> Result<(), Vec<Diagnostic>> {
+    struct Cond {
+        line: usize,
+        parent_active: bool,
+        taken: bool,
+        in_else: bool,
+    }
+
+    let mut consts: HashMap<String, u16> = HashMap::new();
+    let mut stack: Vec<Cond> = Vec::new();
+    let mut diagnostics = Vec::new();
+    for (idx, line) in lines.iter_mut().enumerate() {
+        let line_no = idx + 1;
+        let active = stack
+            .last()
+            .is_none_or(|c| c.parent_active && c.taken != c.in_else);
+        let trimmed = line.text.trim();
+        let condition = if let Some(expr) = strip_directive(trimmed, ".if") {
+            Some(
+                active && {
+                    match evaluate(expr, &consts, None) {
+                        Ok(value) => value != 0,
+                        Err(e) => {
+                            let diagnostic = match e {
+                                ExprError::Undefined(name) => Issue::error(format!(
+                                    "Condition '{}' must use constants defined before this line",
+                                    expr.trim()
+                                ))
+                                .pointing_at(name),
+                                e => expr_diagnostic(e, expr, &consts),
+                            };
+                            diagnostics.push(diagnostic.locate(line_no, &line.text, trimmed));
+                            false
+                        }
+                    }
+                },
+            )
         } else {
-            instr.clone()
+            strip_directive(trimmed, ".ifdef")
+                .map(|name| consts.contains_key(name.trim()))
+                .or_else(|| {
+                    strip_directive(trimmed, ".ifndef")
+                        .map(|name| !consts.contains_key(name.trim()))
+                })
         };
 
-        match parse_opcode_line(resolved.as_bytes()) {
-            Ok((rem, opcode)) => {
-                if rem.iter().all(|b| b.is_ascii_whitespace()) {
-                    let before = program.len();
-                    emit_opcode(opcode, &mut program)?;
-                    let emitted = program.len() - before;
-                    pc_line.extend(std::iter::repeat(line_no).take(emitted));
-                    pc = pc
-                        .checked_add((program.len() - before) as u16)
-                        .ok_or_else(|| "Program too large".to_owned())?;
-                } else {
-                    return Err(format!(
-                        "Parse error on line {}: {}",
-                        line_no,
-                        resolved.trim()
-                    ));
-                }
+        if let Some(taken) = condition {
+            stack.push(Cond {
+                line: line_no,
+                parent_active: active,
+                taken,
+                in_else: false,
+            });
+        } else if trimmed.eq_ignore_ascii_case(".else") {
+            match stack.last_mut() {
+                Some(cond) if cond.in_else => diagnostics
+                    .push(Issue::error("duplicate .else").locate(line_no, &line.text, trimmed)),
+                Some(cond) => cond.in_else = true,
+                None => diagnostics
+                    .push(Issue::error(".else without .if").locate(line_no, &line.text, trimmed)),
+            }
+        } else if trimmed.eq_ignore_ascii_case(".endif") {
+            if stack.pop().is_none() {
+                diagnostics
+                    .push(Issue::error(".endif without .if").locate(line_no, &line.text, trimmed));
             }
-            _ => {
-                return Err(format!(
-                    "Parse error on line {}: {}",
-                    line_no,
-                    resolved.trim()
-                ));
+        } else if active {
+            if let Some((name, value)) = parse_const(trimmed) {
+                consts.insert(name, value);
             }
+            continue;
         }
+        line.text.clear();
+    }
+    for cond in stack {
+        let text = &lines[cond.line - 1].text;
+        diagnostics.push(Issue::error(".if without .endif").locate(cond.line, text, text));
+    }
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(diagnostics)
     }
-
-    Ok((program, pc_line))
 }
 
-fn split_label_and_instr(line: &str) -> (Option<String>, String) {
-    if let Some(colon_pos) = line.find(':') {
-        let (left, right) = line.split_at(colon_pos);
-        let label = left.trim();
-        if !label.is_empty() {
-            return (Some(label.to_string()), right[1..].to_string());
+/// Rewrite local (`.name`, `@name`) and anonymous (`+`, `-`) labels into unique global names so
+/// the two passes only ever see one flat label namespace. Local labels become `Global.name`,
+/// scoped to the closest preceding global label; anonymous labels become `ANON_LABEL_PREFIX<n>`.
+/// Lines that cannot be rewritten are reported and left blank.
+fn qualify_labels(lines: &[&str], diagnostics: &mut Vec<Diagnostic>) -> Vec<String> {
+    // Collect anonymous label definitions up front so `+` can refer forward.
+    let anon_defs: Vec<(usize, char)> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, line)| split_anonymous_label(line).map(|(dir, _)| (idx, dir)))
+        .collect();
+
+    let mut scope: Option<String> = None;
+    let mut out = Vec::with_capacity(lines.len());
+    for (idx, line) in lines.iter().enumerate() {
+        match qualify_line(line, idx, &anon_defs, &mut scope) {
+            Ok(qualified) => out.push(qualified),
+            Err(diagnostic) => {
+                diagnostics.push(diagnostic.locate(idx + 1, line, line));
+                out.push(String::new());
+            }
         }
     }
-    (None, line.to_string())
+    out
 }
 
-fn replace_first_symbol_or_expr(line: &str, replacement: &str) -> String {
-    if let Some((start, end, _expr)) = first_symbol_or_expr_after_mnemonic(line) {
-        let mut out = line.to_string();
-        let mut replace_start = start;
-        let bytes = line.as_bytes();
-        if start > 0 && (bytes[start - 1] == b'>' || bytes[start - 1] == b'<') {
-            replace_start -= 1;
+fn qualify_line(
+    line: &str,
+    idx: usize,
+    anon_defs: &[(usize, char)],
+    scope: &mut Option<String>,
+) -> Result<String, Issue> {
+    let (label, instr) = if let Some((_, rest)) = split_anonymous_label(line) {
+        let n = anon_defs.partition_point(|&(l, _)| l < idx);
+        (
+            Some(format!("{}{}", ANON_LABEL_PREFIX, n)),
+            rest.to_string(),
+        )
+    } else {
+        match split_label_and_instr(line) {
+            (Some(label), rest) => {
+                let label = if let Some(local) = local_label_name(&label) {
+                    qualify_local(scope.as_deref(), local)?
+                } else {
+                    if !label.contains('.') {
+                        *scope = Some(label.clone());
+                    }
+                    label
+                };
+                (Some(label), rest)
+            }
+            (None, rest) => (None, rest),
         }
-        out.replace_range(replace_start..end, replacement);
-        out
+    };
+
+    let instr = qualify_operands(&instr, scope.as_deref(), |dir, count| {
+        // `+` counts forward from the next line, `-` backward including this one.
+        let mut candidates = anon_defs
+            .iter()
+            .enumerate()
+            .filter(|(_, (l, d))| *d == dir && if dir == '+' { *l > idx } else { *l <= idx })
+            .map(|(n, _)| n);
+        let target = if dir == '+' {
+            candidates.nth(count - 1)
+        } else {
+            candidates.rev().nth(count - 1)
+        }?;
+        Some(format!("{}{}", ANON_LABEL_PREFIX, target))
+    })?;
+    Ok(match label {
+        Some(label) => format!("{}: {}", label, instr),
+        None => instr,
+    })
+}
+
+/// Recognise an anonymous label definition (`+`, `-`, `+:` or `-:`) at the start of a line.
+fn split_anonymous_label(line: &str) -> Option<(char, &str)> {
+    let trimmed = line.trim_start();
+    let mut chars = trimmed.chars();
+    let dir = chars.next().filter(|c| *c == '+' || *c == '-')?;
+    let rest = chars.as_str();
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some((dir, rest))
     } else {
-        line.to_string()
+        None
     }
 }
 
-fn first_symbol_or_expr_after_mnemonic(line: &str) -> Option<(usize, usize, String)> {
-    // Skip the mnemonic (first token)
-    let mut iter = line.splitn(2, char::is_whitespace);
-    let _mnemonic = iter.next()?;
-    let rest = iter.next().unwrap_or("");
-    let offset = line.len() - rest.len();
+fn local_label_name(label: &str) -> Option<&str> {
+    label
+        .strip_prefix('.')
+        .or_else(|| label.strip_prefix('@'))
+        .filter(|name| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+fn qualify_local(scope: Option<&str>, local: &str) -> Result<String, Issue> {
+    let scope = scope.ok_or_else(|| {
+        Issue::error(format!(
+            "Local label '{}' has no enclosing global label",
+            local
+        ))
+        .pointing_at(local)
+    })?;
+    Ok(format!("{}.{}", scope, local))
+}
+
+/// Rewrite local and anonymous label references in the operand part of an instruction or
+/// directive. `resolve_anon` maps a direction and count (`--` is 2) to the generated name.
+fn qualify_operands(
+    instr: &str,
+    scope: Option<&str>,
+    resolve_anon: impl Fn(char, usize) -> Option<String>,
+) -> Result<String, Issue> {
+    let trimmed = instr.trim_start();
+    let head_len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    let (head, operand) = trimmed.split_at(head_len);
+    if operand.trim().is_empty() || parse_const(trimmed).is_some() {
+        return Ok(instr.to_string());
+    }
+
+    // `BNE -`, `JMP ++`, `LDA -,X`
+    let op = operand.trim();
+    let run = op.len() - op.trim_start_matches(['+', '-']).len();
+    let tail = &op[run..];
+    if run > 0
+        && op[..run].chars().all(|c| c == op.as_bytes()[0] as char)
+        && (tail.is_empty() || tail.trim_start().starts_with(','))
+    {
+        let dir = op.as_bytes()[0] as char;
+        let name = resolve_anon(dir, run).ok_or_else(|| {
+            Issue::error(format!("No anonymous label for '{}'", &op[..run])).pointing_at(&op[..run])
+        })?;
+        return Ok(format!("{} {}{}", head, name, tail));
+    }
 
-    let bytes = rest.as_bytes();
-    let mut in_quote = false;
+    let bytes = operand.as_bytes();
+    let mut out = String::with_capacity(instr.len());
+    out.push_str(head);
+    let mut copied = 0;
+    let mut quote: Option<u8> = None;
     let mut i = 0;
     while i < bytes.len() {
         let b = bytes[i];
-        if b == b'\'' {
-            in_quote = !in_quote;
-            i += 1;
+        if let Some(q) = quote {
+            if b == b'\\' {
+                i += 1;
+            } else if b == q {
+                quote = None;
+            }
+        } else if b == b'"' || b == b'\'' {
+            quote = Some(b);
+        } else if (b == b'.' || b == b'@')
+            && bytes
+                .get(i + 1)
+                .is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_')
+            && (i == 0 || !(bytes[i - 1].is_ascii_alphanumeric() || bytes[i - 1] == b'_'))
+        {
+            let mut end = i + 1;
+            while end < bytes.len() && (bytes[end].is_ascii_alphanumeric() || bytes[end] == b'_') {
+                end += 1;
+            }
+            out.push_str(&operand[copied..i]);
+            out.push_str(&qualify_local(scope, &operand[i + 1..end])?);
+            copied = end;
+            i = end;
             continue;
         }
-        if in_quote {
-            i += 1;
-            continue;
+        i += 1;
+    }
+    out.push_str(&operand[copied..]);
+    Ok(out)
+}
+
+fn split_label_and_instr(line: &str) -> (Option<String>, String) {
+    if let Some(colon_pos) = find_unquoted(line.as_bytes(), b':') {
+        let (left, right) = line.split_at(colon_pos);
+        let label = left.trim();
+        if !label.is_empty() {
+            return (Some(label.to_string()), right[1..].to_string());
         }
-        if b == b'(' {
-            // Check if this is an expression like (VRAM+VRAM_SIZE) vs addressing mode ($44,X)
-            // Look ahead to see if there's an operator before a comma or closing paren
-            let start = i;
-            let mut j = i + 1;
-            let mut paren_depth = 1;
-            let mut has_operator = false;
-
-            while j < bytes.len() && paren_depth > 0 {
-                let c = bytes[j];
-                if c == b'(' {
-                    paren_depth += 1;
-                } else if c == b')' {
-                    paren_depth -= 1;
-                } else if c == b',' && paren_depth == 1 {
-                    // Found comma at top level - this is addressing mode like ($44,X)
-                    break;
-                } else if (c == b'+' || c == b'-' || c == b'*' || c == b'/') && paren_depth == 1 {
-                    has_operator = true;
-                }
-                j += 1;
-            }
+    }
+    (None, line.to_string())
+}
 
-            // If we found operators and properly closed parens, it's an expression
-            if has_operator && paren_depth == 0 {
-                let expr = &rest[start..j];
-                return Some((offset + start, offset + j, expr.to_string()));
-            }
-            // Otherwise skip this, it's part of addressing mode
-            i += 1;
-            continue;
+/// The expression part of an instruction operand, located so it can be replaced in place.
+struct Operand {
+    start: usize,
+    end: usize,
+    expr: String,
+    kind: OperandKind,
+}
+
+/// How a resolved operand value is written back into the instruction text.
+#[derive(Clone, Copy)]
+enum OperandKind {
+    /// Relative branch target, written as a signed offset.
+    Branch,
+    /// `#expr`, written as its low byte.
+    Immediate,
+    /// `(expr,X)`, `(expr),Y`, a `<`/`>` byte selection or a `ZP` segment label.
+    ZeroPage,
+    /// Any other address, always written as absolute.
+    Word,
+}
+
+impl OperandKind {
+    /// Stand-in value for the first pass, with the same encoded size as the final operand.
+    fn placeholder(self) -> &'static str {
+        match self {
+            OperandKind::Branch => "0",
+            OperandKind::Immediate | OperandKind::ZeroPage => "$00",
+            OperandKind::Word => "$0000",
         }
-        if b.is_ascii_alphabetic() || b == b'_' {
-            let start = i;
-            i += 1;
-            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
-                i += 1;
-            }
-            let end = i;
-            let name = &rest[start..end];
-            // Skip tail letters in hex literals like #$0C that would otherwise
-            // be misread as symbols ("C" in this example).
-            let mut j = start;
-            while j > 0 && bytes[j - 1].is_ascii_hexdigit() {
-                j -= 1;
-            }
-            if j > 0 && bytes[j - 1] == b'$' {
-                continue;
-            }
-            // Skip binary literal prefixes like 0bXXXX
-            if start > 0 && (bytes[start - 1] == b'0') && (b == b'b' || b == b'B') {
-                i = end;
-                continue;
+    }
+}
+
+impl Operand {
+    /// Evaluate the expression and format it for the opcode parser. Immediate values that do not
+    /// fit in a byte are truncated with a warning. Values the linker has to fill in come back as
+    /// a placeholder and a relocation whose offset is still to be set.
+    fn resolve(
+        &self,
+        symbols: &Symbols,
+        pc: Value,
+        warnings: &mut Vec<Issue>,
+    ) -> Result<(String, Option<Relocation>), Issue> {
+        let expr = self.expr.trim();
+        let value = symbols.evaluate(expr, pc.clone())?;
+        if let OperandKind::Branch = self.kind {
+            if value.target != pc.target || value.part != Part::Word {
+                return Err(Issue::error(format!(
+                    "Branch to '{}' cannot reach another segment or object; use JMP",
+                    expr
+                ))
+                .pointing_at(expr));
             }
-            // Skip hex literal with $ prefix (e.g., #$DC)
-            if start > 0 && bytes[start - 1] == b'$' {
-                i = end;
-                continue;
+            let target = match value.target {
+                Some(_) => value.offset,
+                None => fit_word(value.offset)
+                    .map_err(|e| expr_diagnostic(e, expr, symbols.labels))?
+                    .into(),
+            };
+            let offset = target - (pc.offset + 2);
+            if !(i8::MIN as i64..=i8::MAX as i64).contains(&offset) {
+                return Err(
+                    Issue::error(format!("Branch out of range to '{}'", expr)).pointing_at(expr)
+                );
             }
-            // Skip hex literal prefixes like 0xXXXX
-            if start > 0 && (bytes[start - 1] == b'0') && (b == b'x' || b == b'X') {
-                i = end;
-                continue;
+            return Ok((offset.to_string(), None));
+        }
+        if value.target.is_some() {
+            let kind = match self.kind {
+                OperandKind::Immediate => RelocKind::Low,
+                OperandKind::ZeroPage => RelocKind::Byte,
+                _ => RelocKind::Word,
+            };
+            let placeholder = self.kind.placeholder().to_string();
+            return Ok((placeholder, relocation(value, kind, 0)));
+        }
+        let value = value.offset;
+        if let OperandKind::Immediate = self.kind {
+            if !(-128..=255).contains(&value) {
+                warnings.push(
+                    Issue::warning(format!(
+                        "Immediate value '{}' ({}) truncated to ${:02X}",
+                        expr, value, value as u8
+                    ))
+                    .pointing_at(expr),
+                );
             }
-            // Skip register/index tokens
-            if name.eq_ignore_ascii_case("A")
-                || name.eq_ignore_ascii_case("X")
-                || name.eq_ignore_ascii_case("Y")
-            {
-                continue;
+            return Ok((format!("${:02X}", value as u8), None));
+        }
+        let value = fit_word(value).map_err(|e| expr_diagnostic(e, expr, symbols.labels))?;
+        let operand = match self.kind {
+            OperandKind::ZeroPage => {
+                let value = u8::try_from(value).map_err(|_| {
+                    Issue::error(format!(
+                        "Value '{}' (${:04X}) does not fit in zero page",
+                        expr, value
+                    ))
+                    .pointing_at(expr)
+                })?;
+                format!("${:02X}", value)
             }
-            return Some((offset + start, offset + end, name.to_string()));
+            _ => format!("${:04X}", value),
+        };
+        Ok((operand, None))
+    }
+}
+
+/// Locate the expression in an instruction's operand, skipping the addressing-mode syntax around
+/// it. Plain numeric literals are left for the opcode parser, which picks zero-page or absolute
+/// forms from their width. On the 65C02, `(expr)` is a `(zp)` operand for every mnemonic. A
+/// plain operand using a label from `zero_page` takes the zero-page form when there is one.
+fn find_operand(instr: &str, cpu: CpuKind, zero_page: &HashSet<String>) -> Option<Operand> {
+    let lead = instr.len() - instr.trim_start().len();
+    let mnemonic_len = instr[lead..].find(char::is_whitespace)?;
+    let mnemonic = instr[lead..lead + mnemonic_len].to_ascii_uppercase();
+    let after = lead + mnemonic_len;
+    let op_start = after + (instr[after..].len() - instr[after..].trim_start().len());
+    let op_end = instr.trim_end().len();
+    let op = &instr[op_start..op_end];
+    if op.is_empty() || op.eq_ignore_ascii_case("A") {
+        return None;
+    }
+
+    let (start, end, kind) = if let Some(rest) = op.strip_prefix('#') {
+        (1, 1 + rest.len(), OperandKind::Immediate)
+    } else if let Some(close) = op.starts_with('(').then(|| matching_paren(op)).flatten() {
+        let tail = op[close + 1..].trim();
+        let inner = &op[1..close];
+        if index_register(tail) == Some('Y') {
+            (1, close, OperandKind::ZeroPage)
+        } else if tail.is_empty() && index_suffix(inner).is_some_and(|(_, r)| r == 'X') {
+            let (expr_end, _) = index_suffix(inner)?;
+            (1, 1 + expr_end, OperandKind::ZeroPage)
+        } else if tail.is_empty() && mnemonic == "JMP" {
+            (1, close, OperandKind::Word)
+        } else if tail.is_empty() && cpu == CpuKind::Cmos65c02 {
+            (1, close, OperandKind::ZeroPage)
+        } else if tail.is_empty() && is_plain_literal(inner.trim()) {
+            // `LDA ($4400)` is an addressing-mode error, not a parenthesised value.
+            return None;
+        } else {
+            plain_operand(op, &mnemonic)
         }
-        i += 1;
+    } else {
+        plain_operand(op, &mnemonic)
+    };
+
+    let expr = op[start..end].trim();
+    if expr.is_empty() || is_plain_literal(expr) {
+        return None;
     }
-    None
+    let (start, end) = (op_start + start, op_start + end);
+    let kind = match kind {
+        OperandKind::Word
+            if !op.starts_with('(')
+                && uses_label(expr, zero_page)
+                && has_zero_page_form(instr, start..end, cpu) =>
+        {
+            OperandKind::ZeroPage
+        }
+        kind => kind,
+    };
+    Some(Operand {
+        start,
+        end,
+        expr: expr.to_string(),
+        kind,
+    })
 }
 
-fn evaluate_expression(expr: &str, labels: &HashMap<String, u16>) -> Option<u16> {
-    let expr = expr.trim();
+/// Labels defined in the `ZP` segment of `lines`. A label's segment only depends on the
+/// `.segment` lines before it, so this is known before any line is measured.
+fn zero_page_labels(lines: &[String]) -> HashSet<String> {
+    let mut segment = DEFAULT_SEGMENT.to_string();
+    let mut labels = HashSet::new();
+    for line in lines {
+        let (label, instr) = split_label_and_instr(line);
+        if let Some(label) = label
+            && segment == ZERO_PAGE_SEGMENT
+        {
+            labels.insert(label);
+        }
+        if let Ok(Some(name)) = parse_segment(&instr) {
+            segment = name;
+        }
+    }
+    labels
+}
 
-    // Simple expression evaluator supporting +, -, *, / and parentheses
-    // First, try as a simple label
-    if let Some(&value) = labels.get(expr) {
-        return Some(value);
+/// Whether `expr` names one of `labels`.
+fn uses_label(expr: &str, labels: &HashSet<String>) -> bool {
+    !labels.is_empty()
+        && expr
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .any(|word| labels.contains(word))
+}
+
+/// Whether `instr` encodes in two bytes with a zero-page value in place of `operand`.
+fn has_zero_page_form(instr: &str, operand: std::ops::Range<usize>, cpu: CpuKind) -> bool {
+    let mut candidate = instr.to_string();
+    candidate.replace_range(operand, "$00");
+    let mut scratch = Vec::new();
+    match parse_opcode_line(candidate.as_bytes()) {
+        Ok((rem, opcode)) if rem.iter().all(|b| b.is_ascii_whitespace()) => {
+            emit_opcode(opcode, cpu, &mut scratch).is_ok() && scratch.len() == 2
+        }
+        _ => false,
     }
+}
 
-    // Remove outer parentheses if they exist
-    let expr = if expr.starts_with('(') && expr.ends_with(')') {
-        &expr[1..expr.len() - 1]
+/// A number or character literal, optionally signed, that the opcode parser reads directly.
+fn is_plain_literal(expr: &str) -> bool {
+    let unsigned = expr.strip_prefix(['-', '+']).unwrap_or(expr);
+    let char_literal = expr.len() == 3 && expr.starts_with('\'') && expr.ends_with('\'');
+    parse_const_value(unsigned).is_some() || char_literal
+}
+
+/// Expression span and kind for operands written as `expr`, `expr,X` or `expr,Y`.
+fn plain_operand(op: &str, mnemonic: &str) -> (usize, usize, OperandKind) {
+    let end = index_suffix(op).map(|(end, _)| end).unwrap_or(op.len());
+    let kind = if is_branch(mnemonic) {
+        OperandKind::Branch
+    } else if op.starts_with(['<', '>']) {
+        OperandKind::ZeroPage
     } else {
-        expr
+        OperandKind::Word
     };
+    (0, end, kind)
+}
 
-    // Try to find an operator (searching from left to right for + and -)
-    for (i, ch) in expr.char_indices() {
-        if ch == '+' || ch == '-' {
-            let left = evaluate_expression(&expr[..i], labels)?;
-            let right = evaluate_expression(&expr[i + 1..], labels)?;
-            return Some(if ch == '+' {
-                left.wrapping_add(right)
-            } else {
-                left.wrapping_sub(right)
-            });
+/// Index of the `)` closing the `(` at the start of `op`.
+fn matching_paren(op: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_char = false;
+    for (i, b) in op.bytes().enumerate() {
+        match b {
+            b'\'' => in_char = !in_char,
+            _ if in_char => {}
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
         }
     }
+    None
+}
 
-    // Try to find * or /
-    for (i, ch) in expr.char_indices() {
-        if ch == '*' || ch == '/' {
-            let left = evaluate_expression(&expr[..i], labels)?;
-            let right = evaluate_expression(&expr[i + 1..], labels)?;
-            return Some(if ch == '*' {
-                left.wrapping_mul(right)
-            } else if right != 0 {
-                left / right
-            } else {
-                return None; // Division by zero
-            });
+/// Split a trailing top-level `,X`/`,Y` off an operand, returning where the expression ends.
+fn index_suffix(op: &str) -> Option<(usize, char)> {
+    let bytes = op.as_bytes();
+    let mut depth = 0i32;
+    let mut in_char = false;
+    let mut comma = None;
+    for (i, b) in bytes.iter().enumerate() {
+        match b {
+            b'\'' => in_char = !in_char,
+            _ if in_char => {}
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b',' if depth == 0 => comma = Some(i),
+            _ => {}
         }
     }
+    let comma = comma?;
+    index_register(&op[comma..]).map(|reg| (comma, reg))
+}
 
-    // Try as a literal value
-    parse_const_value(expr)
+/// Recognise `,X` / `,Y` (with optional spaces) and return the register.
+fn index_register(tail: &str) -> Option<char> {
+    let reg = tail.strip_prefix(',')?.trim();
+    match reg.to_ascii_uppercase().as_str() {
+        "X" => Some('X'),
+        "Y" => Some('Y'),
+        _ => None,
+    }
 }
 
 fn is_branch(mnemonic: &str) -> bool {
     matches!(
         mnemonic,
-        "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS"
+        "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BRA" | "BVC" | "BVS"
     )
 }
 
@@ -451,8 +1663,262 @@ fn parse_const_value(s: &str) -> Option<u16> {
     }
 }
 
-fn emit_opcode<T: Write>(opcode: OpCode, output: &mut T) -> AssembleResult {
+/// A directive that emits raw data bytes instead of an instruction.
+enum DataDirective {
+    /// `.byte expr|"string", ...`
+    Byte(Vec<DataItem>),
+    /// `.word expr, ...` (little-endian)
+    Word(Vec<String>),
+    /// `.text "string"`
+    Text(Vec<u8>),
+    /// `.fill count[, value]`
+    Fill(String, Option<String>),
+}
+
+enum DataItem {
+    Expr(String),
+    Str(Vec<u8>),
+}
+
+impl DataDirective {
+    /// Number of bytes the directive emits. `.fill` counts must resolve with the labels known so
+    /// far, since the size has to be fixed during the first pass.
+    fn size(&self, labels: &HashMap<String, u16>, pc: Option<u16>) -> Result<usize, Issue> {
+        match self {
+            DataDirective::Byte(items) => Ok(items
+                .iter()
+                .map(|item| match item {
+                    DataItem::Expr(_) => 1,
+                    DataItem::Str(bytes) => bytes.len(),
+                })
+                .sum()),
+            DataDirective::Word(exprs) => Ok(exprs.len() * 2),
+            DataDirective::Text(bytes) => Ok(bytes.len()),
+            DataDirective::Fill(count, _) => match evaluate(count, labels, pc) {
+                Ok(value) => usize::try_from(value).map_err(|_| {
+                    Issue::error(format!("Fill count '{}' must not be negative", count))
+                        .pointing_at(count)
+                }),
+                Err(ExprError::Undefined(_)) => Err(Issue::error(format!(
+                    "Fill count '{}' must be defined before use",
+                    count
+                ))
+                .pointing_at(count)),
+                Err(e) => Err(expr_diagnostic(e, count, labels)),
+            },
+        }
+    }
+
+    /// Append the directive's bytes to `output`, the bytes of the piece at `at`, recording a
+    /// relocation for each value the linker has to fill in.
+    fn emit(
+        &self,
+        symbols: &Symbols,
+        at: Location,
+        output: &mut Vec<u8>,
+        relocations: &mut Vec<Relocation>,
+    ) -> Result<(), Issue> {
+        match self {
+            DataDirective::Byte(items) => {
+                for item in items {
+                    match item {
+                        DataItem::Expr(expr) => {
+                            let value = symbols.evaluate(expr, at.pc(output.len()))?;
+                            relocations.extend(relocation(
+                                value.clone(),
+                                RelocKind::Byte,
+                                output.len(),
+                            ));
+                            output.push(data_byte(expr, value)?)
+                        }
+                        DataItem::Str(bytes) => output.extend_from_slice(bytes),
+                    }
+                }
+            }
+            DataDirective::Word(exprs) => {
+                for expr in exprs {
+                    let value = symbols.evaluate(expr, at.pc(output.len()))?;
+                    let value = match relocation(value.clone(), RelocKind::Word, output.len()) {
+                        Some(relocation) => {
+                            relocations.push(relocation);
+                            0
+                        }
+                        None => fit_word(value.offset)
+                            .map_err(|e| expr_diagnostic(e, expr, symbols.labels))?,
+                    };
+                    output.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            DataDirective::Text(bytes) => output.extend_from_slice(bytes),
+            DataDirective::Fill(_, value) => {
+                let pc = at.pc(output.len());
+                let count = self.size(symbols.labels, at.address(output.len()))?;
+                let value = match value {
+                    Some(expr) => {
+                        let value = symbols.evaluate(expr, pc)?;
+                        if value.target.is_some() {
+                            return Err(Issue::error(format!(
+                                "Fill value '{}' must not depend on where the linker places code",
+                                expr
+                            ))
+                            .pointing_at(expr));
+                        }
+                        data_byte(expr, value)?
+                    }
+                    None => 0,
+                };
+                output.extend(std::iter::repeat_n(value, count));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_data_directive(line: &str) -> Result<Option<DataDirective>, String> {
+    let trimmed = line.trim();
+    let (keyword, rest) = match trimmed.find(char::is_whitespace) {
+        Some(idx) => (&trimmed[..idx], trimmed[idx..].trim()),
+        None => (trimmed, ""),
+    };
+    let keyword = keyword.to_ascii_lowercase();
+    if !matches!(keyword.as_str(), ".byte" | ".word" | ".text" | ".fill") {
+        return Ok(None);
+    }
+    let args = split_data_args(rest)?;
+    if args.is_empty() {
+        return Err(format!("{} requires at least one value", keyword));
+    }
+    let directive = match keyword.as_str() {
+        ".byte" => DataDirective::Byte(
+            args.into_iter()
+                .map(|arg| {
+                    if arg.starts_with('"') {
+                        parse_string_literal(&arg).map(DataItem::Str)
+                    } else {
+                        Ok(DataItem::Expr(arg))
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        ".word" => DataDirective::Word(args),
+        ".text" => {
+            let mut bytes = Vec::new();
+            for arg in args {
+                bytes.extend(parse_string_literal(&arg)?);
+            }
+            DataDirective::Text(bytes)
+        }
+        _ => {
+            if args.len() > 2 {
+                return Err(".fill expects 'count[, value]'".to_string());
+            }
+            let mut args = args.into_iter();
+            let count = args.next().unwrap_or_default();
+            DataDirective::Fill(count, args.next())
+        }
+    };
+    Ok(Some(directive))
+}
+
+/// `.incbin "file"[, offset[, length]]`
+struct Incbin {
+    file: String,
+    offset: Option<String>,
+    length: Option<String>,
+}
+
+fn parse_incbin(line: &str) -> Result<Option<Incbin>, String> {
+    let trimmed = line.trim();
+    let rest = match strip_directive(trimmed, ".incbin") {
+        Some(rest) => rest,
+        None if trimmed.eq_ignore_ascii_case(".incbin") => "",
+        None => return Ok(None),
+    };
+    let args = split_data_args(rest)?;
+    if args.is_empty() || args.len() > 3 {
+        return Err(".incbin expects '\"file\"[, offset[, length]]'".to_string());
+    }
+    let mut args = args.into_iter();
+    let file = parse_string_literal(&args.next().unwrap_or_default())?;
+    Ok(Some(Incbin {
+        file: String::from_utf8_lossy(&file).into_owned(),
+        offset: args.next(),
+        length: args.next(),
+    }))
+}
+
+/// Split directive arguments on commas that are not inside string or character literals.
+fn split_data_args(rest: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut remaining = rest.trim();
+    if remaining.is_empty() {
+        return Ok(args);
+    }
+    loop {
+        let cut = find_unquoted(remaining.as_bytes(), b',');
+        let arg = remaining[..cut.unwrap_or(remaining.len())].trim();
+        if arg.is_empty() {
+            return Err("empty value in directive".to_string());
+        }
+        args.push(arg.to_string());
+        match cut {
+            Some(idx) => remaining = &remaining[idx + 1..],
+            None => break,
+        }
+    }
+    Ok(args)
+}
+
+fn parse_string_literal(s: &str) -> Result<Vec<u8>, String> {
+    let inner = s
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("expected quoted string, found '{}'", s))?;
+    let mut out = Vec::with_capacity(inner.len());
+    let mut bytes = inner.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let escaped = match bytes.next() {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'0') => 0,
+            Some(b'\\') => b'\\',
+            Some(b'"') => b'"',
+            Some(other) => return Err(format!("unknown escape '\\{}'", other as char)),
+            None => return Err("unterminated escape in string".to_string()),
+        };
+        out.push(escaped);
+    }
+    Ok(out)
+}
+
+/// The byte `expr` evaluated to, or a placeholder when the linker fills it in.
+fn data_byte(expr: &str, value: Value) -> Result<u8, Issue> {
+    if value.target.is_some() {
+        return Ok(0);
+    }
+    let value = value.offset;
+    if !(-128..=255).contains(&value) {
+        return Err(Issue::error(format!(
+            "Value '{}' ({}) does not fit in a byte",
+            expr, value
+        ))
+        .pointing_at(expr));
+    }
+    Ok(value as u8)
+}
+
+fn emit_opcode<T: Write>(opcode: OpCode, cpu: CpuKind, output: &mut T) -> AssembleResult {
     let OpCode(mnemonic, am) = opcode;
+    let name = mnemonic_name(mnemonic);
+    let am = match extension(cpu, name, am, output) {
+        Ok(result) => return result,
+        Err(am) => am,
+    };
     match mnemonic {
         Mnemonic::Adc => adc(am, output),
         Mnemonic::And => and(am, output),
@@ -510,6 +1976,112 @@ fn emit_opcode<T: Write>(opcode: OpCode, output: &mut T) -> AssembleResult {
         Mnemonic::Plp => implied(0x28, am, "PLP", output),
         Mnemonic::Stx => stx(am, output),
         Mnemonic::Sty => sty(am, output),
+        Mnemonic::Alr
+        | Mnemonic::Anc
+        | Mnemonic::Arr
+        | Mnemonic::Bra
+        | Mnemonic::Dcp
+        | Mnemonic::Isc
+        | Mnemonic::Lax
+        | Mnemonic::Phx
+        | Mnemonic::Phy
+        | Mnemonic::Plx
+        | Mnemonic::Ply
+        | Mnemonic::Rla
+        | Mnemonic::Rra
+        | Mnemonic::Sax
+        | Mnemonic::Sbx
+        | Mnemonic::Slo
+        | Mnemonic::Sre
+        | Mnemonic::Stz
+        | Mnemonic::Trb
+        | Mnemonic::Tsb => Err(unavailable(cpu, name, am)),
+    }
+}
+
+/// Encode an instruction from the extension set of `cpu`: the 65C02 additions or the
+/// undocumented NMOS opcodes. Hands the addressing mode back when `name` has no extension
+/// opcode in that mode.
+fn extension<T: Write>(
+    cpu: CpuKind,
+    name: &'static str,
+    am: AddressingMode,
+    output: &mut T,
+) -> Result<AssembleResult, AddressingMode> {
+    let find = |mode| extension_opcode(cpu, name, mode);
+    // Zero-page forms fall back to absolute ones, as `SLO $10,Y` has no zero-page encoding.
+    let zp_or_abs = |zp, abs, addr: u8| match find(zp) {
+        Some(opcode) => Some((opcode, vec![addr])),
+        None => find(abs).map(|opcode| (opcode, vec![addr, 0])),
+    };
+    let encoded = match am {
+        AddressingMode::Implied => find(Mode::Implied).map(|opcode| (opcode, vec![])),
+        AddressingMode::Accumulator => find(Mode::Accumulator).map(|opcode| (opcode, vec![])),
+        AddressingMode::Immediate(val, sign) => match find(Mode::Immediate) {
+            Some(opcode) => return Ok(immediate(opcode, val, sign, output)),
+            None => None,
+        },
+        AddressingMode::ZeroPageOrRelative(_, _) => match find(Mode::Relative) {
+            Some(opcode) => return Ok(relative(opcode, am, name, output)),
+            None => match am {
+                AddressingMode::ZeroPageOrRelative(addr, Sign::Implied | Sign::Positive) => {
+                    zp_or_abs(Mode::ZeroPage, Mode::Absolute, addr)
+                }
+                _ => None,
+            },
+        },
+        AddressingMode::ZeroPageX(addr) => zp_or_abs(Mode::ZeroPageX, Mode::AbsoluteX, addr),
+        AddressingMode::ZeroPageY(addr) => zp_or_abs(Mode::ZeroPageY, Mode::AbsoluteY, addr),
+        AddressingMode::Absolute(addr) => {
+            find(Mode::Absolute).map(|opcode| (opcode, addr.to_le_bytes().to_vec()))
+        }
+        AddressingMode::AbsoluteX(addr) => {
+            find(Mode::AbsoluteX).map(|opcode| (opcode, addr.to_le_bytes().to_vec()))
+        }
+        AddressingMode::AbsoluteY(addr) => {
+            find(Mode::AbsoluteY).map(|opcode| (opcode, addr.to_le_bytes().to_vec()))
+        }
+        AddressingMode::Indirect(addr) => u8::try_from(addr)
+            .ok()
+            .and_then(|addr| find(Mode::ZeroPageIndirect).map(|opcode| (opcode, vec![addr]))),
+        AddressingMode::IndexedIndirect(addr) => {
+            find(Mode::IndexedIndirect).map(|opcode| (opcode, vec![addr]))
+        }
+        AddressingMode::IndirectIndexed(addr) => {
+            find(Mode::IndirectIndexed).map(|opcode| (opcode, vec![addr]))
+        }
+    };
+    let Some((opcode, operand)) = encoded else {
+        return Err(am);
+    };
+    if opcode == HALT_OPCODE {
+        return Ok(Err(format!(
+            "{} encodes as ${:02X}, which is reserved as the halt opcode",
+            name, opcode
+        )));
+    }
+    Ok(byte(opcode, output).and_then(|_| {
+        output
+            .write_all(&operand)
+            .map_err(|_| "An error occurred while writing to the buffer".to_owned())
+    }))
+}
+
+/// Error for an extension mnemonic used on the wrong CPU or in a mode it lacks.
+fn unavailable(cpu: CpuKind, name: &str, am: AddressingMode) -> String {
+    if is_extension_mnemonic(cpu, name) {
+        format!("Unexpected operand encountered for {}: {:?}", name, am)
+    } else if cpu == CpuKind::Nmos6502 {
+        format!(
+            "{} is a 65C02 instruction; set cpu = \"65c02\" in chipcade.toml",
+            name
+        )
+    } else {
+        format!(
+            "{} is an undocumented NMOS opcode, not available on the {}",
+            name,
+            cpu.name()
+        )
     }
 }
 
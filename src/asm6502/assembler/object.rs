@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+use super::super::opcodes::CpuKind;
+
+/// Relocatable output of assembling one source unit, combined into a program by [`link`].
+///
+/// [`link`]: super::link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Object {
+    pub cpu: CpuKind,
+    /// One entry per segment piece, in the order the source selected them.
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    /// Input lines as written; `Section::lines` and symbol lines index into these.
+    pub source: Vec<String>,
+}
+
+/// A run of bytes in one segment. Sections without a `start` are placed by the linker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Section {
+    pub segment: String,
+    /// Address fixed with `.org`.
+    pub start: Option<u16>,
+    pub bytes: Vec<u8>,
+    /// Source line per byte.
+    pub lines: Vec<usize>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// A label or `.const` defined by an object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset into `section`, or the absolute value when there is none.
+    pub value: u16,
+    pub section: Option<usize>,
+    pub constant: bool,
+    /// Visible to other objects; local and anonymous labels are not.
+    pub global: bool,
+    pub line: usize,
+}
+
+/// Bytes the linker patches once the address of `target` is known.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Relocation {
+    /// Position of the patched bytes within the section.
+    pub offset: u16,
+    pub kind: RelocKind,
+    pub target: Target,
+    pub addend: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelocKind {
+    /// Little-endian address.
+    Word,
+    /// A value that must fit in one byte, such as a zero-page address.
+    Byte,
+    /// Low byte of the address (`<expr`, `#expr`).
+    Low,
+    /// High byte of the address (`>expr`).
+    High,
+}
+
+/// What a relocated value is measured from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Target {
+    /// Start of a section in the same object.
+    Section(usize),
+    /// A global symbol, usually defined in another object.
+    Symbol(String),
+}
@@ -1,4 +1,10 @@
-use super::{assemble, assemble_with_labels, assemble_with_labels_at};
+use super::diagnostic::Severity;
+use super::{
+    IncbinReader, Object, RelocKind, Target, assemble, assemble_object, assemble_objects,
+    assemble_with_labels, assemble_with_labels_at, assemble_with_segments_at, link,
+    no_incbin_files,
+};
+use crate::asm6502::{CpuKind, SymbolNames, disassemble, opcode_info};
 
 macro_rules! assert_assemble_err {
     ( $ asm : expr ) => {
@@ -156,6 +162,475 @@ fn labels_forward_and_backward() {
     );
 }
 
+#[test]
+fn data_directives() {
+    assert_assemble!(".byte 1, $02, %11, 'A'", &[0x01, 0x02, 0x03, b'A']);
+    assert_assemble!(".word $1234, 5", &[0x34, 0x12, 0x05, 0x00]);
+    assert_assemble!(".text \"Hi; there\\n\"", b"Hi; there\n");
+    assert_assemble!(".byte \"AB\", 0", &[b'A', b'B', 0x00]);
+    assert_assemble!(".fill 3, $EA", &[0xea, 0xea, 0xea]);
+    assert_assemble!(".const N 2\n.fill N", &[0x00, 0x00]);
+
+    // Labels and low/high bytes resolve in the second pass.
+    assert_assemble!(
+        "JMP Table\nTable: .word Table, End\n.byte <Table, >Table\nEnd:",
+        &[0x4c, 0x03, 0x00, 0x03, 0x00, 0x09, 0x00, 0x03, 0x00]
+    );
+
+    assert_assemble_err!(".byte $100");
+    assert_assemble_err!(".byte Missing");
+    assert_assemble_err!(".fill Later\nLater: NOP");
+    assert_assemble_err!(".text nope");
+}
+
+#[test]
+fn data_directives_map_to_source_lines() {
+    let asm = "Start: LDA Table\n\nTable: .byte 1, 2\n.word Start\n.fill 2";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(result.labels.get("Table"), Some(&0x0203));
+    assert_eq!(
+        result.bytes,
+        vec![0xad, 0x03, 0x02, 0x01, 0x02, 0x00, 0x02, 0x00, 0x00]
+    );
+    assert_eq!(result.pc_line, vec![1, 1, 1, 3, 3, 4, 4, 5, 5]);
+}
+
+/// Assemble at $0200 with `blob.bin` holding the bytes 0 to 15 as the only readable file.
+fn assemble_incbin(asm: &str) -> Result<super::AssembleOutput, Vec<super::Diagnostic>> {
+    let read = |_line: usize, name: &str| match name {
+        "blob.bin" => Ok((0..16).collect()),
+        _ => Err("not found".to_string()),
+    };
+    assemble_with_segments_at(asm.as_bytes(), 0x0200, &[], CpuKind::default(), &read)
+}
+
+#[test]
+fn incbin_reads_the_selected_bytes() {
+    let asm = "\
+.const SKIP 2
+Blob: .incbin \"blob.bin\", SKIP * 2, 3 ; level data
+    .INCBIN \"blob.bin\", $0E
+Tail: .incbin \"blob.bin\", 16";
+    let result = assemble_incbin(asm).expect("assembly should succeed");
+
+    assert_eq!(result.bytes, vec![4, 5, 6, 14, 15]);
+    assert_eq!(result.labels.get("Blob"), Some(&0x0200));
+    assert_eq!(result.labels.get("Tail"), Some(&0x0205));
+    assert_eq!(result.pc_line, vec![2, 2, 2, 3, 3]);
+}
+
+#[test]
+fn incbin_reports_the_line_it_reads_from() {
+    let lines = std::cell::RefCell::new(Vec::new());
+    let read = |line: usize, name: &str| {
+        lines.borrow_mut().push((line, name.to_string()));
+        Ok(vec![1, 2])
+    };
+    let asm = ".macro BLOB\n.incbin \"a.bin\"\n.endm\nNOP\nBLOB\n.incbin \"b.bin\" ; b";
+    let result = assemble_with_segments_at(asm.as_bytes(), 0x0200, &[], CpuKind::default(), &read)
+        .expect("assembly should succeed");
+
+    assert_eq!(result.bytes, vec![0xea, 1, 2, 1, 2]);
+    assert_eq!(
+        lines.into_inner(),
+        vec![(5, "a.bin".to_string()), (6, "b.bin".to_string())]
+    );
+}
+
+#[test]
+fn incbin_bounds_must_fit_the_file() {
+    let message = |asm: &str| {
+        let err = assemble_incbin(asm).err().expect("assembly should fail");
+        assert_eq!(err.len(), 1);
+        err[0].message.clone()
+    };
+
+    assert_eq!(
+        message(".incbin \"blob.bin\", 17"),
+        "Offset 17 is past the end of 'blob.bin' (16 bytes)"
+    );
+    assert_eq!(
+        message(".incbin \"blob.bin\", 10, $07"),
+        "Length 7 at offset 10 runs past the end of 'blob.bin' (16 bytes)"
+    );
+    assert_eq!(
+        message(".incbin \"blob.bin\", 0 - 1"),
+        "Incbin offset '0 - 1' must not be negative"
+    );
+    assert_eq!(
+        message(".incbin \"blob.bin\", 0, Size\nSize:"),
+        "Incbin length 'Size' must be defined before use"
+    );
+    assert_eq!(
+        message(".incbin \"blob.bin\", 1, 2, 3"),
+        ".incbin expects '\"file\"[, offset[, length]]'"
+    );
+    assert_eq!(
+        message(".incbin blob.bin"),
+        "expected quoted string, found 'blob.bin'"
+    );
+
+    let err = assemble_incbin("NOP\n  .incbin \"blob.bin\", 4, 20")
+        .err()
+        .expect("assembly should fail");
+    assert_eq!(err[0].line, 2);
+    assert_eq!(err[0].span, Some(25..27));
+}
+
+#[test]
+fn incbin_reports_missing_files() {
+    let err = assemble_incbin("Level: .incbin \"level.bin\" ; missing\nNOP")
+        .err()
+        .expect("assembly should fail");
+
+    assert_eq!(err.len(), 1);
+    assert_eq!(err[0].line, 1);
+    assert_eq!(err[0].message, "Cannot read 'level.bin': not found");
+    assert_eq!(err[0].span, Some(16..25));
+
+    let err = assemble_with_labels_at(".incbin \"blob.bin\"".as_bytes(), 0x0200)
+        .err()
+        .expect("assembly should fail");
+    assert_eq!(
+        err[0].message,
+        "Cannot read 'blob.bin': only project sources can include files"
+    );
+}
+
+#[test]
+fn incbin_objects_keep_the_directive_as_source() {
+    let read = |_line: usize, _name: &str| Ok(vec![7; 20]);
+    let asm = "Start: NOP\nSprite: .incbin \"ship.bin\", 2 ; ship\nRTS";
+    let (object, _) = assemble_object(asm.as_bytes(), CpuKind::default(), &read)
+        .expect("assembly should succeed");
+
+    assert_eq!(object.source[1], "Sprite: .incbin \"ship.bin\", 2 ; ship");
+    let section = &object.sections[0];
+    assert_eq!(section.bytes.len(), 20);
+    assert_eq!(section.lines[1..19], [2; 18]);
+    assert_eq!(section.lines[19], 3);
+}
+
+#[test]
+fn incbin_is_matched_like_other_directives() {
+    let err = assemble_incbin(".incbinx \"blob.bin\"")
+        .err()
+        .expect("assembly should fail");
+    assert_eq!(err[0].hint.as_deref(), Some("did you mean .incbin?"));
+
+    assert_eq!(
+        assemble_incbin("  .InCbIn\t\"blob.bin\", 15").map(|out| out.bytes),
+        Ok(vec![15])
+    );
+}
+
+#[test]
+fn org_starts_a_new_block() {
+    let asm = "Start: JMP Table\n.org $9000\nTable: .byte 7\n.org Table + 4\nEnd: RTS";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(result.bytes, vec![0x4c, 0x00, 0x90]);
+    assert_eq!(result.labels.get("Table"), Some(&0x9000));
+    assert_eq!(result.labels.get("End"), Some(&0x9004));
+    assert_eq!(result.segments.len(), 2);
+    assert_eq!(result.segments[0].start, 0x9000);
+    assert_eq!(result.segments[0].bytes, vec![0x07]);
+    assert_eq!(result.segments[0].pc_line, vec![3]);
+    assert_eq!(result.segments[1].start, 0x9004);
+    assert_eq!(result.segments[1].bytes, vec![0x60]);
+}
+
+#[test]
+fn zero_page_segment_labels_use_zero_page_operands() {
+    let asm = ".segment ZP\ncounter: .byte 0\n.segment CODE\nSTA counter\nLDA counter+1,X\nLDA counter,Y\nINC later\nJMP Code\n.segment ZP\nlater: .byte 0\n.segment CODE\nCode: RTS";
+    let result = assemble_with_segments_at(
+        asm.as_bytes(),
+        0x0200,
+        &[("ZP", 0x0080)],
+        CpuKind::Nmos6502,
+        &no_incbin_files,
+    )
+    .expect("assembly should succeed");
+
+    // `LDA zp,Y` does not exist, so that one stays absolute; `later` is used before it is defined.
+    assert_eq!(
+        result.bytes,
+        vec![
+            0x85, 0x80, 0xB5, 0x81, 0xB9, 0x80, 0x00, 0xE6, 0x81, 0x4C, 0x0C, 0x02, 0x60
+        ]
+    );
+
+    let object = object(".segment ZP\ncounter: .byte 0\n.segment CODE\nInit: STA counter");
+    assert_eq!(object.sections[0].relocations[0].kind, RelocKind::Byte);
+    let linked = link(&[object], 0x0200, &[("ZP", 0x40)]).expect("link should succeed");
+    assert_eq!(linked.bytes, vec![0x85, 0x40]);
+}
+
+#[test]
+fn segments_use_bases_or_follow_code() {
+    let asm = ".segment \"ROMDATA\"\nTable: .byte 1, 2\n.segment DATA\nMsg: .text \"HI\"\n.segment CODE\nStart: LDA Table\nLDA Msg\n.segment ZP\nPtr: .fill 2";
+    let result = assemble_with_segments_at(
+        asm.as_bytes(),
+        0x0200,
+        &[("ROMDATA", 0x9000), ("zp", 0x0080)],
+        CpuKind::Nmos6502,
+        &no_incbin_files,
+    )
+    .expect("assembly should succeed");
+
+    // DATA leaves the byte after CODE free for the halt opcode.
+    assert_eq!(result.bytes, vec![0xad, 0x00, 0x90, 0xad, 0x07, 0x02]);
+    assert_eq!(result.labels.get("Table"), Some(&0x9000));
+    assert_eq!(result.labels.get("Msg"), Some(&0x0207));
+    assert_eq!(result.labels.get("Ptr"), Some(&0x0080));
+    let placed: Vec<_> = result
+        .segments
+        .iter()
+        .map(|seg| (seg.name.as_str(), seg.start, seg.bytes.len()))
+        .collect();
+    assert_eq!(
+        placed,
+        vec![
+            ("ROMDATA", 0x9000, 2),
+            ("DATA", 0x0207, 2),
+            ("ZP", 0x0080, 2)
+        ]
+    );
+}
+
+#[test]
+fn overlapping_segments_are_rejected() {
+    let asm = "LDA #1\nLDA #2\n.org $0202\n.byte 0";
+    let err = assemble_with_labels_at(asm.as_bytes(), 0x0200)
+        .err()
+        .expect("overlap should fail");
+    assert!(err[0].message.contains("overlaps"), "{err:?}");
+
+    assert_assemble_err!(".segment 1+2");
+    assert_assemble_err!(".org Later\nLater: RTS");
+}
+
+#[test]
+fn local_labels_are_scoped_to_previous_global() {
+    let asm = "Clear: LDX #2\n.loop: DEX\nBNE .loop\nRTS\nFill: LDY #2\n@loop: DEY\nBNE @loop\nJMP Clear.loop";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(result.labels.get("Clear.loop"), Some(&0x0202));
+    assert_eq!(result.labels.get("Fill.loop"), Some(&0x0208));
+    assert_eq!(
+        result.bytes,
+        vec![
+            0xa2, 0x02, 0xca, 0xd0, 0xfd, 0x60, 0xa0, 0x02, 0x88, 0xd0, 0xfd, 0x4c, 0x02, 0x02
+        ]
+    );
+
+    assert_assemble_err!(".loop: DEX");
+    assert_assemble_err!("A: NOP\n.x: NOP\n.x: NOP");
+}
+
+#[test]
+fn anonymous_labels_resolve_nearest_definition() {
+    let asm = "- DEX\nBNE -\nBEQ +\n+: NOP\nBCC ++\n+ NOP\n- NOP\n+ JMP --";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(
+        result.bytes,
+        vec![
+            0xca, 0xd0, 0xfd, 0xf0, 0x00, 0xea, 0x90, 0x02, 0xea, 0xea, 0x4c, 0x00, 0x02
+        ]
+    );
+
+    assert_assemble_err!("BNE -");
+    assert_assemble_err!("- NOP\nBNE +");
+}
+
+#[test]
+fn macros_substitute_parameters_and_nest() {
+    let asm = ".macro inc16 lo, hi\nINC lo\nBNE .done\nINC hi\n.done:\n.endm\n.macro twice lo, hi\ninc16 lo, hi\ninc16 lo, hi\n.endm\nStart: twice $10, $11\nRTS";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(
+        result.bytes,
+        vec![
+            0xe6, 0x10, 0xd0, 0x02, 0xe6, 0x11, 0xe6, 0x10, 0xd0, 0x02, 0xe6, 0x11, 0x60
+        ]
+    );
+    assert_eq!(result.labels.get("Start"), Some(&0x0200));
+    assert_eq!(result.labels.get("inc16.2.done"), Some(&0x0206));
+    assert_eq!(result.labels.get("inc16.3.done"), Some(&0x020c));
+    assert_eq!(
+        result.pc_line,
+        vec![11; 12].into_iter().chain([12]).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn macro_errors_name_invocation_and_body_lines() {
+    let asm = "NOP\n.macro load value\nLDA #value\n.endm\nload $100";
+    let err = assemble_with_labels_at(asm.as_bytes(), 0x0200)
+        .err()
+        .expect("oversized immediate should fail");
+    assert_eq!(err[0].line, 5);
+    assert_eq!(err[0].macro_trace, vec![("load".to_string(), 3)]);
+    assert_eq!(err[0].span, None);
+
+    assert_assemble_err!(".macro m a\nLDA a\n.endm\nm");
+    assert_assemble_err!(".macro m\nNOP");
+    assert_assemble_err!(".macro m\nm\n.endm\nm");
+}
+
+#[test]
+fn conditional_assembly() {
+    let asm = ".const DEBUG 1\n.const LEVEL 0\n.if DEBUG\nNOP\n.if LEVEL\nINX\n.else\nINY\n.endif\n.else\nBRK\n.endif\n.ifdef DEBUG\nDEX\n.endif\n.ifndef DEBUG\n.const LEVEL 2\n.endif\nRTS";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(result.bytes, vec![0xea, 0xc8, 0xca, 0x60]);
+    assert_eq!(result.pc_line, vec![4, 8, 14, 19]);
+
+    assert_assemble_err!(".if 1\nNOP");
+    assert_assemble_err!(".else\nNOP");
+    assert_assemble_err!(".if 1\n.else\n.else\n.endif");
+    assert_assemble_err!(".if Later\n.endif\nLater: NOP");
+}
+
+#[test]
+fn conditions_compare_and_combine_constants() {
+    let asm = ".const LEVEL 3\n.const DEBUG_LEVEL 3\n.if LEVEL > 1 && LEVEL < 5\nNOP\n.endif\n.if DEBUG_LEVEL == 3\nINX\n.endif\n.if LEVEL != 3 || !DEBUG_LEVEL\nBRK\n.endif\n.if LEVEL <= 2 || LEVEL >= 3\nINY\n.endif\nRTS";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+    assert_eq!(result.bytes, vec![0xea, 0xe8, 0xc8, 0x60]);
+
+    let asm =
+        ".byte 2 < 3, 3 <= 2, 1 == 1 & 1, 4 > 1 << 1, !0, !5, 1 && 0 || 2, 1 | 2 && 0, 8 >> 1 >= 4";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+    assert_eq!(result.bytes, vec![1, 0, 1, 1, 1, 0, 1, 0, 1]);
+}
+
+#[test]
+fn expressions_follow_operator_precedence() {
+    let asm = ".const A 3\n.const B 1\n.const C 1\nStart: .byte (A+1)*2, A-B-C, -1, 2+3*4, 1<<4|1, $F0&$3C^1, 7%4, ~0&$FF\n.byte <Start+1, >(Start+$100), 'A'+1, ((A))\n.word * + 2, Start - 1";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x1200).expect("assembly should succeed");
+
+    assert_eq!(
+        result.bytes,
+        vec![
+            0x08, 0x01, 0xff, 0x0e, 0x11, 0x31, 0x03, 0xff, 0x01, 0x13, 0x42, 0x03, 0x0e, 0x12,
+            0xff, 0x11
+        ]
+    );
+}
+
+#[test]
+fn operand_expressions() {
+    let asm = ".const PTR $10\n.const W 2\nStart: LDA Table+W*2,X\nLDA (PTR+1),Y\nLDA (PTR,X)\nJMP (Vec)\nLDA #<Table+1\nLDA #>Table\nBNE *+2\nJMP *\nTable: .byte 0\nVec: .word Start";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(
+        result.bytes,
+        vec![
+            0xbd, 0x17, 0x02, 0xb1, 0x11, 0xa1, 0x10, 0x6c, 0x14, 0x02, 0xa9, 0x14, 0xa9, 0x02,
+            0xd0, 0x00, 0x4c, 0x10, 0x02, 0x00, 0x00, 0x02
+        ]
+    );
+}
+
+#[test]
+fn expression_errors_name_the_undefined_symbol() {
+    let err = assemble_with_labels_at("LDA Missing+1".as_bytes(), 0x0200)
+        .err()
+        .expect("undefined symbol should fail");
+    assert_eq!(
+        err[0].to_string(),
+        "Undefined symbol 'Missing' in 'Missing+1' on line 1"
+    );
+    assert_eq!(err[0].span, Some(4..11));
+
+    let err = assemble_with_labels_at(".byte 1/0".as_bytes(), 0x0200)
+        .err()
+        .expect("division by zero should fail");
+    assert!(err[0].message.contains("division by zero"), "{err:?}");
+
+    assert_assemble_err!("LDA (Foo+");
+    assert_assemble_err!(".byte 256");
+}
+
+#[test]
+fn expression_overflow_is_an_error() {
+    for expr in [
+        "$FFFF*$FFFF*$FFFF*$FFFF*$FFFF",
+        "$FFFF<<31<<31<<31",
+        "$7FFF*$FFFF*$FFFF*$FFFF+$FFFF*$FFFF*$FFFF*$FFFF",
+        "0-$7FFF*$FFFF*$FFFF*$FFFF-$7FFF*$FFFF*$FFFF*$FFFF",
+        "Start+$7FFF*$FFFF*$FFFF*$FFFF+$7FFF*$FFFF*$FFFF*$FFFF",
+    ] {
+        let asm = format!("Start: .word {}", expr);
+        let err = assemble_with_labels_at(asm.as_bytes(), 0x0200)
+            .err()
+            .expect("overflow should fail");
+        assert_eq!(
+            err[0].message,
+            format!("Invalid expression '{}' (overflow)", expr)
+        );
+    }
+}
+
+#[test]
+fn errors_are_collected_across_the_file() {
+    let asm = "Start: LDB #1\nLDA Strat\n.byet 1\nNOP\nLDA #1,Y";
+    let err = assemble_with_labels_at(asm.as_bytes(), 0x0200)
+        .err()
+        .expect("assembly should fail");
+
+    let lines: Vec<usize> = err.iter().map(|d| d.line).collect();
+    assert_eq!(lines, vec![1, 2, 3, 5]);
+    assert_eq!(err[0].message, "Unknown instruction 'LDB'");
+    assert_eq!(err[0].span, Some(7..10));
+    assert_eq!(err[0].hint.as_deref(), Some("did you mean LDA?"));
+    assert_eq!(err[1].hint.as_deref(), Some("did you mean 'Start'?"));
+    assert_eq!(err[1].span, Some(4..9));
+    assert_eq!(err[2].hint.as_deref(), Some("did you mean .byte?"));
+    assert_eq!(err[3].hint, None);
+}
+
+#[test]
+fn mnemonic_suggestions_prefer_documented_and_available() {
+    let hint = |asm: &str, cpu: CpuKind| {
+        assemble_with_segments_at(asm.as_bytes(), 0x0200, &[], cpu, &no_incbin_files)
+            .err()
+            .expect("assembly should fail")[0]
+            .hint
+            .clone()
+    };
+
+    // LAD is one edit from both LDA and the undocumented LAX.
+    assert_eq!(
+        hint("LAD #1", CpuKind::Nmos6502).as_deref(),
+        Some("did you mean LDA?")
+    );
+    assert_eq!(
+        hint("ISCC $10", CpuKind::Nmos6502).as_deref(),
+        Some("did you mean ISC?")
+    );
+    assert_eq!(hint("ISCC $10", CpuKind::Cmos65c02), None);
+    assert_eq!(hint("SZT $10", CpuKind::Nmos6502), None);
+    assert_eq!(
+        hint("SZT $10", CpuKind::Cmos65c02).as_deref(),
+        Some("did you mean STZ?")
+    );
+}
+
+#[test]
+fn truncated_immediates_are_warnings() {
+    let asm = ".const BIG $1234\nLDA #BIG\nLDA #<BIG";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(result.bytes, vec![0xa9, 0x34, 0xa9, 0x34]);
+    assert_eq!(result.warnings.len(), 1);
+    assert_eq!(result.warnings[0].severity, Severity::Warning);
+    assert_eq!(result.warnings[0].line, 2);
+    assert_eq!(result.warnings[0].span, Some(5..8));
+}
+
 #[test]
 fn and() {
     // Absolute
@@ -2441,3 +2916,370 @@ fn sty() {
     // ZeroPageY
     assert_assemble_err!("STY $44,Y");
 }
+
+#[test]
+fn opcode_table_sizes_match_encoding() {
+    let asm = "
+        LDA #$01
+        LDA $10
+        LDA $10,X
+        LDA $1234
+        LDA $1234,X
+        LDA $1234,Y
+        LDA ($10,X)
+        LDA ($10),Y
+        LDX $10,Y
+        ASL A
+        ASL $10
+        JMP ($1234)
+        JSR $1234
+        Loop: BNE Loop
+        TAX
+        RTS
+    ";
+    let mut program = Vec::new();
+    assemble(asm.as_bytes(), &mut program).expect("assembly should succeed");
+
+    let mut pos = 0;
+    let mut count = 0;
+    while pos < program.len() {
+        let info = opcode_info(CpuKind::Nmos6502, program[pos])
+            .unwrap_or_else(|| panic!("no table entry for ${:02X}", program[pos]));
+        pos += info.size();
+        count += 1;
+    }
+    assert_eq!(pos, program.len());
+    assert_eq!(count, 16);
+}
+
+#[test]
+fn const_names_are_reported_separately_from_labels() {
+    let asm = ".const SCREEN $8000\nStart: LDA #$01\n STA SCREEN\n";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+
+    assert_eq!(result.labels.get("SCREEN"), Some(&0x8000));
+    assert_eq!(result.labels.get("Start"), Some(&0x0200));
+    assert!(result.constants.contains("SCREEN"));
+    assert!(!result.constants.contains("Start"));
+}
+
+#[test]
+fn disassembly_round_trips_with_labels() {
+    let asm = "
+        .const SCREEN $8000
+        .const FLAG 1
+        Start: LDA #$01
+        STA SCREEN,X
+        LDA ($10),Y
+        Loop: DEX
+        BNE Loop
+        JMP (Vector)
+        Vector: .byte $FF
+    ";
+    let result = assemble_with_labels_at(asm.as_bytes(), 0x0200).expect("assembly should succeed");
+    let names = SymbolNames::new(&result.labels, &result.constants);
+    let lines = disassemble(
+        &result.bytes,
+        0x0200,
+        result.bytes.len(),
+        CpuKind::Nmos6502,
+        &names,
+    );
+    let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+
+    assert_eq!(
+        text,
+        vec![
+            "LDA #$01",
+            "STA SCREEN,X",
+            "LDA ($10),Y",
+            "DEX",
+            "BNE Loop",
+            "JMP (Vector)",
+            ".byte $FF",
+        ]
+    );
+    assert_eq!(names.label_at(0x0200), Some("Start"));
+    assert_eq!(lines[4].addr, 0x0208);
+    assert_eq!(lines[4].bytes, vec![0xD0, 0xFD]);
+}
+
+fn assemble_for(cpu: CpuKind, asm: &str) -> Result<Vec<u8>, String> {
+    assemble_with_segments_at(asm.as_bytes(), 0x0200, &[], cpu, &no_incbin_files)
+        .map(|output| output.bytes)
+        .map_err(|diagnostics| diagnostics[0].message.clone())
+}
+
+#[test]
+fn cmos_instructions_assemble_for_65c02() {
+    let asm = "
+        .const PTR $10
+        Start: BRA Start
+        STZ $10
+        STZ $10,X
+        STZ $1234
+        STZ $1234,X
+        PHX
+        PLY
+        TSB $10
+        TRB $1234
+        LDA (PTR)
+        STA ($20)
+        INC A
+        BIT #$80
+        JMP ($1234)
+    ";
+    let bytes = assemble_for(CpuKind::Cmos65c02, asm).expect("assembly should succeed");
+
+    assert_eq!(
+        bytes,
+        vec![
+            0x80, 0xFE, 0x64, 0x10, 0x74, 0x10, 0x9C, 0x34, 0x12, 0x9E, 0x34, 0x12, 0xDA, 0x7A,
+            0x04, 0x10, 0x1C, 0x34, 0x12, 0xB2, 0x10, 0x92, 0x20, 0x1A, 0x89, 0x80, 0x6C, 0x34,
+            0x12,
+        ]
+    );
+}
+
+#[test]
+fn cmos_instructions_need_the_65c02() {
+    let err = assemble_for(CpuKind::Nmos6502, "Start: BRA Start").unwrap_err();
+    assert!(err.contains("65C02 instruction"), "{err}");
+    assert!(assemble_for(CpuKind::Nmos6502, "STZ $10").is_err());
+    assert!(assemble_for(CpuKind::Nmos6502, "LDA ($10)").is_err());
+    assert!(assemble_for(CpuKind::Nmos6502, "INC A").is_err());
+}
+
+#[test]
+fn undocumented_opcodes_assemble_for_nmos() {
+    let asm = "
+        LAX $10
+        LAX $10,Y
+        SAX $1234
+        DCP $1234,X
+        SLO $10,Y
+        ISC ($10),Y
+        RRA ($10,X)
+        ANC #$80
+        ALR #$01
+        ARR #$7F
+        SBX #$02
+    ";
+    let bytes = assemble_for(CpuKind::Nmos6502, asm).expect("assembly should succeed");
+
+    assert_eq!(
+        bytes,
+        vec![
+            0xA7, 0x10, 0xB7, 0x10, 0x8F, 0x34, 0x12, 0xDF, 0x34, 0x12, 0x1B, 0x10, 0x00, 0xF3,
+            0x10, 0x63, 0x10, 0x0B, 0x80, 0x4B, 0x01, 0x6B, 0x7F, 0xCB, 0x02,
+        ]
+    );
+}
+
+#[test]
+fn undocumented_opcodes_are_rejected_where_unusable() {
+    let err = assemble_for(CpuKind::Cmos65c02, "LAX $10").unwrap_err();
+    assert!(err.contains("undocumented NMOS opcode"), "{err}");
+    // $FF is the halt sentinel the machine appends after the program.
+    let err = assemble_for(CpuKind::Nmos6502, "ISC $1234,X").unwrap_err();
+    assert!(err.contains("halt opcode"), "{err}");
+}
+
+#[test]
+fn disassembly_follows_the_selected_cpu() {
+    let names = SymbolNames::default();
+    let text = |bytes: &[u8], cpu| -> Vec<String> {
+        disassemble(bytes, 0x0200, bytes.len(), cpu, &names)
+            .into_iter()
+            .map(|line| line.text)
+            .collect()
+    };
+    let cmos = [0x80, 0x02, 0xB2, 0x10];
+    let nmos = [0xA7, 0x10, 0xCB, 0x02];
+
+    assert_eq!(
+        text(&cmos, CpuKind::Cmos65c02),
+        vec!["BRA $0204", "LDA ($10)"]
+    );
+    assert_eq!(text(&nmos, CpuKind::Nmos6502), vec!["LAX $10", "SBX #$02"]);
+    assert_eq!(
+        text(&cmos[..2], CpuKind::Nmos6502),
+        vec![".byte $80", ".byte $02"]
+    );
+    assert_eq!(text(&nmos[..1], CpuKind::Cmos65c02), vec![".byte $A7"]);
+}
+
+fn object(asm: &str) -> Object {
+    assemble_object(asm.as_bytes(), CpuKind::default(), &no_incbin_files)
+        .expect("assembly should succeed")
+        .0
+}
+
+#[test]
+fn objects_record_relocations_and_imports() {
+    let asm = "
+        Start: LDA Table+1,X
+        LDX #<Table
+        LDY #>Print
+        JSR Print
+        BNE Start
+        .segment ZP
+        Ptr: .fill 2
+        .segment CODE
+        STA (Ptr),Y
+        Table: .word Start, Table - Start
+    ";
+    let object = object(asm);
+    let code = &object.sections[0];
+    let relocs: Vec<_> = code
+        .relocations
+        .iter()
+        .map(|r| (r.offset, r.kind, r.target.clone(), r.addend))
+        .collect();
+
+    assert_eq!(
+        code.bytes,
+        vec![
+            0xBD, 0x00, 0x00, 0xA2, 0x00, 0xA0, 0x00, 0x20, 0x00, 0x00, 0xD0, 0xF4, 0x91, 0x00,
+            0x00, 0x00, 0x0E, 0x00,
+        ]
+    );
+    assert_eq!(
+        relocs,
+        vec![
+            (1, RelocKind::Word, Target::Section(0), 15),
+            (4, RelocKind::Low, Target::Section(0), 14),
+            (6, RelocKind::High, Target::Symbol("Print".to_string()), 0),
+            (8, RelocKind::Word, Target::Symbol("Print".to_string()), 0),
+            (13, RelocKind::Byte, Target::Section(1), 0),
+            (14, RelocKind::Word, Target::Section(0), 0),
+        ]
+    );
+    let table = object.symbols.iter().find(|s| s.name == "Table").unwrap();
+    assert_eq!(
+        (table.section, table.value, table.global),
+        (Some(0), 14, true)
+    );
+}
+
+#[test]
+fn link_places_objects_and_resolves_symbols() {
+    let main = object(
+        "
+        .const SCREEN $4000
+        Init: JSR Print
+        LDA Msg
+        .segment ZP
+        Ptr: .fill 2
+        .segment DATA
+        Msg: .byte 1
+    ",
+    );
+    let lib = object(
+        "
+        .const SCREEN $4000
+        Print: STA (Ptr),Y
+        STA SCREEN
+        RTS
+        .segment ZP
+        Count: .byte 0
+    ",
+    );
+    let main_lines = main.source.len();
+    let linked = link(&[main, lib], 0x0200, &[("ZP", 0x40)]).expect("link should succeed");
+
+    assert_eq!(
+        linked.bytes,
+        vec![
+            0x20, 0x06, 0x02, 0xAD, 0x0D, 0x02, 0x91, 0x40, 0x8D, 0x00, 0x40, 0x60
+        ]
+    );
+    assert_eq!(linked.labels.get("Count"), Some(&0x42));
+    assert_eq!(linked.labels.get("Msg"), Some(&0x020D));
+    assert!(linked.constants.contains("SCREEN"));
+    let starts: Vec<_> = linked
+        .segments
+        .iter()
+        .map(|s| (s.name.as_str(), s.start, s.bytes.len()))
+        .collect();
+    assert_eq!(starts, vec![("ZP", 0x40, 3), ("DATA", 0x020D, 1)]);
+    // Lines count through both sources: `Print` is on line 3 of the second object.
+    assert_eq!(linked.pc_line[6], main_lines + 3);
+}
+
+#[test]
+fn link_reports_unresolved_and_conflicting_symbols() {
+    let main = object("Init: JSR Prnt\nLDA #Count");
+    let lib = object("Print: RTS\nInit: RTS\n.const Count $12\nBNE Print");
+    let Err(errors) = link(&[main, lib], 0x0200, &[]) else {
+        panic!("link should fail");
+    };
+    let messages: Vec<_> = errors
+        .iter()
+        .map(|d| (d.line, d.message.as_str()))
+        .collect();
+
+    assert_eq!(
+        messages,
+        vec![
+            (1, "Undefined symbol 'Prnt'"),
+            (4, "Symbol 'Init' is already defined by another object"),
+        ]
+    );
+    assert_eq!(errors[0].hint.as_deref(), Some("did you mean 'Print'?"));
+
+    let err = assemble_object(
+        "BNE Elsewhere".as_bytes(),
+        CpuKind::default(),
+        &no_incbin_files,
+    )
+    .unwrap_err();
+    assert!(err[0].message.contains("use JMP"), "{}", err[0].message);
+}
+
+#[test]
+fn assemble_objects_reports_symbols_no_unit_defines() {
+    let lib = object("Print: RTS");
+    let main = "Main: JSR Print\nJSR Clear\nJMP Mian";
+    let gfx = "Clear: LDA #0\nLDB #1\nRTS";
+    let units: [(&[u8], &IncbinReader); 2] = [
+        (main.as_bytes(), &no_incbin_files),
+        (gfx.as_bytes(), &no_incbin_files),
+    ];
+    let results = assemble_objects(&units, CpuKind::default(), &[&lib]);
+
+    // Both units fail in the same pass: the misspelled name and the syntax error.
+    let main_errors = results[0].as_ref().expect_err("Mian is undefined");
+    assert_eq!(main_errors.len(), 1);
+    assert_eq!(main_errors[0].line, 3);
+    assert_eq!(main_errors[0].message, "Undefined symbol 'Mian'");
+    assert_eq!(main_errors[0].span, Some(4..8));
+    assert_eq!(main_errors[0].hint.as_deref(), Some("did you mean 'Main'?"));
+    let gfx_errors = results[1].as_ref().expect_err("LDB is not an instruction");
+    assert_eq!(gfx_errors[0].message, "Unknown instruction 'LDB'");
+
+    // Names defined by another unit or a linked object stay imports.
+    let units: [(&[u8], &IncbinReader); 1] = [(b"Clear: JSR Print\nJMP Clear", &no_incbin_files)];
+    let results = assemble_objects(&units, CpuKind::default(), &[&lib]);
+    let (object, _) = results[0]
+        .as_ref()
+        .expect("Print is defined by the library");
+    assert_eq!(
+        object.sections[0].relocations[0].target,
+        Target::Symbol("Print".to_string())
+    );
+}
+
+#[test]
+fn assemble_objects_skips_the_check_when_a_unit_defines_unknown_names() {
+    // The macro error stops the second unit before its labels are known, so `Helper` may be
+    // one of them.
+    let units: [(&[u8], &IncbinReader); 2] = [
+        (b"JSR Helper", &no_incbin_files),
+        (b".macro BROKEN\nHelper: RTS", &no_incbin_files),
+    ];
+    let results = assemble_objects(&units, CpuKind::default(), &[]);
+
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+}
@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+
+use super::assembler::ANON_LABEL_PREFIX;
+use super::opcodes::{CpuKind, Mode, opcode_info};
+
+/// One decoded instruction, or a `.byte` for bytes that do not decode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembled {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// Instruction text such as `LDA #$20` or `JSR Init`.
+    pub text: String,
+}
+
+/// Symbol names used to annotate disassembly. Labels win over `.const` values at the same
+/// address; among several names, global ones are preferred, then the alphabetically first.
+/// Constants only name 16-bit operands, since small flag and index constants would otherwise
+/// rename most zero-page accesses.
+#[derive(Default)]
+pub struct SymbolNames {
+    labels: HashMap<u16, String>,
+    constants: HashMap<u16, String>,
+}
+
+impl SymbolNames {
+    pub fn new(labels: &HashMap<String, u16>, constants: &HashSet<String>) -> Self {
+        let mut names = Self::default();
+        for (name, addr) in labels {
+            if name.starts_with(ANON_LABEL_PREFIX) {
+                continue;
+            }
+            let table = if constants.contains(name) {
+                &mut names.constants
+            } else {
+                &mut names.labels
+            };
+            let better = |current: &String| {
+                (name.contains('.'), name.as_str()) < (current.contains('.'), current.as_str())
+            };
+            if table.get(addr).is_none_or(better) {
+                table.insert(*addr, name.clone());
+            }
+        }
+        names
+    }
+
+    /// Code or data label defined at `addr`.
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// Best name for a 16-bit operand address.
+    fn absolute(&self, addr: u16) -> Option<&str> {
+        self.label_at(addr)
+            .or_else(|| self.constants.get(&addr).map(String::as_str))
+    }
+}
+
+/// Disassemble `cpu` instructions from `bytes` loaded at `start` until `len` bytes are covered.
+/// The last instruction may extend past `len` when `bytes` holds the rest of it.
+pub fn disassemble(
+    bytes: &[u8],
+    start: u16,
+    len: usize,
+    cpu: CpuKind,
+    names: &SymbolNames,
+) -> Vec<Disassembled> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < len.min(bytes.len()) {
+        let addr = start.wrapping_add(offset as u16);
+        let line = disassemble_one(&bytes[offset..], addr, cpu, names);
+        offset += line.bytes.len();
+        out.push(line);
+    }
+    out
+}
+
+/// Decode the instruction at the start of `bytes`, which is loaded at `addr`. Unknown opcodes
+/// and instructions cut off by the end of `bytes` come back as a single `.byte`.
+pub fn disassemble_one(bytes: &[u8], addr: u16, cpu: CpuKind, names: &SymbolNames) -> Disassembled {
+    let data_byte = || Disassembled {
+        addr,
+        bytes: bytes[..1].to_vec(),
+        text: format!(".byte ${:02X}", bytes[0]),
+    };
+    let Some(info) = opcode_info(cpu, bytes[0]) else {
+        return data_byte();
+    };
+    let Some(encoded) = bytes.get(..info.size()) else {
+        return data_byte();
+    };
+    let byte = encoded.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, encoded.get(2).copied().unwrap_or(0)]);
+    let zp = || {
+        names
+            .label_at(byte as u16)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("${:02X}", byte))
+    };
+    let abs = || {
+        names
+            .absolute(word)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("${:04X}", word))
+    };
+    let operand = match info.mode {
+        Mode::Implied => String::new(),
+        Mode::Accumulator => "A".to_string(),
+        Mode::Immediate => format!("#${:02X}", byte),
+        Mode::ZeroPage => zp(),
+        Mode::ZeroPageX => format!("{},X", zp()),
+        Mode::ZeroPageY => format!("{},Y", zp()),
+        Mode::Absolute => abs(),
+        Mode::AbsoluteX => format!("{},X", abs()),
+        Mode::AbsoluteY => format!("{},Y", abs()),
+        Mode::Indirect => format!("({})", abs()),
+        Mode::IndexedIndirect => format!("({},X)", zp()),
+        Mode::IndirectIndexed => format!("({}),Y", zp()),
+        Mode::ZeroPageIndirect => format!("({})", zp()),
+        Mode::Relative => {
+            let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            names
+                .label_at(target)
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("${:04X}", target))
+        }
+    };
+    let text = if operand.is_empty() {
+        info.mnemonic.to_string()
+    } else {
+        format!("{} {}", info.mnemonic, operand)
+    };
+    Disassembled {
+        addr,
+        bytes: encoded.to_vec(),
+        text,
+    }
+}
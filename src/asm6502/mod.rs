@@ -1,5 +1,12 @@
 mod assembler;
+mod disassembler;
+mod opcodes;
 mod parser;
 mod tokens;
 
-pub use assembler::assemble_with_labels_at;
+pub use assembler::{
+    ANON_LABEL_PREFIX, DEFAULT_SEGMENT, Diagnostic, IncbinReader, Object, assemble_objects,
+    assemble_with_segments_at, link,
+};
+pub use disassembler::{Disassembled, SymbolNames, disassemble, disassemble_one};
+pub use opcodes::{CpuKind, Mode, OpcodeInfo, opcode_info};
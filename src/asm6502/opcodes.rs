@@ -0,0 +1,480 @@
+use serde::{Deserialize, Serialize};
+
+/// Operand addressing mode of an encoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect,
+    IndirectIndexed,
+    /// 65C02 `(zp)` without an index.
+    ZeroPageIndirect,
+    Relative,
+}
+
+impl Mode {
+    /// Number of operand bytes following the opcode.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Mode::Implied | Mode::Accumulator => 0,
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+    /// Base cycle count.
+    pub cycles: u8,
+    /// One more cycle when an index crosses a page; branches also add one when taken.
+    pub extra_cycles: bool,
+}
+
+impl OpcodeInfo {
+    /// Total encoded length including the opcode byte.
+    pub fn size(self) -> usize {
+        1 + self.mode.operand_len()
+    }
+}
+
+/// Processor selected by `cpu` in `chipcade.toml`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CpuKind {
+    /// NMOS 6502, including the stable undocumented opcodes.
+    #[default]
+    Nmos6502,
+    /// WDC/Rockwell 65C02 base instruction set (no bit-branch instructions).
+    Cmos65c02,
+}
+
+impl CpuKind {
+    /// Parse a `cpu` config value such as `6502` or `65c02`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "6502" | "nmos6502" => Some(CpuKind::Nmos6502),
+            "65c02" | "cmos6502" => Some(CpuKind::Cmos65c02),
+            _ => None,
+        }
+    }
+
+    /// Name used in diagnostics.
+    pub fn name(self) -> &'static str {
+        match self {
+            CpuKind::Nmos6502 => "6502",
+            CpuKind::Cmos65c02 => "65c02",
+        }
+    }
+
+    /// Opcodes this processor adds on top of the documented NMOS set.
+    fn extensions(self) -> &'static [(u8, OpcodeInfo)] {
+        match self {
+            CpuKind::Nmos6502 => &NMOS_UNDOCUMENTED,
+            CpuKind::Cmos65c02 => &CMOS_EXTENSIONS,
+        }
+    }
+}
+
+const fn op(mnemonic: &'static str, mode: Mode, cycles: u8, extra_cycles: bool) -> OpcodeInfo {
+    OpcodeInfo {
+        mnemonic,
+        mode,
+        cycles,
+        extra_cycles,
+    }
+}
+
+/// Look up an opcode byte on `cpu`; `None` for opcodes it does not decode.
+pub fn opcode_info(cpu: CpuKind, opcode: u8) -> Option<OpcodeInfo> {
+    OPCODES[opcode as usize].or_else(|| {
+        cpu.extensions()
+            .iter()
+            .find(|(byte, _)| *byte == opcode)
+            .map(|(_, info)| *info)
+    })
+}
+
+/// Opcode byte for an instruction `cpu` adds to the documented NMOS set, if it has one in `mode`.
+pub fn extension_opcode(cpu: CpuKind, mnemonic: &str, mode: Mode) -> Option<u8> {
+    cpu.extensions()
+        .iter()
+        .find(|(_, info)| info.mnemonic == mnemonic && info.mode == mode)
+        .map(|(byte, _)| *byte)
+}
+
+/// Whether `mnemonic` is part of the documented NMOS instruction set.
+pub fn is_documented_mnemonic(mnemonic: &str) -> bool {
+    OPCODES
+        .iter()
+        .flatten()
+        .any(|info| info.mnemonic == mnemonic)
+}
+
+/// Whether `mnemonic` is one of the extension instructions of `cpu`.
+pub fn is_extension_mnemonic(cpu: CpuKind, mnemonic: &str) -> bool {
+    cpu.extensions()
+        .iter()
+        .any(|(_, info)| info.mnemonic == mnemonic)
+}
+
+/// 65C02 additions that the emulator core executes. The Rockwell/WDC bit instructions
+/// (`BBR`, `BBS`, `RMB`, `SMB`) and `STP`/`WAI` are not supported.
+#[rustfmt::skip]
+const CMOS_EXTENSIONS: [(u8, OpcodeInfo); 24] = [
+    (0x04, op("TSB", Mode::ZeroPage, 5, false)),
+    (0x0C, op("TSB", Mode::Absolute, 6, false)),
+    (0x12, op("ORA", Mode::ZeroPageIndirect, 5, false)),
+    (0x14, op("TRB", Mode::ZeroPage, 5, false)),
+    (0x1A, op("INC", Mode::Accumulator, 2, false)),
+    (0x1C, op("TRB", Mode::Absolute, 6, false)),
+    (0x32, op("AND", Mode::ZeroPageIndirect, 5, false)),
+    (0x3A, op("DEC", Mode::Accumulator, 2, false)),
+    (0x52, op("EOR", Mode::ZeroPageIndirect, 5, false)),
+    (0x5A, op("PHY", Mode::Implied, 3, false)),
+    (0x64, op("STZ", Mode::ZeroPage, 3, false)),
+    (0x72, op("ADC", Mode::ZeroPageIndirect, 5, false)),
+    (0x74, op("STZ", Mode::ZeroPageX, 4, false)),
+    (0x7A, op("PLY", Mode::Implied, 4, false)),
+    (0x80, op("BRA", Mode::Relative, 3, true)),
+    (0x89, op("BIT", Mode::Immediate, 2, false)),
+    (0x92, op("STA", Mode::ZeroPageIndirect, 5, false)),
+    (0x9C, op("STZ", Mode::Absolute, 4, false)),
+    (0x9E, op("STZ", Mode::AbsoluteX, 5, false)),
+    (0xB2, op("LDA", Mode::ZeroPageIndirect, 5, false)),
+    (0xD2, op("CMP", Mode::ZeroPageIndirect, 5, false)),
+    (0xDA, op("PHX", Mode::Implied, 3, false)),
+    (0xF2, op("SBC", Mode::ZeroPageIndirect, 5, false)),
+    (0xFA, op("PLX", Mode::Implied, 4, false)),
+];
+
+/// Stable undocumented NMOS opcodes. `ISC abs,X` ($FF) is listed for disassembly, but the
+/// assembler refuses it because $FF is the halt sentinel appended after every program.
+#[rustfmt::skip]
+const NMOS_UNDOCUMENTED: [(u8, OpcodeInfo); 56] = [
+    (0x03, op("SLO", Mode::IndexedIndirect, 8, false)),
+    (0x07, op("SLO", Mode::ZeroPage, 5, false)),
+    (0x0B, op("ANC", Mode::Immediate, 2, false)),
+    (0x0F, op("SLO", Mode::Absolute, 6, false)),
+    (0x13, op("SLO", Mode::IndirectIndexed, 8, false)),
+    (0x17, op("SLO", Mode::ZeroPageX, 6, false)),
+    (0x1B, op("SLO", Mode::AbsoluteY, 7, false)),
+    (0x1F, op("SLO", Mode::AbsoluteX, 7, false)),
+    (0x23, op("RLA", Mode::IndexedIndirect, 8, false)),
+    (0x27, op("RLA", Mode::ZeroPage, 5, false)),
+    (0x2F, op("RLA", Mode::Absolute, 6, false)),
+    (0x33, op("RLA", Mode::IndirectIndexed, 8, false)),
+    (0x37, op("RLA", Mode::ZeroPageX, 6, false)),
+    (0x3B, op("RLA", Mode::AbsoluteY, 7, false)),
+    (0x3F, op("RLA", Mode::AbsoluteX, 7, false)),
+    (0x43, op("SRE", Mode::IndexedIndirect, 8, false)),
+    (0x47, op("SRE", Mode::ZeroPage, 5, false)),
+    (0x4B, op("ALR", Mode::Immediate, 2, false)),
+    (0x4F, op("SRE", Mode::Absolute, 6, false)),
+    (0x53, op("SRE", Mode::IndirectIndexed, 8, false)),
+    (0x57, op("SRE", Mode::ZeroPageX, 6, false)),
+    (0x5B, op("SRE", Mode::AbsoluteY, 7, false)),
+    (0x5F, op("SRE", Mode::AbsoluteX, 7, false)),
+    (0x63, op("RRA", Mode::IndexedIndirect, 8, false)),
+    (0x67, op("RRA", Mode::ZeroPage, 5, false)),
+    (0x6B, op("ARR", Mode::Immediate, 2, false)),
+    (0x6F, op("RRA", Mode::Absolute, 6, false)),
+    (0x73, op("RRA", Mode::IndirectIndexed, 8, false)),
+    (0x77, op("RRA", Mode::ZeroPageX, 6, false)),
+    (0x7B, op("RRA", Mode::AbsoluteY, 7, false)),
+    (0x7F, op("RRA", Mode::AbsoluteX, 7, false)),
+    (0x83, op("SAX", Mode::IndexedIndirect, 6, false)),
+    (0x87, op("SAX", Mode::ZeroPage, 3, false)),
+    (0x8F, op("SAX", Mode::Absolute, 4, false)),
+    (0x97, op("SAX", Mode::ZeroPageY, 4, false)),
+    (0xA3, op("LAX", Mode::IndexedIndirect, 6, false)),
+    (0xA7, op("LAX", Mode::ZeroPage, 3, false)),
+    (0xAF, op("LAX", Mode::Absolute, 4, false)),
+    (0xB3, op("LAX", Mode::IndirectIndexed, 5, true)),
+    (0xB7, op("LAX", Mode::ZeroPageY, 4, false)),
+    (0xBF, op("LAX", Mode::AbsoluteY, 4, true)),
+    (0xC3, op("DCP", Mode::IndexedIndirect, 8, false)),
+    (0xC7, op("DCP", Mode::ZeroPage, 5, false)),
+    (0xCB, op("SBX", Mode::Immediate, 2, false)),
+    (0xCF, op("DCP", Mode::Absolute, 6, false)),
+    (0xD3, op("DCP", Mode::IndirectIndexed, 8, false)),
+    (0xD7, op("DCP", Mode::ZeroPageX, 6, false)),
+    (0xDB, op("DCP", Mode::AbsoluteY, 7, false)),
+    (0xDF, op("DCP", Mode::AbsoluteX, 7, false)),
+    (0xE3, op("ISC", Mode::IndexedIndirect, 8, false)),
+    (0xE7, op("ISC", Mode::ZeroPage, 5, false)),
+    (0xEF, op("ISC", Mode::Absolute, 6, false)),
+    (0xF3, op("ISC", Mode::IndirectIndexed, 8, false)),
+    (0xF7, op("ISC", Mode::ZeroPageX, 6, false)),
+    (0xFB, op("ISC", Mode::AbsoluteY, 7, false)),
+    (0xFF, op("ISC", Mode::AbsoluteX, 7, false)),
+];
+
+#[rustfmt::skip]
+const OPCODES: [Option<OpcodeInfo>; 256] = [
+    Some(op("BRK", Mode::Implied, 7, false)),
+    Some(op("ORA", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    None,
+    Some(op("ORA", Mode::ZeroPage, 3, false)),
+    Some(op("ASL", Mode::ZeroPage, 5, false)),
+    None,
+    Some(op("PHP", Mode::Implied, 3, false)),
+    Some(op("ORA", Mode::Immediate, 2, false)),
+    Some(op("ASL", Mode::Accumulator, 2, false)),
+    None,
+    None,
+    Some(op("ORA", Mode::Absolute, 4, false)),
+    Some(op("ASL", Mode::Absolute, 6, false)),
+    None,
+    Some(op("BPL", Mode::Relative, 2, true)),
+    Some(op("ORA", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    None,
+    Some(op("ORA", Mode::ZeroPageX, 4, false)),
+    Some(op("ASL", Mode::ZeroPageX, 6, false)),
+    None,
+    Some(op("CLC", Mode::Implied, 2, false)),
+    Some(op("ORA", Mode::AbsoluteY, 4, true)),
+    None,
+    None,
+    None,
+    Some(op("ORA", Mode::AbsoluteX, 4, true)),
+    Some(op("ASL", Mode::AbsoluteX, 7, false)),
+    None,
+    Some(op("JSR", Mode::Absolute, 6, false)),
+    Some(op("AND", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    Some(op("BIT", Mode::ZeroPage, 3, false)),
+    Some(op("AND", Mode::ZeroPage, 3, false)),
+    Some(op("ROL", Mode::ZeroPage, 5, false)),
+    None,
+    Some(op("PLP", Mode::Implied, 4, false)),
+    Some(op("AND", Mode::Immediate, 2, false)),
+    Some(op("ROL", Mode::Accumulator, 2, false)),
+    None,
+    Some(op("BIT", Mode::Absolute, 4, false)),
+    Some(op("AND", Mode::Absolute, 4, false)),
+    Some(op("ROL", Mode::Absolute, 6, false)),
+    None,
+    Some(op("BMI", Mode::Relative, 2, true)),
+    Some(op("AND", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    None,
+    Some(op("AND", Mode::ZeroPageX, 4, false)),
+    Some(op("ROL", Mode::ZeroPageX, 6, false)),
+    None,
+    Some(op("SEC", Mode::Implied, 2, false)),
+    Some(op("AND", Mode::AbsoluteY, 4, true)),
+    None,
+    None,
+    None,
+    Some(op("AND", Mode::AbsoluteX, 4, true)),
+    Some(op("ROL", Mode::AbsoluteX, 7, false)),
+    None,
+    Some(op("RTI", Mode::Implied, 6, false)),
+    Some(op("EOR", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    None,
+    Some(op("EOR", Mode::ZeroPage, 3, false)),
+    Some(op("LSR", Mode::ZeroPage, 5, false)),
+    None,
+    Some(op("PHA", Mode::Implied, 3, false)),
+    Some(op("EOR", Mode::Immediate, 2, false)),
+    Some(op("LSR", Mode::Accumulator, 2, false)),
+    None,
+    Some(op("JMP", Mode::Absolute, 3, false)),
+    Some(op("EOR", Mode::Absolute, 4, false)),
+    Some(op("LSR", Mode::Absolute, 6, false)),
+    None,
+    Some(op("BVC", Mode::Relative, 2, true)),
+    Some(op("EOR", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    None,
+    Some(op("EOR", Mode::ZeroPageX, 4, false)),
+    Some(op("LSR", Mode::ZeroPageX, 6, false)),
+    None,
+    Some(op("CLI", Mode::Implied, 2, false)),
+    Some(op("EOR", Mode::AbsoluteY, 4, true)),
+    None,
+    None,
+    None,
+    Some(op("EOR", Mode::AbsoluteX, 4, true)),
+    Some(op("LSR", Mode::AbsoluteX, 7, false)),
+    None,
+    Some(op("RTS", Mode::Implied, 6, false)),
+    Some(op("ADC", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    None,
+    Some(op("ADC", Mode::ZeroPage, 3, false)),
+    Some(op("ROR", Mode::ZeroPage, 5, false)),
+    None,
+    Some(op("PLA", Mode::Implied, 4, false)),
+    Some(op("ADC", Mode::Immediate, 2, false)),
+    Some(op("ROR", Mode::Accumulator, 2, false)),
+    None,
+    Some(op("JMP", Mode::Indirect, 5, false)),
+    Some(op("ADC", Mode::Absolute, 4, false)),
+    Some(op("ROR", Mode::Absolute, 6, false)),
+    None,
+    Some(op("BVS", Mode::Relative, 2, true)),
+    Some(op("ADC", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    None,
+    Some(op("ADC", Mode::ZeroPageX, 4, false)),
+    Some(op("ROR", Mode::ZeroPageX, 6, false)),
+    None,
+    Some(op("SEI", Mode::Implied, 2, false)),
+    Some(op("ADC", Mode::AbsoluteY, 4, true)),
+    None,
+    None,
+    None,
+    Some(op("ADC", Mode::AbsoluteX, 4, true)),
+    Some(op("ROR", Mode::AbsoluteX, 7, false)),
+    None,
+    None,
+    Some(op("STA", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    Some(op("STY", Mode::ZeroPage, 3, false)),
+    Some(op("STA", Mode::ZeroPage, 3, false)),
+    Some(op("STX", Mode::ZeroPage, 3, false)),
+    None,
+    Some(op("DEY", Mode::Implied, 2, false)),
+    None,
+    Some(op("TXA", Mode::Implied, 2, false)),
+    None,
+    Some(op("STY", Mode::Absolute, 4, false)),
+    Some(op("STA", Mode::Absolute, 4, false)),
+    Some(op("STX", Mode::Absolute, 4, false)),
+    None,
+    Some(op("BCC", Mode::Relative, 2, true)),
+    Some(op("STA", Mode::IndirectIndexed, 6, false)),
+    None,
+    None,
+    Some(op("STY", Mode::ZeroPageX, 4, false)),
+    Some(op("STA", Mode::ZeroPageX, 4, false)),
+    Some(op("STX", Mode::ZeroPageY, 4, false)),
+    None,
+    Some(op("TYA", Mode::Implied, 2, false)),
+    Some(op("STA", Mode::AbsoluteY, 5, false)),
+    Some(op("TXS", Mode::Implied, 2, false)),
+    None,
+    None,
+    Some(op("STA", Mode::AbsoluteX, 5, false)),
+    None,
+    None,
+    Some(op("LDY", Mode::Immediate, 2, false)),
+    Some(op("LDA", Mode::IndexedIndirect, 6, false)),
+    Some(op("LDX", Mode::Immediate, 2, false)),
+    None,
+    Some(op("LDY", Mode::ZeroPage, 3, false)),
+    Some(op("LDA", Mode::ZeroPage, 3, false)),
+    Some(op("LDX", Mode::ZeroPage, 3, false)),
+    None,
+    Some(op("TAY", Mode::Implied, 2, false)),
+    Some(op("LDA", Mode::Immediate, 2, false)),
+    Some(op("TAX", Mode::Implied, 2, false)),
+    None,
+    Some(op("LDY", Mode::Absolute, 4, false)),
+    Some(op("LDA", Mode::Absolute, 4, false)),
+    Some(op("LDX", Mode::Absolute, 4, false)),
+    None,
+    Some(op("BCS", Mode::Relative, 2, true)),
+    Some(op("LDA", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    Some(op("LDY", Mode::ZeroPageX, 4, false)),
+    Some(op("LDA", Mode::ZeroPageX, 4, false)),
+    Some(op("LDX", Mode::ZeroPageY, 4, false)),
+    None,
+    Some(op("CLV", Mode::Implied, 2, false)),
+    Some(op("LDA", Mode::AbsoluteY, 4, true)),
+    Some(op("TSX", Mode::Implied, 2, false)),
+    None,
+    Some(op("LDY", Mode::AbsoluteX, 4, true)),
+    Some(op("LDA", Mode::AbsoluteX, 4, true)),
+    Some(op("LDX", Mode::AbsoluteY, 4, true)),
+    None,
+    Some(op("CPY", Mode::Immediate, 2, false)),
+    Some(op("CMP", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    Some(op("CPY", Mode::ZeroPage, 3, false)),
+    Some(op("CMP", Mode::ZeroPage, 3, false)),
+    Some(op("DEC", Mode::ZeroPage, 5, false)),
+    None,
+    Some(op("INY", Mode::Implied, 2, false)),
+    Some(op("CMP", Mode::Immediate, 2, false)),
+    Some(op("DEX", Mode::Implied, 2, false)),
+    None,
+    Some(op("CPY", Mode::Absolute, 4, false)),
+    Some(op("CMP", Mode::Absolute, 4, false)),
+    Some(op("DEC", Mode::Absolute, 6, false)),
+    None,
+    Some(op("BNE", Mode::Relative, 2, true)),
+    Some(op("CMP", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    None,
+    Some(op("CMP", Mode::ZeroPageX, 4, false)),
+    Some(op("DEC", Mode::ZeroPageX, 6, false)),
+    None,
+    Some(op("CLD", Mode::Implied, 2, false)),
+    Some(op("CMP", Mode::AbsoluteY, 4, true)),
+    None,
+    None,
+    None,
+    Some(op("CMP", Mode::AbsoluteX, 4, true)),
+    Some(op("DEC", Mode::AbsoluteX, 7, false)),
+    None,
+    Some(op("CPX", Mode::Immediate, 2, false)),
+    Some(op("SBC", Mode::IndexedIndirect, 6, false)),
+    None,
+    None,
+    Some(op("CPX", Mode::ZeroPage, 3, false)),
+    Some(op("SBC", Mode::ZeroPage, 3, false)),
+    Some(op("INC", Mode::ZeroPage, 5, false)),
+    None,
+    Some(op("INX", Mode::Implied, 2, false)),
+    Some(op("SBC", Mode::Immediate, 2, false)),
+    Some(op("NOP", Mode::Implied, 2, false)),
+    None,
+    Some(op("CPX", Mode::Absolute, 4, false)),
+    Some(op("SBC", Mode::Absolute, 4, false)),
+    Some(op("INC", Mode::Absolute, 6, false)),
+    None,
+    Some(op("BEQ", Mode::Relative, 2, true)),
+    Some(op("SBC", Mode::IndirectIndexed, 5, true)),
+    None,
+    None,
+    None,
+    Some(op("SBC", Mode::ZeroPageX, 4, false)),
+    Some(op("INC", Mode::ZeroPageX, 6, false)),
+    None,
+    Some(op("SED", Mode::Implied, 2, false)),
+    Some(op("SBC", Mode::AbsoluteY, 4, true)),
+    None,
+    None,
+    None,
+    Some(op("SBC", Mode::AbsoluteX, 4, true)),
+    Some(op("INC", Mode::AbsoluteX, 7, false)),
+    None,
+];
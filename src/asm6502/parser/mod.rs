@@ -44,76 +44,111 @@ fn opcode<'a>(input: &'a [u8]) -> Res<'a, OpCode> {
     Ok((input, OpCode(mnemonic, mode)))
 }
 
+/// Every mnemonic the assembler accepts, by name.
+const MNEMONICS: [(&str, Mnemonic); 76] = [
+    ("ADC", Mnemonic::Adc),
+    ("ALR", Mnemonic::Alr),
+    ("ANC", Mnemonic::Anc),
+    ("AND", Mnemonic::And),
+    ("ARR", Mnemonic::Arr),
+    ("ASL", Mnemonic::Asl),
+    ("BCC", Mnemonic::Bcc),
+    ("BCS", Mnemonic::Bcs),
+    ("BEQ", Mnemonic::Beq),
+    ("BIT", Mnemonic::Bit),
+    ("BMI", Mnemonic::Bmi),
+    ("BNE", Mnemonic::Bne),
+    ("BPL", Mnemonic::Bpl),
+    ("BRA", Mnemonic::Bra),
+    ("BRK", Mnemonic::Brk),
+    ("BVC", Mnemonic::Bvc),
+    ("BVS", Mnemonic::Bvs),
+    ("CLC", Mnemonic::Clc),
+    ("CLD", Mnemonic::Cld),
+    ("CLI", Mnemonic::Cli),
+    ("CLV", Mnemonic::Clv),
+    ("CMP", Mnemonic::Cmp),
+    ("CPX", Mnemonic::Cpx),
+    ("CPY", Mnemonic::Cpy),
+    ("DCP", Mnemonic::Dcp),
+    ("DEC", Mnemonic::Dec),
+    ("DEX", Mnemonic::Dex),
+    ("DEY", Mnemonic::Dey),
+    ("EOR", Mnemonic::Eor),
+    ("INC", Mnemonic::Inc),
+    ("INX", Mnemonic::Inx),
+    ("INY", Mnemonic::Iny),
+    ("ISC", Mnemonic::Isc),
+    ("JMP", Mnemonic::Jmp),
+    ("JSR", Mnemonic::Jsr),
+    ("LAX", Mnemonic::Lax),
+    ("LDA", Mnemonic::Lda),
+    ("LDX", Mnemonic::Ldx),
+    ("LDY", Mnemonic::Ldy),
+    ("LSR", Mnemonic::Lsr),
+    ("NOP", Mnemonic::Nop),
+    ("ORA", Mnemonic::Ora),
+    ("PHA", Mnemonic::Pha),
+    ("PHP", Mnemonic::Php),
+    ("PHX", Mnemonic::Phx),
+    ("PHY", Mnemonic::Phy),
+    ("PLA", Mnemonic::Pla),
+    ("PLP", Mnemonic::Plp),
+    ("PLX", Mnemonic::Plx),
+    ("PLY", Mnemonic::Ply),
+    ("RLA", Mnemonic::Rla),
+    ("ROL", Mnemonic::Rol),
+    ("ROR", Mnemonic::Ror),
+    ("RRA", Mnemonic::Rra),
+    ("RTI", Mnemonic::Rti),
+    ("RTS", Mnemonic::Rts),
+    ("SAX", Mnemonic::Sax),
+    ("SBC", Mnemonic::Sbc),
+    ("SBX", Mnemonic::Sbx),
+    ("SEC", Mnemonic::Sec),
+    ("SED", Mnemonic::Sed),
+    ("SEI", Mnemonic::Sei),
+    ("SLO", Mnemonic::Slo),
+    ("SRE", Mnemonic::Sre),
+    ("STA", Mnemonic::Sta),
+    ("STX", Mnemonic::Stx),
+    ("STY", Mnemonic::Sty),
+    ("STZ", Mnemonic::Stz),
+    ("TAX", Mnemonic::Tax),
+    ("TAY", Mnemonic::Tay),
+    ("TRB", Mnemonic::Trb),
+    ("TSB", Mnemonic::Tsb),
+    ("TSX", Mnemonic::Tsx),
+    ("TXA", Mnemonic::Txa),
+    ("TXS", Mnemonic::Txs),
+    ("TYA", Mnemonic::Tya),
+];
+
+/// Upper-case name of `mnemonic`.
+pub fn mnemonic_name(mnemonic: Mnemonic) -> &'static str {
+    MNEMONICS
+        .iter()
+        .find(|(_, candidate)| *candidate == mnemonic)
+        .map_or("", |(name, _)| *name)
+}
+
+/// Names of all supported mnemonics, upper case.
+pub fn mnemonic_names() -> impl Iterator<Item = &'static str> {
+    MNEMONICS.iter().map(|(name, _)| *name)
+}
+
 fn mnemonic<'a>(input: &'a [u8]) -> Res<'a, Mnemonic> {
     let (rest, token) = take_while1(is_alpha).parse(input)?;
     let name = std::str::from_utf8(token)
         .unwrap_or("")
         .to_ascii_uppercase();
-    let mnem = match name.as_str() {
-        "ADC" => Mnemonic::Adc,
-        "AND" => Mnemonic::And,
-        "ASL" => Mnemonic::Asl,
-        "BCC" => Mnemonic::Bcc,
-        "BCS" => Mnemonic::Bcs,
-        "BEQ" => Mnemonic::Beq,
-        "BIT" => Mnemonic::Bit,
-        "BMI" => Mnemonic::Bmi,
-        "BNE" => Mnemonic::Bne,
-        "BPL" => Mnemonic::Bpl,
-        "BRK" => Mnemonic::Brk,
-        "BVC" => Mnemonic::Bvc,
-        "BVS" => Mnemonic::Bvs,
-        "CLC" => Mnemonic::Clc,
-        "CLD" => Mnemonic::Cld,
-        "CLI" => Mnemonic::Cli,
-        "CLV" => Mnemonic::Clv,
-        "CMP" => Mnemonic::Cmp,
-        "CPX" => Mnemonic::Cpx,
-        "CPY" => Mnemonic::Cpy,
-        "DEC" => Mnemonic::Dec,
-        "DEX" => Mnemonic::Dex,
-        "DEY" => Mnemonic::Dey,
-        "EOR" => Mnemonic::Eor,
-        "INC" => Mnemonic::Inc,
-        "INX" => Mnemonic::Inx,
-        "INY" => Mnemonic::Iny,
-        "JMP" => Mnemonic::Jmp,
-        "JSR" => Mnemonic::Jsr,
-        "LDA" => Mnemonic::Lda,
-        "LDX" => Mnemonic::Ldx,
-        "LDY" => Mnemonic::Ldy,
-        "LSR" => Mnemonic::Lsr,
-        "NOP" => Mnemonic::Nop,
-        "ORA" => Mnemonic::Ora,
-        "PHA" => Mnemonic::Pha,
-        "PHP" => Mnemonic::Php,
-        "PLA" => Mnemonic::Pla,
-        "PLP" => Mnemonic::Plp,
-        "ROL" => Mnemonic::Rol,
-        "ROR" => Mnemonic::Ror,
-        "RTI" => Mnemonic::Rti,
-        "RTS" => Mnemonic::Rts,
-        "SBC" => Mnemonic::Sbc,
-        "SEC" => Mnemonic::Sec,
-        "SED" => Mnemonic::Sed,
-        "SEI" => Mnemonic::Sei,
-        "STA" => Mnemonic::Sta,
-        "STX" => Mnemonic::Stx,
-        "STY" => Mnemonic::Sty,
-        "TAX" => Mnemonic::Tax,
-        "TAY" => Mnemonic::Tay,
-        "TSX" => Mnemonic::Tsx,
-        "TXA" => Mnemonic::Txa,
-        "TXS" => Mnemonic::Txs,
-        "TYA" => Mnemonic::Tya,
-        _ => {
-            return Err(nom::Err::Error(nom::error::Error::new(
-                input,
-                nom::error::ErrorKind::Fail,
-            )));
-        }
-    };
-    Ok((rest, mnem))
+    match MNEMONICS.iter().find(|(candidate, _)| *candidate == name) {
+        Some((_, mnem)) => Ok((rest, *mnem)),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
 }
 
 fn addressing_mode<'a>(input: &'a [u8]) -> Res<'a, AddressingMode> {
@@ -1,7 +1,10 @@
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mnemonic {
     Adc,
+    Alr,
+    Anc,
     And,
+    Arr,
     Asl,
     Bcc,
     Bcs,
@@ -10,6 +13,7 @@ pub enum Mnemonic {
     Bmi,
     Bne,
     Bpl,
+    Bra,
     Brk,
     Bvc,
     Bvs,
@@ -20,6 +24,7 @@ pub enum Mnemonic {
     Cmp,
     Cpx,
     Cpy,
+    Dcp,
     Dec,
     Dex,
     Dey,
@@ -27,8 +32,10 @@ pub enum Mnemonic {
     Inc,
     Inx,
     Iny,
+    Isc,
     Jmp,
     Jsr,
+    Lax,
     Lda,
     Ldx,
     Ldy,
@@ -37,35 +44,48 @@ pub enum Mnemonic {
     Ora,
     Pha,
     Php,
+    Phx,
+    Phy,
     Pla,
     Plp,
+    Plx,
+    Ply,
+    Rla,
     Rol,
     Ror,
+    Rra,
     Rti,
     Rts,
+    Sax,
     Sbc,
+    Sbx,
     Sec,
     Sed,
     Sei,
+    Slo,
+    Sre,
     Sta,
     Stx,
     Sty,
+    Stz,
     Tax,
     Tay,
+    Trb,
+    Tsb,
     Tsx,
     Txa,
     Txs,
     Tya,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Sign {
     Implied,
     Positive,
     Negative,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressingMode {
     IndexedIndirect(u8),
     IndirectIndexed(u8),
@@ -0,0 +1,1699 @@
+//! The C front end: a lexer and a recursive-descent parser that turn the preprocessed lines of
+//! a source file into top-level items, statement trees and expression trees. Every node keeps
+//! the position of its first token, or of its operator for a binary operation, so errors point
+//! at file:line:column. What the names mean is left to `machine`, which lowers the trees.
+
+use crate::preprocessor::SourceLine;
+use std::fmt::Display;
+
+/// A place in a unit's preprocessed lines: the index of the line and a column counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Source text with the position it starts at. Text that spans lines has its line breaks
+/// turned into spaces.
+#[derive(Clone, Debug)]
+pub struct Code {
+    pub pos: Pos,
+    pub text: String,
+}
+
+pub enum Item {
+    /// Global variables, or `extern` declarations of them.
+    Decl(Decl),
+    Struct(StructDef),
+    /// A function declaration without a body.
+    Prototype(FnHeader),
+    Function {
+        header: FnHeader,
+        body: Block,
+    },
+}
+
+/// A type as written: `void`, a char or int, or a struct, possibly behind a `*`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Type {
+    pub pos: Pos,
+    pub spec: TypeSpec,
+    pub pointer: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeSpec {
+    Void,
+    Char {
+        signed: bool,
+    },
+    Int {
+        signed: bool,
+    },
+    /// `struct Tag`, or a typedef name.
+    Struct(String),
+}
+
+/// A declaration of one or more names of the same base type.
+pub struct Decl {
+    pub pos: Pos,
+    pub constant: bool,
+    pub external: bool,
+    pub vars: Vec<Declarator>,
+}
+
+/// One name of a declaration, or one field of a struct.
+pub struct Declarator {
+    /// The name.
+    pub pos: Pos,
+    pub name: String,
+    pub ty: Type,
+    /// `[len]`, or `[]` without a length.
+    pub array: Option<Option<Expr>>,
+    pub init: Option<Init>,
+}
+
+pub enum Init {
+    Expr(Expr),
+    /// `{ a, b, ... }`, at its `{`.
+    List(Pos, Vec<Expr>),
+}
+
+/// `struct Tag { ... };`, `typedef struct { ... } Name;` or `typedef struct Tag { ... } Name;`.
+pub struct StructDef {
+    pub pos: Pos,
+    /// The names it defines: `struct Tag` and the typedef name.
+    pub names: Vec<String>,
+    pub fields: Vec<Declarator>,
+}
+
+pub struct FnHeader {
+    pub pos: Pos,
+    pub name: String,
+    pub ret: Type,
+    pub params: Vec<Param>,
+}
+
+/// A function parameter; prototypes may leave out the name.
+pub struct Param {
+    pub ty: Type,
+    pub name: Option<String>,
+}
+
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    /// The closing `}`.
+    pub end: Pos,
+}
+
+pub struct Stmt {
+    pub pos: Pos,
+    /// The last token of the statement.
+    pub end: Pos,
+    pub kind: StmtKind,
+}
+
+pub enum StmtKind {
+    /// An assignment, a call or a step such as `x++`.
+    Expr(Expr),
+    Decl(Decl),
+    Return(Option<Expr>),
+    Block(Block),
+    If {
+        cond: Expr,
+        then: Box<Stmt>,
+        els: Option<Box<Stmt>>,
+    },
+    While {
+        cond: Expr,
+        body: Box<Stmt>,
+    },
+    /// `init` is an `Expr` or `Decl` statement.
+    For {
+        init: Option<Box<Stmt>>,
+        cond: Option<Expr>,
+        step: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    Switch {
+        selector: Expr,
+        body: Box<Stmt>,
+    },
+    /// `case value:`, or `default:` without a value.
+    Case(Option<Expr>),
+    Break,
+    Continue,
+    Empty,
+    /// `asm { ... }` or `__asm__("...")`, one entry per assembler line.
+    Asm(Vec<Code>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    pub pos: Pos,
+    pub kind: ExprKind,
+}
+
+impl Expr {
+    /// The position of the first token, where `pos` of an operation may be its operator.
+    pub fn start(&self) -> Pos {
+        match &self.kind {
+            ExprKind::Binary(_, lhs, _) | ExprKind::Assign(lhs, _) => lhs.start(),
+            _ => self.pos,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind {
+    /// A number or character literal.
+    Number(u16),
+    Ident(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// `base[index]`.
+    Index(Box<Expr>, Box<Expr>),
+    /// `[address]`.
+    Mem(Box<Expr>),
+    /// `base.field`.
+    Field(Box<Expr>, String),
+    Call(String, Vec<Expr>),
+    /// `target = value`, at the `=`.
+    Assign(Box<Expr>, Box<Expr>),
+    PostInc(Box<Expr>),
+    PostDec(Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    /// `~`.
+    BitNot,
+    /// `!`.
+    Not,
+    /// `*`.
+    Deref,
+    /// `&`.
+    AddrOf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Mod,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+/// Format a parse error at `pos`.
+pub fn error(lines: &[SourceLine], pos: Pos, msg: impl Display) -> String {
+    let source = &lines[pos.line];
+    format!(
+        "C parse error: {}:{}:{}: {}",
+        source.path.display(),
+        source.line,
+        pos.col,
+        msg
+    )
+}
+
+/// Parse the preprocessed lines of one source file.
+pub fn parse_unit(lines: &[SourceLine]) -> Result<Vec<Item>, String> {
+    let mut parser = Parser {
+        lines,
+        tokens: lex(lines)?,
+        idx: 0,
+    };
+    let mut items = Vec::new();
+    while parser.idx < parser.tokens.len() {
+        items.push(parser.item()?);
+    }
+    Ok(items)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    Ident,
+    /// A decimal, `0x` or `$` number.
+    Number,
+    Str,
+    Char,
+    /// One of `OPERATORS`, or any other single character.
+    Punct,
+}
+
+struct Token<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    pos: Pos,
+}
+
+/// The punctuation that takes two characters.
+const OPERATORS: [&str; 11] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "++", "--", "->",
+];
+
+fn lex(lines: &[SourceLine]) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    for (line, source) in lines.iter().enumerate() {
+        let text = source.text.as_str();
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            let pos = Pos {
+                line,
+                col: source.col + start,
+            };
+            let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+            let kind = match c {
+                '"' | '\'' => {
+                    let mut escaped = false;
+                    let closed = chars.by_ref().any(|(_, ch)| {
+                        let done = ch == c && !escaped;
+                        escaped = ch == '\\' && !escaped;
+                        done
+                    });
+                    if !closed {
+                        let what = if c == '"' { "string" } else { "character" };
+                        return Err(error(lines, pos, format!("unterminated {} literal", what)));
+                    }
+                    if c == '"' {
+                        TokenKind::Str
+                    } else {
+                        TokenKind::Char
+                    }
+                }
+                c if word(c) => {
+                    while chars.next_if(|(_, ch)| word(*ch)).is_some() {}
+                    if c.is_ascii_digit() {
+                        TokenKind::Number
+                    } else {
+                        TokenKind::Ident
+                    }
+                }
+                '$' if chars.peek().is_some_and(|(_, ch)| ch.is_ascii_hexdigit()) => {
+                    while chars.next_if(|(_, ch)| word(*ch)).is_some() {}
+                    TokenKind::Number
+                }
+                _ => {
+                    if OPERATORS.iter().any(|op| text[start..].starts_with(op)) {
+                        chars.next();
+                    }
+                    TokenKind::Punct
+                }
+            };
+            let end = chars.peek().map_or(text.len(), |(i, _)| *i);
+            tokens.push(Token {
+                kind,
+                text: &text[start..end],
+                pos,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+const KEYWORDS: [&str; 10] = [
+    "if", "else", "while", "for", "switch", "case", "default", "break", "continue", "return",
+];
+
+/// Words that can only start a declaration.
+const TYPE_WORDS: [&str; 18] = [
+    "void", "unsigned", "signed", "char", "int", "short", "long", "float", "double", "struct",
+    "union", "enum", "const", "extern", "typedef", "static", "volatile", "register",
+];
+
+/// Binary operators from the loosest to the tightest binding. Each level is left-associative.
+const BINARY_LEVELS: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[
+        ("<", BinaryOp::Lt),
+        ("<=", BinaryOp::Le),
+        (">", BinaryOp::Gt),
+        (">=", BinaryOp::Ge),
+    ],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Mod),
+    ],
+];
+
+/// The part of a declaration before its names.
+struct Specifiers {
+    pos: Pos,
+    constant: bool,
+    external: bool,
+    typedef: bool,
+    ty: Type,
+    /// The fields of a struct defined in place: `struct Tag { ... }`.
+    fields: Option<Vec<Declarator>>,
+}
+
+struct Parser<'a> {
+    lines: &'a [SourceLine],
+    tokens: Vec<Token<'a>>,
+    idx: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.idx)
+    }
+
+    fn is_punct(&self, idx: usize, c: &str) -> bool {
+        self.tokens
+            .get(idx)
+            .is_some_and(|t| t.kind == TokenKind::Punct && t.text == c)
+    }
+
+    fn is_ident(&self, idx: usize, name: &str) -> bool {
+        self.tokens
+            .get(idx)
+            .is_some_and(|t| t.kind == TokenKind::Ident && t.text == name)
+    }
+
+    /// Position of the last token, for errors at the end of the file.
+    fn last_pos(&self) -> Pos {
+        self.tokens
+            .last()
+            .map_or(Pos { line: 0, col: 1 }, |t| t.pos)
+    }
+
+    /// Position of the next token, or of the last one at the end of the file.
+    fn here(&self) -> Pos {
+        self.peek().map_or_else(|| self.last_pos(), |t| t.pos)
+    }
+
+    fn error(&self, pos: Pos, msg: impl Display) -> String {
+        error(self.lines, pos, msg)
+    }
+
+    /// An error at the next token: `expected` names what should be there.
+    fn expected(&self, expected: &str) -> String {
+        match self.peek() {
+            Some(t) => self.error(t.pos, format!("expected {} before '{}'", expected, t.text)),
+            None => self.error(self.last_pos(), format!("expected {}", expected)),
+        }
+    }
+
+    /// The source text of tokens `start..end`, with tokens on different lines joined by a
+    /// space.
+    fn code(&self, start: usize, end: usize) -> Code {
+        let mut text = String::new();
+        for (i, tok) in self.tokens[start..end].iter().enumerate() {
+            if i > 0 {
+                let prev = &self.tokens[start + i - 1];
+                if prev.pos.line != tok.pos.line {
+                    text.push(' ');
+                } else {
+                    let gap = tok.pos.col - (prev.pos.col + prev.text.len());
+                    text.extend(std::iter::repeat_n(' ', gap));
+                }
+            }
+            text.push_str(tok.text);
+        }
+        Code {
+            pos: self.tokens[start].pos,
+            text,
+        }
+    }
+
+    fn expect(&mut self, c: &str, msg: &str) -> Result<Pos, String> {
+        match self.eat(c) {
+            Some(pos) => Ok(pos),
+            None => Err(self.error(self.here(), msg)),
+        }
+    }
+
+    /// Read the punctuation `c` if it comes next.
+    fn eat(&mut self, c: &str) -> Option<Pos> {
+        if !self.is_punct(self.idx, c) {
+            return None;
+        }
+        self.idx += 1;
+        Some(self.tokens[self.idx - 1].pos)
+    }
+
+    /// The `;` that ends a statement or declaration; `eof` is the error when the file ends
+    /// first.
+    fn semicolon(&mut self, eof: &str) -> Result<(), String> {
+        if self.eat(";").is_some() {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(t) => Err(self.error(t.pos, format!("expected ';' before '{}'", t.text))),
+            None => Err(self.error(self.last_pos(), eof)),
+        }
+    }
+
+    /// The `)` matching the `(` at `open`.
+    fn close_paren(&mut self, open: Pos) -> Result<(), String> {
+        if self.eat(")").is_some() {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(t) => Err(self.error(t.pos, format!("expected ')' before '{}'", t.text))),
+            None => Err(self.error(open, "'(' is never closed")),
+        }
+    }
+
+    /// An identifier that is not a keyword.
+    fn name(&mut self) -> Result<(String, Pos), String> {
+        match self.peek() {
+            Some(t) if t.kind == TokenKind::Ident && !KEYWORDS.contains(&t.text) => {
+                let name = (t.text.to_string(), t.pos);
+                self.idx += 1;
+                Ok(name)
+            }
+            _ => Err(self.expected("a name")),
+        }
+    }
+
+    fn item(&mut self) -> Result<Item, String> {
+        let specs = self.specifiers()?;
+        if let Some(fields) = specs.fields {
+            let mut names = Vec::new();
+            if let TypeSpec::Struct(tag) = &specs.ty.spec
+                && !tag.is_empty()
+            {
+                names.push(tag.clone());
+            }
+            if specs.typedef {
+                names.push(self.name()?.0);
+            } else if self.peek().is_some() && !self.is_punct(self.idx, ";") {
+                return Err(self.error(
+                    self.here(),
+                    "declare struct variables separately from the struct definition",
+                ));
+            }
+            self.semicolon("expected ';' after struct definition")?;
+            if names.is_empty() {
+                return Err(self.error(specs.pos, "a struct needs a tag or a typedef name"));
+            }
+            return Ok(Item::Struct(StructDef {
+                pos: specs.pos,
+                names,
+                fields,
+            }));
+        }
+        if specs.typedef {
+            return Err(self.error(specs.pos, "only 'typedef struct' is supported"));
+        }
+        let star = usize::from(self.is_punct(self.idx, "*"));
+        if self.is_punct(self.idx + star + 1, "(") {
+            let header = self.fn_header(&specs)?;
+            if self.is_punct(self.idx, "{") {
+                let body = self.block()?;
+                return Ok(Item::Function { header, body });
+            }
+            self.semicolon("expected ';' at end of declaration")?;
+            return Ok(Item::Prototype(header));
+        }
+        let vars = self.declarators(&specs)?;
+        self.semicolon("expected ';' at end of declaration")?;
+        Ok(Item::Decl(Decl {
+            pos: specs.pos,
+            constant: specs.constant,
+            external: specs.external,
+            vars,
+        }))
+    }
+
+    /// Qualifiers and a type: `const unsigned char`, `extern int`, `struct Tag { ... }`.
+    fn specifiers(&mut self) -> Result<Specifiers, String> {
+        let pos = self.here();
+        let (mut constant, mut external, mut typedef) = (false, false, false);
+        while let Some(t) = self.peek().filter(|t| t.kind == TokenKind::Ident) {
+            let flag = match t.text {
+                "const" => &mut constant,
+                "extern" => &mut external,
+                "typedef" => &mut typedef,
+                "static" | "volatile" | "register" => {
+                    return Err(self.error(t.pos, format!("'{}' is not supported", t.text)));
+                }
+                _ => break,
+            };
+            *flag = true;
+            self.idx += 1;
+        }
+        let ty_pos = self.here();
+        let Some(word) = self
+            .peek()
+            .filter(|t| t.kind == TokenKind::Ident && !KEYWORDS.contains(&t.text))
+            .map(|t| t.text)
+        else {
+            return Err(self.expected("a type"));
+        };
+        self.idx += 1;
+        let next = self
+            .peek()
+            .filter(|t| t.kind == TokenKind::Ident)
+            .map(|t| t.text);
+        let mut fields = None;
+        let spec = match word {
+            "void" => TypeSpec::Void,
+            "int" => TypeSpec::Int { signed: true },
+            "unsigned" | "signed" => {
+                let signed = word == "signed";
+                self.idx += 1;
+                match next {
+                    Some("char") => TypeSpec::Char { signed },
+                    Some("int") => TypeSpec::Int { signed },
+                    _ => {
+                        return Err(self
+                            .error(ty_pos, format!("expected 'char' or 'int' after '{}'", word)));
+                    }
+                }
+            }
+            "char" => {
+                return Err(self.error(
+                    ty_pos,
+                    "plain 'char' is not supported; use 'unsigned char' or 'signed char'",
+                ));
+            }
+            "short" | "long" | "float" | "double" | "union" | "enum" => {
+                return Err(self.error(ty_pos, format!("'{}' is not supported", word)));
+            }
+            "struct" => {
+                if next.is_some() {
+                    self.idx += 1;
+                }
+                if self.is_punct(self.idx, "{") {
+                    fields = Some(self.struct_fields()?);
+                } else if next.is_none() {
+                    return Err(self.expected("a struct tag or '{'"));
+                }
+                TypeSpec::Struct(next.map_or_else(String::new, |tag| format!("struct {}", tag)))
+            }
+            name => TypeSpec::Struct(name.to_string()),
+        };
+        Ok(Specifiers {
+            pos,
+            constant,
+            external,
+            typedef,
+            ty: Type {
+                pos: ty_pos,
+                spec,
+                pointer: false,
+            },
+            fields,
+        })
+    }
+
+    /// The fields of a struct definition, from `{` to `}`.
+    fn struct_fields(&mut self) -> Result<Vec<Declarator>, String> {
+        let open = self.expect("{", "expected '{'")?;
+        let mut fields = Vec::new();
+        while self.eat("}").is_none() {
+            if self.peek().is_none() {
+                return Err(self.error(open, "'{' is never closed"));
+            }
+            let specs = self.specifiers()?;
+            if specs.fields.is_some() {
+                return Err(self.error(specs.pos, "nested struct definitions are not supported"));
+            }
+            fields.extend(self.declarators(&specs)?);
+            self.semicolon("expected ';' after struct field")?;
+        }
+        Ok(fields)
+    }
+
+    /// Whether a declaration starts at the next token: a type word, or a type name followed by
+    /// the name it declares.
+    fn at_decl(&self) -> bool {
+        self.peek().is_some_and(|t| {
+            t.kind == TokenKind::Ident
+                && (TYPE_WORDS.contains(&t.text)
+                    || (!KEYWORDS.contains(&t.text)
+                        && self
+                            .tokens
+                            .get(self.idx + 1)
+                            .is_some_and(|next| next.kind == TokenKind::Ident)))
+        })
+    }
+
+    /// A declaration inside a function, without its `;`.
+    fn local_decl(&mut self) -> Result<Decl, String> {
+        let specs = self.specifiers()?;
+        if specs.fields.is_some() {
+            return Err(self.error(specs.pos, "structs must be defined at global scope"));
+        }
+        if specs.typedef {
+            return Err(self.error(specs.pos, "'typedef' is only supported at global scope"));
+        }
+        Ok(Decl {
+            pos: specs.pos,
+            constant: specs.constant,
+            external: specs.external,
+            vars: self.declarators(&specs)?,
+        })
+    }
+
+    /// The comma-separated names of a declaration, with their array lengths and initializers.
+    fn declarators(&mut self, specs: &Specifiers) -> Result<Vec<Declarator>, String> {
+        let mut vars = Vec::new();
+        loop {
+            let ty = Type {
+                pointer: self.eat("*").is_some(),
+                ..specs.ty.clone()
+            };
+            let (name, pos) = self.name()?;
+            let array = match self.eat("[") {
+                Some(_) if self.eat("]").is_some() => Some(None),
+                Some(_) => {
+                    let len = self.expr()?;
+                    self.expect("]", "expected ']' after the array length")?;
+                    Some(Some(len))
+                }
+                None => None,
+            };
+            let init = match self.eat("=") {
+                Some(_) => Some(self.initializer()?),
+                None => None,
+            };
+            vars.push(Declarator {
+                pos,
+                name,
+                ty,
+                array,
+                init,
+            });
+            if self.eat(",").is_none() {
+                return Ok(vars);
+            }
+        }
+    }
+
+    /// An expression, or a `{ ... }` list with an optional trailing comma.
+    fn initializer(&mut self) -> Result<Init, String> {
+        let Some(open) = self.eat("{") else {
+            return Ok(Init::Expr(self.expr()?));
+        };
+        let mut items = Vec::new();
+        while !self.is_punct(self.idx, "}") {
+            items.push(self.expr()?);
+            if self.eat(",").is_none() {
+                break;
+            }
+        }
+        if self.eat("}").is_none() {
+            if self.peek().is_none() {
+                return Err(self.error(open, "'{' is never closed"));
+            }
+            return Err(self.expected("',' or '}'"));
+        }
+        Ok(Init::List(open, items))
+    }
+
+    /// The return type, name and parameters of a function.
+    fn fn_header(&mut self, specs: &Specifiers) -> Result<FnHeader, String> {
+        let ret = Type {
+            pointer: self.eat("*").is_some(),
+            ..specs.ty.clone()
+        };
+        let (name, _) = self.name()?;
+        let open = self.expect("(", "expected '('")?;
+        let mut params = Vec::new();
+        if self.is_ident(self.idx, "void") && self.is_punct(self.idx + 1, ")") {
+            self.idx += 1;
+        }
+        while !self.is_punct(self.idx, ")") {
+            if self.peek().is_none() {
+                return Err(self.error(open, "'(' is never closed"));
+            }
+            let param = self.specifiers()?;
+            if param.fields.is_some() {
+                return Err(self.error(param.pos, "structs must be defined at global scope"));
+            }
+            let ty = Type {
+                pointer: self.eat("*").is_some(),
+                ..param.ty
+            };
+            let name = match self.peek() {
+                Some(t) if t.kind == TokenKind::Ident => Some(self.name()?.0),
+                _ => None,
+            };
+            params.push(Param { ty, name });
+            if self.eat(",").is_none() {
+                break;
+            }
+        }
+        self.close_paren(open)?;
+        Ok(FnHeader {
+            pos: specs.pos,
+            name,
+            ret,
+            params,
+        })
+    }
+
+    fn block(&mut self) -> Result<Block, String> {
+        let open = self.expect("{", "expected '{'")?;
+        let mut stmts = Vec::new();
+        loop {
+            if self.peek().is_none() {
+                return Err(self.error(open, "'{' is never closed"));
+            }
+            if self.is_punct(self.idx, "}") {
+                let end = self.tokens[self.idx].pos;
+                self.idx += 1;
+                return Ok(Block { stmts, end });
+            }
+            stmts.push(self.stmt()?);
+        }
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, String> {
+        let Some(&Token { kind, text, pos }) = self.peek() else {
+            return Err(self.error(self.last_pos(), "expected a statement"));
+        };
+        let keyword = match kind {
+            TokenKind::Ident => text,
+            TokenKind::Punct if text == "{" => {
+                let block = self.block()?;
+                let end = block.end;
+                return Ok(Stmt {
+                    pos,
+                    end,
+                    kind: StmtKind::Block(block),
+                });
+            }
+            TokenKind::Punct if text == ";" => ";",
+            _ => "",
+        };
+        if matches!(
+            keyword,
+            ";" | "if"
+                | "while"
+                | "for"
+                | "switch"
+                | "asm"
+                | "__asm"
+                | "__asm__"
+                | "case"
+                | "default"
+                | "break"
+                | "continue"
+                | "return"
+        ) {
+            self.idx += 1;
+        }
+        let kind = match keyword {
+            ";" => StmtKind::Empty,
+            "if" => {
+                let cond = self.condition("if")?;
+                let then = Box::new(self.stmt()?);
+                let els = if self.is_ident(self.idx, "else") {
+                    self.idx += 1;
+                    Some(Box::new(self.stmt()?))
+                } else {
+                    None
+                };
+                StmtKind::If { cond, then, els }
+            }
+            "else" => return Err(self.error(pos, "'else' without a matching 'if'")),
+            "while" => {
+                let cond = self.condition("while")?;
+                let body = Box::new(self.stmt()?);
+                StmtKind::While { cond, body }
+            }
+            "for" => self.for_stmt()?,
+            "switch" => {
+                let selector = self.condition("switch")?;
+                let body = Box::new(self.stmt()?);
+                StmtKind::Switch { selector, body }
+            }
+            "asm" | "__asm" | "__asm__" => {
+                if self.is_ident(self.idx, "volatile") || self.is_ident(self.idx, "__volatile__") {
+                    self.idx += 1;
+                }
+                if self.is_punct(self.idx, "{") {
+                    StmtKind::Asm(self.asm_block()?)
+                } else {
+                    StmtKind::Asm(self.asm_strings(keyword)?)
+                }
+            }
+            "case" => {
+                let value = self.expr()?;
+                self.expect(":", "expected ':' after case value")?;
+                StmtKind::Case(Some(value))
+            }
+            "default" => {
+                self.expect(":", "expected ':' after 'default'")?;
+                StmtKind::Case(None)
+            }
+            "break" | "continue" => {
+                self.expect(";", &format!("expected ';' after '{}'", keyword))?;
+                if keyword == "break" {
+                    StmtKind::Break
+                } else {
+                    StmtKind::Continue
+                }
+            }
+            "return" => {
+                let value = match self.is_punct(self.idx, ";") {
+                    true => None,
+                    false => Some(self.expr()?),
+                };
+                self.semicolon("expected ';'")?;
+                StmtKind::Return(value)
+            }
+            "goto" | "do" => return Err(self.error(pos, format!("'{}' is not supported", text))),
+            _ if self.at_decl() => {
+                let decl = self.local_decl()?;
+                self.semicolon("expected ';' at end of declaration")?;
+                StmtKind::Decl(decl)
+            }
+            _ => {
+                let expr = self.expr()?;
+                self.semicolon("expected ';'")?;
+                StmtKind::Expr(expr)
+            }
+        };
+        let end = self.tokens[self.idx - 1].pos;
+        Ok(Stmt { pos, end, kind })
+    }
+
+    /// The parenthesized condition after `keyword`.
+    fn condition(&mut self, keyword: &str) -> Result<Expr, String> {
+        let open = self.expect("(", &format!("expected '(' after '{}'", keyword))?;
+        if self.is_punct(self.idx, ")") {
+            return Err(self.error(open, format!("empty condition in {}", keyword)));
+        }
+        let cond = self.expr()?;
+        self.close_paren(open)?;
+        Ok(cond)
+    }
+
+    /// `for (init; condition; step) body`, after the `for`; each clause may be empty.
+    fn for_stmt(&mut self) -> Result<StmtKind, String> {
+        let open = self.expect("(", "expected '(' after 'for'")?;
+        let clause_end = |p: &mut Self, c: &str| match p.eat(c) {
+            Some(_) => Ok(()),
+            None => Err(p.error(open, "for requires init; condition; step")),
+        };
+        let init = match self.peek() {
+            Some(t) if !(t.kind == TokenKind::Punct && t.text == ";") => {
+                let pos = t.pos;
+                let kind = match self.at_decl() {
+                    true => StmtKind::Decl(self.local_decl()?),
+                    false => StmtKind::Expr(self.expr()?),
+                };
+                let end = self.tokens[self.idx - 1].pos;
+                Some(Box::new(Stmt { pos, end, kind }))
+            }
+            _ => None,
+        };
+        clause_end(self, ";")?;
+        let cond = match self.is_punct(self.idx, ";") {
+            true => None,
+            false => Some(self.expr()?),
+        };
+        clause_end(self, ";")?;
+        let step = match self.is_punct(self.idx, ")") {
+            true => None,
+            false => Some(self.expr()?),
+        };
+        self.close_paren(open)?;
+        let body = Box::new(self.stmt()?);
+        Ok(StmtKind::For {
+            init,
+            cond,
+            step,
+            body,
+        })
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let target = self.binary(0)?;
+        let Some(pos) = self.eat("=") else {
+            return Ok(target);
+        };
+        let value = self.expr()?;
+        Ok(Expr {
+            pos,
+            kind: ExprKind::Assign(Box::new(target), Box::new(value)),
+        })
+    }
+
+    /// The operators of `BINARY_LEVELS[level]` and tighter ones.
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        let Some(ops) = BINARY_LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some((pos, op)) = self.peek().and_then(|t| {
+            let (_, op) = ops
+                .iter()
+                .find(|(text, _)| t.kind == TokenKind::Punct && t.text == *text)?;
+            Some((t.pos, *op))
+        }) {
+            self.idx += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr {
+                pos,
+                kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let Some(t) = self.peek().filter(|t| t.kind == TokenKind::Punct) else {
+            return self.postfix();
+        };
+        let (pos, text) = (t.pos, t.text);
+        let op = match text {
+            "-" => UnaryOp::Neg,
+            "~" => UnaryOp::BitNot,
+            "!" => UnaryOp::Not,
+            "*" => UnaryOp::Deref,
+            "&" => UnaryOp::AddrOf,
+            "+" => {
+                self.idx += 1;
+                return self.unary();
+            }
+            "++" | "--" => {
+                let op = text.to_string();
+                return Err(self.error(pos, format!("prefix '{}' is not supported", op)));
+            }
+            _ => return self.postfix(),
+        };
+        self.idx += 1;
+        let operand = self.unary()?;
+        Ok(Expr {
+            pos,
+            kind: ExprKind::Unary(op, Box::new(operand)),
+        })
+    }
+
+    /// A primary expression followed by indexes, fields, call arguments, `++` and `--`.
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        loop {
+            let pos = expr.pos;
+            let kind = if let Some(open) = self.eat("[") {
+                let index = self.expr()?;
+                if self.eat("]").is_none() {
+                    if self.peek().is_none() {
+                        return Err(self.error(open, "'[' is never closed"));
+                    }
+                    return Err(self.expected("']'"));
+                }
+                ExprKind::Index(Box::new(expr), Box::new(index))
+            } else if self.eat(".").is_some() {
+                ExprKind::Field(Box::new(expr), self.name()?.0)
+            } else if let Some(open) = self.eat("(") {
+                let ExprKind::Ident(name) = expr.kind else {
+                    return Err(self.error(pos, "only functions can be called by name"));
+                };
+                let mut args = Vec::new();
+                while !self.is_punct(self.idx, ")") {
+                    args.push(self.expr()?);
+                    if self.eat(",").is_none() {
+                        break;
+                    }
+                }
+                self.close_paren(open)?;
+                ExprKind::Call(name, args)
+            } else if self.eat("++").is_some() {
+                ExprKind::PostInc(Box::new(expr))
+            } else if self.eat("--").is_some() {
+                ExprKind::PostDec(Box::new(expr))
+            } else if let Some(arrow) = self.eat("->") {
+                return Err(self.error(arrow, "'->' is not supported"));
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr { pos, kind };
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let Some(&Token { kind, text, pos }) = self.peek() else {
+            return Err(self.expected("an expression"));
+        };
+        let kind = match kind {
+            TokenKind::Number => ExprKind::Number(self.number(text, pos)?),
+            TokenKind::Char => ExprKind::Number(self.char_value(text, pos)?),
+            TokenKind::Ident if !KEYWORDS.contains(&text) => ExprKind::Ident(text.to_string()),
+            TokenKind::Str => {
+                return Err(self.error(pos, "string literals are only supported in asm"));
+            }
+            TokenKind::Punct if text == "(" => {
+                self.idx += 1;
+                let inner = self.expr()?;
+                self.close_paren(pos)?;
+                return Ok(inner);
+            }
+            TokenKind::Punct if text == "[" => {
+                self.idx += 1;
+                let addr = self.expr()?;
+                if self.eat("]").is_none() {
+                    if self.peek().is_none() {
+                        return Err(self.error(pos, "'[' is never closed"));
+                    }
+                    return Err(self.expected("']'"));
+                }
+                return Ok(Expr {
+                    pos,
+                    kind: ExprKind::Mem(Box::new(addr)),
+                });
+            }
+            _ => return Err(self.expected("an expression")),
+        };
+        self.idx += 1;
+        Ok(Expr { pos, kind })
+    }
+
+    /// A decimal, `0x` or `$` number.
+    fn number(&self, text: &str, pos: Pos) -> Result<u16, String> {
+        let hex = text
+            .strip_prefix("0x")
+            .or_else(|| text.strip_prefix("0X"))
+            .or_else(|| text.strip_prefix('$'));
+        let value = match hex {
+            Some(digits) => u32::from_str_radix(digits, 16),
+            None => text.parse::<u32>(),
+        };
+        match value {
+            Ok(value) => u16::try_from(value)
+                .map_err(|_| self.error(pos, format!("'{}' does not fit in 16 bits", text))),
+            Err(_) => Err(self.error(pos, format!("invalid number '{}'", text))),
+        }
+    }
+
+    /// The value of a character literal such as `'a'` or `'\n'`.
+    fn char_value(&self, text: &str, pos: Pos) -> Result<u16, String> {
+        let inner = &text[1..text.len() - 1];
+        let mut chars = inner.chars();
+        let c = match (chars.next(), chars.next(), chars.next()) {
+            (Some('\\'), Some(escape), None) => match escape {
+                'n' => Some('\n'),
+                't' => Some('\t'),
+                'r' => Some('\r'),
+                '0' => Some('\0'),
+                '\\' | '\'' | '"' => Some(escape),
+                _ => None,
+            },
+            (Some(c), None, None) if c != '\\' => Some(c),
+            _ => None,
+        };
+        match c.filter(char::is_ascii) {
+            Some(c) => Ok(c as u16),
+            None => Err(self.error(pos, format!("invalid character literal {}", text))),
+        }
+    }
+
+    /// The lines of `asm { ... }`; a line break or `;` ends an instruction.
+    fn asm_block(&mut self) -> Result<Vec<Code>, String> {
+        let open = self.expect("{", "expected '{'")?;
+        let mut lines = Vec::new();
+        let mut start = self.idx;
+        loop {
+            let Some(tok) = self.peek() else {
+                return Err(self.error(open, "'{' is never closed"));
+            };
+            let (pos, end_line) = (tok.pos, tok.kind == TokenKind::Punct && tok.text == ";");
+            let close = tok.kind == TokenKind::Punct && tok.text == "}";
+            if tok.kind == TokenKind::Punct && tok.text == "{" {
+                return Err(self.error(pos, "unexpected '{' in asm block"));
+            }
+            let new_line = self.idx > start && self.tokens[self.idx - 1].pos.line != pos.line;
+            if (close || end_line || new_line) && self.idx > start {
+                lines.push(self.code(start, self.idx));
+            }
+            if close {
+                self.idx += 1;
+                return Ok(lines);
+            }
+            if end_line {
+                start = self.idx + 1;
+            } else if new_line {
+                start = self.idx;
+            }
+            self.idx += 1;
+        }
+    }
+
+    /// The lines of `__asm__("...")`. The strings are joined, and `\n` or `;` ends an
+    /// instruction.
+    fn asm_strings(&mut self, keyword: &str) -> Result<Vec<Code>, String> {
+        let open = self.expect("(", &format!("expected '{{' or '(' after '{}'", keyword))?;
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        let mut line_pos = open;
+        while let Some(tok) = self.peek().filter(|t| t.kind == TokenKind::Str) {
+            let pos = tok.pos;
+            let mut chars = tok.text[1..tok.text.len() - 1].chars();
+            while let Some(c) = chars.next() {
+                let c = match c {
+                    '\\' => match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c) => c,
+                        None => break,
+                    },
+                    c => c,
+                };
+                if c == '\n' || c == ';' {
+                    let text = line.trim();
+                    if !text.is_empty() {
+                        lines.push(Code {
+                            pos: line_pos,
+                            text: text.to_string(),
+                        });
+                    }
+                    line.clear();
+                    continue;
+                }
+                if line.trim().is_empty() {
+                    line_pos = pos;
+                }
+                line.push(c);
+            }
+            self.idx += 1;
+        }
+        if !line.trim().is_empty() {
+            lines.push(Code {
+                pos: line_pos,
+                text: line.trim().to_string(),
+            });
+        }
+        self.expect(
+            ")",
+            &format!("expected a string or ')' in {}(...)", keyword),
+        )?;
+        self.expect(";", &format!("expected ';' after {}(...)", keyword))?;
+        Ok(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn source(c: &str) -> Vec<SourceLine> {
+        c.lines()
+            .enumerate()
+            .map(|(i, text)| SourceLine {
+                path: PathBuf::from("main.c"),
+                canonical: PathBuf::from("main.c"),
+                line: i + 1,
+                col: text.len() - text.trim_start().len() + 1,
+                text: text.trim().to_string(),
+            })
+            .collect()
+    }
+
+    /// The tokens of `c` as `kind:text`.
+    fn tokens(c: &str) -> Vec<String> {
+        let lines = source(c);
+        lex(&lines)
+            .unwrap()
+            .iter()
+            .map(|t| {
+                let kind = match t.kind {
+                    TokenKind::Ident => "id",
+                    TokenKind::Number => "num",
+                    TokenKind::Str => "str",
+                    TokenKind::Char => "chr",
+                    TokenKind::Punct => "p",
+                };
+                format!("{}:{}", kind, t.text)
+            })
+            .collect()
+    }
+
+    /// An expression with every operand that is itself an operation in parentheses.
+    fn show_expr(expr: &Expr) -> String {
+        let operand = |e: &Expr| match e.kind {
+            ExprKind::Binary(..) | ExprKind::Assign(..) => format!("({})", show_expr(e)),
+            _ => show_expr(e),
+        };
+        match &expr.kind {
+            ExprKind::Number(value) => value.to_string(),
+            ExprKind::Ident(name) => name.clone(),
+            ExprKind::Unary(op, inner) => {
+                let op = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::BitNot => "~",
+                    UnaryOp::Not => "!",
+                    UnaryOp::Deref => "*",
+                    UnaryOp::AddrOf => "&",
+                };
+                format!("{}{}", op, operand(inner))
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                let (text, _) = BINARY_LEVELS
+                    .iter()
+                    .flat_map(|level| level.iter())
+                    .find(|(_, known)| known == op)
+                    .unwrap();
+                format!("{} {} {}", operand(lhs), text, operand(rhs))
+            }
+            ExprKind::Index(base, index) => format!("{}[{}]", show_expr(base), show_expr(index)),
+            ExprKind::Mem(addr) => format!("[{}]", show_expr(addr)),
+            ExprKind::Field(base, field) => format!("{}.{}", show_expr(base), field),
+            ExprKind::Call(name, args) => {
+                let args: Vec<_> = args.iter().map(show_expr).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            ExprKind::Assign(target, value) => match value.kind {
+                ExprKind::Assign(..) => format!("{} = ({})", show_expr(target), show_expr(value)),
+                _ => format!("{} = {}", show_expr(target), show_expr(value)),
+            },
+            ExprKind::PostInc(target) => format!("{}++", show_expr(target)),
+            ExprKind::PostDec(target) => format!("{}--", show_expr(target)),
+        }
+    }
+
+    fn show_type(ty: &Type) -> String {
+        match &ty.spec {
+            TypeSpec::Void => "void".to_string(),
+            TypeSpec::Char { signed: true } => "signed char".to_string(),
+            TypeSpec::Char { signed: false } => "unsigned char".to_string(),
+            TypeSpec::Int { signed: true } => "int".to_string(),
+            TypeSpec::Int { signed: false } => "unsigned int".to_string(),
+            TypeSpec::Struct(name) => name.clone(),
+        }
+    }
+
+    /// A declarator without its base type.
+    fn show_declarator(var: &Declarator) -> String {
+        let mut text = format!("{}{}", if var.ty.pointer { "*" } else { "" }, var.name);
+        match &var.array {
+            Some(Some(len)) => text += &format!("[{}]", show_expr(len)),
+            Some(None) => text += "[]",
+            None => {}
+        }
+        match &var.init {
+            Some(Init::Expr(value)) => text += &format!(" = {}", show_expr(value)),
+            Some(Init::List(_, items)) => {
+                let items: Vec<_> = items.iter().map(show_expr).collect();
+                text += &format!(" = {{ {} }}", items.join(", "));
+            }
+            None => {}
+        }
+        text
+    }
+
+    fn show_decl(decl: &Decl) -> String {
+        let vars: Vec<_> = decl.vars.iter().map(show_declarator).collect();
+        format!(
+            "{}{}{} {};",
+            if decl.external { "extern " } else { "" },
+            if decl.constant { "const " } else { "" },
+            show_type(&decl.vars[0].ty),
+            vars.join(", ")
+        )
+    }
+
+    fn show_header(header: &FnHeader) -> String {
+        let params: Vec<_> = header
+            .params
+            .iter()
+            .map(|param| match &param.name {
+                Some(name) => format!("{} {}", show_type(&param.ty), name),
+                None => show_type(&param.ty),
+            })
+            .collect();
+        format!(
+            "{} {}({})",
+            show_type(&header.ret),
+            header.name,
+            params.join(", ")
+        )
+    }
+
+    /// A compact rendering of a statement tree.
+    fn show(stmt: &Stmt) -> String {
+        let opt = |e: &Option<Expr>| e.as_ref().map_or(String::new(), show_expr);
+        match &stmt.kind {
+            StmtKind::Expr(expr) => format!("{};", show_expr(expr)),
+            StmtKind::Decl(decl) => show_decl(decl),
+            StmtKind::Return(value) => match value {
+                Some(value) => format!("return {};", show_expr(value)),
+                None => "return;".to_string(),
+            },
+            StmtKind::Block(block) => show_block(block),
+            StmtKind::If { cond, then, els } => match els {
+                Some(els) => format!("if({}){} else {}", show_expr(cond), show(then), show(els)),
+                None => format!("if({}){}", show_expr(cond), show(then)),
+            },
+            StmtKind::While { cond, body } => format!("while({}){}", show_expr(cond), show(body)),
+            StmtKind::For {
+                init,
+                cond,
+                step,
+                body,
+            } => format!(
+                "for({}{};{}){}",
+                init.as_ref().map_or(";".to_string(), |init| show(init)),
+                opt(cond),
+                opt(step),
+                show(body)
+            ),
+            StmtKind::Switch { selector, body } => {
+                format!("switch({}){}", show_expr(selector), show(body))
+            }
+            StmtKind::Case(Some(value)) => format!("case {}:", show_expr(value)),
+            StmtKind::Case(None) => "default:".to_string(),
+            StmtKind::Break => "break;".to_string(),
+            StmtKind::Continue => "continue;".to_string(),
+            StmtKind::Empty => ";".to_string(),
+            StmtKind::Asm(lines) => {
+                let lines: Vec<_> = lines.iter().map(|l| l.text.as_str()).collect();
+                format!("asm[{}]", lines.join("|"))
+            }
+        }
+    }
+
+    fn show_block(block: &Block) -> String {
+        let stmts: Vec<_> = block.stmts.iter().map(show).collect();
+        format!("{{{}}}", stmts.join(" "))
+    }
+
+    /// The items of `c`, rendered back to C.
+    fn parse(c: &str) -> Vec<String> {
+        let lines = source(c);
+        let items = parse_unit(&lines).unwrap_or_else(|e| panic!("{}", e));
+        items
+            .iter()
+            .map(|item| match item {
+                Item::Decl(decl) => show_decl(decl),
+                Item::Struct(def) => {
+                    let fields: Vec<_> = def
+                        .fields
+                        .iter()
+                        .map(|f| format!("{} {};", show_type(&f.ty), show_declarator(f)))
+                        .collect();
+                    format!("struct {} {{ {} }}", def.names.join("/"), fields.join(" "))
+                }
+                Item::Prototype(header) => format!("{};", show_header(header)),
+                Item::Function { header, body } => {
+                    format!("{} {}", show_header(header), show_block(body))
+                }
+            })
+            .collect()
+    }
+
+    /// The statements of the single function in `c`.
+    fn stmts(c: &str) -> Vec<Stmt> {
+        let lines = source(c);
+        let mut items = parse_unit(&lines).unwrap_or_else(|e| panic!("{}", e));
+        let Some(Item::Function { body, .. }) = items.pop() else {
+            panic!("expected a function");
+        };
+        body.stmts
+    }
+
+    /// The body of the single function in `c`.
+    fn body(c: &str) -> String {
+        let items = parse(&format!("void F() {{\n{}\n}}", c));
+        items[0].strip_prefix("void F() ").unwrap().to_string()
+    }
+
+    fn parse_error(c: &str) -> String {
+        let lines = source(c);
+        match parse_unit(&lines) {
+            Ok(_) => panic!("expected an error for {:?}", c),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn lexer_splits_words_numbers_literals_and_punctuation() {
+        assert_eq!(
+            tokens("x_1 = 0x1F+'a'; s = \"a \\\" b\";"),
+            [
+                "id:x_1",
+                "p:=",
+                "num:0x1F",
+                "p:+",
+                "chr:'a'",
+                "p:;",
+                "id:s",
+                "p:=",
+                "str:\"a \\\" b\"",
+                "p:;"
+            ]
+        );
+        assert_eq!(tokens("a<=b"), ["id:a", "p:<=", "id:b"]);
+        assert_eq!(tokens("x<<=$FF"), ["id:x", "p:<<", "p:=", "num:$FF"]);
+        assert_eq!(tokens("'\\\\' '\\''"), ["chr:'\\\\'", "chr:'\\''"]);
+    }
+
+    #[test]
+    fn lexer_positions_count_from_the_line_start() {
+        let lines = source("int a;\n    b = 1;");
+        let toks = lex(&lines).unwrap();
+        let b = toks.iter().find(|t| t.text == "b").unwrap();
+        assert_eq!(b.pos, Pos { line: 1, col: 5 });
+    }
+
+    #[test]
+    fn lexer_rejects_unterminated_literals() {
+        let lines = source("s = \"abc;");
+        let e = lex(&lines).err().unwrap();
+        assert_eq!(e, "C parse error: main.c:1:5: unterminated string literal");
+        let lines = source("c = 'a;");
+        let e = lex(&lines).err().unwrap();
+        assert_eq!(
+            e,
+            "C parse error: main.c:1:5: unterminated character literal"
+        );
+    }
+
+    #[test]
+    fn declarations_and_functions_are_items() {
+        assert_eq!(
+            parse(
+                "unsigned char x;\n\
+                 const unsigned char t[] = {\n  1, 2, };\n\
+                 unsigned char Add(unsigned char a,\n  unsigned char b);\n\
+                 void Init(void)\n{\n  x = 1;\n}"
+            ),
+            [
+                "unsigned char x;",
+                "const unsigned char t[] = { 1, 2 };",
+                "unsigned char Add(unsigned char a, unsigned char b);",
+                "void Init() {x = 1;}"
+            ]
+        );
+    }
+
+    #[test]
+    fn declarations_list_several_names() {
+        assert_eq!(
+            parse(
+                "unsigned char a, *p = &a, t[LEN * 2] = { 'a', -1 };\n\
+                 extern ChipSprite sprite[];\n\
+                 void Draw(int, signed char);"
+            ),
+            [
+                "unsigned char a, *p = &a, t[LEN * 2] = { 97, -1 };",
+                "extern ChipSprite sprite[];",
+                "void Draw(int, signed char);"
+            ]
+        );
+        assert_eq!(
+            parse(
+                "typedef struct Enemy {\n  unsigned char x, y;\n  int hp;\n} Enemy;\n\
+                 struct Enemy boss;"
+            ),
+            [
+                "struct struct Enemy/Enemy { unsigned char x; unsigned char y; int hp; }",
+                "struct Enemy boss;"
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions_follow_c_precedence() {
+        assert_eq!(
+            body("x = a + b * c << 1 | d & 0x0F;"),
+            "{x = ((a + (b * c)) << 1) | (d & 15);}"
+        );
+        assert_eq!(
+            body("if (a < 1 || b == 2 && !c) y = -z[i].hp + *p;"),
+            "{if((a < 1) || ((b == 2) && !c))y = -z[i].hp + *p;}"
+        );
+        assert_eq!(
+            body("[VRAM + i] = mem[(x - 1) * 2] ^ ~F(1, G(2));"),
+            "{[VRAM + i] = mem[(x - 1) * 2] ^ ~F(1, G(2));}"
+        );
+        assert_eq!(body("a = b = $10;"), "{a = (b = 16);}");
+    }
+
+    #[test]
+    fn control_flow_nests() {
+        assert_eq!(
+            body("if (a > 1) b = 1; else if (a) { b = 2; } else b = 3;"),
+            "{if(a > 1)b = 1; else if(a){b = 2;} else b = 3;}"
+        );
+        assert_eq!(
+            body("while (i < 10) { if (i == 3) continue; i++; }"),
+            "{while(i < 10){if(i == 3)continue; i++;}}"
+        );
+        assert_eq!(
+            body("for (unsigned char i = 0; i < 4; i++) ;"),
+            "{for(unsigned char i = 0;i < 4;i++);}"
+        );
+        assert_eq!(body("for (;;) break;"), "{for(;;)break;}");
+        assert_eq!(body("return (x);"), "{return x;}");
+    }
+
+    #[test]
+    fn dangling_else_binds_to_the_nearest_if() {
+        assert_eq!(
+            body("if (a) if (b) x = 1; else x = 2;"),
+            "{if(a)if(b)x = 1; else x = 2;}"
+        );
+    }
+
+    #[test]
+    fn switch_cases_are_statements() {
+        assert_eq!(
+            body("switch (s) { case 0: case (1 + 2): x = 1; break; default: x = 0; }"),
+            "{switch(s){case 0: case 1 + 2: x = 1; break; default: x = 0;}}"
+        );
+    }
+
+    #[test]
+    fn statement_spanning_lines_keeps_positions() {
+        let lines = source("void F() {\n  a = b\n      + c;\n}");
+        let items = parse_unit(&lines).unwrap();
+        let Item::Function { body, .. } = &items[0] else {
+            panic!("expected a function");
+        };
+        let stmt = &body.stmts[0];
+        let StmtKind::Expr(expr) = &stmt.kind else {
+            panic!("expected an expression statement");
+        };
+        assert_eq!(show_expr(expr), "a = b + c");
+        assert_eq!(stmt.pos, Pos { line: 1, col: 3 });
+        assert_eq!(stmt.end, Pos { line: 2, col: 10 });
+        assert_eq!(body.end, Pos { line: 3, col: 1 });
+    }
+
+    #[test]
+    fn expression_nodes_keep_their_positions() {
+        let stmts = stmts("void F() {\n  x = a +\n    b * t[i];\n}");
+        let StmtKind::Expr(assign) = &stmts[0].kind else {
+            panic!("expected an expression statement");
+        };
+        let ExprKind::Assign(target, value) = &assign.kind else {
+            panic!("expected an assignment");
+        };
+        assert_eq!(assign.pos, Pos { line: 1, col: 5 });
+        assert_eq!(assign.start(), Pos { line: 1, col: 3 });
+        assert_eq!(target.pos, Pos { line: 1, col: 3 });
+        let ExprKind::Binary(BinaryOp::Add, a, product) = &value.kind else {
+            panic!("expected a sum");
+        };
+        assert_eq!(value.pos, Pos { line: 1, col: 9 });
+        assert_eq!(a.pos, Pos { line: 1, col: 7 });
+        let ExprKind::Binary(BinaryOp::Mul, b, index) = &product.kind else {
+            panic!("expected a product");
+        };
+        assert_eq!(product.pos, Pos { line: 2, col: 7 });
+        assert_eq!(product.start(), Pos { line: 2, col: 5 });
+        assert_eq!(b.pos, Pos { line: 2, col: 5 });
+        assert_eq!(index.pos, Pos { line: 2, col: 9 });
+    }
+
+    #[test]
+    fn asm_blocks_split_on_lines_and_semicolons() {
+        assert_eq!(
+            body("asm {\n  LDA #1; STA $10\n  @loop: DEX\n}"),
+            "{asm[LDA #1|STA $10|@loop: DEX]}"
+        );
+        assert_eq!(
+            body("__asm__ volatile (\"LDA #1\\n\"\n  \"STA $10; RTS\");"),
+            "{asm[LDA #1|STA $10|RTS]}"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_problem() {
+        assert_eq!(
+            parse_error("void F() {\n  else x = 1;\n}"),
+            "C parse error: main.c:2:3: 'else' without a matching 'if'"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  x = 1\n}"),
+            "C parse error: main.c:3:1: expected ';' before '}'"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  x = 1;"),
+            "C parse error: main.c:1:10: '{' is never closed"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  for (i = 0; i < 3) x++;\n}"),
+            "C parse error: main.c:2:7: for requires init; condition; step"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  while () x++;\n}"),
+            "C parse error: main.c:2:9: empty condition in while"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  x = 1 if (y) z;\n}"),
+            "C parse error: main.c:2:9: expected ';' before 'if'"
+        );
+        assert_eq!(
+            parse_error("int x"),
+            "C parse error: main.c:1:5: expected ';' at end of declaration"
+        );
+    }
+
+    #[test]
+    fn expression_errors_point_at_the_bad_token() {
+        assert_eq!(
+            parse_error("void F() {\n  x = (a +) * 2;\n}"),
+            "C parse error: main.c:2:11: expected an expression before ')'"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  x = t[i;\n}"),
+            "C parse error: main.c:2:10: expected ']' before ';'"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  x = 70000;\n}"),
+            "C parse error: main.c:2:7: '70000' does not fit in 16 bits"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  x = p->y;\n}"),
+            "C parse error: main.c:2:8: '->' is not supported"
+        );
+        assert_eq!(
+            parse_error("void F() {\n  ++x;\n}"),
+            "C parse error: main.c:2:3: prefix '++' is not supported"
+        );
+    }
+}
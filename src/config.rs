@@ -1,3 +1,4 @@
+use crate::asm6502::CpuKind;
 use serde::{Deserialize, Serialize};
 use std::fs;
 
@@ -15,6 +16,18 @@ pub struct MachineConfig {
     pub refresh_hz: u32,
 }
 
+impl MachineConfig {
+    /// Processor named by `cpu`: `6502` (NMOS) or `65c02`.
+    pub fn cpu_kind(&self) -> Result<CpuKind, String> {
+        CpuKind::from_name(&self.cpu).ok_or_else(|| {
+            format!(
+                "Unsupported cpu '{}' in chipcade.toml (expected \"6502\" or \"65c02\")",
+                self.cpu
+            )
+        })
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct VideoConfig {
     pub width: u32,
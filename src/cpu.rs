@@ -0,0 +1,395 @@
+use crate::asm6502::{CpuKind, Mode, OpcodeInfo, opcode_info};
+use crate::bus::ChipcadeBus;
+use mos6502::Variant;
+use mos6502::cpu::CPU;
+use mos6502::instruction::{Cmos6502, Instruction, Nmos6502, OpInput};
+use mos6502::memory::Bus;
+use mos6502::registers::{Registers, Status};
+
+/// The emulated processor selected by `cpu` in `chipcade.toml`.
+pub enum Cpu {
+    Nmos(CPU<ChipcadeBus, Nmos6502>),
+    Cmos(CPU<ChipcadeBus, Cmos6502>),
+}
+
+impl Cpu {
+    pub fn new(kind: CpuKind, bus: ChipcadeBus) -> Self {
+        match kind {
+            CpuKind::Nmos6502 => Cpu::Nmos(CPU::new(bus, Nmos6502)),
+            CpuKind::Cmos65c02 => Cpu::Cmos(CPU::new(bus, Cmos6502)),
+        }
+    }
+
+    pub fn kind(&self) -> CpuKind {
+        match self {
+            Cpu::Nmos(_) => CpuKind::Nmos6502,
+            Cpu::Cmos(_) => CpuKind::Cmos65c02,
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        match self {
+            Cpu::Nmos(cpu) => &cpu.registers,
+            Cpu::Cmos(cpu) => &cpu.registers,
+        }
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        match self {
+            Cpu::Nmos(cpu) => &mut cpu.registers,
+            Cpu::Cmos(cpu) => &mut cpu.registers,
+        }
+    }
+
+    pub fn memory(&self) -> &ChipcadeBus {
+        match self {
+            Cpu::Nmos(cpu) => &cpu.memory,
+            Cpu::Cmos(cpu) => &cpu.memory,
+        }
+    }
+
+    pub fn memory_mut(&mut self) -> &mut ChipcadeBus {
+        match self {
+            Cpu::Nmos(cpu) => &mut cpu.memory,
+            Cpu::Cmos(cpu) => &mut cpu.memory,
+        }
+    }
+
+    /// Execute the instruction at PC. Opcodes the processor does not decode are skipped
+    /// without moving PC, as in the underlying cores.
+    pub fn single_step(&mut self) {
+        match self {
+            Cpu::Nmos(cpu) => {
+                if !undocumented_step(cpu) {
+                    cpu.single_step();
+                }
+            }
+            Cpu::Cmos(cpu) => {
+                if !cmos_step(cpu) {
+                    cpu.single_step();
+                }
+            }
+        }
+    }
+}
+
+/// Fetch the operand of the `kind` instruction at PC and advance PC past it. `None` for
+/// opcodes outside the table and modes the extension opcodes do not use.
+fn fetch_operand<V: Variant>(
+    cpu: &mut CPU<ChipcadeBus, V>,
+    kind: CpuKind,
+) -> Option<(OpcodeInfo, OpInput)> {
+    let pc = cpu.registers.program_counter;
+    let info = opcode_info(kind, cpu.memory.get_byte(pc))?;
+    let lo = cpu.memory.get_byte(pc.wrapping_add(1));
+    let hi = cpu.memory.get_byte(pc.wrapping_add(2));
+    let (x, y) = (cpu.registers.index_x, cpu.registers.index_y);
+    let mut pointer = |zp: u8| {
+        u16::from_le_bytes([
+            cpu.memory.get_byte(zp as u16),
+            cpu.memory.get_byte(zp.wrapping_add(1) as u16),
+        ])
+    };
+    let input = match info.mode {
+        Mode::Implied | Mode::Accumulator => OpInput::UseImplied,
+        Mode::Immediate => OpInput::UseImmediate(lo),
+        Mode::ZeroPage => OpInput::UseAddress(lo as u16),
+        Mode::ZeroPageX => OpInput::UseAddress(lo.wrapping_add(x) as u16),
+        Mode::ZeroPageY => OpInput::UseAddress(lo.wrapping_add(y) as u16),
+        Mode::Absolute => OpInput::UseAddress(u16::from_le_bytes([lo, hi])),
+        Mode::AbsoluteX => OpInput::UseAddress(u16::from_le_bytes([lo, hi]).wrapping_add(x as u16)),
+        Mode::AbsoluteY => OpInput::UseAddress(u16::from_le_bytes([lo, hi]).wrapping_add(y as u16)),
+        Mode::IndexedIndirect => OpInput::UseAddress(pointer(lo.wrapping_add(x))),
+        Mode::IndirectIndexed => OpInput::UseAddress(pointer(lo).wrapping_add(y as u16)),
+        Mode::ZeroPageIndirect => OpInput::UseAddress(pointer(lo)),
+        Mode::Indirect | Mode::Relative => return None,
+    };
+    cpu.registers.program_counter = pc.wrapping_add(info.size() as u16);
+    Some((info, input))
+}
+
+fn set_zero_negative(status: &mut Status, value: u8) {
+    status.set(Status::PS_ZERO, value == 0);
+    status.set(Status::PS_NEGATIVE, value & 0x80 != 0);
+}
+
+/// Execute the 65C02 opcodes the core gets wrong: it decodes `INC A`/`DEC A` without
+/// executing them and swaps the effects of `TSB` and `TRB`. Returns false for other opcodes.
+fn cmos_step(cpu: &mut CPU<ChipcadeBus, Cmos6502>) -> bool {
+    let opcode = cpu.memory.get_byte(cpu.registers.program_counter);
+    if !matches!(opcode, 0x1A | 0x3A | 0x04 | 0x0C | 0x14 | 0x1C) {
+        return false;
+    }
+    let Some((info, input)) = fetch_operand(cpu, CpuKind::Cmos65c02) else {
+        return false;
+    };
+    let a = cpu.registers.accumulator;
+    match (info.mnemonic, input) {
+        ("INC", _) | ("DEC", _) => {
+            let value = if info.mnemonic == "INC" {
+                a.wrapping_add(1)
+            } else {
+                a.wrapping_sub(1)
+            };
+            cpu.registers.accumulator = value;
+            set_zero_negative(&mut cpu.registers.status, value);
+        }
+        (mnemonic, OpInput::UseAddress(addr)) => {
+            let value = cpu.memory.get_byte(addr);
+            cpu.registers.status.set(Status::PS_ZERO, a & value == 0);
+            let value = if mnemonic == "TSB" {
+                value | a
+            } else {
+                value & !a
+            };
+            cpu.memory.set_byte(addr, value);
+        }
+        _ => {}
+    }
+    true
+}
+
+/// Execute a stable undocumented NMOS opcode at PC as the documented operations it combines.
+/// Returns false when the opcode is not one of them.
+fn undocumented_step(cpu: &mut CPU<ChipcadeBus, Nmos6502>) -> bool {
+    if Nmos6502::decode(cpu.memory.get_byte(cpu.registers.program_counter)).is_some() {
+        return false;
+    }
+    let Some((info, input)) = fetch_operand(cpu, CpuKind::Nmos6502) else {
+        return false;
+    };
+    let x = cpu.registers.index_x;
+
+    let mut execute = |instruction, input| cpu.execute_instruction((instruction, input));
+    match info.mnemonic {
+        "SLO" => {
+            execute(Instruction::ASL, input);
+            execute(Instruction::ORA, input);
+        }
+        "RLA" => {
+            execute(Instruction::ROL, input);
+            execute(Instruction::AND, input);
+        }
+        "SRE" => {
+            execute(Instruction::LSR, input);
+            execute(Instruction::EOR, input);
+        }
+        "RRA" => {
+            execute(Instruction::ROR, input);
+            execute(Instruction::ADC, input);
+        }
+        "DCP" => {
+            execute(Instruction::DEC, input);
+            execute(Instruction::CMP, input);
+        }
+        "ISC" => {
+            execute(Instruction::INC, input);
+            execute(Instruction::SBC, input);
+        }
+        "LAX" => {
+            execute(Instruction::LDA, input);
+            execute(Instruction::TAX, OpInput::UseImplied);
+        }
+        "ALR" => {
+            execute(Instruction::AND, input);
+            execute(Instruction::LSR, OpInput::UseImplied);
+        }
+        "ANC" => {
+            execute(Instruction::AND, input);
+            let status = &mut cpu.registers.status;
+            status.set(Status::PS_CARRY, status.contains(Status::PS_NEGATIVE));
+        }
+        "ARR" => {
+            execute(Instruction::AND, input);
+            execute(Instruction::ROR, OpInput::UseImplied);
+            let a = cpu.registers.accumulator;
+            let status = &mut cpu.registers.status;
+            status.set(Status::PS_CARRY, a & 0x40 != 0);
+            status.set(Status::PS_OVERFLOW, ((a >> 6) ^ (a >> 5)) & 1 != 0);
+        }
+        "SAX" => {
+            if let OpInput::UseAddress(addr) = input {
+                let value = cpu.registers.accumulator & x;
+                cpu.memory.set_byte(addr, value);
+            }
+        }
+        "SBX" => {
+            if let OpInput::UseImmediate(value) = input {
+                let ax = cpu.registers.accumulator & x;
+                let result = ax.wrapping_sub(value);
+                cpu.registers.index_x = result;
+                cpu.registers.status.set(Status::PS_CARRY, ax >= value);
+                set_zero_negative(&mut cpu.registers.status, result);
+            }
+        }
+        _ => {}
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::sprites::SpritePack;
+
+    /// Execute the single instruction `code` from $0200 after `setup` has prepared the
+    /// registers and memory, and check that PC moved past it.
+    fn step(kind: CpuKind, code: &[u8], setup: impl FnOnce(&mut Cpu)) -> Cpu {
+        let bus = ChipcadeBus::from_config(&Config::default(), None, SpritePack::default());
+        let mut cpu = Cpu::new(kind, bus);
+        for (offset, byte) in code.iter().enumerate() {
+            cpu.memory_mut().set_byte(0x0200 + offset as u16, *byte);
+        }
+        cpu.registers_mut().program_counter = 0x0200;
+        setup(&mut cpu);
+        cpu.single_step();
+        assert_eq!(
+            cpu.registers().program_counter,
+            0x0200 + code.len() as u16,
+            "PC after {:02X?}",
+            code
+        );
+        cpu
+    }
+
+    fn flag(cpu: &Cpu, flag: Status) -> bool {
+        cpu.registers().status.contains(flag)
+    }
+
+    fn byte(cpu: &mut Cpu, addr: u16) -> u8 {
+        cpu.memory_mut().get_byte(addr)
+    }
+
+    #[test]
+    fn lax_loads_a_and_x() {
+        let cpu = step(CpuKind::Nmos6502, &[0xA7, 0x10], |cpu| {
+            cpu.memory_mut().set_byte(0x10, 0x80);
+        });
+        assert_eq!(cpu.registers().accumulator, 0x80);
+        assert_eq!(cpu.registers().index_x, 0x80);
+        assert!(flag(&cpu, Status::PS_NEGATIVE));
+        assert!(!flag(&cpu, Status::PS_ZERO));
+    }
+
+    #[test]
+    fn sax_stores_a_and_x() {
+        let mut cpu = step(CpuKind::Nmos6502, &[0x8F, 0x00, 0x03], |cpu| {
+            cpu.registers_mut().accumulator = 0xF0;
+            cpu.registers_mut().index_x = 0x3C;
+        });
+        assert_eq!(byte(&mut cpu, 0x0300), 0x30);
+        assert_eq!(cpu.registers().accumulator, 0xF0);
+        assert_eq!(cpu.registers().index_x, 0x3C);
+    }
+
+    #[test]
+    fn dcp_decrements_then_compares() {
+        let mut cpu = step(CpuKind::Nmos6502, &[0xD7, 0x10], |cpu| {
+            cpu.registers_mut().index_x = 2;
+            cpu.registers_mut().accumulator = 0x04;
+            cpu.memory_mut().set_byte(0x12, 0x05);
+        });
+        assert_eq!(byte(&mut cpu, 0x12), 0x04);
+        assert!(flag(&cpu, Status::PS_ZERO));
+        assert!(flag(&cpu, Status::PS_CARRY));
+    }
+
+    #[test]
+    fn isc_increments_then_subtracts() {
+        let mut cpu = step(CpuKind::Nmos6502, &[0xE7, 0x10], |cpu| {
+            cpu.registers_mut().accumulator = 0x05;
+            cpu.registers_mut().status.insert(Status::PS_CARRY);
+            cpu.memory_mut().set_byte(0x10, 0x01);
+        });
+        assert_eq!(byte(&mut cpu, 0x10), 0x02);
+        assert_eq!(cpu.registers().accumulator, 0x03);
+        assert!(flag(&cpu, Status::PS_CARRY));
+        assert!(!flag(&cpu, Status::PS_ZERO));
+    }
+
+    #[test]
+    fn slo_shifts_then_ors() {
+        let mut cpu = step(CpuKind::Nmos6502, &[0x0F, 0x00, 0x03], |cpu| {
+            cpu.registers_mut().accumulator = 0x01;
+            cpu.memory_mut().set_byte(0x0300, 0x81);
+        });
+        assert_eq!(byte(&mut cpu, 0x0300), 0x02);
+        assert_eq!(cpu.registers().accumulator, 0x03);
+        assert!(flag(&cpu, Status::PS_CARRY));
+        assert!(!flag(&cpu, Status::PS_NEGATIVE));
+    }
+
+    #[test]
+    fn arr_ands_rotates_and_sets_carry_and_overflow_from_the_result() {
+        let cpu = step(CpuKind::Nmos6502, &[0x6B, 0xFF], |cpu| {
+            cpu.registers_mut().accumulator = 0xC0;
+            cpu.registers_mut().status.insert(Status::PS_CARRY);
+        });
+        assert_eq!(cpu.registers().accumulator, 0xE0);
+        assert!(flag(&cpu, Status::PS_CARRY));
+        assert!(!flag(&cpu, Status::PS_OVERFLOW));
+        assert!(flag(&cpu, Status::PS_NEGATIVE));
+
+        let cpu = step(CpuKind::Nmos6502, &[0x6B, 0x40], |cpu| {
+            cpu.registers_mut().accumulator = 0xFF;
+            cpu.registers_mut().status.remove(Status::PS_CARRY);
+        });
+        assert_eq!(cpu.registers().accumulator, 0x20);
+        assert!(!flag(&cpu, Status::PS_CARRY));
+        assert!(flag(&cpu, Status::PS_OVERFLOW));
+    }
+
+    #[test]
+    fn sbx_subtracts_from_a_and_x() {
+        let cpu = step(CpuKind::Nmos6502, &[0xCB, 0x02], |cpu| {
+            cpu.registers_mut().accumulator = 0x0F;
+            cpu.registers_mut().index_x = 0xF3;
+        });
+        assert_eq!(cpu.registers().index_x, 0x01);
+        assert_eq!(cpu.registers().accumulator, 0x0F);
+        assert!(flag(&cpu, Status::PS_CARRY));
+
+        let cpu = step(CpuKind::Nmos6502, &[0xCB, 0x04], |cpu| {
+            cpu.registers_mut().accumulator = 0x0F;
+            cpu.registers_mut().index_x = 0xF3;
+        });
+        assert_eq!(cpu.registers().index_x, 0xFF);
+        assert!(!flag(&cpu, Status::PS_CARRY));
+        assert!(flag(&cpu, Status::PS_NEGATIVE));
+    }
+
+    #[test]
+    fn cmos_inc_and_dec_a() {
+        let cpu = step(CpuKind::Cmos65c02, &[0x1A], |cpu| {
+            cpu.registers_mut().accumulator = 0xFF;
+        });
+        assert_eq!(cpu.registers().accumulator, 0x00);
+        assert!(flag(&cpu, Status::PS_ZERO));
+
+        let cpu = step(CpuKind::Cmos65c02, &[0x3A], |cpu| {
+            cpu.registers_mut().accumulator = 0x00;
+        });
+        assert_eq!(cpu.registers().accumulator, 0xFF);
+        assert!(flag(&cpu, Status::PS_NEGATIVE));
+        assert!(!flag(&cpu, Status::PS_ZERO));
+    }
+
+    #[test]
+    fn cmos_tsb_sets_and_trb_clears_bits() {
+        let mut cpu = step(CpuKind::Cmos65c02, &[0x04, 0x20], |cpu| {
+            cpu.registers_mut().accumulator = 0x30;
+            cpu.memory_mut().set_byte(0x20, 0x0F);
+        });
+        assert_eq!(byte(&mut cpu, 0x20), 0x3F);
+        assert_eq!(cpu.registers().accumulator, 0x30);
+        assert!(flag(&cpu, Status::PS_ZERO));
+
+        let mut cpu = step(CpuKind::Cmos65c02, &[0x1C, 0x00, 0x03], |cpu| {
+            cpu.registers_mut().accumulator = 0x30;
+            cpu.memory_mut().set_byte(0x0300, 0x3F);
+        });
+        assert_eq!(byte(&mut cpu, 0x0300), 0x0F);
+        assert!(!flag(&cpu, Status::PS_ZERO));
+    }
+}
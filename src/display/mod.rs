@@ -1,15 +1,13 @@
 #[cfg(not(target_arch = "wasm32"))]
 pub mod winit_softbuffer;
 
-use crate::bus::ChipcadeBus;
+use crate::cpu::Cpu;
 use crate::machine::{BuildArtifacts, Machine};
-use mos6502::cpu;
-use mos6502::instruction::Nmos6502;
 
 pub struct FrameProducer {
     machine: Machine,
     artifacts: BuildArtifacts,
-    cpu: Option<cpu::CPU<ChipcadeBus, Nmos6502>>,
+    cpu: Option<Cpu>,
     did_init: bool,
     input_bits: u8,
 }
@@ -44,13 +42,14 @@ impl FrameProducer {
                 .create_cpu(
                     &self.artifacts.program,
                     &self.artifacts.sprites,
+                    &self.artifacts.segments,
                     self.artifacts.entry_point,
                 )
                 .expect("failed to create CPU");
-            cpu.memory.set_input_state(self.input_bits);
+            cpu.memory_mut().set_input_state(self.input_bits);
             cpu
         });
-        cpu.memory.set_input_state(self.input_bits);
+        cpu.memory_mut().set_input_state(self.input_bits);
 
         if !self.did_init {
             let init = Machine::label_address(&self.artifacts.labels, "Init")
@@ -73,7 +72,7 @@ impl FrameProducer {
     pub fn set_input_bits(&mut self, bits: u8) {
         self.input_bits = bits;
         if let Some(cpu) = self.cpu.as_mut() {
-            cpu.memory.set_input_state(bits);
+            cpu.memory_mut().set_input_state(bits);
         }
     }
 }
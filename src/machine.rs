@@ -1,17 +1,30 @@
-use crate::asm6502::assemble_with_labels_at;
+use crate::asm6502::{
+    ANON_LABEL_PREFIX, CpuKind, DEFAULT_SEGMENT, Diagnostic, Disassembled, IncbinReader, Object,
+    SymbolNames, assemble_objects, assemble_with_segments_at, disassemble, disassemble_one, link,
+    opcode_info,
+};
 use crate::bus::ChipcadeBus;
+use crate::c_parser::{
+    self, BinaryOp, Block, Decl, Expr, ExprKind, FnHeader, Init, Item, Pos, Stmt, StmtKind,
+    StructDef, Type, TypeSpec, UnaryOp,
+};
 use crate::config;
+use crate::cpu::Cpu;
+use crate::eval::eval_expression;
+use crate::objects;
+use crate::peephole::{self, PeepholeReport};
+use crate::preprocessor::{self, SourceLine};
 use crate::sprites::validate_sprite_str;
 use crate::sprites::{
     SpriteImage, SpritePack, load_sprite_pack, load_sprite_pack_from_embedded, sprite_consts,
     sprite_to_rgba,
 };
-use mos6502::cpu;
-use mos6502::instruction::Nmos6502;
+use crate::symbols;
 use mos6502::memory::Bus;
 use mos6502::registers::StackPointer;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Cursor;
@@ -27,11 +40,52 @@ pub struct LineOrigin {
     pub line: usize,
 }
 
+#[derive(Clone, Default)]
 struct ExpandedAsm {
     bytes: Vec<u8>,
     line_map: Vec<LineOrigin>,
 }
 
+impl ExpandedAsm {
+    /// Join generated lines, each with the file and line it came from.
+    fn from_lines(lines: Vec<(String, PathBuf, usize)>) -> Self {
+        let mut expanded = Self::default();
+        for (line, file, line_no) in lines {
+            expanded.push(
+                &line,
+                LineOrigin {
+                    file,
+                    line: line_no,
+                },
+            );
+        }
+        expanded
+    }
+
+    fn push(&mut self, line: &str, origin: LineOrigin) {
+        self.bytes.extend_from_slice(line.as_bytes());
+        self.bytes.push(b'\n');
+        self.line_map.push(origin);
+    }
+
+    fn append(&mut self, other: &ExpandedAsm) {
+        self.bytes.extend_from_slice(&other.bytes);
+        self.line_map.extend_from_slice(&other.line_map);
+    }
+}
+
+/// One source file of a build, assembled to its own object in `build/obj/`.
+struct BuildUnit {
+    /// Path of the source relative to `src/`, which names the object.
+    name: PathBuf,
+    expanded: ExpandedAsm,
+    /// Labels the C compiler generated, which other objects cannot see.
+    private: HashSet<String>,
+}
+
+/// Name of the unit holding the C runtime routines the program calls.
+const C_RUNTIME_UNIT: &str = "c_runtime";
+
 #[derive(Clone)]
 pub struct RunArtifacts {
     pub config: config::Config,
@@ -77,7 +131,7 @@ pub struct DebugStep {
 }
 
 pub struct DebugSession {
-    cpu: cpu::CPU<ChipcadeBus, Nmos6502>,
+    cpu: Cpu,
     artifacts: BuildArtifacts,
     init_addr: Option<u16>,
     update_addr: Option<u16>,
@@ -93,23 +147,46 @@ impl DebugSession {
         Some((pc - self.artifacts.load_addr) as usize)
     }
 
+    /// Source origin and expanded ASM line for a PC in the main program or any placed segment.
+    fn pc_origin(&self, pc: u16) -> Option<(&LineOrigin, usize)> {
+        if let Some(idx) = self.pc_index(pc)
+            && let Some(orig) = self.artifacts.pc_line_map.get(idx)
+        {
+            let asm_line = self
+                .artifacts
+                .pc_asm_line_map
+                .get(idx)
+                .copied()
+                .unwrap_or(0);
+            return Some((orig, asm_line));
+        }
+        let segment = self
+            .artifacts
+            .segments
+            .iter()
+            .find(|seg| pc >= seg.start && ((pc - seg.start) as usize) < seg.bytes.len())?;
+        let idx = (pc - segment.start) as usize;
+        let orig = segment.pc_line_map.get(idx)?;
+        let asm_line = segment.pc_asm_line_map.get(idx).copied().unwrap_or(0);
+        Some((orig, asm_line))
+    }
+
     fn ensure_ready(&mut self) {
         if self.did_init {
             return;
         }
         // Position PC at Init if present, else entry point, else Update.
         if let Some(init) = self.init_addr.or(self.artifacts.entry_point) {
-            self.cpu.registers.program_counter = init;
+            self.cpu.registers_mut().program_counter = init;
         } else if let Some(update) = self.update_addr {
-            self.cpu.registers.program_counter = update;
+            self.cpu.registers_mut().program_counter = update;
         }
         self.in_init = self.init_addr.is_some();
         self.did_init = true;
     }
 
     fn map_line(&self, pc: u16) -> Option<DebugLine> {
-        let idx = self.pc_index(pc)?;
-        self.artifacts.pc_line_map.get(idx).map(|orig| DebugLine {
+        self.pc_origin(pc).map(|(orig, _)| DebugLine {
             file: orig
                 .file
                 .file_name()
@@ -121,7 +198,7 @@ impl DebugSession {
     }
 
     pub fn peek_registers(&self) -> DebugRegisters {
-        let regs = &self.cpu.registers;
+        let regs = self.cpu.registers();
         DebugRegisters {
             a: regs.accumulator,
             x: regs.index_x,
@@ -133,13 +210,12 @@ impl DebugSession {
     }
 
     pub fn peek_line(&self) -> Option<DebugLine> {
-        self.map_line(self.cpu.registers.program_counter)
+        self.map_line(self.cpu.registers().program_counter)
     }
 
     pub fn peek_source_line(&self) -> Option<DebugSourceLine> {
-        let pc = self.cpu.registers.program_counter;
-        let idx = self.pc_index(pc)?;
-        let orig = self.artifacts.pc_line_map.get(idx)?;
+        let pc = self.cpu.registers().program_counter;
+        let (orig, _) = self.pc_origin(pc)?;
         let content = fs::read_to_string(&orig.file).ok()?;
         let text = content
             .lines()
@@ -160,11 +236,8 @@ impl DebugSession {
     }
 
     pub fn peek_asm_window(&self, radius: usize) -> Vec<DebugAsmLine> {
-        let pc = self.cpu.registers.program_counter;
-        let Some(idx) = self.pc_index(pc) else {
-            return Vec::new();
-        };
-        let Some(current_asm_line_no) = self.artifacts.pc_asm_line_map.get(idx).copied() else {
+        let pc = self.cpu.registers().program_counter;
+        let Some((_, current_asm_line_no)) = self.pc_origin(pc) else {
             return Vec::new();
         };
         if current_asm_line_no == 0 || self.artifacts.asm_lines.is_empty() {
@@ -187,14 +260,14 @@ impl DebugSession {
 
     pub fn step(&mut self) -> DebugStep {
         self.ensure_ready();
-        let pc = self.cpu.registers.program_counter;
+        let pc = self.cpu.registers().program_counter;
         let line = self.map_line(pc);
-        let opcode = self.cpu.memory.get_byte(pc);
+        let opcode = self.cpu.memory_mut().get_byte(pc);
         let regs_before = self.peek_registers();
         let stop_reason = if opcode == 0x00 {
             if self.in_init {
                 if let Some(update) = self.update_addr {
-                    self.cpu.registers.program_counter = update;
+                    self.cpu.registers_mut().program_counter = update;
                     self.in_init = false;
                     Some("Init BRK -> Update".to_string())
                 } else {
@@ -203,7 +276,7 @@ impl DebugSession {
             } else {
                 // Loop Update on BRK if present.
                 if let Some(update) = self.update_addr {
-                    self.cpu.registers.program_counter = update;
+                    self.cpu.registers_mut().program_counter = update;
                     None
                 } else {
                     Some("BRK".to_string())
@@ -228,25 +301,25 @@ impl DebugSession {
 
     pub fn step_with_frame(&mut self) -> (DebugStep, Vec<u8>) {
         let step = self.step();
-        let frame = self.cpu.memory.render_frame_rgba();
+        let frame = self.cpu.memory().render_frame_rgba();
         (step, frame)
     }
 
     pub fn current_frame_rgba(&self) -> Vec<u8> {
-        self.cpu.memory.render_frame_rgba()
+        self.cpu.memory().render_frame_rgba()
     }
 
     pub fn run_to_rts(&mut self) -> DebugStep {
         self.ensure_ready();
         loop {
-            let pc = self.cpu.registers.program_counter;
+            let pc = self.cpu.registers().program_counter;
             let line = self.map_line(pc);
-            let opcode = self.cpu.memory.get_byte(pc);
+            let opcode = self.cpu.memory_mut().get_byte(pc);
             let regs_before = self.peek_registers();
             if opcode == 0x00 {
                 if self.in_init {
                     if let Some(update) = self.update_addr {
-                        self.cpu.registers.program_counter = update;
+                        self.cpu.registers_mut().program_counter = update;
                         self.in_init = false;
                         let regs = self.peek_registers();
                         return DebugStep {
@@ -256,7 +329,7 @@ impl DebugSession {
                         };
                     }
                 } else if let Some(update) = self.update_addr {
-                    self.cpu.registers.program_counter = update;
+                    self.cpu.registers_mut().program_counter = update;
                     continue;
                 }
                 return DebugStep {
@@ -285,31 +358,76 @@ impl DebugSession {
 
     pub fn run_to_rts_with_frame(&mut self) -> (DebugStep, Vec<u8>) {
         let step = self.run_to_rts();
-        let frame = self.cpu.memory.render_frame_rgba();
+        let frame = self.cpu.memory().render_frame_rgba();
         (step, frame)
     }
 
     pub fn read_byte(&mut self, addr: u16) -> u8 {
-        self.cpu.memory.get_byte(addr)
+        self.cpu.memory_mut().get_byte(addr)
     }
 
     pub fn read_bytes(&mut self, addr: u16, len: usize) -> Vec<u8> {
         let mut out = Vec::with_capacity(len);
         for i in 0..len {
-            out.push(self.cpu.memory.get_byte(addr.wrapping_add(i as u16)));
+            out.push(self.cpu.memory_mut().get_byte(addr.wrapping_add(i as u16)));
         }
         out
     }
 
+    /// Disassemble `count` instructions starting at `addr` from memory as it is now, so code
+    /// written at runtime shows up as it will execute.
+    pub fn disassemble_at(&mut self, addr: u16, count: usize) -> Vec<Disassembled> {
+        let names = SymbolNames::new(&self.artifacts.labels, &self.artifacts.constants);
+        let cpu = self.cpu.kind();
+        let mut out = Vec::with_capacity(count);
+        let mut pc = addr;
+        for _ in 0..count {
+            let bytes = self.read_bytes(pc, 3);
+            let line = disassemble_one(&bytes, pc, cpu, &names);
+            pc = pc.wrapping_add(line.bytes.len() as u16);
+            out.push(line);
+        }
+        out
+    }
+
+    /// True when the instruction at PC no longer matches the assembled program, e.g. after
+    /// self-modifying writes, so its source mapping would be misleading.
+    pub fn pc_code_modified(&mut self) -> bool {
+        let pc = self.cpu.registers().program_counter;
+        let Some(opcode) = self.assembled_byte(pc) else {
+            return false;
+        };
+        let len = opcode_info(self.cpu.kind(), opcode).map_or(1, |info| info.size());
+        (0..len as u16).any(|i| {
+            let addr = pc.wrapping_add(i);
+            self.assembled_byte(addr) != Some(self.cpu.memory_mut().get_byte(addr))
+        })
+    }
+
+    /// Byte the build placed at `addr`, if it lies in the program or a segment.
+    fn assembled_byte(&self, addr: u16) -> Option<u8> {
+        if let Some(idx) = self.pc_index(addr)
+            && let Some(byte) = self.artifacts.program.get(idx)
+        {
+            return Some(*byte);
+        }
+        self.artifacts.segments.iter().find_map(|seg| {
+            let offset = addr.checked_sub(seg.start)?;
+            seg.bytes.get(offset as usize).copied()
+        })
+    }
+
     pub fn label_address(&self, name: &str) -> Option<u16> {
         self.artifacts.labels.get(name).copied()
     }
 
+    /// Named labels sorted by name; local labels appear fully qualified (`Global.local`).
     pub fn labels(&self) -> Vec<(String, u16)> {
         let mut out: Vec<(String, u16)> = self
             .artifacts
             .labels
             .iter()
+            .filter(|(k, _)| !k.starts_with(ANON_LABEL_PREFIX))
             .map(|(k, v)| (k.clone(), *v))
             .collect();
         out.sort_by(|a, b| a.0.cmp(&b.0));
@@ -323,11 +441,31 @@ pub struct BuildArtifacts {
     pub sprites: crate::sprites::SpritePack,
     pub entry_point: Option<u16>,
     pub labels: std::collections::HashMap<String, u16>,
+    /// Names in `labels` that are `.const` values rather than addresses.
+    pub constants: HashSet<String>,
     pub load_addr: u16,
     pub line_map: Vec<LineOrigin>,
     pub pc_line_map: Vec<LineOrigin>,
     pub asm_lines: Vec<String>,
     pub pc_asm_line_map: Vec<usize>,
+    pub segments: Vec<ProgramSegment>,
+}
+
+/// Assembled bytes placed outside the main program block via `.segment` or `.org`.
+#[derive(Clone)]
+pub struct ProgramSegment {
+    pub name: String,
+    pub start: u16,
+    pub bytes: Vec<u8>,
+    pub pc_line_map: Vec<LineOrigin>,
+    pub pc_asm_line_map: Vec<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    pub name: String,
+    pub start: u16,
+    pub len: usize,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -335,10 +473,13 @@ pub struct BuildMeta {
     pub config: config::Config,
     pub entry_point: Option<u16>,
     pub labels: std::collections::HashMap<String, u16>,
+    /// Names in `labels` that are `.const` values rather than addresses.
+    pub constants: Vec<String>,
     pub palette_bytes: Vec<u8>,
     pub sprite_base: u16,
     pub program_len: usize,
     pub sprite_images: Vec<SpriteImage>,
+    pub segments: Vec<SegmentInfo>,
 }
 
 #[derive(Clone)]
@@ -352,7 +493,16 @@ pub struct ProjectPaths {
     pub config: PathBuf,
     pub asm_main: PathBuf,
     pub build_dir: PathBuf,
+    /// Cached object files, one per assembled unit.
+    pub obj_dir: PathBuf,
+    /// Prebuilt object files linked into every build.
+    pub lib_dir: PathBuf,
     pub program_bin: PathBuf,
+    pub listing: PathBuf,
+    pub vice_labels: PathBuf,
+    pub debug_info: PathBuf,
+    pub symbol_map: PathBuf,
+    pub symbol_json: PathBuf,
     pub vram_dump: PathBuf,
     pub palette: PathBuf,
 }
@@ -383,7 +533,14 @@ impl ProjectPaths {
         Self {
             config: root.join("chipcade.toml"),
             asm_main: asm_dir.join("main.asm"),
+            obj_dir: build_dir.join("obj"),
+            lib_dir: root.join("lib"),
             program_bin: build_dir.join("program.bin"),
+            listing: build_dir.join("program.lst"),
+            vice_labels: build_dir.join("program.vs"),
+            debug_info: build_dir.join("program.dbg"),
+            symbol_map: build_dir.join("program.sym"),
+            symbol_json: build_dir.join("program.json"),
             vram_dump: build_dir.join("vram_dump.png"),
             build_dir,
             palette,
@@ -395,7 +552,11 @@ pub struct Machine {
     paths: ProjectPaths,
     config: config::Config,
     mem_map: config::MemoryMap,
+    cpu: CpuKind,
     sys_consts: Vec<SystemConst>,
+    defines: Vec<(String, u16)>,
+    /// Run the peephole optimizer over transpiled C (`chipcade build -O`).
+    optimize: bool,
     palette_bytes: Option<Vec<u8>>,
     last_tick: Option<Instant>,
     tick_accum: Duration,
@@ -450,6 +611,79 @@ impl Machine {
         &self.paths.program_bin
     }
 
+    /// Disassemble a built 64 KB image using its embedded label table. Without a range, every
+    /// placed block is shown; `from` (a label or address expression) defaults to the program
+    /// start and `len` to `DISASM_DEFAULT_LEN` bytes.
+    pub fn disassemble_image(
+        image: &[u8],
+        from: Option<&str>,
+        len: Option<usize>,
+    ) -> Result<String, String> {
+        let (meta, artifacts) = Self::artifacts_from_image(image)?;
+        let cpu = meta.config.machine.cpu_kind()?;
+        let names = SymbolNames::new(&artifacts.labels, &artifacts.constants);
+        let from = match from {
+            Some(token) => Some(match artifacts.labels.get(token) {
+                Some(addr) => *addr,
+                None => {
+                    let value = eval_expression(token)
+                        .map_err(|e| format!("Unknown label or invalid address `{token}`: {e}"))?
+                        .value;
+                    u16::try_from(value).map_err(|_| format!("Address out of range: {value}"))?
+                }
+            }),
+            None => None,
+        };
+        let ranges: Vec<(String, usize, usize)> = if from.is_none() && len.is_none() {
+            std::iter::once((
+                DEFAULT_SEGMENT.to_string(),
+                artifacts.load_addr,
+                artifacts.program.len(),
+            ))
+            .chain(
+                artifacts
+                    .segments
+                    .iter()
+                    .map(|seg| (seg.name.clone(), seg.start, seg.bytes.len())),
+            )
+            .map(|(name, start, len)| (name, start as usize, len))
+            .collect()
+        } else {
+            let start = from.unwrap_or(artifacts.load_addr) as usize;
+            let len = len.unwrap_or(DISASM_DEFAULT_LEN);
+            vec![(String::new(), start, len)]
+        };
+
+        let mut out = String::new();
+        for (name, start, len) in ranges {
+            let len = len.min(image.len() - start);
+            if !name.is_empty() {
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&format!(
+                    "; {} ${:04X}-${:04X}\n",
+                    name,
+                    start,
+                    (start + len).saturating_sub(1)
+                ));
+            }
+            for line in disassemble(&image[start..], start as u16, len, cpu, &names) {
+                if let Some(label) = names.label_at(line.addr) {
+                    out.push_str(&format!("{}:\n", label));
+                }
+                let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                out.push_str(&format!(
+                    "{:04X}  {:<8}  {}\n",
+                    line.addr,
+                    bytes.join(" "),
+                    line.text
+                ));
+            }
+        }
+        Ok(out)
+    }
+
     /// Reconstruct build artifacts from a raw 64 KB image that contains embedded meta at META_ADDR.
     pub fn artifacts_from_image(image: &[u8]) -> Result<(BuildMeta, BuildArtifacts), String> {
         let meta = parse_flat_image(image)?;
@@ -476,16 +710,34 @@ impl Machine {
             images: meta.sprite_images.clone(),
         };
 
+        let mut segments = Vec::new();
+        for info in &meta.segments {
+            let start = info.start as usize;
+            let end = start
+                .checked_add(info.len)
+                .filter(|end| *end <= image.len())
+                .ok_or_else(|| format!("segment {} does not fit in program image", info.name))?;
+            segments.push(ProgramSegment {
+                name: info.name.clone(),
+                start: info.start,
+                bytes: image[start..end].to_vec(),
+                pc_line_map: Vec::new(),
+                pc_asm_line_map: Vec::new(),
+            });
+        }
+
         let artifacts = BuildArtifacts {
             entry_point: meta.entry_point,
             program,
             sprites,
             labels: meta.labels.clone(),
+            constants: meta.constants.iter().cloned().collect(),
             load_addr: mem_map.ram,
             line_map: Vec::new(),
             pc_line_map: Vec::new(),
             asm_lines: Vec::new(),
             pc_asm_line_map: Vec::new(),
+            segments,
         };
 
         Ok((meta, artifacts))
@@ -496,9 +748,12 @@ impl Machine {
         let sys_consts = system_constants(&mem_map, &meta.config);
         Self {
             paths: ProjectPaths::new("."),
+            cpu: meta.config.machine.cpu_kind().unwrap_or_default(),
             config: meta.config,
             mem_map,
             sys_consts,
+            defines: Vec::new(),
+            optimize: false,
             palette_bytes: Some(meta.palette_bytes),
             last_tick: None,
             tick_accum: Duration::ZERO,
@@ -522,6 +777,7 @@ impl Machine {
                 .expect("config path is not valid UTF-8"),
         )
         .map_err(|e| e)?;
+        let cpu = config.machine.cpu_kind()?;
         let mem_map = config::MemoryMap::from_config(&config);
         let sys_consts = system_constants(&mem_map, &config);
 
@@ -529,13 +785,29 @@ impl Machine {
             paths,
             config,
             mem_map,
+            cpu,
             sys_consts,
+            defines: Vec::new(),
+            optimize: false,
             palette_bytes: None,
             last_tick: None,
             tick_accum: Duration::ZERO,
         })
     }
 
+    /// Build-time defines (e.g. from `--define DEBUG=1`), emitted as constants into the generated
+    /// `chipcade.inc`/`chipcade.h` so ASM `.if`/`.ifdef` and C code can test them.
+    pub fn with_defines(mut self, defines: Vec<(String, u16)>) -> Self {
+        self.defines = defines;
+        self
+    }
+
+    /// Run the peephole optimizer over the assembly transpiled from C.
+    pub fn with_optimize(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
     pub fn print_sys_constants(&self) {
         println!("System constants:");
         for c in &self.sys_consts {
@@ -577,6 +849,8 @@ impl Machine {
     fn build_impl(&self, silent: bool) -> Result<BuildArtifacts, String> {
         let artifacts = self.assemble_impl(silent)?;
         self.write_build_image(&artifacts)?;
+        self.write_listing(&artifacts)?;
+        self.write_symbols(&artifacts)?;
         Ok(artifacts)
     }
 
@@ -622,7 +896,7 @@ impl Machine {
         }
         let sprite_consts = sprite_consts(&sprite_pack.images);
 
-        write_chipcade_headers(&self.paths, &self.sys_consts, &sprite_consts)?;
+        write_chipcade_headers(&self.paths, &self.sys_consts, &sprite_consts, &self.defines)?;
 
         let c_root = self
             .paths
@@ -634,49 +908,212 @@ impl Machine {
         if c_root.exists() {
             collect_c_paths(&c_root, &c_root, &mut c_sources)?;
         }
-        let expanded = if !c_sources.is_empty() {
-            let c_expanded =
-                transpile_c_sources(&c_root, &c_sources, &self.sys_consts, &sprite_consts)?;
-            if self.paths.asm_main.exists() {
-                let asm_expanded = expand_asm(&self.paths.asm_main, &mut HashSet::new())?;
-                merge_expanded_asm(asm_expanded, c_expanded)
-            } else {
-                c_expanded
+        // Each source file is assembled to its own object so an error in one still reports the
+        // others and unchanged files are reused from `build/obj/`.
+        let src_root = c_root.canonicalize().unwrap_or_else(|_| c_root.clone());
+        let mut units = Vec::new();
+        if self.paths.asm_main.exists() || c_sources.is_empty() {
+            units.extend(expand_asm_units(&self.paths.asm_main, &src_root)?);
+        }
+        if !c_sources.is_empty() {
+            let (c_units, report) = transpile_c_sources(
+                &c_root,
+                &c_sources,
+                &self.sys_consts,
+                &sprite_consts,
+                &self.defines,
+                self.optimize.then_some(self.cpu),
+            )?;
+            if let Some(report) = report.filter(|_| !silent) {
+                println!(
+                    "Peephole optimizer: {} rewrite(s), {} bytes and {} cycles saved.",
+                    report.rewrites, report.bytes, report.cycles
+                );
             }
-        } else {
-            expand_asm(&self.paths.asm_main, &mut HashSet::new())?
-        };
-        let asm = expanded.bytes.clone();
-        let line_map = expanded.line_map.clone();
-        let asm_lines: Vec<String> = String::from_utf8_lossy(&asm)
-            .lines()
-            .map(|s| s.to_string())
-            .collect();
+            units.extend(c_units);
+        }
 
-        let origin = self.mem_map.ram;
-        let assembled = assemble_with_labels_at(&mut Cursor::new(asm), origin).map_err(|msg| {
-            if let Some((file, line)) = map_error_to_origin(&msg, &line_map) {
-                let project_root = self.paths.config.parent().unwrap_or_else(|| Path::new("."));
-                let rel = relative_path(project_root, &file);
-                let trimmed = msg
-                    .splitn(2, ':')
-                    .nth(1)
-                    .map(|s| s.trim())
-                    .unwrap_or(msg.as_str());
-                format!("Assembly error: {}:{} -> {}", rel.display(), line, trimmed)
-            } else {
-                format!("Assembly error: {}", msg)
+        let mut libs = Vec::new();
+        if self.paths.lib_dir.is_dir() {
+            let mut paths: Vec<PathBuf> = fs::read_dir(&self.paths.lib_dir)
+                .map_err(|e| format!("Failed to read {}: {e}", self.paths.lib_dir.display()))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "o"))
+                .collect();
+            paths.sort();
+            for path in paths {
+                let file = objects::read_object(&path)?;
+                if file.object.cpu != self.cpu {
+                    return Err(format!(
+                        "{} was assembled for the {}, but this project targets the {}",
+                        relative_path(&project_root, &path).display(),
+                        file.object.cpu.name(),
+                        self.cpu.name()
+                    ));
+                }
+                libs.push(file);
             }
-        })?;
+        }
+
+        let object_paths: Vec<PathBuf> = units
+            .iter()
+            .map(|unit| {
+                let mut name = unit.name.clone().into_os_string();
+                name.push(".o");
+                self.paths.obj_dir.join(name)
+            })
+            .collect();
+        let keys: Vec<u64> = units
+            .iter()
+            .map(|unit| {
+                objects::source_key(&unit.expanded.bytes, &unit.expanded.line_map, self.cpu)
+            })
+            .collect();
+        let mut slots: Vec<Option<objects::ObjectFile>> = object_paths
+            .iter()
+            .zip(&keys)
+            .map(|(path, key)| {
+                objects::read_object(path)
+                    .ok()
+                    .filter(|f| f.key == *key && objects::incbins_unchanged(&f.incbins))
+            })
+            .collect();
+        let cached = slots.iter().flatten().count();
 
-        let mut pc_line_map = Vec::new();
-        let mut pc_asm_line_map = Vec::new();
-        for line_no in &assembled.pc_line {
-            pc_asm_line_map.push(*line_no);
-            if let Some(orig) = line_map.get(line_no.saturating_sub(1)) {
-                pc_line_map.push(orig.clone());
+        let stale: Vec<usize> = (0..units.len()).filter(|&i| slots[i].is_none()).collect();
+        let incbins: Vec<RefCell<Vec<(PathBuf, u64)>>> =
+            stale.iter().map(|_| RefCell::new(Vec::new())).collect();
+        let readers: Vec<_> = stale
+            .iter()
+            .zip(&incbins)
+            .map(|(&idx, incbins)| {
+                let line_map = &units[idx].expanded.line_map;
+                move |line: usize, name: &str| {
+                    let path = incbin_path(line_map, line, name);
+                    let data = fs::read(&path).map_err(|e| e.to_string())?;
+                    incbins
+                        .borrow_mut()
+                        .push((path, objects::content_key(&data)));
+                    Ok(data)
+                }
+            })
+            .collect();
+        let inputs: Vec<(&[u8], &IncbinReader)> = stale
+            .iter()
+            .zip(&readers)
+            .map(|(&idx, read)| (units[idx].expanded.bytes.as_slice(), read as &IncbinReader))
+            .collect();
+        let linked: Vec<&Object> = slots
+            .iter()
+            .flatten()
+            .chain(&libs)
+            .map(|file| &file.object)
+            .collect();
+        let results = assemble_objects(&inputs, self.cpu, &linked);
+        drop(readers);
+
+        let mut failures = Vec::new();
+        let mut error_count = 0;
+        for ((idx, result), incbins) in stale.into_iter().zip(results).zip(incbins) {
+            let unit = &units[idx];
+            match result {
+                Ok((mut object, warnings)) => {
+                    if !silent && !warnings.is_empty() {
+                        eprintln!(
+                            "{}",
+                            render_diagnostics(
+                                &warnings,
+                                &unit.expanded.line_map,
+                                &object.source,
+                                &project_root
+                            )
+                        );
+                    }
+                    for symbol in &mut object.symbols {
+                        if unit.private.contains(&symbol.name) {
+                            symbol.global = false;
+                        }
+                    }
+                    let file = objects::ObjectFile {
+                        key: keys[idx],
+                        object,
+                        line_map: unit.expanded.line_map.clone(),
+                        incbins: incbins.into_inner(),
+                    };
+                    objects::write_object(&object_paths[idx], &file)?;
+                    slots[idx] = Some(file);
+                }
+                Err(diagnostics) => {
+                    let lines: Vec<String> = String::from_utf8_lossy(&unit.expanded.bytes)
+                        .lines()
+                        .map(str::to_string)
+                        .collect();
+                    failures.push(render_diagnostics(
+                        &diagnostics,
+                        &unit.expanded.line_map,
+                        &lines,
+                        &project_root,
+                    ));
+                    error_count += diagnostics.len();
+                }
             }
         }
+        if !failures.is_empty() {
+            return Err(format!(
+                "{}\n\nAssembly failed with {} error(s)",
+                failures.join("\n\n"),
+                error_count
+            ));
+        }
+        let files: Vec<objects::ObjectFile> = slots.into_iter().flatten().chain(libs).collect();
+        if !silent {
+            println!("Linking {} object(s), {} unchanged.", files.len(), cached);
+        }
+
+        let mut line_map = Vec::new();
+        let mut asm_lines = Vec::new();
+        let mut linked_objects = Vec::with_capacity(files.len());
+        for mut file in files {
+            // Keep one origin per source line so lines count through the objects in order.
+            let last = file.line_map.last().cloned().unwrap_or(LineOrigin {
+                file: PathBuf::from("asm"),
+                line: 1,
+            });
+            file.line_map.resize(file.object.source.len(), last);
+            line_map.extend(file.line_map);
+            asm_lines.extend(file.object.source.iter().cloned());
+            linked_objects.push(file.object);
+        }
+
+        let origin = self.mem_map.ram;
+        let rom_data_base = self
+            .mem_map
+            .rom
+            .saturating_add(sprite_pack.data.len() as u16);
+        let segment_bases = [("ZP", self.mem_map.zero_page), ("ROMDATA", rom_data_base)];
+        let assembled = link(&linked_objects, origin, &segment_bases).map_err(|diagnostics| {
+            format!(
+                "{}\n\nLinking failed with {} error(s)",
+                render_diagnostics(&diagnostics, &line_map, &asm_lines, &project_root),
+                diagnostics.len()
+            )
+        })?;
+
+        let (pc_line_map, pc_asm_line_map) = map_pc_lines(&assembled.pc_line, &line_map);
+        let segments = assembled
+            .segments
+            .into_iter()
+            .map(|seg| {
+                let (pc_line_map, pc_asm_line_map) = map_pc_lines(&seg.pc_line, &line_map);
+                ProgramSegment {
+                    name: seg.name,
+                    start: seg.start,
+                    bytes: seg.bytes,
+                    pc_line_map,
+                    pc_asm_line_map,
+                }
+            })
+            .collect();
 
         let entry_point = assembled
             .labels
@@ -689,11 +1126,13 @@ impl Machine {
             program: assembled.bytes,
             sprites: sprite_pack,
             labels: assembled.labels,
+            constants: assembled.constants,
             load_addr: origin,
             line_map,
             pc_line_map,
             asm_lines,
             pc_asm_line_map,
+            segments,
         })
     }
 
@@ -701,8 +1140,9 @@ impl Machine {
         &self,
         program: &[u8],
         sprites: &SpritePack,
+        segments: &[ProgramSegment],
         entry_point: Option<u16>,
-    ) -> Result<cpu::CPU<ChipcadeBus, Nmos6502>, String> {
+    ) -> Result<Cpu, String> {
         let palette_bytes = match &self.palette_bytes {
             Some(p) => p.clone(),
             None => load_palette_file(
@@ -710,9 +1150,9 @@ impl Machine {
                 self.config.palette.global_colors as usize,
             )?,
         };
-        let mut cpu = cpu::CPU::new(
+        let mut cpu = Cpu::new(
+            self.cpu,
             ChipcadeBus::from_config(&self.config, Some(&palette_bytes), sprites.clone()),
-            Nmos6502,
         );
 
         let load_addr: u16 = self.mem_map.ram;
@@ -720,14 +1160,17 @@ impl Machine {
         // Place an invalid opcode as a stop sentinel so cpu.run() exits
         program.push(0xff);
 
-        cpu.memory.set_bytes(load_addr, &program);
+        cpu.memory_mut().set_bytes(load_addr, &program);
+        for segment in segments {
+            cpu.memory_mut().set_bytes(segment.start, &segment.bytes);
+        }
 
         if entry_point.is_none() {
             println!("Warning: 'Init'/'Update' labels not found; starting at program base.");
         }
         let start_pc = self.entry_address(entry_point);
-        cpu.registers.program_counter = start_pc;
-        cpu.registers.stack_pointer = StackPointer(0xFF); // Initialize stack pointer to top of stack
+        cpu.registers_mut().program_counter = start_pc;
+        cpu.registers_mut().stack_pointer = StackPointer(0xFF); // Initialize stack pointer to top of stack
 
         Ok(cpu)
     }
@@ -737,6 +1180,7 @@ impl Machine {
         &self,
         mut program: Vec<u8>,
         sprites: SpritePack,
+        segments: &[ProgramSegment],
         entry_point: Option<u16>,
     ) -> Result<RunArtifacts, String> {
         let palette_bytes = match &self.palette_bytes {
@@ -746,23 +1190,26 @@ impl Machine {
                 self.config.palette.global_colors as usize,
             )?,
         };
-        let mut cpu = cpu::CPU::new(
+        let mut cpu = Cpu::new(
+            self.cpu,
             ChipcadeBus::from_config(&self.config, Some(&palette_bytes), sprites.clone()),
-            Nmos6502,
         );
 
         let load_addr: u16 = self.mem_map.ram;
         // Place an invalid opcode as a stop sentinel so cpu.run() exits
         program.push(0xff);
 
-        cpu.memory.set_bytes(load_addr, &program);
+        cpu.memory_mut().set_bytes(load_addr, &program);
+        for segment in segments {
+            cpu.memory_mut().set_bytes(segment.start, &segment.bytes);
+        }
 
         if entry_point.is_none() {
             println!("Warning: 'Start' label not found; starting at program base.");
         }
         let start_pc = entry_point.unwrap_or(load_addr);
-        cpu.registers.program_counter = start_pc;
-        cpu.registers.stack_pointer = StackPointer(0xFF); // Initialize stack pointer to top of stack
+        cpu.registers_mut().program_counter = start_pc;
+        cpu.registers_mut().stack_pointer = StackPointer(0xFF); // Initialize stack pointer to top of stack
 
         // Run until BRK (0x00) or invalid (0xFF)
         let mut steps: u64 = 0;
@@ -773,8 +1220,8 @@ impl Machine {
                 stop_reason = "step limit reached".to_string();
                 break;
             }
-            let pc = cpu.registers.program_counter;
-            let opcode = cpu.memory.get_byte(pc);
+            let pc = cpu.registers().program_counter;
+            let opcode = cpu.memory_mut().get_byte(pc);
             if opcode == 0x00 {
                 stop_reason = "BRK".to_string();
                 break;
@@ -787,7 +1234,7 @@ impl Machine {
             steps += 1;
         }
 
-        let vram_rgba = cpu.memory.render_frame_rgba();
+        let vram_rgba = cpu.memory().render_frame_rgba();
 
         println!(
             "Execution stopped after {} steps, reason: {}",
@@ -795,7 +1242,8 @@ impl Machine {
         );
         println!(
             "Final PC: ${:04X}, SP: ${:02X}",
-            cpu.registers.program_counter, cpu.registers.stack_pointer.0
+            cpu.registers().program_counter,
+            cpu.registers().stack_pointer.0
         );
 
         Ok(RunArtifacts {
@@ -810,19 +1258,15 @@ impl Machine {
     }
 
     /// Run one frame starting at the given entry point on an existing CPU. Returns the rendered VRAM along with step count and stop reason.
-    pub fn run_frame(
-        &self,
-        cpu: &mut cpu::CPU<ChipcadeBus, Nmos6502>,
-        entry_point: u16,
-    ) -> (Vec<u8>, u64, String) {
-        cpu.registers.program_counter = entry_point;
+    pub fn run_frame(&self, cpu: &mut Cpu, entry_point: u16) -> (Vec<u8>, u64, String) {
+        cpu.registers_mut().program_counter = entry_point;
         let mut steps: u64 = 0;
         let stop_reason: String = loop {
             if steps >= 1_000_000 {
                 break "step limit reached".to_string();
             }
-            let pc = cpu.registers.program_counter;
-            let opcode = cpu.memory.get_byte(pc);
+            let pc = cpu.registers().program_counter;
+            let opcode = cpu.memory_mut().get_byte(pc);
             if opcode == 0x00 {
                 break "BRK".to_string();
             }
@@ -833,14 +1277,19 @@ impl Machine {
             steps += 1;
         };
 
-        let vram_rgba = cpu.memory.render_frame_rgba();
+        let vram_rgba = cpu.memory().render_frame_rgba();
         (vram_rgba, steps, stop_reason)
     }
 
     /// Assemble and run in one step (current CLI behavior).
     pub fn run(&self) -> Result<RunArtifacts, String> {
         let build = self.assemble_impl(true)?; // silent=true to avoid duplicate output
-        self.execute(build.program, build.sprites, build.entry_point)
+        self.execute(
+            build.program,
+            build.sprites,
+            &build.segments,
+            build.entry_point,
+        )
     }
 
     fn write_build_image(&self, artifacts: &BuildArtifacts) -> Result<(), String> {
@@ -882,6 +1331,54 @@ impl Machine {
         }
         image[rom_start..rom_end].copy_from_slice(&artifacts.sprites.data);
 
+        // Named segments at their assembled addresses, clear of the fixed hardware regions
+        let reserved = [
+            (
+                "VRAM",
+                self.mem_map.video_ram as usize,
+                self.mem_map.palette_ram as usize,
+            ),
+            (
+                "palette RAM",
+                self.mem_map.palette_ram as usize,
+                self.mem_map.sprite_ram as usize,
+            ),
+            (
+                "sprite RAM",
+                self.mem_map.sprite_ram as usize,
+                self.mem_map.io as usize,
+            ),
+            ("IO", self.mem_map.io as usize, self.mem_map.rom as usize),
+            ("sprite graphics", rom_start, rom_end),
+            ("build metadata", META_ADDR, image.len()),
+        ];
+        let placed =
+            std::iter::once(("CODE", start, end)).chain(artifacts.segments.iter().map(|seg| {
+                (
+                    seg.name.as_str(),
+                    seg.start as usize,
+                    seg.start as usize + seg.bytes.len(),
+                )
+            }));
+        for (name, seg_start, seg_end) in placed {
+            if seg_end > image.len() {
+                return Err(format!("segment {name} does not fit in 64 KB image"));
+            }
+            for (region, region_start, region_end) in reserved {
+                if seg_start < region_end && region_start < seg_end {
+                    return Err(format!(
+                        "segment {name} at ${seg_start:04X}-${:04X} overlaps {region} at ${region_start:04X}-${:04X}",
+                        seg_end - 1,
+                        region_end - 1
+                    ));
+                }
+            }
+        }
+        for segment in &artifacts.segments {
+            let seg_start = segment.start as usize;
+            image[seg_start..seg_start + segment.bytes.len()].copy_from_slice(&segment.bytes);
+        }
+
         if let Err(e) = fs::create_dir_all(&self.paths.build_dir) {
             return Err(format!(
                 "Failed to create build dir {}: {e}",
@@ -893,10 +1390,24 @@ impl Machine {
             config: self.config.clone(),
             entry_point: artifacts.entry_point,
             labels: artifacts.labels.clone(),
+            constants: {
+                let mut constants: Vec<String> = artifacts.constants.iter().cloned().collect();
+                constants.sort();
+                constants
+            },
             palette_bytes: palette_bytes.clone(),
             program_len: artifacts.program.len(),
             sprite_base: self.mem_map.rom,
             sprite_images: artifacts.sprites.images.clone(),
+            segments: artifacts
+                .segments
+                .iter()
+                .map(|seg| SegmentInfo {
+                    name: seg.name.clone(),
+                    start: seg.start,
+                    len: seg.bytes.len(),
+                })
+                .collect(),
         };
         let meta_bytes =
             bincode::serialize(&meta).map_err(|e| format!("Failed to serialize meta: {e}"))?;
@@ -922,10 +1433,60 @@ impl Machine {
         Ok(())
     }
 
+    /// Write `build/program.lst` next to the build image.
+    fn write_listing(&self, artifacts: &BuildArtifacts) -> Result<(), String> {
+        let project_root = self.paths.config.parent().unwrap_or_else(|| Path::new("."));
+        fs::write(
+            &self.paths.listing,
+            render_listing(artifacts, project_root, self.cpu),
+        )
+        .map_err(|e| {
+            format!(
+                "Failed to write listing to {}: {e}",
+                self.paths.listing.display()
+            )
+        })
+    }
+
+    /// Write the symbol and line tables for external emulators and debuggers next to the build
+    /// image.
+    fn write_symbols(&self, artifacts: &BuildArtifacts) -> Result<(), String> {
+        let project_root = self.paths.config.parent().unwrap_or_else(|| Path::new("."));
+        let image_name = file_name(&self.paths.program_bin)?;
+        let outputs = [
+            (
+                &self.paths.vice_labels,
+                symbols::render_vice_labels(artifacts),
+            ),
+            (
+                &self.paths.debug_info,
+                symbols::render_debug_info(artifacts, project_root, &image_name),
+            ),
+            (
+                &self.paths.symbol_map,
+                symbols::render_symbol_map(artifacts),
+            ),
+            (
+                &self.paths.symbol_json,
+                symbols::render_symbol_json(artifacts, project_root)?,
+            ),
+        ];
+        for (path, text) in outputs {
+            fs::write(path, text)
+                .map_err(|e| format!("Failed to write symbols to {}: {e}", path.display()))?;
+        }
+        Ok(())
+    }
+
     /// Create a debugging session with a CPU initialized to the program entry.
     pub fn start_debug_session(&self) -> Result<DebugSession, String> {
         let build = self.assemble_impl(true)?; // silent
-        let cpu = self.create_cpu(&build.program, &build.sprites, build.entry_point)?;
+        let cpu = self.create_cpu(
+            &build.program,
+            &build.sprites,
+            &build.segments,
+            build.entry_point,
+        )?;
         let init_addr = build.labels.get("Init").copied();
         let update_addr = build.labels.get("Update").copied();
         Ok(DebugSession {
@@ -1152,12 +1713,14 @@ impl Machine {
             sprite_pack = load_sprite_pack_from_embedded(embedded).map_err(|e| (None, e))?;
         }
         let sprite_consts = sprite_consts(&sprite_pack.images);
-        if let Err(e) = write_chipcade_headers(&self.paths, &self.sys_consts, &sprite_consts) {
+        if let Err(e) =
+            write_chipcade_headers(&self.paths, &self.sys_consts, &sprite_consts, &self.defines)
+        {
             return Err((None, e));
         }
 
         let expanded =
-            match expand_asm_inline(base_dir, &virtual_path, content, &mut HashSet::new()) {
+            match expand_asm_inline(base_dir, &virtual_path, content, &mut HashSet::new(), None) {
                 Ok(e) => e,
                 Err(e) => return Err((None, e)),
             };
@@ -1186,16 +1749,27 @@ impl Machine {
         }
 
         let origin = self.mem_map.ram;
-        if let Err(msg) = assemble_with_labels_at(&mut Cursor::new(asm), origin) {
-            if let Some((file, line)) = map_error_to_origin(&msg, &line_map) {
-                let project_root = self.paths.config.parent().unwrap_or_else(|| Path::new("."));
-                let rel = relative_path(project_root, &file);
-                let decorated = if file == virtual_path {
+        let read = |line: usize, name: &str| {
+            fs::read(incbin_path(&line_map, line, name)).map_err(|e| e.to_string())
+        };
+        if let Err(diagnostics) =
+            assemble_with_segments_at(&mut Cursor::new(asm), origin, &[], self.cpu, &read)
+        {
+            let Some(diagnostic) = diagnostics.first() else {
+                return Ok(());
+            };
+            let msg = match &diagnostic.hint {
+                Some(hint) => format!("{} ({})", diagnostic.message, hint),
+                None => diagnostic.message.clone(),
+            };
+            if let Some(origin) = line_map.get(diagnostic.line.wrapping_sub(1)) {
+                let rel = relative_path(&project_root, &origin.file);
+                let decorated = if origin.file == virtual_path {
                     msg
                 } else {
                     format!("{}: {msg}", rel.display())
                 };
-                return Err((Some(line), decorated));
+                return Err((Some(origin.line), decorated));
             }
             return Err((None, msg));
         }
@@ -1459,7 +2033,10 @@ impl Default for Machine {
             paths: ProjectPaths::new(&root),
             config,
             mem_map,
+            cpu: CpuKind::default(),
             sys_consts,
+            defines: Vec::new(),
+            optimize: false,
             palette_bytes: None,
             last_tick: None,
             tick_accum: Duration::ZERO,
@@ -1550,19 +2127,112 @@ fn load_palette_file(path: &Path, expected_colors: usize) -> Result<Vec<u8>, Str
     Ok(out)
 }
 
-fn expand_asm(path: &Path, visited: &mut HashSet<PathBuf>) -> Result<ExpandedAsm, String> {
+/// Expand `main` and every `.asm` file it includes, directly or not, into one unit per file,
+/// named by its path under `src_root`.
+fn expand_asm_units(main: &Path, src_root: &Path) -> Result<Vec<BuildUnit>, String> {
+    let mut units = Vec::new();
+    expand_asm_unit(
+        main,
+        ExpandedAsm::default(),
+        src_root,
+        &mut HashSet::new(),
+        &mut units,
+    )?;
+    Ok(units)
+}
+
+/// Expand the unit for `path`, which starts with the `prelude` of definitions made before it
+/// was included, and the units of the `.asm` files it includes. Returns the unit's own lines,
+/// or `None` when the file already has a unit.
+fn expand_asm_unit(
+    path: &Path,
+    prelude: ExpandedAsm,
+    src_root: &Path,
+    seen: &mut HashSet<PathBuf>,
+    units: &mut Vec<BuildUnit>,
+) -> Result<Option<ExpandedAsm>, String> {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    // A file included more than once is still one object.
+    if !seen.insert(canonical) {
+        return Ok(None);
+    }
+    // Keep the unit ahead of the units it includes.
+    let index = units.len();
+    units.push(BuildUnit {
+        name: relative_path(src_root, path),
+        expanded: ExpandedAsm::default(),
+        private: HashSet::new(),
+    });
+    let mut split = AsmSplit {
+        prelude,
+        src_root,
+        seen,
+        units,
+    };
+    let body = expand_asm(path, &mut HashSet::new(), Some(&mut split))?;
+    let mut expanded = split.prelude;
+    expanded.append(&body);
+    units[index].expanded = expanded;
+    Ok(Some(body))
+}
+
+/// State for splitting the `.asm` files a unit includes into units of their own.
+struct AsmSplit<'a> {
+    /// The definitions the unit starts with.
+    prelude: ExpandedAsm,
+    src_root: &'a Path,
+    seen: &'a mut HashSet<PathBuf>,
+    units: &'a mut Vec<BuildUnit>,
+}
+
+/// The lines of `expanded` that define names without emitting bytes or labels: `.const`s,
+/// `.macro` blocks and the conditionals around them.
+fn asm_definitions(expanded: &ExpandedAsm) -> ExpandedAsm {
+    let text = String::from_utf8_lossy(&expanded.bytes);
+    let mut definitions = ExpandedAsm::default();
+    let mut in_macro = false;
+    for (line, origin) in text.lines().zip(&expanded.line_map) {
+        let directive = asm_directive(line);
+        let keep = in_macro
+            || matches!(
+                directive.as_deref(),
+                Some(".const" | ".macro" | ".if" | ".ifdef" | ".ifndef" | ".else" | ".endif")
+            );
+        match directive.as_deref() {
+            Some(".macro") => in_macro = true,
+            Some(".endm" | ".endmacro") => in_macro = false,
+            _ => {}
+        }
+        if keep {
+            definitions.push(line, origin.clone());
+        }
+    }
+    definitions
+}
+
+fn expand_asm(
+    path: &Path,
+    visited: &mut HashSet<PathBuf>,
+    split: Option<&mut AsmSplit<'_>>,
+) -> Result<ExpandedAsm, String> {
     let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
     let content = fs::read_to_string(path)
         .map_err(|e| format!("failed to read asm file {}: {e}", path.display()))?;
     let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
-    expand_asm_inline(base_dir, &canonical, &content, visited)
+    expand_asm_inline(base_dir, &canonical, &content, visited, split)
 }
 
+/// Expand `.include` directives. With `split`, an included `.asm` file becomes a unit of its
+/// own that starts with the definitions made before the directive, and the directive is kept
+/// as a comment followed by the file's definitions. Every other file is inlined, as is an
+/// `.asm` file included inside a conditional or macro block, since only the assembler knows
+/// whether that block is assembled.
 fn expand_asm_inline(
     base_dir: &Path,
     virtual_path: &Path,
     content: &str,
     visited: &mut HashSet<PathBuf>,
+    mut split: Option<&mut AsmSplit<'_>>,
 ) -> Result<ExpandedAsm, String> {
     let canonical = virtual_path
         .canonicalize()
@@ -1571,11 +2241,18 @@ fn expand_asm_inline(
         return Err(format!("Include cycle detected at {}", canonical.display()));
     }
 
-    let mut bytes = Vec::new();
-    let mut line_map = Vec::new();
-    let includes: Vec<ExpandedAsm> = Vec::new();
-
+    let mut expanded = ExpandedAsm::default();
+    let mut blocks = 0usize;
     for (idx, line) in content.lines().enumerate() {
+        let origin = LineOrigin {
+            file: canonical.clone(),
+            line: idx + 1,
+        };
+        match asm_directive(line).as_deref() {
+            Some(".if" | ".ifdef" | ".ifndef" | ".macro") => blocks += 1,
+            Some(".endif" | ".endm" | ".endmacro") => blocks = blocks.saturating_sub(1),
+            _ => {}
+        }
         let trimmed = line.trim_start();
         if let Some(rest) = trimmed.strip_prefix(".include") {
             let rest = rest.trim_start();
@@ -1583,11 +2260,32 @@ fn expand_asm_inline(
                 if let Some(end_quote) = stripped.find('"') {
                     let include_path = &stripped[..end_quote];
                     let include_full = base_dir.join(include_path);
-                    let included = expand_asm(&include_full, visited)?;
+                    let is_asm = include_full
+                        .extension()
+                        .is_some_and(|ext| ext.eq_ignore_ascii_case("asm"));
+                    if is_asm
+                        && blocks == 0
+                        && let Some(split) = split.as_deref_mut()
+                    {
+                        expanded.push(&format!("; {}", trimmed), origin);
+                        let mut before = split.prelude.clone();
+                        before.append(&expanded);
+                        let body = expand_asm_unit(
+                            &include_full,
+                            asm_definitions(&before),
+                            split.src_root,
+                            split.seen,
+                            split.units,
+                        )?;
+                        if let Some(body) = body {
+                            expanded.append(&asm_definitions(&body));
+                        }
+                        continue;
+                    }
+                    let included = expand_asm(&include_full, visited, None)?;
 
                     // Inline all includes at the point they're declared
-                    bytes.extend_from_slice(&included.bytes);
-                    line_map.extend_from_slice(&included.line_map);
+                    expanded.append(&included);
                     continue;
                 }
             }
@@ -1596,36 +2294,283 @@ fn expand_asm_inline(
                 virtual_path.display()
             ));
         }
-        bytes.extend_from_slice(line.as_bytes());
-        bytes.push(b'\n');
-        line_map.push(LineOrigin {
-            file: canonical.clone(),
-            line: idx + 1,
-        });
+        expanded.push(line, origin);
     }
 
-    // Append all includes at the end
-    for included in includes {
-        bytes.extend_from_slice(&included.bytes);
-        line_map.extend_from_slice(&included.line_map);
+    visited.remove(&canonical);
+    Ok(expanded)
+}
+
+/// The lowercase directive an ASM line starts with, such as `.if`, ignoring any comment.
+fn asm_directive(line: &str) -> Option<String> {
+    let code = line.split(';').next().unwrap_or_default();
+    let word = code.split_whitespace().next()?;
+    word.starts_with('.').then(|| word.to_ascii_lowercase())
+}
+
+/// Path of the file an `.incbin` on `line` of an expanded unit names, relative to the source
+/// file that line came from.
+fn incbin_path(line_map: &[LineOrigin], line: usize, name: &str) -> PathBuf {
+    line_map
+        .get(line.wrapping_sub(1))
+        .and_then(|origin| origin.file.parent())
+        .unwrap_or_else(|| Path::new("."))
+        .join(name)
+}
+
+/// Map assembler PC line numbers back to their source origins and expanded ASM lines.
+fn map_pc_lines(pc_line: &[usize], line_map: &[LineOrigin]) -> (Vec<LineOrigin>, Vec<usize>) {
+    let mut pc_line_map = Vec::new();
+    let mut pc_asm_line_map = Vec::new();
+    for line_no in pc_line {
+        pc_asm_line_map.push(*line_no);
+        if let Some(orig) = line_map.get(line_no.saturating_sub(1)) {
+            pc_line_map.push(orig.clone());
+        }
     }
+    (pc_line_map, pc_asm_line_map)
+}
 
-    visited.remove(&canonical);
-    Ok(ExpandedAsm { bytes, line_map })
+/// Bytes disassembled by `chipcade disasm --from` when no length is given.
+const DISASM_DEFAULT_LEN: usize = 256;
+
+/// Bytes shown per listing row; longer data continues on following rows.
+const LISTING_BYTES_PER_ROW: usize = 4;
+
+/// Render the assembler listing: one row per expanded ASM line with its address, emitted bytes,
+/// cycle count and source location. Transpiled C statements are shown as comments above the code
+/// generated for them.
+fn render_listing(artifacts: &BuildArtifacts, project_root: &Path, cpu: CpuKind) -> String {
+    let mut bytes_by_line: Vec<Vec<(u16, u8)>> = vec![Vec::new(); artifacts.asm_lines.len() + 1];
+    let blocks = std::iter::once((
+        artifacts.load_addr,
+        &artifacts.program,
+        &artifacts.pc_asm_line_map,
+    ))
+    .chain(
+        artifacts
+            .segments
+            .iter()
+            .map(|seg| (seg.start, &seg.bytes, &seg.pc_asm_line_map)),
+    );
+    for (start, bytes, lines) in blocks {
+        for (offset, (byte, line)) in bytes.iter().zip(lines).enumerate() {
+            if let Some(slot) = bytes_by_line.get_mut(*line) {
+                slot.push((start.wrapping_add(offset as u16), *byte));
+            }
+        }
+    }
+
+    let locations: Vec<String> = (0..artifacts.asm_lines.len())
+        .map(|idx| {
+            artifacts
+                .line_map
+                .get(idx)
+                .map(|origin| {
+                    format!(
+                        "{}:{}",
+                        relative_path(project_root, &origin.file).display(),
+                        origin.line
+                    )
+                })
+                .unwrap_or_default()
+        })
+        .collect();
+    let loc_width = locations.iter().map(String::len).max().unwrap_or(0);
+    let bytes_width = LISTING_BYTES_PER_ROW * 3 - 1;
+
+    let mut out = String::new();
+    out.push_str("; CHIPcade listing\n");
+    out.push_str("; Cycles are base counts; + adds one on a page crossing or a taken branch.\n\n");
+    out.push_str(&format!(
+        "{:<4}  {:<bw$}  {:>3}  {:<lw$}  SOURCE\n",
+        "ADDR",
+        "BYTES",
+        "CYC",
+        "FILE:LINE",
+        bw = bytes_width,
+        lw = loc_width
+    ));
+    let mut c_sources: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    let mut last_c_origin: Option<(PathBuf, usize)> = None;
+    for (idx, text) in artifacts.asm_lines.iter().enumerate() {
+        let origin = artifacts.line_map.get(idx);
+        let is_c = |o: &&LineOrigin| o.file.extension().is_some_and(|e| e == "c" || e == "h");
+        if let Some(origin) = origin.filter(is_c) {
+            let key = (origin.file.clone(), origin.line);
+            if last_c_origin.as_ref() != Some(&key) {
+                let c_line = c_sources
+                    .entry(origin.file.clone())
+                    .or_insert_with(|| {
+                        fs::read_to_string(&origin.file)
+                            .map(|text| text.lines().map(str::to_string).collect())
+                            .unwrap_or_default()
+                    })
+                    .get(origin.line.wrapping_sub(1))
+                    .map(|line| line.trim().to_string())
+                    .unwrap_or_default();
+                out.push_str(&format!("; {}: {}\n", locations[idx], c_line));
+                last_c_origin = Some(key);
+            }
+        }
+
+        let bytes = &bytes_by_line[idx + 1];
+        let cycles = listing_cycles(text, bytes, cpu);
+        let mut rows = bytes.chunks(LISTING_BYTES_PER_ROW);
+        let (addr, first) = match rows.next() {
+            Some(row) => (format!("{:04X}", row[0].0), listing_bytes(row)),
+            None => (String::new(), String::new()),
+        };
+        let line = format!(
+            "{:<4}  {:<bw$}  {:>3}  {:<lw$}  {}",
+            addr,
+            first,
+            cycles,
+            locations[idx],
+            text.trim_end(),
+            bw = bytes_width,
+            lw = loc_width
+        );
+        out.push_str(line.trim_end());
+        out.push('\n');
+        for row in rows {
+            out.push_str(&format!("{:04X}  {}\n", row[0].0, listing_bytes(row)));
+        }
+    }
+
+    out.push_str("\n; Blocks\n");
+    let summary = std::iter::once((
+        DEFAULT_SEGMENT,
+        artifacts.load_addr,
+        artifacts.program.len(),
+    ))
+    .chain(
+        artifacts
+            .segments
+            .iter()
+            .map(|seg| (seg.name.as_str(), seg.start, seg.bytes.len())),
+    );
+    for (name, start, len) in summary {
+        let end = (start as usize + len).saturating_sub(1);
+        out.push_str(&format!(
+            ";   {:<10} ${:04X}-${:04X}  {} bytes\n",
+            name, start, end, len
+        ));
+    }
+    out
+}
+
+fn listing_bytes(row: &[(u16, u8)]) -> String {
+    row.iter()
+        .map(|(_, byte)| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Base cycles of the instruction a line emitted, e.g. "4" or "5+". Empty for data and lines
+/// without code.
+fn listing_cycles(text: &str, bytes: &[(u16, u8)], cpu: CpuKind) -> String {
+    let instr = match text.split_once(':') {
+        Some((label, rest)) if !label.trim().contains([' ', '\t', '"', '\'']) => rest,
+        _ => text,
+    }
+    .trim();
+    if bytes.is_empty() || instr.starts_with('.') {
+        return String::new();
+    }
+    match opcode_info(cpu, bytes[0].1) {
+        Some(info) if info.size() <= bytes.len() => format!(
+            "{}{}",
+            info.cycles,
+            if info.extra_cycles { "+" } else { "" }
+        ),
+        _ => String::new(),
+    }
 }
 
-fn map_error_to_origin(msg: &str, map: &[LineOrigin]) -> Option<(PathBuf, usize)> {
-    // Expect format: "Parse error on line X: ..."
-    let needle = "Parse error on line ";
-    let idx = msg.find(needle)?;
-    let rest = &msg[idx + needle.len()..];
-    let line_str = rest.split(':').next()?.trim();
-    let line_num: usize = line_str.parse().ok()?;
-    let origin = map.get(line_num.saturating_sub(1))?;
-    Some((origin.file.clone(), origin.line))
+/// Render assembler diagnostics with their source location, an excerpt of the offending line and
+/// caret markers under the offending text.
+fn render_diagnostics(
+    diagnostics: &[Diagnostic],
+    map: &[LineOrigin],
+    asm_lines: &[String],
+    project_root: &Path,
+) -> String {
+    let mut sources: HashMap<PathBuf, Option<Vec<String>>> = HashMap::new();
+    let mut source_line = |origin: &LineOrigin| -> Option<String> {
+        sources
+            .entry(origin.file.clone())
+            .or_insert_with(|| {
+                fs::read_to_string(&origin.file)
+                    .ok()
+                    .map(|text| text.lines().map(str::to_string).collect())
+            })
+            .as_ref()
+            .and_then(|lines| lines.get(origin.line.wrapping_sub(1)).cloned())
+    };
+    let location = |origin: &LineOrigin| {
+        format!(
+            "{}:{}",
+            relative_path(project_root, &origin.file).display(),
+            origin.line
+        )
+    };
+
+    let mut blocks = Vec::with_capacity(diagnostics.len());
+    for diagnostic in diagnostics {
+        let mut out = format!("{}: {}", diagnostic.severity, diagnostic.message);
+        let origin = map.get(diagnostic.line.wrapping_sub(1));
+        let gutter = " ".repeat(origin.map_or(0, |o| o.line.to_string().len()));
+        if let Some(origin) = origin {
+            let text = source_line(origin);
+            // Columns refer to the assembled line, so they only apply when the source line is
+            // that same line (not C source or a rewritten directive).
+            let span = diagnostic.span.clone().filter(|span| {
+                text.as_deref().is_some_and(|text| {
+                    asm_lines.get(diagnostic.line - 1).map(String::as_str) == Some(text)
+                        && text.get(span.clone()).is_some()
+                })
+            });
+            match (&text, &span) {
+                (Some(text), Some(span)) => out.push_str(&format!(
+                    "\n{}--> {}:{}",
+                    gutter,
+                    location(origin),
+                    text[..span.start].chars().count() + 1
+                )),
+                _ => out.push_str(&format!("\n{}--> {}", gutter, location(origin))),
+            }
+            if let Some(text) = &text {
+                out.push_str(&format!("\n{} |\n{} | {}", gutter, origin.line, text));
+                if let Some(span) = span {
+                    let indent: String = text[..span.start]
+                        .chars()
+                        .map(|c| if c == '\t' { '\t' } else { ' ' })
+                        .collect();
+                    let carets = "^".repeat(text[span].chars().count().max(1));
+                    out.push_str(&format!("\n{} | {}{}", gutter, indent, carets));
+                }
+            }
+        }
+        for (name, body_line) in diagnostic.macro_trace.iter().rev() {
+            let at = map
+                .get(body_line.wrapping_sub(1))
+                .map(&location)
+                .unwrap_or_else(|| format!("line {}", body_line));
+            out.push_str(&format!(
+                "\n{} = note: in macro '{}' at {}",
+                gutter, name, at
+            ));
+        }
+        if let Some(hint) = &diagnostic.hint {
+            out.push_str(&format!("\n{} = help: {}", gutter, hint));
+        }
+        blocks.push(out);
+    }
+    blocks.join("\n\n")
 }
 
-fn relative_path(base: &Path, path: &Path) -> PathBuf {
+pub(crate) fn relative_path(base: &Path, path: &Path) -> PathBuf {
     if let Ok(rel) = path.strip_prefix(base) {
         return rel.to_path_buf();
     }
@@ -1745,6 +2690,7 @@ fn write_chipcade_headers(
     paths: &ProjectPaths,
     sys_consts: &[SystemConst],
     sprite_consts: &[(String, u32)],
+    defines: &[(String, u16)],
 ) -> Result<(), String> {
     let include_dir = paths
         .asm_main
@@ -1774,6 +2720,12 @@ fn write_chipcade_headers(
             inc.push_str(&format!(".const {} {}\n", name, val));
         }
     }
+    if !defines.is_empty() {
+        inc.push_str("\n; Build defines\n");
+        for (name, val) in defines {
+            inc.push_str(&format!(".const {} {}\n", name, val));
+        }
+    }
 
     let include_inc = include_dir.join("chipcade.inc");
     fs::write(&include_inc, inc)
@@ -1813,31 +2765,146 @@ fn write_chipcade_headers(
             hdr.push_str(&format!("#define {} {}\n", name, val));
         }
     }
+    if !defines.is_empty() {
+        hdr.push_str("\n/* Build defines */\n");
+        for (name, val) in defines {
+            hdr.push_str(&format!("#define {} {}\n", name, val));
+        }
+    }
     hdr.push_str("\n#endif /* CHIPCADE_H */\n");
 
     let include_h = include_dir.join("chipcade.h");
     fs::write(&include_h, hdr).map_err(|e| format!("Failed to write {}: {e}", include_h.display()))
 }
 
-#[derive(Clone)]
+/// A C integer type: `char` is 8 bits and `int` is 16 bits. Pointers are 16-bit and point
+/// at chars; `signed` then describes the char.
+#[derive(Clone, Copy, PartialEq)]
+struct CType {
+    wide: bool,
+    signed: bool,
+    pointer: bool,
+}
+
+impl CType {
+    const UCHAR: CType = CType {
+        wide: false,
+        signed: false,
+        pointer: false,
+    };
+
+    fn char(signed: bool) -> CType {
+        CType {
+            wide: false,
+            signed,
+            pointer: false,
+        }
+    }
+
+    fn size(self) -> u8 {
+        if self.wide { 2 } else { 1 }
+    }
+}
+
+/// A variable in zero page; 16-bit values are stored low byte first.
+#[derive(Clone, Copy)]
 struct CVar {
     addr: u8,
+    ty: CType,
 }
 
-#[derive(Clone)]
-enum CTerm {
-    Imm(u8),
-    Var(u8),
+/// A global char array, stored under a label with the array's name: in RAM, or with the
+/// ROM data when `constant`.
+#[derive(Clone, Copy)]
+struct CArray {
+    len: u16,
+    signed: bool,
+    constant: bool,
 }
 
-#[derive(Clone)]
-struct AddrExpr {
-    base: u16,
-    offset: Option<CTerm>,
+/// A global struct, or array of `len` structs, stored in RAM under a label with its name.
+#[derive(Clone, Copy)]
+struct CStructVar {
+    len: Option<u16>,
+    size: u8,
 }
 
+/// A field of a struct variable, named `var.field` in the symbol table.
 #[derive(Clone, Copy)]
-enum CmpOp {
+struct CField {
+    offset: u8,
+    ty: CType,
+}
+
+/// A struct type: its fields with their offsets, and its size in bytes.
+#[derive(Clone, PartialEq)]
+struct CStruct {
+    fields: Vec<(String, u8, CType)>,
+    size: u8,
+}
+
+/// What a C identifier names.
+#[derive(Clone, Copy)]
+enum CSym {
+    Var(CVar),
+    Array(CArray),
+    Struct(CStructVar),
+    Field(CField),
+}
+
+#[derive(Clone)]
+enum CTerm {
+    Imm(u16),
+    Var(CVar),
+    /// Address of a label, such as an array.
+    Label(String),
+}
+
+/// A value in memory: one byte, or two for a 16-bit struct field.
+#[derive(Clone)]
+enum AddrExpr {
+    /// An address or label operand, plus an optional index variable times `stride`.
+    Abs {
+        base: String,
+        index: Option<CVar>,
+        /// The size of the elements the index counts, such as a struct.
+        stride: u8,
+        /// The scaled index may pass 255, so it is added to the base in 16 bits.
+        wide_index: bool,
+        ty: CType,
+    },
+    /// The address held by the pointer variable at `ptr`, plus a constant `offset` or an
+    /// index variable.
+    Ptr {
+        ptr: u8,
+        offset: u8,
+        index: Option<CVar>,
+        ty: CType,
+    },
+}
+
+impl AddrExpr {
+    /// A byte at `base`, indexed by an optional variable.
+    fn byte(base: String, index: Option<CVar>, signed: bool) -> AddrExpr {
+        AddrExpr::Abs {
+            base,
+            index,
+            stride: 1,
+            wide_index: index.is_some_and(|index| index.ty.wide),
+            ty: CType::char(signed),
+        }
+    }
+
+    /// The type of the value.
+    fn ty(&self) -> CType {
+        match self {
+            AddrExpr::Abs { ty, .. } | AddrExpr::Ptr { ty, .. } => *ty,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CmpOp {
     Eq,
     Ne,
     Lt,
@@ -1846,18 +2913,67 @@ enum CmpOp {
     Ge,
 }
 
+impl CmpOp {
+    /// The comparison that holds exactly when this one does not.
+    fn negate(self) -> CmpOp {
+        match self {
+            CmpOp::Eq => CmpOp::Ne,
+            CmpOp::Ne => CmpOp::Eq,
+            CmpOp::Lt => CmpOp::Ge,
+            CmpOp::Ge => CmpOp::Lt,
+            CmpOp::Gt => CmpOp::Le,
+            CmpOp::Le => CmpOp::Gt,
+        }
+    }
+
+    /// The comparison `op` is, if it is one.
+    fn from_binary(op: BinaryOp) -> Option<CmpOp> {
+        Some(match op {
+            BinaryOp::Eq => CmpOp::Eq,
+            BinaryOp::Ne => CmpOp::Ne,
+            BinaryOp::Lt => CmpOp::Lt,
+            BinaryOp::Le => CmpOp::Le,
+            BinaryOp::Gt => CmpOp::Gt,
+            BinaryOp::Ge => CmpOp::Ge,
+            _ => return None,
+        })
+    }
+}
+
+/// A condition of `if`, `while` or `for`.
+enum CCond {
+    Cmp(CExpr, CmpOp, CExpr),
+    /// A plain expression, true when non-zero.
+    Truth(CExpr),
+    Not(Box<CCond>),
+    And(Box<CCond>, Box<CCond>),
+    Or(Box<CCond>, Box<CCond>),
+}
+
 #[derive(Clone)]
 enum CExpr {
     Term(CTerm),
     Mem(AddrExpr),
     Not(Box<CExpr>),
     Bin(Box<CExpr>, CBinOp, Box<CExpr>),
+    Call(CCall),
+}
+
+/// A call with its arguments lowered, checked against the signature `func`.
+#[derive(Clone)]
+struct CCall {
+    name: String,
+    args: Vec<CExpr>,
+    func: CFunc,
 }
 
 #[derive(Clone, Copy)]
 enum CBinOp {
     Add,
     Sub,
+    Mul,
+    Div,
+    Mod,
     Shl,
     Shr,
     And,
@@ -1869,922 +2985,2227 @@ const C_EXPR_TMP_LHS: u8 = 0x20;
 const C_EXPR_TMP_RHS: u8 = 0x21;
 const C_EXPR_TMP_CNT: u8 = 0x22;
 const C_EXPR_TMP_CMP: u8 = 0x23;
+const C_EXPR_TMP_LHS_HI: u8 = 0x24;
+const C_EXPR_TMP_RHS_HI: u8 = 0x25;
+const C_EXPR_TMP_CMP_HI: u8 = 0x26;
+/// Zero-page pointer (`$27`/`$28`) for memory accesses with a 16-bit offset.
+const C_EXPR_TMP_PTR: u8 = 0x27;
+/// Scratch bytes (`$29`-`$2B`) of the runtime library.
+const C_RUNTIME_TMP: u8 = 0x29;
+/// Registers that carry the argument bytes of a C function call, in order. A 16-bit argument
+/// takes two of them, low byte first.
+const C_ARG_REGS: [&str; 3] = ["A", "X", "Y"];
+
+/// A routine of the C runtime library, appended to the program when generated code calls it.
+struct CRuntimeRoutine {
+    name: &'static str,
+    /// Routines this one calls.
+    deps: &'static [&'static str],
+    code: &'static str,
+}
 
-enum FlowBlock {
-    If {
-        else_label: String,
-        end_label: String,
+/// Multiply and divide for `*`, `/` and `%`. Operands arrive in the expression temporaries:
+/// the left one in $20 (high byte $24) and the right one in $21 (high byte $25). Products come
+/// back in A (and X); quotients in $20/$24 and remainders in `C_RUNTIME_TMP` ($29/$2A), which
+/// take the sign of the dividend in the signed versions. Nothing else in zero page is touched,
+/// so a comparison's left side survives in `C_EXPR_TMP_CMP`.
+const C_RUNTIME: &[CRuntimeRoutine] = &[
+    CRuntimeRoutine {
+        name: "CRTMUL8",
+        deps: &[],
+        code: "
+CRTMUL8:
+    LDA #$00
+    LDX #$08
+CRTMUL8L:
+    ASL A
+    ASL $20
+    BCC CRTMUL8S
+    CLC
+    ADC $21
+CRTMUL8S:
+    DEX
+    BNE CRTMUL8L
+    RTS
+",
     },
-    Else {
-        end_label: String,
+    CRuntimeRoutine {
+        name: "CRTMUL16",
+        deps: &[],
+        code: "
+CRTMUL16:
+    LDA #$00
+    STA $29
+    STA $2A
+    LDY #$10
+CRTMUL16L:
+    ASL $29
+    ROL $2A
+    ASL $20
+    ROL $24
+    BCC CRTMUL16S
+    LDA $29
+    CLC
+    ADC $21
+    STA $29
+    LDA $2A
+    ADC $25
+    STA $2A
+CRTMUL16S:
+    DEY
+    BNE CRTMUL16L
+    LDA $29
+    LDX $2A
+    RTS
+",
+    },
+    CRuntimeRoutine {
+        name: "CRTDIV8",
+        deps: &[],
+        code: "
+CRTDIV8:
+    LDA #$00
+    LDX #$08
+CRTDIV8L:
+    ASL $20
+    ROL A
+    BCS CRTDIV8T
+    CMP $21
+    BCC CRTDIV8S
+CRTDIV8T:
+    SBC $21
+    INC $20
+CRTDIV8S:
+    DEX
+    BNE CRTDIV8L
+    STA $29
+    LDA $20
+    RTS
+",
+    },
+    CRuntimeRoutine {
+        name: "CRTDIV16",
+        deps: &[],
+        code: "
+CRTDIV16:
+    LDA #$00
+    STA $29
+    STA $2A
+    LDY #$10
+CRTDIV16L:
+    ASL $20
+    ROL $24
+    ROL $29
+    ROL $2A
+    LDA #$00
+    ROL A
+    STA $2B
+    LDA $29
+    SEC
+    SBC $21
+    TAX
+    LDA $2A
+    SBC $25
+    BCS CRTDIV16T
+    LSR $2B
+    BCC CRTDIV16S
+CRTDIV16T:
+    STX $29
+    STA $2A
+    INC $20
+CRTDIV16S:
+    DEY
+    BNE CRTDIV16L
+    RTS
+",
+    },
+    CRuntimeRoutine {
+        name: "CRTSDIV8",
+        deps: &["CRTDIV8"],
+        code: "
+CRTSDIV8:
+    LDA $20
+    PHA
+    EOR $21
+    PHA
+    LDA $20
+    BPL CRTSDIV8A
+    LDA #$00
+    SEC
+    SBC $20
+    STA $20
+CRTSDIV8A:
+    LDA $21
+    BPL CRTSDIV8B
+    LDA #$00
+    SEC
+    SBC $21
+    STA $21
+CRTSDIV8B:
+    JSR CRTDIV8
+    PLA
+    BPL CRTSDIV8C
+    LDA #$00
+    SEC
+    SBC $20
+    STA $20
+CRTSDIV8C:
+    PLA
+    BPL CRTSDIV8D
+    LDA #$00
+    SEC
+    SBC $29
+    STA $29
+CRTSDIV8D:
+    LDA $20
+    RTS
+",
     },
+    CRuntimeRoutine {
+        name: "CRTSDIV16",
+        deps: &["CRTDIV16"],
+        code: "
+CRTSDIV16:
+    LDA $24
+    PHA
+    EOR $25
+    PHA
+    LDA $24
+    BPL CRTSDIV16A
+    LDA #$00
+    SEC
+    SBC $20
+    STA $20
+    LDA #$00
+    SBC $24
+    STA $24
+CRTSDIV16A:
+    LDA $25
+    BPL CRTSDIV16B
+    LDA #$00
+    SEC
+    SBC $21
+    STA $21
+    LDA #$00
+    SBC $25
+    STA $25
+CRTSDIV16B:
+    JSR CRTDIV16
+    PLA
+    BPL CRTSDIV16C
+    LDA #$00
+    SEC
+    SBC $20
+    STA $20
+    LDA #$00
+    SBC $24
+    STA $24
+CRTSDIV16C:
+    PLA
+    BPL CRTSDIV16D
+    LDA #$00
+    SEC
+    SBC $29
+    STA $29
+    LDA #$00
+    SBC $2A
+    STA $2A
+CRTSDIV16D:
+    RTS
+",
+    },
+];
+
+/// Signature of a C function, from its definition or a prototype.
+#[derive(Clone, PartialEq)]
+struct CFunc {
+    params: Vec<CType>,
+    ret: Option<CType>,
+}
+
+/// The header of a function definition or prototype.
+struct CFnHeader {
+    name: String,
+    func: CFunc,
+    /// Parameter names; prototypes may leave them out.
+    param_names: Vec<Option<String>>,
+}
+
+/// A loop or switch that `break` (and, for loops, `continue`) can leave.
+enum FlowBlock {
     While {
         start_label: String,
         end_label: String,
     },
     For {
-        start_label: String,
         end_label: String,
-        step_stmt: Option<String>,
+        /// Label before the step, created by the first `continue`.
+        continue_label: Option<String>,
+    },
+    /// The body is emitted first; the dispatch on `selector` goes after it, once every
+    /// `case` is known.
+    Switch {
+        selector: CExpr,
+        end_label: String,
+        cases: Vec<(u16, String)>,
+        default_label: Option<String>,
     },
 }
 
-fn transpile_c_sources(
-    c_root: &Path,
-    paths: &[PathBuf],
-    sys_consts: &[SystemConst],
-    sprite_consts: &[(String, u32)],
-) -> Result<ExpandedAsm, String> {
-    let mut ordered = paths.to_vec();
-    ordered.sort_by(|a, b| {
-        let ra = a.strip_prefix(c_root).unwrap_or(a);
-        let rb = b.strip_prefix(c_root).unwrap_or(b);
-        let a_main = ra == Path::new("main.c");
-        let b_main = rb == Path::new("main.c");
-        b_main
-            .cmp(&a_main)
-            .then_with(|| ra.to_string_lossy().cmp(&rb.to_string_lossy()))
-    });
+/// What compiling the body of one function needs.
+struct CFnCtx<'a> {
+    lines: &'a [SourceLine],
+    name: &'a str,
+    /// The globals and the parameters and locals in scope.
+    vars: HashMap<String, CSym>,
+    consts: &'a HashMap<String, u16>,
+    fns: &'a HashMap<String, CFunc>,
+    structs: &'a HashMap<String, CStruct>,
+    next_zp: &'a mut u8,
+    /// Labels generated so far in this file.
+    labels: &'a mut Vec<String>,
+    out: &'a mut Vec<(String, PathBuf, usize)>,
+    /// Indices in `out` of inline assembly lines, which the optimizer leaves alone.
+    inline_asm: &'a mut HashSet<usize>,
+    /// Enclosing loops and switches, innermost last.
+    flow: Vec<FlowBlock>,
+}
 
-    let mut consts: HashMap<String, u16> = HashMap::new();
-    for c in sys_consts {
-        consts.insert(c.name.to_string(), c.value as u16);
+impl<'a> CFnCtx<'a> {
+    /// The file and line `pos` maps to in the listing.
+    fn source(&self, pos: Pos) -> (&'a Path, usize) {
+        let source = &self.lines[pos.line];
+        (&source.canonical, source.line)
     }
-    for (name, value) in sprite_consts {
-        consts.insert(name.clone(), *value as u16);
+
+    fn push(&mut self, pos: Pos, text: String) {
+        let (file, line_no) = self.source(pos);
+        self.out.push((text, file.to_path_buf(), line_no));
     }
 
-    let mut vars: HashMap<String, CVar> = HashMap::new();
-    let mut next_zp: u8 = 0x40;
-    for path in &ordered {
-        let content = fs::read_to_string(path)
-            .map_err(|e| format!("failed to read c file {}: {e}", path.display()))?;
-        let mut in_fn = false;
-        for (idx, raw) in content.lines().enumerate() {
-            let line_no = idx + 1;
-            let line = strip_c_comments(raw).trim();
-            if line.is_empty() || line.starts_with("#include") {
-                continue;
-            }
-            if in_fn {
-                if line == "}" {
-                    in_fn = false;
-                }
-                continue;
-            }
-            if parse_fn_proto(line)?.is_some() {
-                continue;
-            }
-            if parse_extern_decl(line)? {
-                continue;
-            }
-            if parse_fn_start(line)?.is_some() {
-                in_fn = true;
-                continue;
-            }
-            if let Some(name) = parse_global_char_decl(line)? {
-                if vars.contains_key(&name) {
-                    return Err(format!(
-                        "C parse error: {}:{}: duplicate global '{}'",
-                        path.display(),
-                        line_no,
-                        name
-                    ));
-                }
-                if next_zp == 0xFF {
-                    return Err(format!(
-                        "C parse error: {}:{}: out of zero-page space for globals",
-                        path.display(),
-                        line_no
-                    ));
-                }
-                vars.insert(name, CVar { addr: next_zp });
-                next_zp = next_zp.saturating_add(1);
-            }
+    fn error(&self, pos: Pos, msg: impl std::fmt::Display) -> String {
+        c_parser::error(self.lines, pos, msg)
+    }
+
+    /// What the statement lowering helpers need to emit code for the line at `pos`.
+    fn emit(&mut self, pos: Pos) -> CEmit<'_> {
+        let (source_file, line_no) = self.source(pos);
+        CEmit {
+            line_no,
+            names: CNames {
+                lines: self.lines,
+                vars: &self.vars,
+                consts: self.consts,
+                fns: self.fns,
+            },
+            out: self.out,
+            source_file,
+            labels: self.labels,
         }
     }
 
-    let mut asm_lines: Vec<(String, PathBuf, usize)> = Vec::new();
+    /// The names in scope.
+    fn names(&self) -> CNames<'_> {
+        CNames {
+            lines: self.lines,
+            vars: &self.vars,
+            consts: self.consts,
+            fns: self.fns,
+        }
+    }
 
-    let mut defined_fns: HashSet<String> = HashSet::new();
-    let mut label_counter: usize = 0;
-    for path in &ordered {
-        let content = fs::read_to_string(path)
-            .map_err(|e| format!("failed to read c file {}: {e}", path.display()))?;
-        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-        let mut in_fn: Option<String> = None;
-        let mut local_vars: HashMap<String, CVar> = HashMap::new();
-        let mut flow_stack: Vec<FlowBlock> = Vec::new();
-        let mut pending_else_end: Option<String> = None;
-        for (idx, raw) in content.lines().enumerate() {
-            let line_no = idx + 1;
-            let line = strip_c_comments(raw).trim();
-            if line.is_empty() || line.starts_with("#include") {
-                continue;
-            }
+    fn next_label(&mut self, prefix: &str) -> String {
+        next_c_label(self.labels, prefix)
+    }
 
-            if in_fn.is_none() {
-                if parse_global_char_decl(line)?.is_some() {
-                    continue;
-                }
-                if parse_extern_decl(line)? {
-                    continue;
-                }
-                if parse_fn_proto(line)?.is_some() {
-                    continue;
-                }
-                if let Some(name) = parse_fn_start(line)? {
-                    if !defined_fns.insert(name.clone()) {
-                        return Err(format!(
-                            "C parse error: {}:{}: duplicate function '{}'",
-                            path.display(),
-                            line_no,
-                            name
-                        ));
-                    }
-                    asm_lines.push((format!("{}:", name), canonical.clone(), line_no));
-                    in_fn = Some(name);
-                    local_vars.clear();
-                    pending_else_end = None;
-                    continue;
-                }
-                return Err(format!(
-                    "C parse error: {}:{}: expected global char declaration or function",
-                    path.display(),
-                    line_no
-                ));
+    fn function(&mut self, header: &CFnHeader, pos: Pos, body: &Block) -> Result<(), String> {
+        self.push(pos, format!("{}:", header.name));
+        let mut regs = C_ARG_REGS.iter();
+        for (param, ty) in header.param_names.iter().zip(&header.func.params) {
+            let param = param.clone().unwrap_or_default();
+            if self.vars.contains_key(&param) {
+                return Err(self.error(pos, format!("duplicate parameter '{}'", param)));
             }
-
-            let mut scoped_vars = vars.clone();
-            for (k, v) in &local_vars {
-                scoped_vars.insert(k.clone(), v.clone());
+            let Some(var) = alloc_zp(self.next_zp, *ty) else {
+                return Err(self.error(pos, "out of zero-page space for parameters"));
+            };
+            for (addr, reg) in (var.addr..var.addr + ty.size()).zip(&mut regs) {
+                self.push(pos, format!("ST{} ${:02X}", reg, addr));
             }
+            self.vars.insert(param, CSym::Var(var));
+        }
+        self.scope(&body.stmts)?;
+        self.push(body.end, self.exit().to_string());
+        self.push(body.end, String::new());
+        Ok(())
+    }
 
-            if let Some(end_label) = pending_else_end.take() {
-                if parse_else_start(line) {
-                    flow_stack.push(FlowBlock::Else { end_label });
-                    continue;
-                }
-                asm_lines.push((format!("{}:", end_label), canonical.clone(), line_no));
-            }
+    /// Compile `stmts`; the locals they declare go out of scope at the end.
+    fn scope(&mut self, stmts: &[Stmt]) -> Result<(), String> {
+        let mut declared = Vec::new();
+        for stmt in stmts {
+            self.stmt(stmt, &mut declared)?;
+        }
+        for name in declared {
+            self.vars.remove(&name);
+        }
+        Ok(())
+    }
 
-            if let Some((name, init)) = parse_char_decl(line)? {
-                if scoped_vars.contains_key(&name) {
-                    return Err(format!(
-                        "C parse error: {}:{}: duplicate local '{}'",
-                        path.display(),
-                        line_no,
-                        name
-                    ));
-                }
-                if next_zp == 0xFF {
-                    return Err(format!(
-                        "C parse error: {}:{}: out of zero-page space for locals",
-                        path.display(),
-                        line_no
-                    ));
+    fn stmt(&mut self, stmt: &Stmt, declared: &mut Vec<String>) -> Result<(), String> {
+        match &stmt.kind {
+            StmtKind::Expr(expr) => compile_c_expr_stmt(expr, &mut self.emit(stmt.pos))?,
+            StmtKind::Decl(decl) => self.local_decl(decl, declared)?,
+            StmtKind::Return(value) => self.ret(stmt.pos, value.as_ref())?,
+            StmtKind::Block(block) => self.scope(&block.stmts)?,
+            StmtKind::Empty => {}
+            StmtKind::Asm(lines) => {
+                for line in lines {
+                    let text = resolve_asm_names(&line.text, &self.vars, self.consts);
+                    self.inline_asm.insert(self.out.len());
+                    self.push(line.pos, text);
                 }
-                let var = CVar { addr: next_zp };
-                next_zp = next_zp.saturating_add(1);
-                if let Some(expr) = init {
-                    let mut with_local = scoped_vars.clone();
-                    with_local.insert(name.clone(), var.clone());
-                    emit_expr_into_a(
-                        &expr,
-                        &with_local,
-                        &consts,
-                        line_no,
-                        path,
-                        &mut asm_lines,
-                        &canonical,
-                    )?;
-                    asm_lines.push((format!("STA ${:02X}", var.addr), canonical.clone(), line_no));
+            }
+            StmtKind::If { cond, then, els } => {
+                let end_label = self.next_label("CIFEND");
+                let else_label = self.next_label("CIFELSE");
+                self.condition(cond, &else_label)?;
+                self.scope(std::slice::from_ref(then))?;
+                self.push(then.end, format!("JMP {}", end_label));
+                self.push(then.end, format!("{}:", else_label));
+                if let Some(els) = els {
+                    self.scope(std::slice::from_ref(els))?;
                 }
-                local_vars.insert(name, var);
-                continue;
+                self.push(stmt.end, format!("{}:", end_label));
             }
-
-            if let Some(cond_src) = parse_if_start(line)? {
-                let end_label = format!("CIFEND{}", label_counter);
-                label_counter = label_counter.saturating_add(1);
-                let else_label = format!("CIFELSE{}", label_counter);
-                label_counter = label_counter.saturating_add(1);
-                emit_condition_false_jump(
-                    &cond_src,
-                    &else_label,
-                    line_no,
-                    path,
-                    &scoped_vars,
-                    &consts,
-                    &mut asm_lines,
-                    &canonical,
-                    &mut label_counter,
-                )?;
-                flow_stack.push(FlowBlock::If {
-                    else_label,
-                    end_label,
+            StmtKind::While { cond, body } => {
+                let start_label = self.next_label("CWHILES");
+                let end_label = self.next_label("CWHILEE");
+                self.push(stmt.pos, format!("{}:", start_label));
+                self.condition(cond, &end_label)?;
+                self.flow.push(FlowBlock::While {
+                    start_label: start_label.clone(),
+                    end_label: end_label.clone(),
                 });
-                continue;
+                self.scope(std::slice::from_ref(body))?;
+                self.flow.pop();
+                self.push(stmt.end, format!("JMP {}", start_label));
+                self.push(stmt.end, format!("{}:", end_label));
             }
-
-            if let Some(cond_src) = parse_while_start(line)? {
-                let start_label = format!("CWHILES{}", label_counter);
-                label_counter = label_counter.saturating_add(1);
-                let end_label = format!("CWHILEE{}", label_counter);
-                label_counter = label_counter.saturating_add(1);
-                asm_lines.push((format!("{}:", start_label), canonical.clone(), line_no));
-                emit_condition_false_jump(
-                    &cond_src,
-                    &end_label,
-                    line_no,
-                    path,
-                    &scoped_vars,
-                    &consts,
-                    &mut asm_lines,
-                    &canonical,
-                    &mut label_counter,
-                )?;
-                flow_stack.push(FlowBlock::While {
-                    start_label,
-                    end_label,
+            StmtKind::For {
+                init,
+                cond,
+                step,
+                body,
+            } => {
+                // A variable declared in the init clause is scoped to the loop.
+                let mut loop_vars = Vec::new();
+                if let Some(init) = init {
+                    self.stmt(init, &mut loop_vars)?;
+                }
+                let start_label = self.next_label("CFORS");
+                let end_label = self.next_label("CFORE");
+                self.push(stmt.pos, format!("{}:", start_label));
+                if let Some(cond) = cond {
+                    self.condition(cond, &end_label)?;
+                }
+                self.flow.push(FlowBlock::For {
+                    end_label: end_label.clone(),
+                    continue_label: None,
                 });
-                continue;
+                self.scope(std::slice::from_ref(body))?;
+                let Some(FlowBlock::For { continue_label, .. }) = self.flow.pop() else {
+                    unreachable!("for loop missing from the flow stack");
+                };
+                if let Some(label) = continue_label {
+                    self.push(body.end, format!("{}:", label));
+                }
+                if let Some(step) = step {
+                    compile_c_expr_stmt(step, &mut self.emit(step.start()))?;
+                }
+                self.push(stmt.end, format!("JMP {}", start_label));
+                self.push(stmt.end, format!("{}:", end_label));
+                for name in loop_vars {
+                    self.vars.remove(&name);
+                }
             }
-
-            if let Some((init_stmt, cond_src, step_stmt)) = parse_for_start(line)? {
-                if let Some(init_stmt) = init_stmt {
-                    compile_c_stmt(
-                        &format!("{};", init_stmt),
-                        line_no,
-                        path,
-                        in_fn.as_deref().unwrap_or_default(),
-                        &scoped_vars,
-                        &consts,
-                        &mut asm_lines,
-                        &canonical,
-                    )?;
-                }
-                let start_label = format!("CFORS{}", label_counter);
-                label_counter = label_counter.saturating_add(1);
-                let end_label = format!("CFORE{}", label_counter);
-                label_counter = label_counter.saturating_add(1);
-                asm_lines.push((format!("{}:", start_label), canonical.clone(), line_no));
-                if let Some(cond_src) = cond_src {
-                    emit_condition_false_jump(
-                        &cond_src,
-                        &end_label,
-                        line_no,
-                        path,
-                        &scoped_vars,
-                        &consts,
-                        &mut asm_lines,
-                        &canonical,
-                        &mut label_counter,
-                    )?;
-                }
-                flow_stack.push(FlowBlock::For {
-                    start_label,
-                    end_label,
-                    step_stmt,
+            StmtKind::Switch { selector, body } => {
+                let selector = self.names().value(selector)?;
+                let dispatch_label = self.next_label("CSWD");
+                let end_label = self.next_label("CSWE");
+                self.push(stmt.pos, format!("JMP {}", dispatch_label));
+                self.flow.push(FlowBlock::Switch {
+                    selector,
+                    end_label: end_label.clone(),
+                    cases: Vec::new(),
+                    default_label: None,
                 });
-                continue;
+                self.scope(std::slice::from_ref(body))?;
+                let Some(FlowBlock::Switch {
+                    selector,
+                    cases,
+                    default_label,
+                    ..
+                }) = self.flow.pop()
+                else {
+                    unreachable!("switch missing from the flow stack");
+                };
+                self.push(stmt.end, format!("JMP {}", end_label));
+                self.push(stmt.end, format!("{}:", dispatch_label));
+                let fallback = default_label.as_ref().unwrap_or(&end_label);
+                emit_switch_dispatch(&selector, &cases, fallback, &mut self.emit(stmt.end));
+                self.push(stmt.end, format!("{}:", end_label));
             }
-
-            if line == "}" {
-                if let Some(block) = flow_stack.pop() {
-                    match block {
-                        FlowBlock::If {
-                            else_label,
-                            end_label,
-                        } => {
-                            asm_lines.push((
-                                format!("JMP {}", end_label),
-                                canonical.clone(),
-                                line_no,
-                            ));
-                            asm_lines.push((
-                                format!("{}:", else_label),
-                                canonical.clone(),
-                                line_no,
-                            ));
-                            pending_else_end = Some(end_label);
-                        }
-                        FlowBlock::Else { end_label } => {
-                            asm_lines.push((format!("{}:", end_label), canonical.clone(), line_no));
-                        }
-                        FlowBlock::While {
-                            start_label,
-                            end_label,
-                        } => {
-                            asm_lines.push((
-                                format!("JMP {}", start_label),
-                                canonical.clone(),
-                                line_no,
-                            ));
-                            asm_lines.push((format!("{}:", end_label), canonical.clone(), line_no));
-                        }
-                        FlowBlock::For {
-                            start_label,
-                            end_label,
-                            step_stmt,
-                        } => {
-                            if let Some(step_stmt) = step_stmt {
-                                compile_c_stmt(
-                                    &format!("{};", step_stmt),
-                                    line_no,
-                                    path,
-                                    in_fn.as_deref().unwrap_or_default(),
-                                    &scoped_vars,
-                                    &consts,
-                                    &mut asm_lines,
-                                    &canonical,
-                                )?;
-                            }
-                            asm_lines.push((
-                                format!("JMP {}", start_label),
-                                canonical.clone(),
-                                line_no,
-                            ));
-                            asm_lines.push((format!("{}:", end_label), canonical.clone(), line_no));
+            StmtKind::Case(value) => {
+                if !matches!(self.flow.last(), Some(FlowBlock::Switch { .. })) {
+                    let what = if value.is_some() { "case" } else { "default" };
+                    return Err(self.error(stmt.pos, format!("'{}' outside of switch", what)));
+                }
+                let value = match value {
+                    Some(value) => Some((value.start(), self.names().constant(value)?)),
+                    None => None,
+                };
+                let label = self.next_label("CCASE");
+                let lines = self.lines;
+                let Some(FlowBlock::Switch {
+                    selector,
+                    cases,
+                    default_label,
+                    ..
+                }) = self.flow.last_mut()
+                else {
+                    unreachable!("switch missing from the flow stack");
+                };
+                match value {
+                    Some((pos, v)) => {
+                        let v = if cexpr_is_wide(selector) {
+                            v
+                        } else {
+                            c_const_byte(v).map(u16::from).map_err(|e| {
+                                c_parser::error(lines, pos, format!("invalid case value: {}", e))
+                            })?
+                        };
+                        if cases.iter().any(|(known, _)| *known == v) {
+                            return Err(c_parser::error(lines, pos, "duplicate case value"));
                         }
+                        cases.push((v, label.clone()));
                     }
-                    continue;
-                }
-
-                if let Some(end_label) = pending_else_end.take() {
-                    asm_lines.push((format!("{}:", end_label), canonical.clone(), line_no));
-                }
-
-                if let Some(name) = &in_fn {
-                    if name == "Init" || name == "Update" {
-                        asm_lines.push(("BRK".to_string(), canonical.clone(), line_no));
-                    } else {
-                        asm_lines.push(("RTS".to_string(), canonical.clone(), line_no));
+                    None if default_label.is_some() => {
+                        return Err(c_parser::error(
+                            lines,
+                            stmt.pos,
+                            "duplicate default in switch",
+                        ));
                     }
+                    None => *default_label = Some(label.clone()),
                 }
-                asm_lines.push(("".to_string(), canonical.clone(), line_no));
-                in_fn = None;
-                local_vars.clear();
-                continue;
+                self.push(stmt.pos, format!("{}:", label));
+            }
+            StmtKind::Break => {
+                let target = self.flow.last().map(|block| match block {
+                    FlowBlock::While { end_label, .. }
+                    | FlowBlock::For { end_label, .. }
+                    | FlowBlock::Switch { end_label, .. } => end_label.clone(),
+                });
+                let Some(target) = target else {
+                    return Err(self.error(stmt.pos, "'break' outside of loop or switch"));
+                };
+                self.push(stmt.pos, format!("JMP {}", target));
+            }
+            StmtKind::Continue => {
+                let labels = &mut *self.labels;
+                let target = self.flow.iter_mut().rev().find_map(|block| match block {
+                    FlowBlock::While { start_label, .. } => Some(start_label.clone()),
+                    FlowBlock::For { continue_label, .. } => Some(
+                        continue_label
+                            .get_or_insert_with(|| next_c_label(labels, "CFORC"))
+                            .clone(),
+                    ),
+                    FlowBlock::Switch { .. } => None,
+                });
+                let Some(target) = target else {
+                    return Err(self.error(stmt.pos, "'continue' outside of loop"));
+                };
+                self.push(stmt.pos, format!("JMP {}", target));
             }
-
-            let fn_name = in_fn.clone().unwrap_or_default();
-            compile_c_stmt(
-                line,
-                line_no,
-                path,
-                &fn_name,
-                &scoped_vars,
-                &consts,
-                &mut asm_lines,
-                &canonical,
-            )?;
         }
+        Ok(())
+    }
 
-        if in_fn.is_some() {
-            return Err(format!(
-                "C parse error: {}: unterminated function body",
-                path.display()
-            ));
+    /// Compile a local variable declaration.
+    fn local_decl(&mut self, decl: &Decl, declared: &mut Vec<String>) -> Result<(), String> {
+        if decl.constant {
+            return Err(self.error(decl.pos, "'const' is only supported at global scope"));
         }
-        if pending_else_end.is_some() {
-            return Err(format!(
-                "C parse error: {}: dangling else handling at end of function",
-                path.display()
-            ));
+        if decl.external {
+            return Err(self.error(decl.pos, "'extern' is only supported at global scope"));
         }
-        if !flow_stack.is_empty() {
-            return Err(format!(
-                "C parse error: {}: unterminated control-flow block",
-                path.display()
-            ));
+        for var in &decl.vars {
+            if let TypeSpec::Struct(name) = &var.ty.spec
+                && !var.ty.pointer
+            {
+                let msg = if self.structs.contains_key(name) {
+                    "structs must be declared at global scope".to_string()
+                } else {
+                    unknown_type_msg(name)
+                };
+                return Err(self.error(var.ty.pos, msg));
+            }
+            let ty = match c_type(&var.ty) {
+                Ok(Some(ty)) => ty,
+                Ok(None) => {
+                    let msg = format!("variable '{}' cannot be void", var.name);
+                    return Err(self.error(var.ty.pos, msg));
+                }
+                Err(e) => return Err(self.error(var.ty.pos, e)),
+            };
+            if var.array.is_some() {
+                return Err(self.error(var.pos, "arrays must be declared at global scope"));
+            }
+            if self.vars.contains_key(&var.name) {
+                return Err(self.error(var.pos, format!("duplicate local '{}'", var.name)));
+            }
+            let Some(cvar) = alloc_zp(self.next_zp, ty) else {
+                return Err(self.error(var.pos, "out of zero-page space for locals"));
+            };
+            self.vars.insert(var.name.clone(), CSym::Var(cvar));
+            declared.push(var.name.clone());
+            match &var.init {
+                Some(Init::Expr(init)) => {
+                    let ctx = self.emit(decl.pos);
+                    let value = ctx.names.value(init)?;
+                    emit_expr(&value, ty, ctx.line_no, ctx.out, ctx.source_file);
+                    emit_store_var(cvar, ctx.line_no, ctx.out, ctx.source_file);
+                }
+                Some(Init::List(pos, _)) => {
+                    let msg = "'{ ... }' initializers are only supported for arrays";
+                    return Err(self.error(*pos, msg));
+                }
+                None => {}
+            }
         }
+        Ok(())
     }
 
-    let mut bytes = Vec::new();
-    let mut line_map = Vec::new();
-    for (line, source_file, source_line) in asm_lines {
-        bytes.extend_from_slice(line.as_bytes());
-        bytes.push(b'\n');
-        line_map.push(LineOrigin {
-            file: source_file,
-            line: source_line,
-        });
+    /// What leaving the function runs: `Init` and `Update` end the frame with `BRK`.
+    fn exit(&self) -> &'static str {
+        if self.name == "Init" || self.name == "Update" {
+            "BRK"
+        } else {
+            "RTS"
+        }
     }
 
-    Ok(ExpandedAsm { bytes, line_map })
-}
-
-fn strip_c_comments(line: &str) -> &str {
-    match line.find("//") {
-        Some(i) => &line[..i],
-        None => line,
+    /// Compile `return`, which needs a value exactly when the function has a return type.
+    fn ret(&mut self, pos: Pos, value: Option<&Expr>) -> Result<(), String> {
+        let ret = self.fns.get(self.name).and_then(|func| func.ret);
+        match (value, ret) {
+            (None, Some(_)) => Err(self.error(pos, format!("'{}' must return a value", self.name))),
+            (Some(value), None) => {
+                let msg = format!("void function '{}' cannot return a value", self.name);
+                Err(self.error(value.start(), msg))
+            }
+            (None, None) => {
+                self.push(pos, self.exit().to_string());
+                Ok(())
+            }
+            (Some(value), Some(ty)) => {
+                let mut ctx = self.emit(pos);
+                let value = ctx.names.value(value)?;
+                emit_expr(&value, ty, ctx.line_no, ctx.out, ctx.source_file);
+                ctx.push("RTS".to_string());
+                Ok(())
+            }
+        }
     }
-}
 
-fn merge_expanded_asm(mut a: ExpandedAsm, b: ExpandedAsm) -> ExpandedAsm {
-    if !a.bytes.ends_with(b"\n") {
-        a.bytes.push(b'\n');
-        let origin = a.line_map.last().cloned().unwrap_or(LineOrigin {
-            file: PathBuf::from("asm"),
-            line: 1,
-        });
-        a.line_map.push(origin);
-    }
-    a.bytes.extend_from_slice(&b.bytes);
-    a.line_map.extend(b.line_map);
-    ExpandedAsm {
-        bytes: a.bytes,
-        line_map: a.line_map,
+    /// Jump to `false_label` unless `cond` holds.
+    fn condition(&mut self, cond: &Expr, false_label: &str) -> Result<(), String> {
+        let mut ctx = self.emit(cond.start());
+        let cond = ctx.names.cond(cond)?;
+        emit_cond_jump(&cond, false, false_label, &mut ctx);
+        Ok(())
     }
 }
 
-fn parse_char_decl(line: &str) -> Result<Option<(String, Option<String>)>, String> {
-    let mut s = line.trim();
-    if let Some(rest) = s.strip_prefix("unsigned char ") {
-        s = rest;
-    } else if let Some(rest) = s.strip_prefix("signed char ") {
-        s = rest;
-    } else {
-        return Ok(None);
+fn transpile_c_sources(
+    c_root: &Path,
+    paths: &[PathBuf],
+    sys_consts: &[SystemConst],
+    sprite_consts: &[(String, u32)],
+    defines: &[(String, u16)],
+    optimize: Option<CpuKind>,
+) -> Result<(Vec<BuildUnit>, Option<PeepholeReport>), String> {
+    let mut ordered = paths.to_vec();
+    ordered.sort_by(|a, b| {
+        let ra = a.strip_prefix(c_root).unwrap_or(a);
+        let rb = b.strip_prefix(c_root).unwrap_or(b);
+        let a_main = ra == Path::new("main.c");
+        let b_main = rb == Path::new("main.c");
+        b_main
+            .cmp(&a_main)
+            .then_with(|| ra.to_string_lossy().cmp(&rb.to_string_lossy()))
+    });
+
+    let mut consts: HashMap<String, u16> = HashMap::new();
+    for c in sys_consts {
+        consts.insert(c.name.to_string(), c.value as u16);
     }
-    if let Some(rest) = s.strip_suffix(';') {
-        s = rest.trim();
-    } else {
-        return Err("expected ';' after declaration".to_string());
+    for (name, value) in sprite_consts {
+        consts.insert(name.clone(), *value as u16);
     }
-    let (name, init) = if let Some(eq) = s.find('=') {
-        let name = s[..eq].trim();
-        let init = s[eq + 1..].trim();
-        if init.is_empty() {
-            return Err("expected initializer expression".to_string());
-        }
-        (name, Some(init.to_string()))
-    } else {
-        (s, None)
+    for (name, value) in defines {
+        consts.insert(name.clone(), *value);
+    }
+
+    let mut vars: HashMap<String, CSym> = HashMap::new();
+    let mut fns: HashMap<String, CFunc> = HashMap::new();
+    let mut structs: HashMap<String, CStruct> = HashMap::new();
+    // The sprite attribute layout `chipcade.h` declares.
+    let sprite_fields = ["x", "y", "tile", "flags", "c0", "c1", "c2", "reserved"];
+    let chip_sprite = CStruct {
+        fields: (0u8..)
+            .zip(sprite_fields)
+            .map(|(offset, name)| (name.to_string(), offset, CType::UCHAR))
+            .collect(),
+        size: sprite_fields.len() as u8,
     };
-    validate_ident(name)?;
-    Ok(Some((name.to_string(), init)))
-}
+    structs.insert("ChipSprite".to_string(), chip_sprite);
 
-fn parse_global_char_decl(line: &str) -> Result<Option<String>, String> {
-    match parse_char_decl(line)? {
-        Some((_name, Some(_))) => Err("global initializers are not supported yet".to_string()),
-        Some((name, None)) => Ok(Some(name)),
-        None => Ok(None),
-    }
-}
+    // Build defines act like `-D` options, so `#ifdef` sees them without `chipcade.h`.
+    let include_dirs = [c_root.to_path_buf(), c_root.join("include")];
+    let predefined: Vec<(String, String)> = defines
+        .iter()
+        .map(|(name, value)| (name.clone(), value.to_string()))
+        .collect();
+    let units = ordered
+        .iter()
+        .map(|path| {
+            let lines = preprocessor::preprocess(path, &include_dirs, &predefined)?;
+            let items = c_parser::parse_unit(&lines)?;
+            Ok((lines, items))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut next_zp: u8 = 0x40;
+    for (lines, items) in &units {
+        for item in items {
+            let decl = match item {
+                Item::Function { header, .. } | Item::Prototype(header) => {
+                    let body = matches!(item, Item::Function { .. });
+                    let c_err = |e: String| c_parser::error(lines, header.pos, e);
+                    let header = c_fn_header(lines, header, body)?;
+                    if (header.name == "Init" || header.name == "Update")
+                        && (!header.func.params.is_empty() || header.func.ret.is_some())
+                    {
+                        return Err(c_err(format!(
+                            "'{}' must be declared as 'void {}()'",
+                            header.name, header.name
+                        )));
+                    }
+                    if let Some(known) = fns.get(&header.name)
+                        && *known != header.func
+                    {
+                        return Err(c_err(format!(
+                            "conflicting declarations of '{}'",
+                            header.name
+                        )));
+                    }
+                    fns.insert(header.name, header.func);
+                    continue;
+                }
+                Item::Struct(def) => {
+                    let c_struct = c_struct(lines, def)?;
+                    for name in &def.names {
+                        // A header included by several files defines its structs again.
+                        if structs.get(name).is_some_and(|known| *known != c_struct) {
+                            return Err(c_parser::error(
+                                lines,
+                                def.pos,
+                                format!("conflicting definitions of struct '{}'", name),
+                            ));
+                        }
+                        structs.insert(name.clone(), c_struct.clone());
+                    }
+                    continue;
+                }
+                Item::Decl(decl) if decl.external => continue,
+                Item::Decl(decl) => decl,
+            };
+            for var in &decl.vars {
+                let c_err = |pos: Pos, e: String| c_parser::error(lines, pos, e);
+                let names = CNames {
+                    lines,
+                    vars: &vars,
+                    consts: &consts,
+                    fns: &fns,
+                };
+                if let TypeSpec::Struct(type_name) = &var.ty.spec
+                    && !var.ty.pointer
+                {
+                    let Some(def) = structs.get(type_name) else {
+                        return Err(c_err(var.ty.pos, unknown_type_msg(type_name)));
+                    };
+                    if decl.constant {
+                        return Err(c_err(
+                            decl.pos,
+                            "const structs are not supported".to_string(),
+                        ));
+                    }
+                    if var.init.is_some() {
+                        let msg = "struct variables cannot have initializers".to_string();
+                        return Err(c_err(var.pos, msg));
+                    }
+                    if vars.contains_key(&var.name) {
+                        return Err(c_err(var.pos, format!("duplicate global '{}'", var.name)));
+                    }
+                    let len = match &var.array {
+                        Some(Some(len)) => {
+                            let n = names.constant(len)?;
+                            if n == 0 || n.checked_mul(def.size as u16).is_none() {
+                                return Err(c_err(
+                                    len.start(),
+                                    format!("invalid array length {}", n),
+                                ));
+                            }
+                            Some(n)
+                        }
+                        Some(None) => {
+                            let msg = format!("array '{}' needs a length", var.name);
+                            return Err(c_err(var.pos, msg));
+                        }
+                        None => None,
+                    };
+                    let struct_var = CStructVar {
+                        len,
+                        size: def.size,
+                    };
+                    for (field, offset, ty) in &def.fields {
+                        let field_sym = CField {
+                            offset: *offset,
+                            ty: *ty,
+                        };
+                        vars.insert(format!("{}.{}", var.name, field), CSym::Field(field_sym));
+                    }
+                    vars.insert(var.name.clone(), CSym::Struct(struct_var));
+                    continue;
+                }
+                let ty = match c_type(&var.ty) {
+                    Ok(Some(ty)) => ty,
+                    Ok(None) => {
+                        let msg = format!("variable '{}' cannot be void", var.name);
+                        return Err(c_err(var.ty.pos, msg));
+                    }
+                    Err(e) => return Err(c_err(var.ty.pos, e)),
+                };
+                if vars.contains_key(&var.name) || (decl.constant && consts.contains_key(&var.name))
+                {
+                    return Err(c_err(var.pos, format!("duplicate global '{}'", var.name)));
+                }
+                if decl.constant && var.init.is_none() {
+                    let msg = format!("const '{}' needs an initializer", var.name);
+                    return Err(c_err(var.pos, msg));
+                }
+                if let Some(len) = &var.array {
+                    if ty.wide {
+                        let msg = "only arrays of 'unsigned char' or 'signed char' are supported";
+                        return Err(c_err(var.ty.pos, msg.to_string()));
+                    }
+                    let items = match &var.init {
+                        Some(Init::List(_, items)) => names.init_bytes(items)?.len(),
+                        Some(Init::Expr(init)) => {
+                            let msg = "an array initializer must be a '{ ... }' list";
+                            return Err(c_err(init.start(), msg.to_string()));
+                        }
+                        None => 0,
+                    };
+                    let (len_pos, len) = match len {
+                        Some(len) => (len.start(), names.constant(len)?),
+                        None if var.init.is_none() => {
+                            let msg =
+                                format!("array '{}' needs a length or an initializer", var.name);
+                            return Err(c_err(var.pos, msg));
+                        }
+                        None => (var.pos, items as u16),
+                    };
+                    if len == 0 {
+                        return Err(c_err(len_pos, "invalid array length 0".to_string()));
+                    }
+                    if items > len as usize {
+                        return Err(c_err(
+                            var.pos,
+                            format!("too many initializers for '{}[{}]'", var.name, len),
+                        ));
+                    }
+                    let array = CArray {
+                        len,
+                        signed: ty.signed,
+                        constant: decl.constant,
+                    };
+                    vars.insert(var.name.clone(), CSym::Array(array));
+                    continue;
+                }
+                match &var.init {
+                    Some(Init::Expr(init)) => {
+                        let value = names.global_init(init)?;
+                        match value {
+                            CInit::Value(v) if !ty.wide => {
+                                c_const_byte(v).map_err(|e| c_err(init.start(), e))?;
+                            }
+                            CInit::Label(_) if !ty.wide => {
+                                let msg = "an address needs a pointer or int variable";
+                                return Err(c_err(init.start(), msg.to_string()));
+                            }
+                            _ => {}
+                        }
+                        if decl.constant {
+                            let CInit::Value(v) = value else {
+                                let msg = format!("const '{}' must be a number", var.name);
+                                return Err(c_err(init.start(), msg));
+                            };
+                            consts.insert(var.name.clone(), v);
+                            continue;
+                        }
+                    }
+                    Some(Init::List(pos, _)) => {
+                        let msg = "'{ ... }' initializers are only supported for arrays";
+                        return Err(c_err(*pos, msg.to_string()));
+                    }
+                    None => {}
+                }
+                let Some(cvar) = alloc_zp(&mut next_zp, ty) else {
+                    return Err(c_err(
+                        var.pos,
+                        "out of zero-page space for globals".to_string(),
+                    ));
+                };
+                vars.insert(var.name.clone(), CSym::Var(cvar));
+            }
+        }
+    }
+
+    // Each file becomes its own unit, so editing one file leaves the objects of the others
+    // alone unless its globals move theirs.
+    let mut build_units = Vec::new();
+    let mut calls = Vec::new();
+    let mut report = optimize.map(|_| PeepholeReport::default());
+    let mut defined_fns: HashSet<String> = HashSet::new();
+    for ((lines, items), path) in units.iter().zip(&ordered) {
+        let mut asm_lines: Vec<(String, PathBuf, usize)> = Vec::new();
+        let mut labels = Vec::new();
+        let mut inline_asm: HashSet<usize> = HashSet::new();
+        for item in items {
+            let decl = match item {
+                Item::Function { header, body } => {
+                    let pos = header.pos;
+                    let header = c_fn_header(lines, header, true)?;
+                    if !defined_fns.insert(header.name.clone()) {
+                        let msg = format!("duplicate function '{}'", header.name);
+                        return Err(c_parser::error(lines, pos, msg));
+                    }
+                    let mut ctx = CFnCtx {
+                        lines,
+                        name: &header.name,
+                        vars: vars.clone(),
+                        consts: &consts,
+                        fns: &fns,
+                        structs: &structs,
+                        next_zp: &mut next_zp,
+                        labels: &mut labels,
+                        out: &mut asm_lines,
+                        inline_asm: &mut inline_asm,
+                        flow: Vec::new(),
+                    };
+                    ctx.function(&header, pos, body)?;
+                    continue;
+                }
+                Item::Decl(decl) if !decl.external => decl,
+                _ => continue,
+            };
+            let names = CNames {
+                lines,
+                vars: &vars,
+                consts: &consts,
+                fns: &fns,
+            };
+            let source = &lines[decl.pos.line];
+            let (canonical, line_no) = (&source.canonical, source.line);
+            for var in &decl.vars {
+                let data = match (vars.get(&var.name), &var.init) {
+                    (Some(CSym::Struct(svar)), _) => vec![
+                        ".segment \"BSS\"".to_string(),
+                        format!("{}:", var.name),
+                        format!(".fill {}", svar.len.unwrap_or(1) * svar.size as u16),
+                    ],
+                    (Some(CSym::Array(array)), init) => {
+                        let segment = match init {
+                            _ if array.constant => "ROMDATA",
+                            Some(_) => "DATA",
+                            None => "BSS",
+                        };
+                        let mut data = vec![
+                            format!(".segment \"{}\"", segment),
+                            format!("{}:", var.name),
+                        ];
+                        match init {
+                            Some(Init::List(_, items)) => {
+                                let mut bytes = names.init_bytes(items)?;
+                                bytes.resize(array.len as usize, 0);
+                                for chunk in bytes.chunks(16) {
+                                    let items: Vec<String> =
+                                        chunk.iter().map(|b| format!("${:02X}", b)).collect();
+                                    data.push(format!(".byte {}", items.join(", ")));
+                                }
+                            }
+                            _ => data.push(format!(".fill {}", array.len)),
+                        }
+                        data
+                    }
+                    // Initialized globals are part of the image, so they hold their
+                    // values before `Init` runs.
+                    (Some(CSym::Var(cvar)), Some(Init::Expr(init))) => {
+                        let value = match names.global_init(init)? {
+                            CInit::Value(v) if cvar.ty.wide => format!("${:04X}", v),
+                            CInit::Value(v) => format!("${:02X}", v as u8),
+                            CInit::Label(label) => label,
+                        };
+                        vec![
+                            ".segment \"ZP\"".to_string(),
+                            format!(".org ${:04X}", cvar.addr),
+                            format!("{} {}", if cvar.ty.wide { ".word" } else { ".byte" }, value),
+                        ]
+                    }
+                    _ => Vec::new(),
+                };
+                if !data.is_empty() {
+                    for text in data.into_iter().chain([".segment \"CODE\"".to_string()]) {
+                        asm_lines.push((text, canonical.clone(), line_no));
+                    }
+                }
+            }
+        }
 
-fn parse_extern_decl(line: &str) -> Result<bool, String> {
-    let s = line.trim();
-    if !s.starts_with("extern ") {
-        return Ok(false);
+        // The runtime routines are hand-written, so only the transpiled code is optimized.
+        if let (Some(cpu), Some(total)) = (optimize, report.as_mut()) {
+            let report = peephole::optimize(&mut asm_lines, &inline_asm, cpu);
+            if let Some((_, file, line)) = asm_lines.first().cloned() {
+                let summary = format!(
+                    "; peephole: {} rewrite(s), {} bytes and {} cycles saved",
+                    report.rewrites, report.bytes, report.cycles
+                );
+                asm_lines.insert(0, (summary, file, line));
+            }
+            total.rewrites += report.rewrites;
+            total.bytes += report.bytes;
+            total.cycles += report.cycles;
+        }
+        calls.extend(
+            asm_lines
+                .iter()
+                .filter(|(line, ..)| line.starts_with("JSR "))
+                .cloned(),
+        );
+        build_units.push(BuildUnit {
+            name: relative_path(c_root, path),
+            expanded: ExpandedAsm::from_lines(asm_lines),
+            private: labels.into_iter().collect(),
+        });
     }
-    if !s.ends_with(';') {
-        return Err("extern declaration must end with ';'".to_string());
+
+    let runtime = c_runtime(&calls);
+    if !runtime.is_empty() {
+        build_units.push(BuildUnit {
+            name: PathBuf::from(C_RUNTIME_UNIT),
+            expanded: ExpandedAsm::from_lines(runtime),
+            private: HashSet::new(),
+        });
     }
-    Ok(true)
+    Ok((build_units, report))
 }
 
-fn parse_fn_proto(line: &str) -> Result<Option<String>, String> {
-    let s = line.trim();
-    let Some(rest) = s.strip_prefix("void ") else {
-        return Ok(None);
-    };
-    let Some(open) = rest.find('(') else {
-        return Ok(None);
-    };
-    let name = rest[..open].trim();
-    validate_ident(name)?;
-    let tail = rest[open..].trim();
-    if tail == "();" || tail == "()" {
-        return Ok(Some(name.to_string()));
+/// The runtime routines that the generated code calls, with their dependencies. Their lines map
+/// to the first call.
+fn c_runtime(asm_lines: &[(String, PathBuf, usize)]) -> Vec<(String, PathBuf, usize)> {
+    let mut used: Vec<(&CRuntimeRoutine, PathBuf, usize)> = Vec::new();
+    let mut work: Vec<(&str, PathBuf, usize)> = asm_lines
+        .iter()
+        .filter_map(|(line, file, line_no)| {
+            Some((line.strip_prefix("JSR ")?, file.clone(), *line_no))
+        })
+        .collect();
+    work.reverse();
+    while let Some((name, file, line_no)) = work.pop() {
+        let Some(routine) = C_RUNTIME.iter().find(|r| r.name == name) else {
+            continue;
+        };
+        if used.iter().any(|(r, ..)| r.name == name) {
+            continue;
+        }
+        for dep in routine.deps {
+            work.push((dep, file.clone(), line_no));
+        }
+        used.push((routine, file, line_no));
     }
-    Ok(None)
+    let mut runtime = Vec::new();
+    for (routine, file, line_no) in used {
+        for line in routine
+            .code
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+        {
+            runtime.push((line.to_string(), file.clone(), line_no));
+        }
+    }
+    runtime
 }
 
-fn parse_fn_start(line: &str) -> Result<Option<String>, String> {
-    let s = line.trim();
-    let Some(rest) = s.strip_prefix("void ") else {
-        return Ok(None);
-    };
-    let Some(open) = rest.find('(') else {
-        return Err("expected function parameters".to_string());
+/// An inline assembler line with the C names in its operand replaced: variables and
+/// parameters by their zero-page address, struct fields by `label+offset` and constants by
+/// their value. Labels, mnemonics, local labels and the `X`/`Y`/`A` register operands are
+/// left alone.
+fn resolve_asm_names(
+    line: &str,
+    vars: &HashMap<String, CSym>,
+    consts: &HashMap<String, u16>,
+) -> String {
+    let ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut rest = line;
+    let mut out = String::new();
+    if let Some((label, after)) = rest.split_once(':')
+        && !label.is_empty()
+        && label.chars().all(|c| ident_char(c) || c == '.' || c == '@')
+    {
+        out.push_str(label);
+        out.push(':');
+        rest = after;
+    }
+    let lead = rest.len() - rest.trim_start().len();
+    let word_end = rest[lead..]
+        .find(char::is_whitespace)
+        .map_or(rest.len(), |i| lead + i);
+    let mnemonic = &rest[lead..word_end];
+    out.push_str(&rest[..word_end]);
+    rest = &rest[word_end..];
+    let shift = ["ASL", "LSR", "ROL", "ROR"]
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(mnemonic));
+    if shift && rest.trim().eq_ignore_ascii_case("a") {
+        out.push_str(rest);
+        return out;
+    }
+
+    let mut prev: Option<char> = None;
+    let mut chars = rest.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c == '"' || c == '\'' {
+            let mut end = rest.len();
+            for (i, ch) in chars.by_ref() {
+                if ch == c {
+                    end = i + 1;
+                    break;
+                }
+            }
+            out.push_str(&rest[start..end]);
+            prev = Some(c);
+            continue;
+        }
+        if !ident_char(c) {
+            out.push(c);
+            if !c.is_whitespace() {
+                prev = Some(c);
+            }
+            continue;
+        }
+        // A name may continue with `.field`.
+        let mut end = start + 1;
+        while let Some(&(i, ch)) = chars.peek() {
+            let field = ch == '.' && rest[i + 1..].starts_with(|c: char| c.is_ascii_alphabetic());
+            if !ident_char(ch) && !field {
+                break;
+            }
+            end = i + 1;
+            chars.next();
+        }
+        let name = &rest[start..end];
+        let register =
+            prev == Some(',') && (name.eq_ignore_ascii_case("x") || name.eq_ignore_ascii_case("y"));
+        let resolved = match prev {
+            _ if c.is_ascii_digit() || register => None,
+            // Hex digits, binary digits and local labels.
+            Some('$' | '%' | '.' | '@') => None,
+            _ => match vars.get(name) {
+                Some(CSym::Var(var)) => Some(format!("${:02X}", var.addr)),
+                Some(CSym::Field(field)) => name
+                    .split_once('.')
+                    .map(|(label, _)| label_plus(label, field.offset as u16)),
+                _ => consts.get(name).map(|v| {
+                    if *v > 0xFF {
+                        format!("${:04X}", v)
+                    } else {
+                        format!("${:02X}", v)
+                    }
+                }),
+            },
+        };
+        out.push_str(resolved.as_deref().unwrap_or(name));
+        prev = name.chars().last();
+    }
+    out
+}
+
+/// The char, int or char pointer type `ty` names, or `None` for `void`. Other types give the
+/// reason they are not supported.
+fn c_type(ty: &Type) -> Result<Option<CType>, String> {
+    let (wide, signed) = match &ty.spec {
+        TypeSpec::Void if !ty.pointer => return Ok(None),
+        TypeSpec::Char { signed } => (ty.pointer, *signed),
+        TypeSpec::Int { signed } if !ty.pointer => (true, *signed),
+        TypeSpec::Struct(name) if !ty.pointer => {
+            return Err(format!(
+                "'{}' is a struct; expected a char or int type",
+                name
+            ));
+        }
+        _ => return Err("only pointers to chars are supported".to_string()),
     };
-    let name = rest[..open].trim();
-    validate_ident(name)?;
-    let tail = rest[open..].trim();
-    if tail != "() {" && tail != "(){" {
-        return Err("only zero-arg 'void Name() {' functions are supported".to_string());
+    Ok(Some(CType {
+        wide,
+        signed,
+        pointer: ty.pointer,
+    }))
+}
+
+/// The error for a struct type `name` that was never defined.
+fn unknown_type_msg(name: &str) -> String {
+    if name.starts_with("struct ") {
+        format!("unknown struct '{}'", name)
+    } else {
+        format!("unknown type '{}'", name)
     }
-    Ok(Some(name.to_string()))
 }
 
-fn parse_if_start(line: &str) -> Result<Option<String>, String> {
-    parse_control_start(line, "if")
+/// The signature of a function definition (`body`) or prototype. Definitions must name
+/// their parameters.
+fn c_fn_header(lines: &[SourceLine], header: &FnHeader, body: bool) -> Result<CFnHeader, String> {
+    let ret = c_type(&header.ret).map_err(|e| c_parser::error(lines, header.ret.pos, e))?;
+    let mut params = Vec::new();
+    let mut param_names = Vec::new();
+    for (i, param) in header.params.iter().enumerate() {
+        let Ok(Some(ty)) = c_type(&param.ty) else {
+            let msg = format!(
+                "parameter {} of '{}' must be a char or int type",
+                i + 1,
+                header.name
+            );
+            return Err(c_parser::error(lines, param.ty.pos, msg));
+        };
+        if body && param.name.is_none() {
+            let msg = format!("parameter {} needs a name", i + 1);
+            return Err(c_parser::error(lines, param.ty.pos, msg));
+        }
+        params.push(ty);
+        param_names.push(param.name.clone());
+    }
+    let bytes: usize = params.iter().map(|ty| ty.size() as usize).sum();
+    if bytes > C_ARG_REGS.len() {
+        let msg = format!(
+            "'{}' takes {} bytes of parameters; at most {} are supported (passed in A, X and Y)",
+            header.name,
+            bytes,
+            C_ARG_REGS.len()
+        );
+        return Err(c_parser::error(lines, header.pos, msg));
+    }
+    Ok(CFnHeader {
+        name: header.name.clone(),
+        func: CFunc { params, ret },
+        param_names,
+    })
 }
 
-fn parse_while_start(line: &str) -> Result<Option<String>, String> {
-    parse_control_start(line, "while")
+/// The layout of a struct definition: its fields in order, without padding.
+fn c_struct(lines: &[SourceLine], def: &StructDef) -> Result<CStruct, String> {
+    let err = |pos: Pos, msg: String| c_parser::error(lines, pos, msg);
+    let mut fields: Vec<(String, u8, CType)> = Vec::new();
+    let mut size = 0u8;
+    for field in &def.fields {
+        if field.array.is_some() {
+            return Err(err(
+                field.pos,
+                format!("array field '{}' is not supported", field.name),
+            ));
+        }
+        if field.init.is_some() {
+            let msg = format!("field '{}' cannot have an initializer", field.name);
+            return Err(err(field.pos, msg));
+        }
+        let ty = match c_type(&field.ty) {
+            Ok(Some(ty)) => ty,
+            Ok(None) => {
+                return Err(err(
+                    field.ty.pos,
+                    format!("field '{}' cannot be void", field.name),
+                ));
+            }
+            Err(e) => return Err(err(field.ty.pos, e)),
+        };
+        if fields.iter().any(|(known, ..)| *known == field.name) {
+            return Err(err(field.pos, format!("duplicate field '{}'", field.name)));
+        }
+        fields.push((field.name.clone(), size, ty));
+        size = size
+            .checked_add(ty.size())
+            .ok_or_else(|| err(field.pos, "structs are limited to 255 bytes".to_string()))?;
+    }
+    if fields.is_empty() {
+        return Err(err(
+            def.pos,
+            "a struct needs at least one field".to_string(),
+        ));
+    }
+    Ok(CStruct { fields, size })
 }
 
-fn parse_else_start(line: &str) -> bool {
-    let s = line.trim();
-    s == "else {" || s == "else{"
+/// `value` as a char, which may be signed (`-128` to `255`).
+fn c_const_byte(value: u16) -> Result<u8, String> {
+    match value {
+        0..=0xFF => Ok(value as u8),
+        0xFF80.. => Ok(value as u8),
+        _ => Err(format!("{} does not fit in a char", value as i16)),
+    }
 }
 
-fn parse_for_start(
-    line: &str,
-) -> Result<Option<(Option<String>, Option<String>, Option<String>)>, String> {
-    let s = line.trim();
-    let Some(rest) = s.strip_prefix("for") else {
-        return Ok(None);
-    };
-    let rest = rest.trim_start();
-    let Some(open) = rest.find('(') else {
-        return Err("expected for-loop parentheses".to_string());
-    };
-    let Some(close) = rest.rfind(')') else {
-        return Err("expected ')' in for loop".to_string());
-    };
-    let tail = rest[close + 1..].trim();
-    if tail != "{" {
-        return Err("for requires '{' on the same line in this C subset".to_string());
+/// The initial value of a global.
+enum CInit {
+    Value(u16),
+    /// A label address, such as an array.
+    Label(String),
+}
+
+/// Reserve zero page for a variable of type `ty`. `None` when it would reach `$FF`.
+fn alloc_zp(next_zp: &mut u8, ty: CType) -> Option<CVar> {
+    let addr = *next_zp;
+    *next_zp = addr.checked_add(ty.size())?;
+    Some(CVar { addr, ty })
+}
+
+/// Jump to the label of the case matching `selector`, or to `fallback`. Dense 8-bit cases
+/// go through a jump table; anything else compares the cases one by one.
+fn emit_switch_dispatch(
+    selector: &CExpr,
+    cases: &[(u16, String)],
+    fallback: &str,
+    ctx: &mut CEmit,
+) {
+    if cexpr_is_wide(selector) {
+        emit_cexpr_into_ax(selector, ctx.line_no, ctx.out, ctx.source_file);
+        for (value, label) in cases {
+            let next = ctx.next_label("CSWN");
+            ctx.push(format!("CMP #${:02X}", value & 0xFF));
+            ctx.push(format!("BNE {}", next));
+            ctx.push(format!("CPX #${:02X}", value >> 8));
+            ctx.push(format!("BNE {}", next));
+            ctx.push(format!("JMP {}", label));
+            ctx.push(format!("{}:", next));
+        }
+        ctx.push(format!("JMP {}", fallback));
+        return;
     }
-    if open >= close {
-        return Err("empty for(...) clause".to_string());
+
+    emit_cexpr_into_a(selector, ctx.line_no, ctx.out, ctx.source_file);
+    let min = cases.iter().map(|(v, _)| *v).min().unwrap_or(0);
+    let max = cases.iter().map(|(v, _)| *v).max().unwrap_or(0);
+    let span = max - min + 1;
+    // A table costs two bytes per value in range; a compare costs seven per case. The
+    // doubled table index must fit in a byte.
+    if cases.len() < 4 || span > 2 * cases.len() as u16 || span > 0x80 {
+        for (value, label) in cases {
+            let next = ctx.next_label("CSWN");
+            ctx.push(format!("CMP #${:02X}", value));
+            ctx.push(format!("BNE {}", next));
+            ctx.push(format!("JMP {}", label));
+            ctx.push(format!("{}:", next));
+        }
+        ctx.push(format!("JMP {}", fallback));
+        return;
     }
-    let body = rest[open + 1..close].trim();
-    let parts: Vec<&str> = body.split(';').map(|p| p.trim()).collect();
-    if parts.len() != 3 {
-        return Err("for requires init; condition; step".to_string());
+
+    let in_range = ctx.next_label("CSWN");
+    let table = ctx.next_label("CSWN");
+    if min != 0 {
+        ctx.push("SEC".to_string());
+        ctx.push(format!("SBC #${:02X}", min));
+    }
+    ctx.push(format!("CMP #${:02X}", span));
+    ctx.push(format!("BCC {}", in_range));
+    ctx.push(format!("JMP {}", fallback));
+    ctx.push(format!("{}:", in_range));
+    ctx.push("ASL A".to_string());
+    ctx.push("TAX".to_string());
+    ctx.push(format!("LDA {},X", table));
+    ctx.push(format!("STA ${:02X}", C_EXPR_TMP_PTR));
+    ctx.push(format!("LDA {}+1,X", table));
+    ctx.push(format!("STA ${:02X}", C_EXPR_TMP_PTR + 1));
+    ctx.push(format!("JMP (${:02X})", C_EXPR_TMP_PTR));
+    ctx.push(format!("{}:", table));
+    for value in min..=max {
+        let label = cases
+            .iter()
+            .find(|(v, _)| *v == value)
+            .map_or(fallback, |(_, label)| label.as_str());
+        ctx.push(format!(".word {}", label));
     }
-    let init = if parts[0].is_empty() {
-        None
-    } else {
-        Some(parts[0].to_string())
-    };
-    let cond = if parts[1].is_empty() {
-        None
-    } else {
-        Some(parts[1].to_string())
-    };
-    let step = if parts[2].is_empty() {
-        None
-    } else {
-        Some(parts[2].to_string())
-    };
-    Ok(Some((init, cond, step)))
 }
 
-fn parse_control_start(line: &str, keyword: &str) -> Result<Option<String>, String> {
-    let s = line.trim();
-    let Some(rest) = s.strip_prefix(keyword) else {
-        return Ok(None);
-    };
-    let rest = rest.trim_start();
-    let Some(open) = rest.find('(') else {
-        return Err(format!("expected condition for {}", keyword));
-    };
-    let Some(close) = rest.rfind(')') else {
-        return Err(format!("expected ')' in {} condition", keyword));
-    };
-    let tail = rest[close + 1..].trim();
-    if tail != "{" {
-        return Err(format!(
-            "{} requires '{{' on the same line in this C subset",
-            keyword
-        ));
+/// What lowering a statement or condition needs: the names in scope, where the code goes
+/// and the source line it maps to.
+struct CEmit<'a> {
+    line_no: usize,
+    names: CNames<'a>,
+    out: &'a mut Vec<(String, PathBuf, usize)>,
+    source_file: &'a Path,
+    labels: &'a mut Vec<String>,
+}
+
+impl CEmit<'_> {
+    fn next_label(&mut self, prefix: &str) -> String {
+        next_c_label(self.labels, prefix)
     }
-    if open >= close {
-        return Err(format!("empty condition in {}", keyword));
+
+    fn push(&mut self, line: String) {
+        self.out
+            .push((line, self.source_file.to_path_buf(), self.line_no));
     }
-    Ok(Some(rest[open + 1..close].trim().to_string()))
 }
 
-fn emit_condition_false_jump(
-    cond_src: &str,
+/// A new label for generated control flow, numbered within the file so that editing one file
+/// leaves the labels of the others alone.
+fn next_c_label(labels: &mut Vec<String>, prefix: &str) -> String {
+    let label = format!("{}{}", prefix, labels.len());
+    labels.push(label.clone());
+    label
+}
+
+/// Jump to `label` when `cond` evaluates to `when`, and fall through otherwise.
+fn emit_cond_jump(cond: &CCond, when: bool, label: &str, ctx: &mut CEmit) {
+    match cond {
+        CCond::Cmp(left, op, right) => {
+            // Jumping when the comparison holds is jumping when its negation fails.
+            let op = if when { op.negate() } else { *op };
+            emit_compare_false_jump(left, op, right, label, ctx);
+        }
+        CCond::Truth(expr) => {
+            if cexpr_is_wide(expr) {
+                emit_cexpr_into_ax(expr, ctx.line_no, ctx.out, ctx.source_file);
+                ctx.push(format!("STX ${:02X}", C_EXPR_TMP_CMP));
+                ctx.push(format!("ORA ${:02X}", C_EXPR_TMP_CMP));
+            } else {
+                emit_cexpr_into_a(expr, ctx.line_no, ctx.out, ctx.source_file);
+                ctx.push("CMP #$00".to_string());
+            }
+            let branch = if when { "BNE" } else { "BEQ" };
+            ctx.push(format!("{} {}", branch, label));
+        }
+        CCond::Not(inner) => emit_cond_jump(inner, !when, label, ctx),
+        // `a && b` is false as soon as `a` is, and `a || b` true as soon as `a` is; either
+        // way `a` decides when it matches `when`, and `b` decides otherwise.
+        CCond::And(a, b) | CCond::Or(a, b) => {
+            let short = matches!(cond, CCond::Or(..));
+            if short == when {
+                emit_cond_jump(a, when, label, ctx);
+                emit_cond_jump(b, when, label, ctx);
+            } else {
+                let skip = ctx.next_label("CCOND");
+                emit_cond_jump(a, short, &skip, ctx);
+                emit_cond_jump(b, when, label, ctx);
+                ctx.push(format!("{}:", skip));
+            }
+        }
+    }
+}
+
+/// Jump to `false_label` unless `left op right`.
+fn emit_compare_false_jump(
+    left: &CExpr,
+    op: CmpOp,
+    right: &CExpr,
     false_label: &str,
-    line_no: usize,
-    path: &Path,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-    out: &mut Vec<(String, PathBuf, usize)>,
-    source_file: &Path,
-    label_counter: &mut usize,
-) -> Result<(), String> {
-    let (left, op, right) = parse_condition(cond_src).map_err(|e| {
-        format!(
-            "C parse error: {}:{}: invalid condition '{}': {}",
-            path.display(),
-            line_no,
-            cond_src,
-            e
-        )
-    })?;
-    emit_expr_into_a(&left, vars, consts, line_no, path, out, source_file)?;
-    out.push((
-        format!("STA ${:02X}", C_EXPR_TMP_CMP),
-        source_file.to_path_buf(),
-        line_no,
-    ));
-    emit_expr_into_a(&right, vars, consts, line_no, path, out, source_file)?;
-    out.push((
-        format!("STA ${:02X}", C_EXPR_TMP_RHS),
-        source_file.to_path_buf(),
-        line_no,
-    ));
-    out.push((
-        format!("LDA ${:02X}", C_EXPR_TMP_CMP),
-        source_file.to_path_buf(),
-        line_no,
-    ));
-    out.push((
-        format!("CMP ${:02X}", C_EXPR_TMP_RHS),
-        source_file.to_path_buf(),
-        line_no,
-    ));
+    ctx: &mut CEmit,
+) {
+    // A signed char promotes to `int`, so it is compared in 16 bits against any other value.
+    let signed = cexpr_is_signed(&[left, right]);
+    if signed || cexpr_is_wide(left) || cexpr_is_wide(right) {
+        emit_wide_condition_false_jump(left, op, right, signed, false_label, ctx);
+        return;
+    }
+    emit_cexpr_into_a(left, ctx.line_no, ctx.out, ctx.source_file);
+    // A call on the right may use the temporaries, so the left side waits on the stack.
+    let spill = cexpr_has_call(right);
+    if spill {
+        ctx.push("PHA".to_string());
+    } else {
+        ctx.push(format!("STA ${:02X}", C_EXPR_TMP_CMP));
+    }
+    emit_cexpr_into_a(right, ctx.line_no, ctx.out, ctx.source_file);
+    ctx.push(format!("STA ${:02X}", C_EXPR_TMP_RHS));
+    if spill {
+        ctx.push("PLA".to_string());
+    } else {
+        ctx.push(format!("LDA ${:02X}", C_EXPR_TMP_CMP));
+    }
+    ctx.push(format!("CMP ${:02X}", C_EXPR_TMP_RHS));
 
     match op {
-        CmpOp::Eq => out.push((
-            format!("BNE {}", false_label),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        CmpOp::Ne => out.push((
-            format!("BEQ {}", false_label),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        CmpOp::Lt => out.push((
-            format!("BCS {}", false_label),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        CmpOp::Ge => out.push((
-            format!("BCC {}", false_label),
-            source_file.to_path_buf(),
-            line_no,
-        )),
+        CmpOp::Eq => ctx.push(format!("BNE {}", false_label)),
+        CmpOp::Ne => ctx.push(format!("BEQ {}", false_label)),
+        CmpOp::Lt => ctx.push(format!("BCS {}", false_label)),
+        CmpOp::Ge => ctx.push(format!("BCC {}", false_label)),
         CmpOp::Gt => {
-            out.push((
-                format!("BCC {}", false_label),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            out.push((
-                format!("BEQ {}", false_label),
-                source_file.to_path_buf(),
-                line_no,
-            ));
+            ctx.push(format!("BCC {}", false_label));
+            ctx.push(format!("BEQ {}", false_label));
         }
         CmpOp::Le => {
-            let ok_label = format!("CCMPOK{}", *label_counter);
-            *label_counter = label_counter.saturating_add(1);
-            out.push((
-                format!("BCC {}", ok_label),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            out.push((
-                format!("BEQ {}", ok_label),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            out.push((
-                format!("JMP {}", false_label),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            out.push((format!("{}:", ok_label), source_file.to_path_buf(), line_no));
+            let ok_label = ctx.next_label("CCMPOK");
+            ctx.push(format!("BCC {}", ok_label));
+            ctx.push(format!("BEQ {}", ok_label));
+            ctx.push(format!("JMP {}", false_label));
+            ctx.push(format!("{}:", ok_label));
         }
     }
-    Ok(())
 }
 
-fn parse_condition(src: &str) -> Result<(String, CmpOp, String), String> {
-    let ops: [(&str, CmpOp); 6] = [
-        ("==", CmpOp::Eq),
-        ("!=", CmpOp::Ne),
-        (">=", CmpOp::Ge),
-        ("<=", CmpOp::Le),
-        (">", CmpOp::Gt),
-        ("<", CmpOp::Lt),
-    ];
-    for (text, op) in ops {
-        if let Some(idx) = src.find(text) {
-            let left = src[..idx].trim();
-            let right = src[idx + text.len()..].trim();
-            if left.is_empty() || right.is_empty() {
-                return Err("missing left or right side".to_string());
+/// 16-bit comparison for `emit_compare_false_jump`. Ordering subtracts the operands and
+/// reads the carry, or N xor V when `signed`.
+fn emit_wide_condition_false_jump(
+    left: &CExpr,
+    op: CmpOp,
+    right: &CExpr,
+    signed: bool,
+    false_label: &str,
+    ctx: &mut CEmit,
+) {
+    emit_cexpr_into_ax(left, ctx.line_no, ctx.out, ctx.source_file);
+    let spill = cexpr_has_call(right);
+    if spill {
+        ctx.push("PHA".to_string());
+        ctx.push("TXA".to_string());
+        ctx.push("PHA".to_string());
+    } else {
+        ctx.push(format!("STA ${:02X}", C_EXPR_TMP_CMP));
+        ctx.push(format!("STX ${:02X}", C_EXPR_TMP_CMP_HI));
+    }
+    emit_cexpr_into_ax(right, ctx.line_no, ctx.out, ctx.source_file);
+    ctx.push(format!("STA ${:02X}", C_EXPR_TMP_RHS));
+    ctx.push(format!("STX ${:02X}", C_EXPR_TMP_RHS_HI));
+    if spill {
+        ctx.push("PLA".to_string());
+        ctx.push(format!("STA ${:02X}", C_EXPR_TMP_CMP_HI));
+        ctx.push("PLA".to_string());
+        ctx.push(format!("STA ${:02X}", C_EXPR_TMP_CMP));
+    }
+
+    let (lhs, rhs) = (C_EXPR_TMP_CMP, C_EXPR_TMP_RHS);
+    let (lhs_hi, rhs_hi) = (C_EXPR_TMP_CMP_HI, C_EXPR_TMP_RHS_HI);
+    match op {
+        CmpOp::Eq => {
+            ctx.push(format!("LDA ${:02X}", lhs));
+            ctx.push(format!("CMP ${:02X}", rhs));
+            ctx.push(format!("BNE {}", false_label));
+            ctx.push(format!("LDA ${:02X}", lhs_hi));
+            ctx.push(format!("CMP ${:02X}", rhs_hi));
+            ctx.push(format!("BNE {}", false_label));
+        }
+        CmpOp::Ne => {
+            let ok_label = ctx.next_label("CCMPOK");
+            ctx.push(format!("LDA ${:02X}", lhs));
+            ctx.push(format!("CMP ${:02X}", rhs));
+            ctx.push(format!("BNE {}", ok_label));
+            ctx.push(format!("LDA ${:02X}", lhs_hi));
+            ctx.push(format!("CMP ${:02X}", rhs_hi));
+            ctx.push(format!("BEQ {}", false_label));
+            ctx.push(format!("{}:", ok_label));
+        }
+        CmpOp::Lt | CmpOp::Ge | CmpOp::Gt | CmpOp::Le => {
+            // a > b is b < a, and a <= b is !(b < a).
+            let swapped = matches!(op, CmpOp::Gt | CmpOp::Le);
+            let (a, a_hi, b, b_hi) = if swapped {
+                (rhs, rhs_hi, lhs, lhs_hi)
+            } else {
+                (lhs, lhs_hi, rhs, rhs_hi)
+            };
+            ctx.push(format!("LDA ${:02X}", a));
+            ctx.push(format!("CMP ${:02X}", b));
+            ctx.push(format!("LDA ${:02X}", a_hi));
+            ctx.push(format!("SBC ${:02X}", b_hi));
+            // Lt and Gt hold when a < b, Ge and Le when it does not.
+            let less = matches!(op, CmpOp::Lt | CmpOp::Gt);
+            if signed {
+                let ok_label = ctx.next_label("CCMPOK");
+                ctx.push(format!("BVC {}", ok_label));
+                ctx.push("EOR #$80".to_string());
+                ctx.push(format!("{}:", ok_label));
+                let branch = if less { "BPL" } else { "BMI" };
+                ctx.push(format!("{} {}", branch, false_label));
+            } else {
+                let branch = if less { "BCS" } else { "BCC" };
+                ctx.push(format!("{} {}", branch, false_label));
             }
-            return Ok((left.to_string(), op, right.to_string()));
         }
     }
-    Err("expected comparison operator".to_string())
 }
 
-fn compile_c_stmt(
-    stmt: &str,
-    line_no: usize,
-    path: &Path,
-    fn_name: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-    out: &mut Vec<(String, PathBuf, usize)>,
-    source_file: &Path,
-) -> Result<(), String> {
-    let s = stmt.trim();
-    if s == "return;" {
-        if fn_name == "Init" || fn_name == "Update" {
-            out.push(("BRK".to_string(), source_file.to_path_buf(), line_no));
-        } else {
-            out.push(("RTS".to_string(), source_file.to_path_buf(), line_no));
+/// Compile an expression statement: an assignment, a call, or `++` or `--`.
+fn compile_c_expr_stmt(expr: &Expr, ctx: &mut CEmit) -> Result<(), String> {
+    let names = ctx.names;
+    match &expr.kind {
+        ExprKind::Call(name, args) => {
+            let call = names.call(expr.pos, name, args)?;
+            emit_call(&call, ctx.line_no, ctx.out, ctx.source_file);
+        }
+        ExprKind::PostInc(target) | ExprKind::PostDec(target) => {
+            let inc = matches!(expr.kind, ExprKind::PostInc(_));
+            if let Some(addr) = names.mem(target)? {
+                emit_step_addr(&addr, inc, ctx.line_no, ctx.out, ctx.source_file);
+                return Ok(());
+            }
+            let var = names.scalar(target)?;
+            if inc {
+                ctx.push(format!("INC ${:02X}", var.addr));
+                if var.ty.wide {
+                    let done_label = format!("CINC{}_{}", ctx.line_no, ctx.out.len());
+                    ctx.push(format!("BNE {}", done_label));
+                    ctx.push(format!("INC ${:02X}", var.addr + 1));
+                    ctx.push(format!("{}:", done_label));
+                }
+            } else {
+                if var.ty.wide {
+                    let done_label = format!("CDEC{}_{}", ctx.line_no, ctx.out.len());
+                    ctx.push(format!("LDA ${:02X}", var.addr));
+                    ctx.push(format!("BNE {}", done_label));
+                    ctx.push(format!("DEC ${:02X}", var.addr + 1));
+                    ctx.push(format!("{}:", done_label));
+                }
+                ctx.push(format!("DEC ${:02X}", var.addr));
+            }
+        }
+        ExprKind::Assign(target, value) => {
+            if let ExprKind::Index(array, _) = &target.kind
+                && let ExprKind::Ident(name) = &array.kind
+                && let Some(CSym::Array(CArray { constant: true, .. })) = names.vars.get(name)
+            {
+                let msg = format!("cannot assign to const array '{}'", name);
+                return Err(names.error(array.pos, msg));
+            }
+            if let Some(addr) = names.mem(target)? {
+                let value = names.value(value)?;
+                emit_expr(&value, addr.ty(), ctx.line_no, ctx.out, ctx.source_file);
+                emit_store_addr(&addr, ctx.line_no, ctx.out, ctx.source_file);
+            } else {
+                let var = names.scalar(target)?;
+                let value = names.value(value)?;
+                emit_expr(&value, var.ty, ctx.line_no, ctx.out, ctx.source_file);
+                emit_store_var(var, ctx.line_no, ctx.out, ctx.source_file);
+            }
+        }
+        _ => {
+            let msg = "expected an assignment, a call, '++' or '--'";
+            return Err(names.error(expr.start(), msg));
         }
-        return Ok(());
     }
+    Ok(())
+}
 
-    if let Some(call) = s.strip_suffix(");") {
-        if let Some(name) = call.strip_suffix('(') {
-            let fn_ident = name.trim();
-            validate_ident(fn_ident).map_err(|e| {
-                format!(
-                    "C parse error: {}:{}: invalid call target: {}",
-                    path.display(),
-                    line_no,
-                    e
-                )
-            })?;
-            out.push((
-                format!("JSR {}", fn_ident),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            return Ok(());
+/// Add or subtract one from the value at `addr`.
+fn emit_step_addr(
+    addr: &AddrExpr,
+    inc: bool,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    emit_load_addr(addr, line_no, out, source_file);
+    let done_label = format!("CSTEP{}_{}", line_no, out.len());
+    let ops = if inc {
+        ["CLC", "ADC #$01", "BCC", "INX"]
+    } else {
+        ["SEC", "SBC #$01", "BCS", "DEX"]
+    };
+    out.push((ops[0].to_string(), source_file.to_path_buf(), line_no));
+    out.push((ops[1].to_string(), source_file.to_path_buf(), line_no));
+    if addr.ty().wide {
+        for line in [
+            format!("{} {}", ops[2], done_label),
+            ops[3].to_string(),
+            format!("{}:", done_label),
+        ] {
+            out.push((line, source_file.to_path_buf(), line_no));
         }
     }
+    emit_store_addr(addr, line_no, out, source_file);
+}
 
-    if let Some(name) = s.strip_suffix("++;") {
-        let name = name.trim();
-        let Some(var) = vars.get(name) else {
-            return Err(format!(
-                "C parse error: {}:{}: unknown variable '{}'",
-                path.display(),
-                line_no,
-                name
-            ));
-        };
+/// Store A, and X as the high byte of a 16-bit variable, into `var`.
+fn emit_store_var(
+    var: CVar,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    out.push((
+        format!("STA ${:02X}", var.addr),
+        source_file.to_path_buf(),
+        line_no,
+    ));
+    if var.ty.wide {
         out.push((
-            format!("INC ${:02X}", var.addr),
+            format!("STX ${:02X}", var.addr + 1),
             source_file.to_path_buf(),
             line_no,
         ));
-        return Ok(());
     }
-    if let Some(name) = s.strip_suffix("--;") {
-        let name = name.trim();
-        let Some(var) = vars.get(name) else {
-            return Err(format!(
-                "C parse error: {}:{}: unknown variable '{}'",
-                path.display(),
-                line_no,
+}
+
+/// Call `call.name`: argument bytes go in A, X and Y in that order and a result comes back in
+/// A, with the high byte of a 16-bit result in X.
+fn emit_call(
+    call: &CCall,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    let params = &call.func.params;
+    let push = |out: &mut Vec<_>, line: &str| {
+        out.push((line.to_string(), source_file.to_path_buf(), line_no));
+    };
+    // Evaluating an argument clobbers A, X and Y, so earlier ones wait on the stack.
+    for (i, (arg, ty)) in call.args.iter().zip(params).enumerate() {
+        if i > 0 {
+            push(out, "PHA");
+            if params[i - 1].wide {
+                push(out, "TXA");
+                push(out, "PHA");
+            }
+        }
+        emit_expr(arg, *ty, line_no, out, source_file);
+    }
+    if let Some(last) = params.last() {
+        let slots: usize = params.iter().map(|ty| ty.size() as usize).sum();
+        let first = slots - last.size() as usize;
+        if last.wide && first == 1 {
+            for op in ["PHA", "TXA", "TAY", "PLA", "TAX"] {
+                push(out, op);
+            }
+        } else if !last.wide && first > 0 {
+            push(out, &format!("TA{}", C_ARG_REGS[first]));
+        }
+        for slot in (0..first).rev() {
+            push(out, "PLA");
+            if slot > 0 {
+                push(out, &format!("TA{}", C_ARG_REGS[slot]));
+            }
+        }
+    }
+    push(out, &format!("JSR {}", call.name));
+}
+
+/// The names C expressions resolve against, and the lines their positions refer to.
+#[derive(Clone, Copy)]
+struct CNames<'a> {
+    lines: &'a [SourceLine],
+    vars: &'a HashMap<String, CSym>,
+    consts: &'a HashMap<String, u16>,
+    fns: &'a HashMap<String, CFunc>,
+}
+
+/// The base of a `[BASE + OFFSET]` access.
+enum CBase<'e> {
+    Addr(u16),
+    /// An array or struct, and whether its bytes are signed.
+    Label(&'e str, bool),
+}
+
+impl CNames<'_> {
+    fn error(&self, pos: Pos, msg: impl std::fmt::Display) -> String {
+        c_parser::error(self.lines, pos, msg)
+    }
+
+    /// The value of a constant expression: numbers and constants joined by arithmetic, which
+    /// wraps at 16 bits.
+    fn constant(&self, expr: &Expr) -> Result<u16, String> {
+        Ok(match &expr.kind {
+            ExprKind::Number(value) => *value,
+            ExprKind::Ident(name) => match self.consts.get(name) {
+                _ if self.vars.contains_key(name) => {
+                    let msg = format!("'{}' is a variable; only constants are allowed here", name);
+                    return Err(self.error(expr.pos, msg));
+                }
+                Some(value) => *value,
+                None => return Err(self.error(expr.pos, format!("unknown identifier '{}'", name))),
+            },
+            ExprKind::Unary(UnaryOp::Neg, inner) => self.constant(inner)?.wrapping_neg(),
+            ExprKind::Unary(UnaryOp::BitNot, inner) => !self.constant(inner)?,
+            ExprKind::Binary(op, lhs, rhs) => {
+                let (a, b) = (self.constant(lhs)?, self.constant(rhs)?);
+                match op {
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Mul => a.wrapping_mul(b),
+                    BinaryOp::Div | BinaryOp::Mod if b == 0 => {
+                        return Err(self.error(expr.pos, "division by zero"));
+                    }
+                    BinaryOp::Div => a / b,
+                    BinaryOp::Mod => a % b,
+                    BinaryOp::Shl => a.checked_shl(b.into()).unwrap_or(0),
+                    BinaryOp::Shr => a.checked_shr(b.into()).unwrap_or(0),
+                    BinaryOp::BitAnd => a & b,
+                    BinaryOp::BitXor => a ^ b,
+                    BinaryOp::BitOr => a | b,
+                    _ => return Err(self.error(expr.pos, "expected a constant")),
+                }
+            }
+            _ => return Err(self.error(expr.start(), "expected a constant")),
+        })
+    }
+
+    /// Lower `expr` as a value.
+    fn value(&self, expr: &Expr) -> Result<CExpr, String> {
+        Ok(match &expr.kind {
+            ExprKind::Number(value) => CExpr::Term(CTerm::Imm(*value)),
+            ExprKind::Ident(name) => match self.vars.get(name) {
+                Some(CSym::Var(var)) => CExpr::Term(CTerm::Var(*var)),
+                // Arrays and structs stand for their address.
+                Some(CSym::Array(_) | CSym::Struct(_)) => CExpr::Term(CTerm::Label(name.clone())),
+                _ => CExpr::Term(CTerm::Imm(self.constant(expr)?)),
+            },
+            // `-x` is `0 - x`; a negated constant is its 16-bit two's complement.
+            ExprKind::Unary(UnaryOp::Neg, inner) => match self.value(inner)? {
+                CExpr::Term(CTerm::Imm(v)) => CExpr::Term(CTerm::Imm(v.wrapping_neg())),
+                inner => CExpr::Bin(
+                    Box::new(CExpr::Term(CTerm::Imm(0))),
+                    CBinOp::Sub,
+                    Box::new(inner),
+                ),
+            },
+            ExprKind::Unary(UnaryOp::BitNot, inner) => CExpr::Not(Box::new(self.value(inner)?)),
+            ExprKind::Unary(UnaryOp::AddrOf, target) => self.addr_of(expr.pos, target)?,
+            ExprKind::Unary(UnaryOp::Not, _) => {
+                return Err(self.error(expr.pos, "'!' is only supported in conditions"));
+            }
+            ExprKind::Unary(UnaryOp::Deref, _)
+            | ExprKind::Index(..)
+            | ExprKind::Mem(_)
+            | ExprKind::Field(..) => CExpr::Mem(self.access(expr)?),
+            ExprKind::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    BinaryOp::Add => CBinOp::Add,
+                    BinaryOp::Sub => CBinOp::Sub,
+                    BinaryOp::Mul => CBinOp::Mul,
+                    BinaryOp::Div => CBinOp::Div,
+                    BinaryOp::Mod => CBinOp::Mod,
+                    BinaryOp::Shl => CBinOp::Shl,
+                    BinaryOp::Shr => CBinOp::Shr,
+                    BinaryOp::BitAnd => CBinOp::And,
+                    BinaryOp::BitXor => CBinOp::Xor,
+                    BinaryOp::BitOr => CBinOp::Or,
+                    _ => {
+                        let msg = "comparisons, '&&' and '||' are only supported in conditions";
+                        return Err(self.error(expr.pos, msg));
+                    }
+                };
+                let (lhs, rhs) = (self.value(lhs)?, self.value(rhs)?);
+                match op {
+                    CBinOp::Mul | CBinOp::Div | CBinOp::Mod => {
+                        fold_muldiv(lhs, op, rhs).map_err(|e| self.error(expr.pos, e))?
+                    }
+                    _ => CExpr::Bin(Box::new(lhs), op, Box::new(rhs)),
+                }
+            }
+            ExprKind::Call(name, args) => {
+                let call = self.call(expr.pos, name, args)?;
+                if call.func.ret.is_none() {
+                    let msg = format!("'{}' does not return a value", name);
+                    return Err(self.error(expr.pos, msg));
+                }
+                CExpr::Call(call)
+            }
+            ExprKind::Assign(..) => {
+                return Err(self.error(expr.pos, "an assignment is a statement, not a value"));
+            }
+            ExprKind::PostInc(_) | ExprKind::PostDec(_) => {
+                return Err(self.error(expr.pos, "'++' and '--' are statements, not values"));
+            }
+        })
+    }
+
+    /// Lower a condition: comparisons and values joined by `!`, `&&` and `||`.
+    fn cond(&self, expr: &Expr) -> Result<CCond, String> {
+        if let ExprKind::Binary(op, lhs, rhs) = &expr.kind
+            && let Some(op) = CmpOp::from_binary(*op)
+        {
+            return Ok(CCond::Cmp(self.value(lhs)?, op, self.value(rhs)?));
+        }
+        Ok(match &expr.kind {
+            ExprKind::Unary(UnaryOp::Not, inner) => CCond::Not(Box::new(self.cond(inner)?)),
+            ExprKind::Binary(BinaryOp::And, a, b) => {
+                CCond::And(Box::new(self.cond(a)?), Box::new(self.cond(b)?))
+            }
+            ExprKind::Binary(BinaryOp::Or, a, b) => {
+                CCond::Or(Box::new(self.cond(a)?), Box::new(self.cond(b)?))
+            }
+            _ => CCond::Truth(self.value(expr)?),
+        })
+    }
+
+    /// A call of `name`. Routines without a prototype, such as ASM labels, take and return
+    /// unsigned chars.
+    fn call(&self, pos: Pos, name: &str, args: &[Expr]) -> Result<CCall, String> {
+        if self.vars.contains_key(name) {
+            return Err(self.error(pos, format!("'{}' is a variable, not a function", name)));
+        }
+        let func = self.fns.get(name).cloned().unwrap_or_else(|| CFunc {
+            params: vec![CType::UCHAR; args.len()],
+            ret: Some(CType::UCHAR),
+        });
+        if func.params.len() != args.len() {
+            let msg = format!(
+                "'{}' takes {} argument(s), got {}",
+                name,
+                func.params.len(),
+                args.len()
+            );
+            return Err(self.error(pos, msg));
+        }
+        let slots: usize = func.params.iter().map(|ty| ty.size() as usize).sum();
+        if slots > C_ARG_REGS.len() {
+            let msg = format!(
+                "at most {} argument bytes can be passed to '{}'",
+                C_ARG_REGS.len(),
                 name
-            ));
+            );
+            return Err(self.error(pos, msg));
+        }
+        let args = args
+            .iter()
+            .map(|arg| self.value(arg))
+            .collect::<Result<_, _>>()?;
+        Ok(CCall {
+            name: name.to_string(),
+            args,
+            func,
+        })
+    }
+
+    /// The variable `expr` assigns to or steps.
+    fn scalar(&self, expr: &Expr) -> Result<CVar, String> {
+        let ExprKind::Ident(name) = &expr.kind else {
+            return Err(self.error(expr.start(), "invalid assignment target"));
         };
-        out.push((
-            format!("DEC ${:02X}", var.addr),
-            source_file.to_path_buf(),
-            line_no,
-        ));
-        return Ok(());
+        scalar_var(self.vars, name)
+            .ok_or_else(|| self.error(expr.pos, not_scalar_msg(self.vars, name)))
+    }
+
+    /// `&target`: the address of a variable, array, struct, field or element with a
+    /// constant index.
+    fn addr_of(&self, pos: Pos, target: &Expr) -> Result<CExpr, String> {
+        match &target.kind {
+            ExprKind::Ident(name) => match self.vars.get(name) {
+                Some(CSym::Var(var)) => return Ok(CExpr::Term(CTerm::Imm(var.addr as u16))),
+                Some(CSym::Array(_) | CSym::Struct(_)) => {
+                    return Ok(CExpr::Term(CTerm::Label(name.clone())));
+                }
+                _ if !self.consts.contains_key(name) => {
+                    return Err(self.error(target.pos, format!("unknown identifier '{}'", name)));
+                }
+                _ => {}
+            },
+            ExprKind::Index(array, index) => {
+                if let ExprKind::Ident(name) = &array.kind
+                    && let Some(CSym::Struct(CStructVar {
+                        len: Some(len),
+                        size,
+                    })) = self.vars.get(name)
+                {
+                    let idx = self.const_index(index, *len, name)?;
+                    let label = label_plus(name, idx * *size as u16);
+                    return Ok(CExpr::Term(CTerm::Label(label)));
+                }
+            }
+            _ => {}
+        }
+        match self.mem(target)? {
+            Some(AddrExpr::Abs {
+                base, index: None, ..
+            }) => Ok(CExpr::Term(CTerm::Label(base))),
+            _ => Err(self.error(
+                pos,
+                "'&' needs a variable, array, struct, field or element with a constant index",
+            )),
+        }
     }
 
-    let Some(eq_idx) = s.find('=') else {
-        return Err(format!(
-            "C parse error: {}:{}: unsupported statement",
-            path.display(),
-            line_no
-        ));
-    };
-    let lhs = s[..eq_idx].trim();
-    let mut rhs = s[eq_idx + 1..].trim();
-    if let Some(stripped) = rhs.strip_suffix(';') {
-        rhs = stripped.trim();
-    } else {
-        return Err(format!(
-            "C parse error: {}:{}: expected ';'",
-            path.display(),
-            line_no
-        ));
+    /// `access` for the expressions that are memory accesses; `None` for others.
+    fn mem(&self, expr: &Expr) -> Result<Option<AddrExpr>, String> {
+        match &expr.kind {
+            ExprKind::Unary(UnaryOp::Deref, _)
+            | ExprKind::Index(..)
+            | ExprKind::Mem(_)
+            | ExprKind::Field(..) => self.access(expr).map(Some),
+            _ => Ok(None),
+        }
     }
 
-    if let Some(lhs_mem) = parse_mem_access_expr(lhs, vars, consts) {
-        let addr = parse_addr_expr(&lhs_mem, vars, consts).map_err(|e| {
-            format!(
-                "C parse error: {}:{}: invalid address expression: {}",
-                path.display(),
-                line_no,
-                e
-            )
-        })?;
-        emit_expr_into_a(rhs, vars, consts, line_no, path, out, source_file)?;
-        emit_store_addr(&addr, line_no, out, source_file);
-        return Ok(());
+    /// A memory access: `[...]`, `mem[...]` and the other views, an element of an array or
+    /// pointer, `*ptr`, or a struct field.
+    fn access(&self, expr: &Expr) -> Result<AddrExpr, String> {
+        match &expr.kind {
+            ExprKind::Mem(addr) => self.addr(addr),
+            ExprKind::Index(array, index) => {
+                let ExprKind::Ident(name) = &array.kind else {
+                    return Err(
+                        self.error(array.start(), "only arrays and pointers can be indexed")
+                    );
+                };
+                match name.as_str() {
+                    "mem" | "data" => self.addr(index),
+                    "sprite_data" => {
+                        let base = CBase::Addr(self.sprite_ram(array.pos)?);
+                        self.offset_addr(base, Some(index))
+                    }
+                    _ => self.element(name, array.pos, index),
+                }
+            }
+            ExprKind::Field(base, field) => self.field(base, field),
+            ExprKind::Unary(UnaryOp::Deref, ptr) => {
+                let ExprKind::Ident(name) = &ptr.kind else {
+                    return Err(self.error(ptr.start(), "'*' needs a pointer variable"));
+                };
+                match scalar_var(self.vars, name) {
+                    Some(var) if var.ty.pointer => Ok(AddrExpr::Ptr {
+                        ptr: var.addr,
+                        offset: 0,
+                        index: None,
+                        ty: CType::char(var.ty.signed),
+                    }),
+                    _ => Err(self.error(ptr.pos, format!("'{}' is not a pointer", name))),
+                }
+            }
+            _ => Err(self.error(expr.start(), "expected a memory access")),
+        }
     }
 
-    validate_ident(lhs).map_err(|e| {
-        format!(
-            "C parse error: {}:{}: invalid assignment target: {}",
-            path.display(),
-            line_no,
-            e
-        )
-    })?;
-    let Some(lhs_var) = vars.get(lhs) else {
-        return Err(format!(
-            "C parse error: {}:{}: unknown variable '{}'",
-            path.display(),
-            line_no,
-            lhs
-        ));
-    };
+    /// `BASE` or `BASE + OFFSET`, where the base is a constant address, an array or a struct
+    /// and the offset a constant or a variable.
+    fn addr(&self, expr: &Expr) -> Result<AddrExpr, String> {
+        let (base, offset) = match &expr.kind {
+            ExprKind::Binary(BinaryOp::Add, base, offset) => (&**base, Some(&**offset)),
+            _ => (expr, None),
+        };
+        let label = match &base.kind {
+            ExprKind::Ident(name) => match self.vars.get(name) {
+                Some(CSym::Array(array)) => Some(CBase::Label(name, array.signed)),
+                Some(CSym::Struct(_)) => Some(CBase::Label(name, false)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let base = match label {
+            Some(label) => label,
+            None => CBase::Addr(self.constant(base)?),
+        };
+        self.offset_addr(base, offset)
+    }
 
-    if let Some(rhs_mem) = parse_mem_access_expr(rhs, vars, consts) {
-        let addr = parse_addr_expr(&rhs_mem, vars, consts).map_err(|e| {
-            format!(
-                "C parse error: {}:{}: invalid address expression: {}",
-                path.display(),
-                line_no,
-                e
-            )
-        })?;
-        emit_load_addr(&addr, line_no, out, source_file);
-        out.push((
-            format!("STA ${:02X}", lhs_var.addr),
-            source_file.to_path_buf(),
-            line_no,
-        ));
-        return Ok(());
+    fn offset_addr(&self, base: CBase, offset: Option<&Expr>) -> Result<AddrExpr, String> {
+        let signed = matches!(base, CBase::Label(_, true));
+        if let Some(index) = offset.and_then(|offset| self.index_var(offset)) {
+            let base = match base {
+                CBase::Addr(addr) => format!("${:04X}", addr),
+                CBase::Label(name, _) => name.to_string(),
+            };
+            return Ok(AddrExpr::byte(base, Some(index), signed));
+        }
+        let offset = match offset {
+            Some(offset) => self.constant(offset)?,
+            None => 0,
+        };
+        let base = match base {
+            CBase::Addr(addr) => format!("${:04X}", addr.wrapping_add(offset)),
+            CBase::Label(name, _) => label_plus(name, offset),
+        };
+        Ok(AddrExpr::byte(base, None, signed))
     }
 
-    emit_expr_into_a(rhs, vars, consts, line_no, path, out, source_file)?;
-    out.push((
-        format!("STA ${:02X}", lhs_var.addr),
-        source_file.to_path_buf(),
-        line_no,
-    ));
-    Ok(())
-}
+    /// `name[index]` on an array or pointer.
+    fn element(&self, name: &str, pos: Pos, index: &Expr) -> Result<AddrExpr, String> {
+        match self.vars.get(name) {
+            Some(CSym::Array(array)) => {
+                if let Some(var) = self.index_var(index) {
+                    return Ok(AddrExpr::byte(name.to_string(), Some(var), array.signed));
+                }
+                let idx = self.const_index(index, array.len, name)?;
+                Ok(AddrExpr::byte(label_plus(name, idx), None, array.signed))
+            }
+            Some(CSym::Var(var)) if var.ty.pointer => {
+                let (offset, index) = match self.index_var(index) {
+                    Some(index) => (0, Some(index)),
+                    None => {
+                        let offset = self.constant(index)?;
+                        let offset = u8::try_from(offset).map_err(|_| {
+                            let msg =
+                                format!("constant pointer index {} must be below 256", offset);
+                            self.error(index.start(), msg)
+                        })?;
+                        (offset, None)
+                    }
+                };
+                Ok(AddrExpr::Ptr {
+                    ptr: var.addr,
+                    offset,
+                    index,
+                    ty: CType::char(var.ty.signed),
+                })
+            }
+            Some(CSym::Struct(_)) => {
+                let msg = format!("'{}' holds structs; select a field with '.'", name);
+                Err(self.error(pos, msg))
+            }
+            Some(CSym::Var(_) | CSym::Field(_)) => {
+                Err(self.error(pos, format!("'{}' is not an array or pointer", name)))
+            }
+            None => Err(self.error(pos, format!("unknown identifier '{}'", name))),
+        }
+    }
 
-fn parse_mem_access_expr(
-    expr: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Option<String> {
-    let s = expr.trim();
-    if s.starts_with('[') && s.ends_with(']') {
-        return Some(s[1..s.len() - 1].trim().to_string());
+    /// The variable an index names, if it is one; other indexes must be constant.
+    fn index_var(&self, index: &Expr) -> Option<CVar> {
+        match &index.kind {
+            ExprKind::Ident(name) => scalar_var(self.vars, name),
+            _ => None,
+        }
     }
-    if let Some(rest) = s.strip_prefix("mem[") {
-        return rest.strip_suffix(']').map(|r| r.trim().to_string());
+
+    /// A constant index into the array `name` of `len` elements.
+    fn const_index(&self, index: &Expr, len: u16, name: &str) -> Result<u16, String> {
+        let value = self.constant(index)?;
+        if value >= len {
+            let msg = format!(
+                "index {} is out of bounds for '{}' ({} elements)",
+                value, name, len
+            );
+            return Err(self.error(index.start(), msg));
+        }
+        Ok(value)
     }
-    if let Some(rest) = s.strip_prefix("data[") {
-        return rest.strip_suffix(']').map(|r| r.trim().to_string());
+
+    /// `var.field` or `var[index].field` on a struct variable, or `sprite[index].field`.
+    fn field(&self, base: &Expr, field: &str) -> Result<AddrExpr, String> {
+        let (array, index) = match &base.kind {
+            ExprKind::Index(array, index) => (&**array, Some(&**index)),
+            _ => (base, None),
+        };
+        let ExprKind::Ident(name) = &array.kind else {
+            return Err(self.error(array.start(), "expected a struct variable before '.'"));
+        };
+        if let (Some(index), "sprite") = (index, name.as_str()) {
+            return self.sprite_field(array.pos, index, field);
+        }
+        let var = match self.vars.get(name) {
+            Some(CSym::Struct(var)) => *var,
+            Some(_) => return Err(self.error(array.pos, format!("'{}' is not a struct", name))),
+            None => return Err(self.error(array.pos, format!("unknown identifier '{}'", name))),
+        };
+        let Some(CSym::Field(f)) = self.vars.get(&format!("{}.{}", name, field)) else {
+            let msg = format!("'{}' has no field '{}'", name, field);
+            return Err(self.error(array.pos, msg));
+        };
+        let field_at = |base: String| AddrExpr::Abs {
+            base,
+            index: None,
+            stride: 1,
+            wide_index: false,
+            ty: f.ty,
+        };
+        let field_base = label_plus(name, f.offset as u16);
+        let (len, index) = match (var.len, index) {
+            (None, None) => return Ok(field_at(field_base)),
+            (None, Some(_)) => {
+                return Err(self.error(array.pos, format!("'{}' is not an array", name)));
+            }
+            (Some(_), None) => {
+                let msg = format!(
+                    "'{}' is an array of structs; index it before '.{}'",
+                    name, field
+                );
+                return Err(self.error(array.pos, msg));
+            }
+            (Some(len), Some(index)) => (len, index),
+        };
+        if let Some(index) = self.index_var(index) {
+            return Ok(AddrExpr::Abs {
+                base: field_base,
+                index: Some(index),
+                stride: var.size,
+                wide_index: index.ty.wide || (len - 1) as usize * var.size as usize > 0xFF,
+                ty: f.ty,
+            });
+        }
+        let idx = self.const_index(index, len, name)?;
+        let offset = idx * var.size as u16 + f.offset as u16;
+        Ok(field_at(label_plus(name, offset)))
     }
-    if let Some(rest) = s.strip_prefix("sprite_data[") {
-        if let Some(inner) = rest.strip_suffix(']') {
-            return Some(format!("SPRITE_RAM + {}", inner.trim()));
+
+    /// `sprite[index].field`: a byte of the attributes of one of the 64 sprites.
+    fn sprite_field(&self, pos: Pos, index: &Expr, field: &str) -> Result<AddrExpr, String> {
+        let Some(offset) = sprite_field_offset(field) else {
+            return Err(self.error(pos, format!("sprites have no field '{}'", field)));
+        };
+        let base = self.sprite_ram(pos)?.wrapping_add(offset as u16);
+        if let Some(index) = self.index_var(index) {
+            return Ok(AddrExpr::Abs {
+                base: format!("${:04X}", base),
+                index: Some(index),
+                stride: 8,
+                wide_index: true,
+                ty: CType::UCHAR,
+            });
         }
+        let n = self.constant(index)?;
+        if n > 63 {
+            let msg = format!("sprite index {} is out of range (0-63)", n);
+            return Err(self.error(index.start(), msg));
+        }
+        Ok(AddrExpr::byte(
+            format!("${:04X}", base + n * 8),
+            None,
+            false,
+        ))
     }
-    parse_sprite_access_expr(s, vars, consts)
-}
 
-fn parse_sprite_access_expr(
-    s: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Option<String> {
-    let Some(rest) = s.strip_prefix("sprite[") else {
-        return None;
-    };
-    let close = rest.find(']')?;
-    let idx_tok = rest[..close].trim();
-    if idx_tok.is_empty() {
-        return None;
+    fn sprite_ram(&self, pos: Pos) -> Result<u16, String> {
+        self.consts
+            .get("SPRITE_RAM")
+            .copied()
+            .ok_or_else(|| self.error(pos, "'SPRITE_RAM' is not defined"))
     }
-    let after = rest[close + 1..].trim_start();
-    let field = after.strip_prefix('.')?.trim();
-    if field.is_empty() || field.contains(char::is_whitespace) {
-        return None;
+
+    /// The bytes of a `{ a, b, ... }` array initializer.
+    fn init_bytes(&self, items: &[Expr]) -> Result<Vec<u8>, String> {
+        items
+            .iter()
+            .map(|item| c_const_byte(self.constant(item)?).map_err(|e| self.error(item.start(), e)))
+            .collect()
     }
 
-    let field_off = sprite_field_offset(field)?;
-    let idx = parse_u16_token(idx_tok, vars, consts).ok()?;
-    if idx > 63 {
-        return None;
+    /// A global initializer: a constant, or the address of a variable or an array element
+    /// (`&name`, `table`, `&table[k]`).
+    fn global_init(&self, expr: &Expr) -> Result<CInit, String> {
+        match self.value(expr) {
+            Ok(CExpr::Term(CTerm::Imm(value))) => Ok(CInit::Value(value)),
+            Ok(CExpr::Term(CTerm::Label(label))) => Ok(CInit::Label(label)),
+            _ => self.constant(expr).map(CInit::Value),
+        }
     }
-    let offset = idx.saturating_mul(8).saturating_add(field_off as u16);
-    Some(format!("SPRITE_RAM + {}", offset))
 }
 
 fn sprite_field_offset(field: &str) -> Option<u8> {
@@ -2801,92 +5222,240 @@ fn sprite_field_offset(field: &str) -> Option<u8> {
     }
 }
 
-fn parse_addr_expr(
-    expr: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<AddrExpr, String> {
-    let parts: Vec<&str> = expr
-        .split('+')
-        .map(|p| p.trim())
-        .filter(|p| !p.is_empty())
-        .collect();
-    if parts.is_empty() || parts.len() > 2 {
-        return Err("only 'BASE' or 'BASE + OFFSET' is supported".to_string());
+fn label_plus(label: &str, offset: u16) -> String {
+    if offset == 0 {
+        label.to_string()
+    } else {
+        format!("{}+{}", label, offset)
+    }
+}
+
+/// Point `C_EXPR_TMP_PTR` at `lo`/`hi` (operands for `ADC`) plus the 16-bit variable `index`.
+fn emit_addr_pointer(
+    lo: &str,
+    hi: &str,
+    index: CVar,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    for line in [
+        format!("LDA ${:02X}", index.addr),
+        "CLC".to_string(),
+        format!("ADC {}", lo),
+        format!("STA ${:02X}", C_EXPR_TMP_PTR),
+        format!("LDA ${:02X}", index.addr + 1),
+        format!("ADC {}", hi),
+        format!("STA ${:02X}", C_EXPR_TMP_PTR + 1),
+    ] {
+        out.push((line, source_file.to_path_buf(), line_no));
     }
-    let base = parse_u16_token(parts[0], vars, consts)?;
-    let offset = if parts.len() == 2 {
-        Some(parse_term(parts[1], vars, consts)?)
+}
+
+/// The high byte of `index` as an operand: zero for a char.
+fn index_hi(index: CVar) -> String {
+    if index.ty.wide {
+        format!("${:02X}", index.addr + 1)
     } else {
-        None
-    };
-    Ok(AddrExpr { base, offset })
+        "#$00".to_string()
+    }
 }
 
-fn emit_load_addr(
-    addr: &AddrExpr,
+/// Multiply `index` by the constant `stride` into A, shifting and adding from the top bit.
+fn emit_scaled_index_a(
+    index: CVar,
+    stride: u8,
     line_no: usize,
     out: &mut Vec<(String, PathBuf, usize)>,
     source_file: &Path,
 ) {
-    match &addr.offset {
-        None => out.push((
-            format!("LDA ${:04X}", addr.base),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        Some(CTerm::Imm(v)) => out.push((
-            format!("LDA ${:04X}", addr.base.wrapping_add(*v as u16)),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        Some(CTerm::Var(v)) => {
-            out.push((
-                format!("LDY ${:02X}", v),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            out.push((
-                format!("LDA ${:04X},Y", addr.base),
-                source_file.to_path_buf(),
-                line_no,
-            ));
+    let mut push = |line: String| out.push((line, source_file.to_path_buf(), line_no));
+    push(format!("LDA ${:02X}", index.addr));
+    for bit in (0..stride.ilog2()).rev() {
+        push("ASL A".to_string());
+        if stride & (1 << bit) != 0 {
+            push("CLC".to_string());
+            push(format!("ADC ${:02X}", index.addr));
         }
     }
 }
 
-fn emit_store_addr(
+/// Point `C_EXPR_TMP_PTR` at `lo`/`hi` (operands for `ADC`) plus `index` times `stride`,
+/// computed in 16 bits.
+fn emit_scaled_pointer(
+    lo: &str,
+    hi: &str,
+    index: CVar,
+    stride: u8,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    let (ptr, ptr_hi) = (C_EXPR_TMP_PTR, C_EXPR_TMP_PTR + 1);
+    let mut push = |line: String| out.push((line, source_file.to_path_buf(), line_no));
+    push(format!("LDA ${:02X}", index.addr));
+    push(format!("STA ${:02X}", ptr));
+    push(format!("LDA {}", index_hi(index)));
+    push(format!("STA ${:02X}", ptr_hi));
+    for bit in (0..stride.ilog2()).rev() {
+        push(format!("ASL ${:02X}", ptr));
+        push(format!("ROL ${:02X}", ptr_hi));
+        if stride & (1 << bit) != 0 {
+            push(format!("LDA ${:02X}", ptr));
+            push("CLC".to_string());
+            push(format!("ADC ${:02X}", index.addr));
+            push(format!("STA ${:02X}", ptr));
+            push(format!("LDA ${:02X}", ptr_hi));
+            push(format!("ADC {}", index_hi(index)));
+            push(format!("STA ${:02X}", ptr_hi));
+        }
+    }
+    push(format!("LDA ${:02X}", ptr));
+    push("CLC".to_string());
+    push(format!("ADC {}", lo));
+    push(format!("STA ${:02X}", ptr));
+    push(format!("LDA ${:02X}", ptr_hi));
+    push(format!("ADC {}", hi));
+    push(format!("STA ${:02X}", ptr_hi));
+}
+
+/// Load (`LDA`) or store (`STA`) the value `addr` names; a 16-bit value goes through A (low
+/// byte) and X (high byte). A and X stay intact for a store while the address is computed.
+fn emit_addr_op(
     addr: &AddrExpr,
+    op: &str,
     line_no: usize,
     out: &mut Vec<(String, PathBuf, usize)>,
     source_file: &Path,
 ) {
-    match &addr.offset {
-        None => out.push((
-            format!("STA ${:04X}", addr.base),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        Some(CTerm::Imm(v)) => out.push((
-            format!("STA ${:04X}", addr.base.wrapping_add(*v as u16)),
-            source_file.to_path_buf(),
-            line_no,
-        )),
-        Some(CTerm::Var(v)) => {
-            out.push((
-                format!("LDY ${:02X}", v),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            out.push((
-                format!("STA ${:04X},Y", addr.base),
-                source_file.to_path_buf(),
-                line_no,
-            ));
+    let store = op == "STA";
+    let wide = addr.ty().wide;
+    let push = |line: String, out: &mut Vec<(String, PathBuf, usize)>| {
+        out.push((line, source_file.to_path_buf(), line_no))
+    };
+    let (lo, hi, index, stride) = match addr {
+        AddrExpr::Abs {
+            base, index: None, ..
+        } => {
+            push(format!("{} {}", op, base), out);
+            if wide {
+                let op_hi = if store { "STX" } else { "LDX" };
+                push(format!("{} {}+1", op_hi, base), out);
+            }
+            return;
+        }
+        AddrExpr::Abs {
+            base,
+            index: Some(index),
+            stride,
+            wide_index: false,
+            ..
+        } => {
+            if *stride == 1 {
+                push(format!("LDY ${:02X}", index.addr), out);
+            } else {
+                if store {
+                    push("PHA".to_string(), out);
+                }
+                emit_scaled_index_a(*index, *stride, line_no, out, source_file);
+                push("TAY".to_string(), out);
+                if store {
+                    push("PLA".to_string(), out);
+                }
+            }
+            push(format!("{} {},Y", op, base), out);
+            if wide && store {
+                push("TXA".to_string(), out);
+                push(format!("STA {}+1,Y", base), out);
+            } else if wide {
+                push(format!("LDX {}+1,Y", base), out);
+            }
+            return;
+        }
+        AddrExpr::Abs {
+            base,
+            index: Some(index),
+            stride,
+            ..
+        } => (
+            format!("#<{}", base),
+            format!("#>{}", base),
+            *index,
+            *stride,
+        ),
+        AddrExpr::Ptr {
+            ptr,
+            index: Some(index),
+            ..
+        } if index.ty.wide => (
+            format!("${:02X}", ptr),
+            format!("${:02X}", ptr + 1),
+            *index,
+            1,
+        ),
+        AddrExpr::Ptr {
+            ptr, offset, index, ..
+        } => {
+            match index {
+                Some(index) => push(format!("LDY ${:02X}", index.addr), out),
+                None => push(format!("LDY #${:02X}", offset), out),
+            }
+            push(format!("{} (${:02X}),Y", op, ptr), out);
+            return;
+        }
+    };
+    if store {
+        push("PHA".to_string(), out);
+    }
+    if stride == 1 {
+        emit_addr_pointer(&lo, &hi, index, line_no, out, source_file);
+    } else {
+        emit_scaled_pointer(&lo, &hi, index, stride, line_no, out, source_file);
+    }
+    if store {
+        push("PLA".to_string(), out);
+    }
+    let ptr = C_EXPR_TMP_PTR;
+    match (wide, store) {
+        (false, _) => {
+            push("LDY #$00".to_string(), out);
+            push(format!("{} (${:02X}),Y", op, ptr), out);
+        }
+        (true, true) => {
+            push("LDY #$00".to_string(), out);
+            push(format!("STA (${:02X}),Y", ptr), out);
+            push("INY".to_string(), out);
+            push("TXA".to_string(), out);
+            push(format!("STA (${:02X}),Y", ptr), out);
+        }
+        (true, false) => {
+            push("LDY #$01".to_string(), out);
+            push(format!("LDA (${:02X}),Y", ptr), out);
+            push("TAX".to_string(), out);
+            push("DEY".to_string(), out);
+            push(format!("LDA (${:02X}),Y", ptr), out);
         }
     }
 }
 
+fn emit_load_addr(
+    addr: &AddrExpr,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    emit_addr_op(addr, "LDA", line_no, out, source_file);
+}
+
+fn emit_store_addr(
+    addr: &AddrExpr,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    emit_addr_op(addr, "STA", line_no, out, source_file);
+}
+
 fn emit_term_into_a(
     term: &CTerm,
     out: &mut Vec<(String, PathBuf, usize)>,
@@ -2895,224 +5464,145 @@ fn emit_term_into_a(
 ) {
     match term {
         CTerm::Imm(v) => out.push((
-            format!("LDA #${:02X}", v),
+            format!("LDA #${:02X}", *v as u8),
             source_file.to_path_buf(),
             line_no,
         )),
         CTerm::Var(v) => out.push((
-            format!("LDA ${:02X}", v),
+            format!("LDA ${:02X}", v.addr),
+            source_file.to_path_buf(),
+            line_no,
+        )),
+        CTerm::Label(label) => out.push((
+            format!("LDA #<{}", label),
             source_file.to_path_buf(),
             line_no,
         )),
     }
 }
 
-fn emit_expr_into_a(
-    expr: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
+/// Sign- or zero-extend A into X.
+fn emit_extend_a(
+    signed: bool,
     line_no: usize,
-    path: &Path,
     out: &mut Vec<(String, PathBuf, usize)>,
     source_file: &Path,
-) -> Result<(), String> {
-    let toks = tokenize_expr(expr).map_err(|e| {
-        format!(
-            "C parse error: {}:{}: invalid expression '{}': {}",
-            path.display(),
-            line_no,
-            expr,
-            e
-        )
-    })?;
-    let mut idx = 0usize;
-    let parsed = parse_expr_or(&toks, &mut idx, vars, consts).map_err(|e| {
-        format!(
-            "C parse error: {}:{}: invalid expression '{}': {}",
-            path.display(),
-            line_no,
-            expr,
-            e
-        )
-    })?;
-    if idx != toks.len() {
-        return Err(format!(
-            "C parse error: {}:{}: invalid expression '{}': trailing token '{}'",
-            path.display(),
-            line_no,
-            expr,
-            toks[idx]
-        ));
-    }
-    emit_cexpr_into_a(&parsed, line_no, out, source_file)
-}
-
-fn tokenize_expr(expr: &str) -> Result<Vec<String>, String> {
-    let mut out = Vec::new();
-    let chars: Vec<char> = expr.chars().collect();
-    let mut i = 0usize;
-    while i < chars.len() {
-        let c = chars[i];
-        if c.is_ascii_whitespace() {
-            i += 1;
-            continue;
-        }
-        if i + 1 < chars.len() {
-            let two = [chars[i], chars[i + 1]];
-            if two == ['<', '<'] || two == ['>', '>'] {
-                out.push(two.iter().collect());
-                i += 2;
-                continue;
-            }
-        }
-        if matches!(c, '+' | '-' | '&' | '|' | '^' | '~' | '(' | ')') {
-            out.push(c.to_string());
-            i += 1;
-            continue;
-        }
-        let start = i;
-        while i < chars.len() {
-            let ch = chars[i];
-            if ch.is_ascii_whitespace()
-                || matches!(
-                    ch,
-                    '+' | '-' | '&' | '|' | '^' | '~' | '<' | '>' | '(' | ')'
-                )
-            {
-                break;
-            }
-            i += 1;
-        }
-        if start == i {
-            return Err(format!("unexpected token '{}'", chars[i]));
-        }
-        out.push(chars[start..i].iter().collect::<String>());
-    }
-    if out.is_empty() {
-        return Err("empty expression".to_string());
+) {
+    let ops: &[&str] = if signed {
+        // Carry takes the sign bit; $00 + $FF + C is $00 or $FF, inverted.
+        &[
+            "PHA", "ASL A", "LDA #$00", "ADC #$FF", "EOR #$FF", "TAX", "PLA",
+        ]
+    } else {
+        &["LDX #$00"]
+    };
+    for op in ops {
+        out.push((op.to_string(), source_file.to_path_buf(), line_no));
     }
-    Ok(out)
 }
 
-fn parse_expr_or(
-    toks: &[String],
-    idx: &mut usize,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CExpr, String> {
-    let mut node = parse_expr_xor(toks, idx, vars, consts)?;
-    while *idx < toks.len() && toks[*idx] == "|" {
-        *idx += 1;
-        let rhs = parse_expr_xor(toks, idx, vars, consts)?;
-        node = CExpr::Bin(Box::new(node), CBinOp::Or, Box::new(rhs));
+/// Evaluate `expr` as a value of type `ty`: into A, or A and X for 16-bit values. The
+/// expression is computed in 16 bits when `ty` or one of its operands is 16-bit.
+fn emit_expr(
+    expr: &CExpr,
+    ty: CType,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    if ty.wide || cexpr_is_wide(expr) {
+        emit_cexpr_into_ax(expr, line_no, out, source_file);
+    } else {
+        emit_cexpr_into_a(expr, line_no, out, source_file);
     }
-    Ok(node)
 }
 
-fn parse_expr_xor(
-    toks: &[String],
-    idx: &mut usize,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CExpr, String> {
-    let mut node = parse_expr_and(toks, idx, vars, consts)?;
-    while *idx < toks.len() && toks[*idx] == "^" {
-        *idx += 1;
-        let rhs = parse_expr_and(toks, idx, vars, consts)?;
-        node = CExpr::Bin(Box::new(node), CBinOp::Xor, Box::new(rhs));
+/// Whether an operand of `expr` is a 16-bit variable, an address or a value above 255.
+fn cexpr_is_wide(expr: &CExpr) -> bool {
+    match expr {
+        CExpr::Term(CTerm::Imm(v)) => *v > 0xFF,
+        CExpr::Term(CTerm::Var(v)) => v.ty.wide,
+        CExpr::Term(CTerm::Label(_)) => true,
+        CExpr::Mem(addr) => addr.ty().wide,
+        CExpr::Not(inner) => cexpr_is_wide(inner),
+        CExpr::Bin(lhs, _, rhs) => cexpr_is_wide(lhs) || cexpr_is_wide(rhs),
+        CExpr::Call(call) => call.func.ret.is_some_and(|ret| ret.wide),
     }
-    Ok(node)
 }
 
-fn parse_expr_and(
-    toks: &[String],
-    idx: &mut usize,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CExpr, String> {
-    let mut node = parse_expr_shift(toks, idx, vars, consts)?;
-    while *idx < toks.len() && toks[*idx] == "&" {
-        *idx += 1;
-        let rhs = parse_expr_shift(toks, idx, vars, consts)?;
-        node = CExpr::Bin(Box::new(node), CBinOp::And, Box::new(rhs));
+/// Whether evaluating `expr` calls a function, which may use the expression temporaries.
+fn cexpr_has_call(expr: &CExpr) -> bool {
+    match expr {
+        CExpr::Term(_) | CExpr::Mem(_) => false,
+        CExpr::Not(inner) => cexpr_has_call(inner),
+        CExpr::Bin(lhs, _, rhs) => cexpr_has_call(lhs) || cexpr_has_call(rhs),
+        CExpr::Call(_) => true,
     }
-    Ok(node)
 }
 
-fn parse_expr_shift(
-    toks: &[String],
-    idx: &mut usize,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CExpr, String> {
-    let mut node = parse_expr_addsub(toks, idx, vars, consts)?;
-    while *idx < toks.len() && (toks[*idx] == "<<" || toks[*idx] == ">>") {
-        let op = if toks[*idx] == "<<" {
-            CBinOp::Shl
-        } else {
-            CBinOp::Shr
+/// Whether 16-bit arithmetic on these operands is signed: chars promote to `int`, and an
+/// `unsigned int` operand makes the operation unsigned.
+fn cexpr_is_signed(exprs: &[&CExpr]) -> bool {
+    fn visit(expr: &CExpr, signed: &mut bool, unsigned_int: &mut bool) {
+        let ty = match expr {
+            CExpr::Term(CTerm::Var(v)) => v.ty,
+            CExpr::Mem(addr) => addr.ty(),
+            CExpr::Call(call) => match call.func.ret {
+                Some(ty) => ty,
+                None => return,
+            },
+            CExpr::Term(_) => return,
+            CExpr::Not(inner) => return visit(inner, signed, unsigned_int),
+            CExpr::Bin(lhs, _, rhs) => {
+                visit(lhs, signed, unsigned_int);
+                return visit(rhs, signed, unsigned_int);
+            }
         };
-        *idx += 1;
-        let rhs = parse_expr_addsub(toks, idx, vars, consts)?;
-        node = CExpr::Bin(Box::new(node), op, Box::new(rhs));
+        if ty.wide && (ty.pointer || !ty.signed) {
+            *unsigned_int = true;
+        } else if ty.signed {
+            *signed = true;
+        }
     }
-    Ok(node)
-}
-
-fn parse_expr_addsub(
-    toks: &[String],
-    idx: &mut usize,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CExpr, String> {
-    let mut node = parse_expr_unary(toks, idx, vars, consts)?;
-    while *idx < toks.len() && (toks[*idx] == "+" || toks[*idx] == "-") {
-        let op = if toks[*idx] == "+" {
-            CBinOp::Add
-        } else {
-            CBinOp::Sub
-        };
-        *idx += 1;
-        let rhs = parse_expr_unary(toks, idx, vars, consts)?;
-        node = CExpr::Bin(Box::new(node), op, Box::new(rhs));
+    let (mut signed, mut unsigned_int) = (false, false);
+    for expr in exprs {
+        visit(expr, &mut signed, &mut unsigned_int);
     }
-    Ok(node)
+    signed && !unsigned_int
 }
 
-fn parse_expr_unary(
-    toks: &[String],
-    idx: &mut usize,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CExpr, String> {
-    if *idx < toks.len() && toks[*idx] == "~" {
-        *idx += 1;
-        let inner = parse_expr_unary(toks, idx, vars, consts)?;
-        return Ok(CExpr::Not(Box::new(inner)));
-    }
-    if *idx < toks.len() && toks[*idx] == "(" {
-        *idx += 1;
-        let inner = parse_expr_or(toks, idx, vars, consts)?;
-        if *idx >= toks.len() || toks[*idx] != ")" {
-            return Err("expected ')'".to_string());
-        }
-        *idx += 1;
-        return Ok(inner);
-    }
-    if *idx >= toks.len() {
-        return Err("unexpected end of expression".to_string());
-    }
-    let tok = &toks[*idx];
-    if tok == ")" {
-        return Err("unexpected ')'".to_string());
-    }
-    if let Some(mem_expr) = parse_mem_access_expr(tok, vars, consts) {
-        let addr = parse_addr_expr(&mem_expr, vars, consts)?;
-        *idx += 1;
-        return Ok(CExpr::Mem(addr));
-    }
-    *idx += 1;
-    Ok(CExpr::Term(parse_term(tok, vars, consts)?))
+/// `lhs op rhs` for `*`, `/` and `%`, computed here when both sides are constants and turned
+/// into a shift or mask when `rhs` is a power of two. Other cases call the runtime library.
+fn fold_muldiv(lhs: CExpr, op: CBinOp, rhs: CExpr) -> Result<CExpr, String> {
+    let CExpr::Term(CTerm::Imm(b)) = rhs else {
+        return Ok(CExpr::Bin(Box::new(lhs), op, Box::new(rhs)));
+    };
+    if b == 0 && !matches!(op, CBinOp::Mul) {
+        return Err("division by zero".to_string());
+    }
+    if let CExpr::Term(CTerm::Imm(a)) = lhs {
+        let value = match op {
+            CBinOp::Mul => a.wrapping_mul(b),
+            CBinOp::Div => a / b,
+            _ => a % b,
+        };
+        return Ok(CExpr::Term(CTerm::Imm(value)));
+    }
+    // Shifting right rounds negative values down instead of towards zero, so signed
+    // division keeps the runtime call. An 8-bit shift also cannot go past 7 bits.
+    let shift = b.is_power_of_two() && (b <= 0x80 || cexpr_is_wide(&lhs));
+    let unsigned = !cexpr_is_signed(&[&lhs]);
+    let folded = match op {
+        CBinOp::Mul if shift => (CBinOp::Shl, b.trailing_zeros() as u16),
+        CBinOp::Div if shift && unsigned => (CBinOp::Shr, b.trailing_zeros() as u16),
+        CBinOp::Mod if b.is_power_of_two() && unsigned => (CBinOp::And, b - 1),
+        _ => (op, b),
+    };
+    Ok(CExpr::Bin(
+        Box::new(lhs),
+        folded.0,
+        Box::new(CExpr::Term(CTerm::Imm(folded.1))),
+    ))
 }
 
 fn emit_cexpr_into_a(
@@ -3120,39 +5610,49 @@ fn emit_cexpr_into_a(
     line_no: usize,
     out: &mut Vec<(String, PathBuf, usize)>,
     source_file: &Path,
-) -> Result<(), String> {
+) {
     match expr {
-        CExpr::Term(t) => {
-            emit_term_into_a(t, out, source_file, line_no);
-            Ok(())
-        }
-        CExpr::Mem(addr) => {
-            emit_load_addr(addr, line_no, out, source_file);
-            Ok(())
-        }
+        CExpr::Term(t) => emit_term_into_a(t, out, source_file, line_no),
+        CExpr::Mem(addr) => emit_load_addr(addr, line_no, out, source_file),
         CExpr::Not(inner) => {
-            emit_cexpr_into_a(inner, line_no, out, source_file)?;
+            emit_cexpr_into_a(inner, line_no, out, source_file);
             out.push(("EOR #$FF".to_string(), source_file.to_path_buf(), line_no));
-            Ok(())
         }
+        CExpr::Call(call) => emit_call(call, line_no, out, source_file),
         CExpr::Bin(lhs, op, rhs) => {
-            emit_cexpr_into_a(lhs, line_no, out, source_file)?;
-            out.push((
-                format!("STA ${:02X}", C_EXPR_TMP_LHS),
-                source_file.to_path_buf(),
-                line_no,
-            ));
-            emit_cexpr_into_a(rhs, line_no, out, source_file)?;
+            emit_cexpr_into_a(lhs, line_no, out, source_file);
+            // A right side with operators of its own reuses the temporaries, so the left
+            // side waits on the stack.
+            let nested = !matches!(**rhs, CExpr::Term(_) | CExpr::Mem(_));
+            if nested {
+                out.push(("PHA".to_string(), source_file.to_path_buf(), line_no));
+            } else {
+                out.push((
+                    format!("STA ${:02X}", C_EXPR_TMP_LHS),
+                    source_file.to_path_buf(),
+                    line_no,
+                ));
+            }
+            emit_cexpr_into_a(rhs, line_no, out, source_file);
             out.push((
                 format!("STA ${:02X}", C_EXPR_TMP_RHS),
                 source_file.to_path_buf(),
                 line_no,
             ));
-            out.push((
-                format!("LDA ${:02X}", C_EXPR_TMP_LHS),
-                source_file.to_path_buf(),
-                line_no,
-            ));
+            if nested {
+                out.push(("PLA".to_string(), source_file.to_path_buf(), line_no));
+                out.push((
+                    format!("STA ${:02X}", C_EXPR_TMP_LHS),
+                    source_file.to_path_buf(),
+                    line_no,
+                ));
+            } else {
+                out.push((
+                    format!("LDA ${:02X}", C_EXPR_TMP_LHS),
+                    source_file.to_path_buf(),
+                    line_no,
+                ));
+            }
 
             match op {
                 CBinOp::Add => {
@@ -3192,6 +5692,32 @@ fn emit_cexpr_into_a(
                         line_no,
                     ));
                 }
+                CBinOp::Mul => {
+                    out.push((
+                        "JSR CRTMUL8".to_string(),
+                        source_file.to_path_buf(),
+                        line_no,
+                    ));
+                }
+                CBinOp::Div | CBinOp::Mod => {
+                    let routine = if cexpr_is_signed(&[lhs, rhs]) {
+                        "CRTSDIV8"
+                    } else {
+                        "CRTDIV8"
+                    };
+                    out.push((
+                        format!("JSR {}", routine),
+                        source_file.to_path_buf(),
+                        line_no,
+                    ));
+                    if matches!(op, CBinOp::Mod) {
+                        out.push((
+                            format!("LDA ${:02X}", C_RUNTIME_TMP),
+                            source_file.to_path_buf(),
+                            line_no,
+                        ));
+                    }
+                }
                 CBinOp::Shl | CBinOp::Shr => {
                     let loop_label = format!("CEXSHIFT{}_{}", line_no, out.len());
                     let done_label = format!("CEXDONE{}_{}", line_no, out.len());
@@ -3265,65 +5791,153 @@ fn emit_cexpr_into_a(
                     ));
                 }
             }
-            Ok(())
         }
     }
 }
 
-fn parse_term(
-    token: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<CTerm, String> {
-    if let Some(var) = vars.get(token) {
-        return Ok(CTerm::Var(var.addr));
-    }
-    let value = parse_u16_token(token, vars, consts)?;
-    if value > 0xFF {
-        return Err(format!("value '{}' does not fit in 8 bits", token));
+/// 16-bit counterpart of `emit_cexpr_into_a`: the low byte ends up in A and the high byte in
+/// X. The left operand of a binary operator waits on the stack while the right one is computed.
+fn emit_cexpr_into_ax(
+    expr: &CExpr,
+    line_no: usize,
+    out: &mut Vec<(String, PathBuf, usize)>,
+    source_file: &Path,
+) {
+    let mut push = |line: String| out.push((line, source_file.to_path_buf(), line_no));
+    match expr {
+        CExpr::Term(CTerm::Imm(v)) => {
+            let [lo, hi] = v.to_le_bytes();
+            push(format!("LDA #${:02X}", lo));
+            push(format!("LDX #${:02X}", hi));
+        }
+        CExpr::Term(CTerm::Var(v)) if v.ty.wide => {
+            push(format!("LDA ${:02X}", v.addr));
+            push(format!("LDX ${:02X}", v.addr + 1));
+        }
+        CExpr::Term(CTerm::Var(v)) => {
+            push(format!("LDA ${:02X}", v.addr));
+            emit_extend_a(v.ty.signed, line_no, out, source_file);
+        }
+        CExpr::Term(CTerm::Label(label)) => {
+            push(format!("LDA #<{}", label));
+            push(format!("LDX #>{}", label));
+        }
+        CExpr::Mem(addr) => {
+            emit_load_addr(addr, line_no, out, source_file);
+            if !addr.ty().wide {
+                emit_extend_a(addr.ty().signed, line_no, out, source_file);
+            }
+        }
+        CExpr::Call(call) => {
+            emit_call(call, line_no, out, source_file);
+            if let Some(ret) = call.func.ret
+                && !ret.wide
+            {
+                emit_extend_a(ret.signed, line_no, out, source_file);
+            }
+        }
+        CExpr::Not(inner) => {
+            emit_cexpr_into_ax(inner, line_no, out, source_file);
+            for op in ["EOR #$FF", "PHA", "TXA", "EOR #$FF", "TAX", "PLA"] {
+                out.push((op.to_string(), source_file.to_path_buf(), line_no));
+            }
+        }
+        CExpr::Bin(lhs, op, rhs) => {
+            emit_cexpr_into_ax(lhs, line_no, out, source_file);
+            for op in ["PHA", "TXA", "PHA"] {
+                out.push((op.to_string(), source_file.to_path_buf(), line_no));
+            }
+            emit_cexpr_into_ax(rhs, line_no, out, source_file);
+            let mut push = |line: String| out.push((line, source_file.to_path_buf(), line_no));
+            push(format!("STA ${:02X}", C_EXPR_TMP_RHS));
+            push(format!("STX ${:02X}", C_EXPR_TMP_RHS_HI));
+            push("PLA".to_string());
+            push(format!("STA ${:02X}", C_EXPR_TMP_LHS_HI));
+            push("PLA".to_string());
+            push(format!("STA ${:02X}", C_EXPR_TMP_LHS));
+
+            let bytewise = |push: &mut dyn FnMut(String), setup: Option<&str>, mnemonic: &str| {
+                push(format!("LDA ${:02X}", C_EXPR_TMP_LHS));
+                if let Some(setup) = setup {
+                    push(setup.to_string());
+                }
+                push(format!("{} ${:02X}", mnemonic, C_EXPR_TMP_RHS));
+                push(format!("STA ${:02X}", C_EXPR_TMP_LHS));
+                push(format!("LDA ${:02X}", C_EXPR_TMP_LHS_HI));
+                push(format!("{} ${:02X}", mnemonic, C_EXPR_TMP_RHS_HI));
+                push("TAX".to_string());
+                push(format!("LDA ${:02X}", C_EXPR_TMP_LHS));
+            };
+            match op {
+                CBinOp::Add => bytewise(&mut push, Some("CLC"), "ADC"),
+                CBinOp::Sub => bytewise(&mut push, Some("SEC"), "SBC"),
+                CBinOp::And => bytewise(&mut push, None, "AND"),
+                CBinOp::Xor => bytewise(&mut push, None, "EOR"),
+                CBinOp::Or => bytewise(&mut push, None, "ORA"),
+                CBinOp::Mul => push("JSR CRTMUL16".to_string()),
+                CBinOp::Div | CBinOp::Mod => {
+                    if cexpr_is_signed(&[lhs, rhs]) {
+                        push("JSR CRTSDIV16".to_string());
+                    } else {
+                        push("JSR CRTDIV16".to_string());
+                    }
+                    let (lo, hi) = match op {
+                        CBinOp::Div => (C_EXPR_TMP_LHS, C_EXPR_TMP_LHS_HI),
+                        _ => (C_RUNTIME_TMP, C_RUNTIME_TMP + 1),
+                    };
+                    push(format!("LDA ${:02X}", lo));
+                    push(format!("LDX ${:02X}", hi));
+                }
+                CBinOp::Shl | CBinOp::Shr => {
+                    let loop_label = format!("CEXSHIFT{}_{}", line_no, out.len());
+                    let done_label = format!("CEXDONE{}_{}", line_no, out.len());
+                    let mut push =
+                        |line: String| out.push((line, source_file.to_path_buf(), line_no));
+                    push(format!("LDA ${:02X}", C_EXPR_TMP_RHS));
+                    push("AND #$0F".to_string());
+                    push(format!("STA ${:02X}", C_EXPR_TMP_CNT));
+                    push(format!("{}:", loop_label));
+                    push(format!("LDA ${:02X}", C_EXPR_TMP_CNT));
+                    push(format!("BEQ {}", done_label));
+                    if matches!(op, CBinOp::Shl) {
+                        push(format!("ASL ${:02X}", C_EXPR_TMP_LHS));
+                        push(format!("ROL ${:02X}", C_EXPR_TMP_LHS_HI));
+                    } else {
+                        if cexpr_is_signed(&[lhs]) {
+                            push(format!("LDA ${:02X}", C_EXPR_TMP_LHS_HI));
+                            push("CMP #$80".to_string());
+                            push(format!("ROR ${:02X}", C_EXPR_TMP_LHS_HI));
+                        } else {
+                            push(format!("LSR ${:02X}", C_EXPR_TMP_LHS_HI));
+                        }
+                        push(format!("ROR ${:02X}", C_EXPR_TMP_LHS));
+                    }
+                    push(format!("DEC ${:02X}", C_EXPR_TMP_CNT));
+                    push(format!("JMP {}", loop_label));
+                    push(format!("{}:", done_label));
+                    push(format!("LDA ${:02X}", C_EXPR_TMP_LHS));
+                    push(format!("LDX ${:02X}", C_EXPR_TMP_LHS_HI));
+                }
+            }
+        }
     }
-    Ok(CTerm::Imm(value as u8))
 }
 
-fn parse_u16_token(
-    token: &str,
-    vars: &HashMap<String, CVar>,
-    consts: &HashMap<String, u16>,
-) -> Result<u16, String> {
-    if vars.contains_key(token) {
-        return Err(format!(
-            "variable '{}' is not allowed in this expression",
-            token
-        ));
-    }
-    if let Some(v) = consts.get(token) {
-        return Ok(*v);
-    }
-    if let Some(hex) = token.strip_prefix("0x") {
-        return u16::from_str_radix(hex, 16)
-            .map_err(|_| format!("invalid hex literal '{}'", token));
-    }
-    if let Some(hex) = token.strip_prefix('$') {
-        return u16::from_str_radix(hex, 16)
-            .map_err(|_| format!("invalid hex literal '{}'", token));
+/// Why `name` cannot be assigned as a scalar variable.
+fn not_scalar_msg(vars: &HashMap<String, CSym>, name: &str) -> String {
+    match vars.get(name) {
+        Some(CSym::Array(_)) => format!("cannot assign to array '{}'", name),
+        Some(CSym::Struct(_)) => format!("cannot assign to struct '{}'", name),
+        _ => format!("unknown variable '{}'", name),
     }
-    token
-        .parse::<u16>()
-        .map_err(|_| format!("unknown token '{}'", token))
 }
 
-fn validate_ident(name: &str) -> Result<(), String> {
-    let mut chars = name.chars();
-    let Some(first) = chars.next() else {
-        return Err("empty identifier".to_string());
-    };
-    if !(first.is_ascii_alphabetic() || first == '_') {
-        return Err(format!("'{}' is not a valid identifier", name));
-    }
-    if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
-        return Err(format!("'{}' is not a valid identifier", name));
+/// The scalar or pointer variable called `name`.
+fn scalar_var(vars: &HashMap<String, CSym>, name: &str) -> Option<CVar> {
+    match vars.get(name) {
+        Some(CSym::Var(var)) => Some(*var),
+        _ => None,
     }
-    Ok(())
 }
 
 fn save_rgba_png(width: u32, height: u32, rgba: &[u8], path: &Path) -> Result<(), String> {
@@ -3404,3 +6018,1017 @@ pub fn scaffold_project(name: PathBuf, lang: ScaffoldLanguage) {
 
     println!("Created new Chipcade project at {}", root.display());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build `source` as the `main.c` of a new project, with and without `-O`, run `Init` and
+    /// check the bytes it leaves at `$2000`.
+    fn assert_c_writes(name: &str, source: &str, expected: &[u8]) {
+        for optimize in [false, true] {
+            let root = std::env::temp_dir().join(format!(
+                "chipcade-c-{}-{}-{}",
+                std::process::id(),
+                name,
+                optimize
+            ));
+            let _ = fs::remove_dir_all(&root);
+            scaffold_project(root.clone(), ScaffoldLanguage::C);
+            fs::write(root.join("src/main.c"), source).unwrap();
+            let machine = Machine::new(root.clone()).unwrap().with_optimize(optimize);
+            let mut session = machine
+                .start_debug_session()
+                .unwrap_or_else(|e| panic!("{}", e));
+            let update = session.label_address("Update").unwrap();
+            let mut steps = 0;
+            while session.peek_registers().pc != update {
+                session.step();
+                steps += 1;
+                assert!(steps < 1_000_000, "Init of {} does not finish", name);
+            }
+            let got = session.read_bytes(0x2000, expected.len());
+            let _ = fs::remove_dir_all(&root);
+            assert_eq!(got, expected, "{} (optimize: {})", name, optimize);
+        }
+    }
+
+    #[test]
+    fn c_functions_take_and_return_chars() {
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned char r;
+
+unsigned char Add(unsigned char a, unsigned char b) {
+    return a + b;
+}
+
+unsigned char Max3(unsigned char a, unsigned char b, unsigned char c) {
+    if (a > b) {
+        b = a;
+    }
+    if (c > b) {
+        return c;
+    }
+    return b;
+}
+
+void Store(unsigned char v) {
+    [0x2002] = v;
+}
+
+void Init() {
+    [0x2000] = Add(3, 4);
+    [0x2001] = Max3(5, 9, 2);
+    r = Add(1, 1);
+    Store(r);
+    [0x2003] = Max3(9, 5, 2);
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("char_functions", source, &[7, 9, 2, 9]);
+    }
+
+    #[test]
+    fn c_calls_nest_in_expressions() {
+        let source = r#"
+#include "include/chipcade.h"
+
+int w;
+
+unsigned char Twice(unsigned char v) {
+    return v + v;
+}
+
+int Wide(unsigned char v) {
+    return v * 100;
+}
+
+signed char Neg(unsigned char v) {
+    return -v;
+}
+
+void Init() {
+    [0x2000] = Twice(3) + Twice(Twice(1)) * 2;
+    if (Twice(2) == Twice(1) + 2) {
+        [0x2001] = 1;
+    }
+    w = Wide(3) + Wide(Twice(2));
+    [0x2002] = w;
+    [0x2003] = w >> 8;
+    if (Wide(5) > Wide(4)) {
+        [0x2004] = 1;
+    }
+    w = Neg(3);
+    [0x2005] = w >> 8;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("nested_calls", source, &[14, 1, 0xBC, 0x02, 1, 0xFF]);
+    }
+
+    /// The error building `main` as the body of `Init` reports.
+    fn c_build_error(name: &str, main: &str) -> String {
+        let root =
+            std::env::temp_dir().join(format!("chipcade-c-error-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        scaffold_project(root.clone(), ScaffoldLanguage::C);
+        let source = format!(
+            "unsigned char x;\nunsigned char t[4];\nunsigned char One(unsigned char a);\n\
+             void Init() {{\n{}\n}}\nvoid Update() {{\n}}\n",
+            main
+        );
+        fs::write(root.join("src/main.c"), source).unwrap();
+        let result = Machine::new(root.clone()).unwrap().start_debug_session();
+        let _ = fs::remove_dir_all(&root);
+        match result {
+            Ok(_) => panic!("{} should not build", name),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn c_expression_errors_point_at_the_bad_token() {
+        for (name, main, expected) in [
+            (
+                "unknown",
+                "    x = 1 + nope;",
+                "main.c:5:13: unknown identifier 'nope'",
+            ),
+            ("div", "    x = x / 0;", "main.c:5:11: division by zero"),
+            (
+                "bounds",
+                "    x = t[9];",
+                "main.c:5:11: index 9 is out of bounds for 't' (4 elements)",
+            ),
+            (
+                "args",
+                "    if (x == One(1, 2)) x = 0;",
+                "main.c:5:14: 'One' takes 1 argument(s), got 2",
+            ),
+        ] {
+            let e = c_build_error(name, main);
+            assert!(e.contains(expected), "{}: {}", name, e);
+        }
+    }
+
+    #[test]
+    fn c_ints_are_16_bit() {
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned int u;
+int w;
+int s;
+
+void Init() {
+    u = 0x1234;
+    u = u + 0x00FF;
+    [0x2000] = u;
+    [0x2001] = u >> 8;
+    w = 300;
+    w = w - 400;
+    [0x2002] = w;
+    [0x2003] = w >> 8;
+    u = 0xFFFF;
+    u++;
+    [0x2004] = u;
+    [0x2005] = u >> 8;
+    w = 0x00FF;
+    w++;
+    [0x2006] = w >> 8;
+    s = -5;
+    if (s < 10) {
+        [0x2007] = 1;
+    }
+    u = 60000;
+    if (u > 1000) {
+        [0x2008] = 1;
+    }
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("ints", source, &[0x33, 0x13, 0x9C, 0xFF, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn c_unary_minus() {
+        let source = r#"
+#include "include/chipcade.h"
+
+int d;
+int e;
+unsigned char x;
+unsigned char y;
+
+void Init() {
+    x = -1;
+    [0x2000] = x;
+    d = -300;
+    [0x2001] = d;
+    [0x2002] = d >> 8;
+    if (d <= -1) {
+        [0x2003] = 1;
+    }
+    y = 5;
+    x = -y;
+    [0x2004] = x;
+    e = -d;
+    [0x2005] = e;
+    [0x2006] = e >> 8;
+    x = 10 - -(y + 1);
+    [0x2007] = x;
+    if (-d > 0) {
+        [0x2008] = 1;
+    }
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes(
+            "unary_minus",
+            source,
+            &[0xFF, 0xD4, 0xFE, 1, 0xFB, 0x2C, 0x01, 16, 1],
+        );
+    }
+
+    #[test]
+    fn c_signed_char_comparisons_are_signed() {
+        let source = r#"
+#include "include/chipcade.h"
+
+signed char c;
+signed char d;
+unsigned char a;
+unsigned char b;
+
+void Init() {
+    c = -1;
+    d = 3;
+    a = 1;
+    b = 200;
+    if (c < 0) {
+        [0x2000] = 1;
+    }
+    if (c < a) {
+        [0x2001] = 1;
+    }
+    if (c == 255) {
+        [0x2002] = 1;
+    }
+    if (c == -1) {
+        [0x2003] = 1;
+    }
+    if (c < d) {
+        [0x2004] = 1;
+    }
+    if (b > a) {
+        [0x2005] = 1;
+    }
+    if (d >= c && a > c) {
+        [0x2006] = 1;
+    }
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("signed_compare", source, &[1, 1, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn c_arrays_pointers_and_address_of() {
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned char table[8];
+signed char deltas[4];
+unsigned char count;
+unsigned char sum;
+unsigned int total;
+int neg;
+unsigned char *p;
+unsigned char *q;
+unsigned char eq;
+
+unsigned char Sum(unsigned char *buf, unsigned char n) {
+    unsigned char i = 0;
+    unsigned char s = 0;
+    while (i < n) {
+        s = s + buf[i];
+        i++;
+    }
+    return s;
+}
+
+void Init() {
+    unsigned char i = 0;
+    while (i < 8) {
+        table[i] = i + 1;
+        i++;
+    }
+    deltas[2] = -5;
+    neg = deltas[2];
+    p = table;
+    *p = 10;
+    p++;
+    *p = 20;
+    p[2] = 30;
+    sum = Sum(table, 8);
+    q = &table[7];
+    eq = 0;
+    if (q > p) {
+        eq = 1;
+    }
+    p = &count;
+    *p = 99;
+    total = table[7] + 300;
+    [0x2000] = table[0];
+    [0x2001] = table[1];
+    [0x2002] = table[3];
+    [0x2003] = sum;
+    [0x2004] = count;
+    [0x2005] = eq;
+    [0x2006] = neg;
+    [0x2007] = total;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes(
+            "arrays",
+            source,
+            &[0x0A, 0x14, 0x1E, 0x59, 0x63, 0x01, 0xFB, 0x34],
+        );
+    }
+
+    #[test]
+    fn c_global_initializers_and_const_tables() {
+        let source = r#"
+#include "include/chipcade.h"
+
+const unsigned char LEVEL_LEN = 6;
+const unsigned char level1[] = {
+    1, 2, 3,   // row 0
+    4, 5, 0xFF,
+};
+const signed char wave[8] = { 0, 3, -3, -128 };
+unsigned char buf[4] = { 9, 8 };
+unsigned char lives = 3;
+signed char dir = -1;
+int score = -300;
+unsigned int big = 0xBEEF;
+unsigned char *cursor = &buf[1];
+unsigned char *start = level1;
+unsigned char total;
+int w;
+
+void Init() {
+    unsigned char i = 0;
+    total = 0;
+    while (i < LEVEL_LEN) {
+        total = total + level1[i];
+        i++;
+    }
+    w = wave[3];
+    [0x2000] = lives;
+    [0x2001] = dir;
+    [0x2002] = total;
+    [0x2003] = *cursor;
+    [0x2004] = start[5];
+    [0x2005] = buf[0];
+    [0x2006] = wave[2];
+    [0x2007] = w >> 8;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes(
+            "initializers",
+            source,
+            &[0x03, 0xFF, 0x0E, 0x08, 0xFF, 0x09, 0xFD, 0xFF],
+        );
+    }
+
+    #[test]
+    fn c_structs_and_field_access() {
+        let source = r#"
+#include "include/chipcade.h"
+
+struct Enemy {
+    unsigned char x;
+    unsigned char y;
+    int vx;
+    signed char hp;
+};
+
+typedef struct Point {
+    unsigned char x, y;
+} Point;
+
+struct Enemy enemies[8];
+struct Enemy boss;
+Point big[100];
+ChipSprite shadow[2];
+unsigned char i;
+unsigned int k;
+int sum;
+
+void Init() {
+    i = 0;
+    while (i < 8) {
+        enemies[i].x = i + 10;
+        enemies[i].vx = -100;
+        enemies[i].hp = 3;
+        i++;
+    }
+    enemies[2].vx = 1000;
+    enemies[3].hp--;
+    enemies[4].vx++;
+    i = 2;
+    sum = enemies[i].vx + enemies[3].hp;
+    boss.vx = 0x1234;
+    boss.x = enemies[7].x;
+    boss.vx++;
+    i = 99;
+    big[i].y = 42;
+    k = 99;
+    big[k].x = 7;
+    big[k].x++;
+    shadow[1].tile = 5;
+    sprite[0].x = shadow[1].tile;
+    if (enemies[0].vx < 0) {
+        [0x2006] = 1;
+    }
+    [0x2000] = enemies[5].x;
+    [0x2001] = enemies[3].hp;
+    [0x2002] = boss.x;
+    [0x2003] = big[99].y;
+    [0x2004] = big[99].x;
+    [0x2005] = sprite[0].x;
+    [0x2007] = enemies[4].vx;
+    [0x2008] = sum;
+    [0x2009] = boss.vx;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes(
+            "structs_1",
+            source,
+            &[0x0F, 0x02, 0x11, 0x2A, 0x08, 0x05, 0x01, 0x9D, 0xEA, 0x35],
+        );
+        let source = r#"
+#include "include/chipcade.h"
+
+struct Enemy {
+    unsigned char x, y;
+    int vx;
+    signed char hp;
+};
+
+struct Enemy enemies[8];
+struct Enemy boss;
+unsigned char *p;
+unsigned char i;
+
+void Init() {
+    i = 3;
+    enemies[i].x = 40;
+    enemies[i].x = enemies[i].x + 1;
+    p = &enemies[3];
+    [0x2000] = *p;
+    p = &enemies[3].y;
+    *p = 7;
+    [0x2001] = enemies[3].y;
+    p = &boss;
+    p[4] = 9;
+    [0x2002] = boss.hp;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("structs_2", source, &[0x29, 0x07, 0x09]);
+    }
+
+    #[test]
+    fn c_conditions_with_and_or_not() {
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned char x;
+unsigned char y;
+unsigned char flag;
+int w;
+unsigned char *p;
+unsigned char r;
+
+void Init() {
+    x = 12;
+    y = 15;
+    flag = 0;
+    w = 256;
+    p = 0;
+    r = 0;
+    if (x > 10 && y < 20) {
+        r = r + 1;
+    }
+    if (x > 10 && y > 20) {
+        r = r + 2;
+    }
+    [0x2000] = r;
+    r = 0;
+    if (x < 10 || y == 15) {
+        r = r + 1;
+    }
+    if (x < 10 || y != 15) {
+        r = r + 2;
+    }
+    if (!flag) {
+        r = r + 4;
+    }
+    if (flag) {
+        r = r + 8;
+    }
+    if (w) {
+        r = r + 16;
+    }
+    if (p) {
+        r = r + 32;
+    }
+    if (!(x == 12 && (y == 1 || y == 15))) {
+        r = r + 64;
+    }
+    if ((x == 12 && y == 1) || !(flag || x != 12)) {
+        r = r + 128;
+    }
+    [0x2001] = r;
+    r = 0;
+    while (x && y != 20) {
+        x = x - 1;
+        y = y + 1;
+        r = r + 1;
+    }
+    [0x2002] = r;
+    r = 0;
+    if (x != 7 || !(y == 20) && flag) {
+        r = 1;
+    }
+    if ((x << 1) > 13 && !!y) {
+        r = r + 2;
+    }
+    [0x2003] = r;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("logic", source, &[0x01, 0x95, 0x05, 0x02]);
+    }
+
+    #[test]
+    fn c_switch_break_and_continue() {
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned char state;
+unsigned char i;
+unsigned char r;
+unsigned char n;
+int w;
+
+void Step() {
+    switch (state) {
+    case 0:
+        r = r + 1;
+        state = 1;
+        break;
+    case 1:
+        r = r + 16;
+        state = 2;
+        break;
+    case 2:
+    case 3:
+        r = r + 64;
+        state = 0;
+        break;
+    default:
+        r = 0;
+    }
+}
+
+void Init() {
+    r = 0;
+    state = 0;
+    Step();
+    Step();
+    Step();
+    [0x2000] = r;
+    state = 9;
+    Step();
+    [0x2001] = r;
+    n = 0;
+    for (i = 0; i < 10; i++) {
+        if (i == 3) {
+            continue;
+        }
+        if (i == 7) {
+            break;
+        }
+        n = n + i;
+    }
+    [0x2002] = n;
+    n = 0;
+    i = 0;
+    while (1) {
+        i = i + 1;
+        if (i > 5) {
+            break;
+        }
+        switch (i) {
+        case 2:
+            continue;
+        case 200:
+            n = n + 100;
+        case 4:
+            n = n + 10;
+            break;
+        }
+        n = n + 1;
+    }
+    [0x2003] = n;
+    w = 1000;
+    r = 0;
+    switch (w) {
+    case 1000:
+        r = 5;
+    case -1:
+        r = r + 1;
+        break;
+    default:
+        r = 99;
+    }
+    [0x2004] = r;
+    r = 0;
+    for (i = 97; i <= 103; i++) {
+        switch (i) {
+        case 98:
+            r = r + 1;
+            break;
+        case 99:
+            r = r + 2;
+            break;
+        case 101:
+            r = r + 4;
+            break;
+        case 102:
+            r = r + 8;
+            break;
+        default:
+            r = r + 32;
+        }
+    }
+    [0x2005] = r;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("switch", source, &[0x51, 0x00, 0x12, 0x0E, 0x06, 0x6F]);
+    }
+
+    #[test]
+    fn c_multiply_divide_and_modulo() {
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned char a;
+unsigned char b;
+signed char sa;
+signed char sb;
+int w;
+int v;
+unsigned int u;
+unsigned char row;
+unsigned char col;
+unsigned char *p;
+unsigned char buf[4];
+const unsigned char TILE = 8;
+
+void Init() {
+    a = 13;
+    b = 5;
+    [0x2000] = a * b;
+    [0x2001] = a / b;
+    [0x2002] = a % b;
+    sa = -13;
+    sb = 5;
+    [0x2003] = sa / sb;
+    [0x2004] = sa % sb;
+    w = -1000;
+    v = 7;
+    w = w / v;
+    [0x2005] = w;
+    [0x2006] = w >> 8;
+    w = -1000;
+    w = w % v;
+    [0x2007] = w;
+    w = 300;
+    v = 250;
+    u = w * v;
+    [0x2008] = u;
+    [0x2009] = u >> 8;
+    u = 60000;
+    u = u / 7;
+    [0x200A] = u;
+    [0x200B] = u >> 8;
+    u = 60000;
+    u = u % 40000;
+    [0x200C] = u;
+    [0x200D] = u >> 8;
+    row = 3;
+    col = 5;
+    w = row * 320 + col * TILE;
+    [0x200E] = w;
+    [0x200F] = w >> 8;
+    [0x2010] = a % 4 + a / 4 * 16;
+    w = 0 - 9;
+    w = w / 4;
+    [0x2011] = w;
+    [0x2012] = 200 / 7 * 3;
+    buf[2] = 6;
+    p = &buf[2];
+    [0x2013] = *p * *p;
+    [0x2014] = 250 / b;
+    a = 200;
+    b = 150;
+    [0x2015] = a / b;
+    [0x2016] = a % b;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes(
+            "muldiv_1",
+            source,
+            &[
+                0x41, 0x02, 0x03, 0xFE, 0xFD, 0x72, 0xFF, 0xFA, 0xF8, 0x24, 0x7B, 0x21, 0x20, 0x4E,
+                0xE8, 0x03, 0x31, 0xFE, 0x54, 0x24, 0x32, 0x01, 0x32,
+            ],
+        );
+        let source = r#"
+#include "include/chipcade.h"
+
+unsigned char a;
+unsigned char b;
+unsigned char r;
+int w;
+int v;
+
+void Init() {
+    a = 20;
+    b = 6;
+    r = 0;
+    if (3 == a / b) {
+        r = r + 1;
+    }
+    if (a % b == 2 && a - b * 3 == 2) {
+        r = r + 2;
+    }
+    w = 1000;
+    v = 3;
+    if (w / v == 333) {
+        r = r + 4;
+    }
+    if (w - 1 > v * 333) {
+        r = r + 8;
+    }
+    [0x2000] = r;
+    [0x2001] = a + b * 2;
+    [0x2002] = 1 + a / b;
+    [0x2003] = a - (b - 1) * (b + 1);
+    w = v * (w / 10) + w % 7;
+    [0x2004] = w;
+    [0x2005] = w >> 8;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("muldiv_2", source, &[0x07, 0x20, 0x04, 0xF1, 0x32, 0x01]);
+    }
+
+    #[test]
+    fn c_inline_asm_blocks() {
+        let source = r#"
+#include "include/chipcade.h"
+
+#define OUT 0x2000
+
+typedef struct {
+    unsigned char x;
+    unsigned char y;
+} Point;
+
+const unsigned char STEP = 3;
+unsigned char total;
+unsigned int wide;
+Point p;
+
+unsigned char Twice(unsigned char v) {
+    unsigned char r;
+    asm {
+        LDA v
+        ASL A
+        STA r      // C comments still work
+    }
+    return r;
+}
+
+void Init() {
+    unsigned char i = 0;
+    total = 0;
+    wide = 0x1234;
+    p.y = 9;
+    asm
+    {
+        LDX #0
+    @loop:
+        LDA total; CLC; ADC #STEP; STA total
+        INX
+        CPX #4
+        BNE @loop
+        STX i
+    }
+    __asm__("LDA total\n"
+            "STA $2000");
+    __asm__ volatile ("LDA i; STA $2001");
+    asm { LDA wide+1
+          STA OUT+2 }
+    asm { LDA p.y
+          STA OUT+3 }
+    total = Twice(21);
+    [OUT + 4] = total;
+}
+
+void Update() {
+}
+"#;
+        assert_c_writes("inline_asm", source, &[0x0C, 0x04, 0x12, 0x09, 0x2A]);
+    }
+
+    /// Build a scaffolded ASM project with `main` appended to its `main.asm` and the other
+    /// `files` written under `src/`.
+    fn build_asm_project(
+        name: &str,
+        main: &str,
+        files: &[(&str, &str)],
+    ) -> Result<BuildArtifacts, String> {
+        let root = std::env::temp_dir().join(format!("chipcade-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        scaffold_project(root.clone(), ScaffoldLanguage::Asm);
+        for (path, text) in files {
+            fs::write(root.join("src").join(path), text).unwrap();
+        }
+        let mut source = fs::read_to_string(root.join("src/main.asm")).unwrap();
+        source.push_str(main);
+        fs::write(root.join("src/main.asm"), source).unwrap();
+        let artifacts = Machine::new(root.clone()).and_then(|machine| machine.build_silent());
+        let _ = fs::remove_dir_all(&root);
+        artifacts
+    }
+
+    #[test]
+    fn asm_includes_in_skipped_blocks_are_not_linked() {
+        let main = "\n.ifdef DEBUG\n.include \"debug.asm\"\n.endif\n";
+        let debug = [("debug.asm", "DebugThing: RTS\n")];
+        let artifacts = build_asm_project("ifdef-include", main, &debug).unwrap();
+        assert!(!artifacts.labels.contains_key("DebugThing"));
+
+        let main = format!(".const DEBUG 1{}", main);
+        let artifacts = build_asm_project("ifdef-include-debug", &main, &debug).unwrap();
+        assert!(artifacts.labels.contains_key("DebugThing"));
+    }
+
+    #[test]
+    fn asm_headers_with_data_are_assembled_once() {
+        let main = "\n.include \"tables.inc\"\n.include \"reader.asm\"\n";
+        let files = [
+            ("tables.inc", "Tbl: .byte 1,2,3\n"),
+            ("reader.asm", "ReadTbl: LDA Tbl\n    RTS\n"),
+        ];
+        let artifacts = build_asm_project("header-data", main, &files).unwrap();
+        assert!(artifacts.labels.contains_key("Tbl"));
+        assert!(artifacts.labels.contains_key("ReadTbl"));
+    }
+
+    #[test]
+    fn asm_includes_share_macros_and_constants() {
+        let main = "
+.const STEP 2
+.macro SETA value
+    LDA #value
+.endm
+.include \"shared.asm\"
+Twice: DOUBLE
+";
+        let shared = "
+.if STEP > 1
+Step: SETA STEP
+.endif
+    .fill STEP, $EA
+    RTS
+.macro DOUBLE
+    ASL A
+.endm
+";
+        let artifacts =
+            build_asm_project("shared-macros", main, &[("shared.asm", shared)]).unwrap();
+        let step = artifacts.labels["Step"];
+        let offset = (step - artifacts.load_addr) as usize;
+        assert_eq!(
+            artifacts.program[offset..offset + 5],
+            [0xA9, 0x02, 0xEA, 0xEA, 0x60]
+        );
+        let twice = (artifacts.labels["Twice"] - artifacts.load_addr) as usize;
+        assert_eq!(artifacts.program[twice], 0x0A);
+    }
+
+    #[test]
+    fn code_running_off_its_end_halts_before_segments() {
+        let root = std::env::temp_dir().join(format!("chipcade-halt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        scaffold_project(root.clone(), ScaffoldLanguage::Asm);
+        // Without the halt byte, the run would go on into the data as `LDA #$42 / STA $2001`.
+        fs::write(
+            root.join("src/main.asm"),
+            "Init: LDA #1\nSTA $2000\n.segment DATA\n.byte $A9,$42,$8D,$01,$20\n",
+        )
+        .unwrap();
+        let machine = Machine::new(root.clone()).unwrap();
+        let session = machine.start_debug_session();
+        let _ = fs::remove_dir_all(&root);
+        let mut session = session.unwrap_or_else(|e| panic!("{}", e));
+
+        let mut stop_reason = None;
+        for _ in 0..10 {
+            stop_reason = session.step().stop_reason;
+            if stop_reason.is_some() {
+                break;
+            }
+        }
+        assert_eq!(stop_reason.as_deref(), Some("HALT"));
+        assert_eq!(session.read_bytes(0x2000, 2), [0x01, 0x00]);
+    }
+
+    #[test]
+    fn listing_shows_incbin_lines_as_written() {
+        let root = std::env::temp_dir().join(format!("chipcade-incbin-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        scaffold_project(root.clone(), ScaffoldLanguage::Asm);
+        fs::create_dir_all(root.join("src/data")).unwrap();
+        fs::write(root.join("src/data/blob.bin"), [1, 2, 3, 4, 5]).unwrap();
+        fs::write(
+            root.join("src/level.asm"),
+            "Blob: .incbin \"data/blob.bin\", 1, 3 ; level data\n",
+        )
+        .unwrap();
+        let mut main = fs::read_to_string(root.join("src/main.asm")).unwrap();
+        main.push_str("\n.include \"level.asm\"\n");
+        fs::write(root.join("src/main.asm"), main).unwrap();
+
+        let machine = Machine::new(root.clone()).unwrap();
+        let artifacts = machine.build_silent();
+        let listing = artifacts
+            .as_ref()
+            .map(|artifacts| render_listing(artifacts, &root, machine.cpu));
+        let _ = fs::remove_dir_all(&root);
+        let listing = listing.unwrap_or_else(|e| panic!("{}", e));
+
+        let rows: Vec<&str> = listing
+            .lines()
+            .filter(|l| l.contains("level.asm:"))
+            .collect();
+        assert_eq!(rows.len(), 1, "{}", listing);
+        assert!(rows[0].contains(" 02 03 04 "), "{}", rows[0]);
+        assert!(
+            rows[0].ends_with("Blob: .incbin \"data/blob.bin\", 1, 3 ; level data"),
+            "{}",
+            rows[0]
+        );
+        assert!(!listing.contains(".byte $02"), "{}", listing);
+    }
+}
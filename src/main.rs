@@ -1,10 +1,16 @@
 mod asm6502;
 mod bus;
+mod c_parser;
 mod config;
+mod cpu;
 mod display;
 mod eval;
 mod machine;
+mod objects;
+mod peephole;
+mod preprocessor;
 mod sprites;
+mod symbols;
 
 #[cfg(not(target_arch = "wasm32"))]
 use clap::{Parser, Subcommand};
@@ -48,6 +54,9 @@ enum Commands {
         /// Scale factor for rendering/output (default: 3)
         #[arg(long, default_value_t = 3)]
         scale: u32,
+        /// Define a build constant for `.if`/`.ifdef` (repeatable; VALUE defaults to 1)
+        #[arg(long = "define", short = 'D', value_name = "NAME[=VALUE]", value_parser = parse_define)]
+        defines: Vec<(String, u16)>,
     },
     /// Launch the UI-based editor
     Edit {
@@ -85,6 +94,24 @@ enum Commands {
         /// Project root (contains chipcade.toml, src/, build/, etc.)
         #[arg(default_value = ".")]
         project: PathBuf,
+        /// Define a build constant for `.if`/`.ifdef` (repeatable; VALUE defaults to 1)
+        #[arg(long = "define", short = 'D', value_name = "NAME[=VALUE]", value_parser = parse_define)]
+        defines: Vec<(String, u16)>,
+        /// Run the peephole optimizer over the assembly transpiled from C
+        #[arg(short = 'O', long = "optimize")]
+        optimize: bool,
+    },
+    /// Disassemble a built image using its embedded labels
+    Disasm {
+        /// Built 64 KB image
+        #[arg(default_value = "build/program.bin")]
+        image: PathBuf,
+        /// Label or address to start at (default: program start; all blocks without a range)
+        #[arg(long, value_name = "ADDR")]
+        from: Option<String>,
+        /// Number of bytes to disassemble (default: 256 with --from)
+        #[arg(long, value_name = "N", value_parser = parse_address)]
+        len: Option<u16>,
     },
     /// Interactive debugger REPL (step, registers, memory)
     Repl {
@@ -97,6 +124,9 @@ enum Commands {
         /// Keep preview window in normal stacking order (topmost is default).
         #[arg(long, default_value_t = false)]
         no_topmost: bool,
+        /// Define a build constant for `.if`/`.ifdef` (repeatable; VALUE defaults to 1)
+        #[arg(long = "define", short = 'D', value_name = "NAME[=VALUE]", value_parser = parse_define)]
+        defines: Vec<(String, u16)>,
     },
     /// Build current project and run it in browser via wasm
     Wasm {
@@ -144,35 +174,61 @@ fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Run { project, scale } => match Machine::new(project) {
-            Ok(machine) => match machine.build() {
-                Ok(artifacts) => {
-                    let producer = crate::display::FrameProducer::new(machine, artifacts);
-                    let backend = crate::display::winit_softbuffer::WinitSoftbufferBackend;
-                    if let Err(e) = crate::display::DisplayBackend::run(backend, producer, scale) {
-                        eprintln!("{e}");
+        Commands::Run {
+            project,
+            scale,
+            defines,
+        } => match Machine::new(project) {
+            Ok(machine) => {
+                let machine = machine.with_defines(defines);
+                match machine.build() {
+                    Ok(artifacts) => {
+                        let producer = crate::display::FrameProducer::new(machine, artifacts);
+                        let backend = crate::display::winit_softbuffer::WinitSoftbufferBackend;
+                        if let Err(e) =
+                            crate::display::DisplayBackend::run(backend, producer, scale)
+                        {
+                            eprintln!("{e}");
+                        }
                     }
+                    Err(e) => eprintln!("{e}"),
                 }
-                Err(e) => eprintln!("{e}"),
-            },
+            }
             Err(e) => eprintln!("{e}"),
         },
-        Commands::Build { project } => match Machine::new(project) {
-            Ok(machine) => match machine.build() {
-                Ok(_) => println!("Build finished: {}", machine.program_bin_path().display()),
-                Err(e) => eprintln!("{e}"),
-            },
+        Commands::Build {
+            project,
+            defines,
+            optimize,
+        } => match Machine::new(project) {
+            Ok(machine) => {
+                let machine = machine.with_defines(defines).with_optimize(optimize);
+                match machine.build() {
+                    Ok(_) => println!("Build finished: {}", machine.program_bin_path().display()),
+                    Err(e) => eprintln!("{e}"),
+                }
+            }
             Err(e) => eprintln!("{e}"),
         },
+        Commands::Disasm { image, from, len } => match std::fs::read(&image) {
+            Ok(bytes) => {
+                match Machine::disassemble_image(&bytes, from.as_deref(), len.map(usize::from)) {
+                    Ok(text) => print!("{text}"),
+                    Err(e) => eprintln!("{}: {e}", image.display()),
+                }
+            }
+            Err(e) => eprintln!("Failed to read {}: {e}", image.display()),
+        },
         Commands::Repl {
             project,
             no_preview,
             no_topmost,
+            defines,
         } => {
             if no_preview {
-                run_repl(project);
+                run_repl(project, defines);
             } else {
-                run_repl_with_preview(project, !no_topmost);
+                run_repl_with_preview(project, defines, !no_topmost);
             }
         }
         Commands::Wasm {
@@ -270,13 +326,13 @@ fn run_wasm(project: PathBuf, build_only: bool) -> Result<(), String> {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn run_repl(project: PathBuf) {
+fn run_repl(project: PathBuf, defines: Vec<(String, u16)>) {
     use rustyline::DefaultEditor;
     use rustyline::error::ReadlineError;
 
     let history_path = repl_history_path(&project);
     let machine = match Machine::new(project) {
-        Ok(m) => m,
+        Ok(m) => m.with_defines(defines),
         Err(e) => {
             eprintln!("{e}");
             return;
@@ -331,6 +387,7 @@ fn run_repl(project: PathBuf) {
                 println!("  run [n]                  Run until stop (default cap 1000000)");
                 println!("  rts                      Run until RTS/stop");
                 println!("  mem <addr> [len]         Dump memory bytes");
+                println!("  disasm [addr] [n]        Disassemble n instructions (default PC, 8)");
                 println!("  quit | exit              Exit REPL");
             }
             "build" => match machine.build() {
@@ -341,21 +398,21 @@ fn run_repl(project: PathBuf) {
                 Ok(s) => {
                     session = Some(s);
                     println!("Debug session ready.");
-                    if let Some(s) = session.as_ref() {
+                    if let Some(s) = session.as_mut() {
                         print_step(s, None);
                     }
                 }
                 Err(e) => eprintln!("{e}"),
             },
             "regs" => {
-                if let Some(s) = session.as_ref() {
+                if let Some(s) = session.as_mut() {
                     print_step(s, None);
                 } else {
                     println!("No debug session. Use `debug` first.");
                 }
             }
             "line" => {
-                if let Some(s) = session.as_ref() {
+                if let Some(s) = session.as_mut() {
                     print_step(s, None);
                 } else {
                     println!("No debug session. Use `debug` first.");
@@ -482,6 +539,13 @@ fn run_repl(project: PathBuf) {
                     println!("No debug session. Use `debug` first.");
                 }
             }
+            "disasm" => {
+                if let Some(s) = session.as_mut() {
+                    disasm_command(s, parts);
+                } else {
+                    println!("No debug session. Use `debug` first.");
+                }
+            }
             "quit" | "exit" => break,
             _ => println!("Unknown command `{}`. Type `help`.", cmd),
         }
@@ -490,7 +554,7 @@ fn run_repl(project: PathBuf) {
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn run_repl_with_preview(project: PathBuf, topmost: bool) {
+fn run_repl_with_preview(project: PathBuf, defines: Vec<(String, u16)>, topmost: bool) {
     use rustyline::DefaultEditor;
     use rustyline::error::ReadlineError;
     use softbuffer::{Context, Surface};
@@ -505,7 +569,7 @@ fn run_repl_with_preview(project: PathBuf, topmost: bool) {
 
     let history_path = repl_history_path(&project);
     let machine = match Machine::new(project) {
-        Ok(m) => m,
+        Ok(m) => m.with_defines(defines),
         Err(e) => {
             eprintln!("{e}");
             return;
@@ -596,6 +660,7 @@ fn run_repl_with_preview(project: PathBuf, topmost: bool) {
                 println!("  run [n]                  Run until stop (default cap 1000000)");
                 println!("  rts                      Run until RTS/stop");
                 println!("  mem <addr> [len]         Dump memory bytes");
+                println!("  disasm [addr] [n]        Disassemble n instructions (default PC, 8)");
                 println!("  quit | exit              Exit REPL + preview");
             }
             "build" => match machine.build() {
@@ -607,21 +672,21 @@ fn run_repl_with_preview(project: PathBuf, topmost: bool) {
                     send_frame(machine, &s, evt_tx);
                     *session = Some(s);
                     println!("Debug session ready.");
-                    if let Some(s) = session.as_ref() {
+                    if let Some(s) = session.as_mut() {
                         print_step(s, None);
                     }
                 }
                 Err(e) => eprintln!("{e}"),
             },
             "regs" => {
-                if let Some(s) = session.as_ref() {
+                if let Some(s) = session.as_mut() {
                     print_step(s, None);
                 } else {
                     println!("No debug session. Use `debug` first.");
                 }
             }
             "line" => {
-                if let Some(s) = session.as_ref() {
+                if let Some(s) = session.as_mut() {
                     print_step(s, None);
                 } else {
                     println!("No debug session. Use `debug` first.");
@@ -762,6 +827,13 @@ fn run_repl_with_preview(project: PathBuf, topmost: bool) {
                     println!("No debug session. Use `debug` first.");
                 }
             }
+            "disasm" => {
+                if let Some(s) = session.as_mut() {
+                    disasm_command(s, parts);
+                } else {
+                    println!("No debug session. Use `debug` first.");
+                }
+            }
             _ => println!("Unknown command `{}`. Type `help`.", cmd),
         }
         false
@@ -1072,6 +1144,29 @@ fn run_repl_with_preview(project: PathBuf, topmost: bool) {
     }
 }
 
+/// Parse a `--define NAME[=VALUE]` argument; the value accepts the same notation as `eval`.
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_define(arg: &str) -> Result<(String, u16), String> {
+    let (name, value) = arg.split_once('=').unwrap_or((arg, "1"));
+    let name = name.trim();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(format!("invalid define name '{name}'"));
+    }
+    let value = eval_expression(value)?.value;
+    let value = u16::try_from(value)
+        .map_err(|_| format!("define {name}={value} does not fit in 16 bits"))?;
+    Ok((name.to_string(), value))
+}
+
+/// Parse an address or length given as a hex/dec/bin expression, e.g. `$0200`.
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_address(arg: &str) -> Result<u16, String> {
+    let value = eval_expression(arg)?.value;
+    u16::try_from(value).map_err(|_| format!("{value} does not fit in 16 bits"))
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 fn parse_usize(tok: Option<&str>, default: usize) -> usize {
     match tok {
@@ -1102,7 +1197,10 @@ fn parse_addr(token: &str, session: &crate::machine::DebugSession) -> Result<u16
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn print_step(session: &crate::machine::DebugSession, step: Option<&crate::machine::DebugStep>) {
+fn print_step(
+    session: &mut crate::machine::DebugSession,
+    step: Option<&crate::machine::DebugStep>,
+) {
     let regs = session.peek_registers();
     println!("\x1b[1;36mregs:\x1b[0m");
     println!(
@@ -1121,8 +1219,15 @@ fn print_step(session: &crate::machine::DebugSession, step: Option<&crate::machi
 
     println!("\x1b[1;36masm:\x1b[0m");
     let asm_window = session.peek_asm_window(3);
-    if asm_window.is_empty() {
-        println!("\t(no asm mapping)");
+    if asm_window.is_empty() || session.pc_code_modified() {
+        let reason = if asm_window.is_empty() {
+            "no asm mapping"
+        } else {
+            "code changed since build"
+        };
+        println!("\t({reason}; disassembled from memory)");
+        let pc = regs.pc;
+        print_disassembly(session, pc, 4, pc);
     } else {
         for line in asm_window {
             let marker = if line.is_current {
@@ -1187,6 +1292,46 @@ fn is_update_frame_boundary(
     session.read_byte(step.registers.pc) == 0x00
 }
 
+/// REPL `disasm [addr] [n]`: disassemble live memory, defaulting to the PC.
+#[cfg(not(target_arch = "wasm32"))]
+fn disasm_command<'a>(
+    session: &mut crate::machine::DebugSession,
+    mut args: impl Iterator<Item = &'a str>,
+) {
+    let addr = match args.next() {
+        Some(tok) => match parse_addr(tok, session) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("{e}");
+                return;
+            }
+        },
+        None => session.peek_registers().pc,
+    };
+    let count = parse_usize(args.next(), 8).clamp(1, 256);
+    let pc = session.peek_registers().pc;
+    print_disassembly(session, addr, count, pc);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn print_disassembly(session: &mut crate::machine::DebugSession, addr: u16, count: usize, pc: u16) {
+    for line in session.disassemble_at(addr, count) {
+        let marker = if line.addr == pc {
+            "\x1b[1;32m>\x1b[0m"
+        } else {
+            " "
+        };
+        let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        println!(
+            "\t{} {:04X}  {:<8}  {}",
+            marker,
+            line.addr,
+            bytes.join(" "),
+            colorize_asm_line(&line.text)
+        );
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 fn dump_mem(session: &mut crate::machine::DebugSession, addr: u16, len: usize) {
     let bytes = session.read_bytes(addr, len);
@@ -1222,9 +1367,7 @@ fn main() {
 
 #[cfg(target_arch = "wasm32")]
 fn run_wasm_player() -> Result<(), String> {
-    use crate::bus::ChipcadeBus;
-    use mos6502::cpu;
-    use mos6502::instruction::Nmos6502;
+    use crate::cpu::Cpu;
     use mos6502::memory::Bus;
     use std::cell::RefCell;
     use std::rc::Rc;
@@ -1244,26 +1387,26 @@ fn run_wasm_player() -> Result<(), String> {
         }
     }
 
-    fn run_frame_cpu(cpu: &mut cpu::CPU<ChipcadeBus, Nmos6502>, entry_point: u16) -> Vec<u8> {
-        cpu.registers.program_counter = entry_point;
+    fn run_frame_cpu(cpu: &mut Cpu, entry_point: u16) -> Vec<u8> {
+        cpu.registers_mut().program_counter = entry_point;
         let mut steps: u64 = 0;
         loop {
             if steps >= 1_000_000 {
                 break;
             }
-            let pc = cpu.registers.program_counter;
-            let opcode = cpu.memory.get_byte(pc);
+            let pc = cpu.registers().program_counter;
+            let opcode = cpu.memory_mut().get_byte(pc);
             if opcode == 0x00 || opcode == 0xFF {
                 break;
             }
             cpu.single_step();
             steps += 1;
         }
-        cpu.memory.render_frame_rgba()
+        cpu.memory().render_frame_rgba()
     }
 
     struct WasmState {
-        cpu: cpu::CPU<ChipcadeBus, Nmos6502>,
+        cpu: Cpu,
         update_entry: u16,
         ctx: web_sys::CanvasRenderingContext2d,
         width: u32,
@@ -1276,6 +1419,7 @@ fn run_wasm_player() -> Result<(), String> {
     let mut cpu = machine.create_cpu(
         &artifacts.program,
         &artifacts.sprites,
+        &artifacts.segments,
         artifacts.entry_point,
     )?;
 
@@ -1373,7 +1517,7 @@ fn run_wasm_player() -> Result<(), String> {
     let tick = Closure::<dyn FnMut()>::new(move || {
         let mut state = tick_state.borrow_mut();
         let bits = *tick_input_bits.borrow();
-        state.cpu.memory.set_input_state(bits);
+        state.cpu.memory_mut().set_input_state(bits);
         let update_entry = state.update_entry;
         let rgba = run_frame_cpu(&mut state.cpu, update_entry);
         let img = web_sys::ImageData::new_with_u8_clamped_array_and_sh(
@@ -0,0 +1,93 @@
+use crate::asm6502::{CpuKind, Object};
+use crate::machine::LineOrigin;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const OBJECT_MAGIC: [u8; 4] = *b"CHPO";
+/// Bumped whenever the layout of `ObjectFile` changes, so stale objects are rebuilt.
+const OBJECT_VERSION: u32 = 3;
+
+/// An assembled unit as stored in `build/obj/` or a project's `lib/` directory.
+#[derive(Serialize, Deserialize)]
+pub struct ObjectFile {
+    /// Hash of the source the object was assembled from; see `source_key`.
+    pub key: u64,
+    pub object: Object,
+    /// Source file and line for each line of `object.source`.
+    pub line_map: Vec<LineOrigin>,
+    /// Files read by `.incbin`, each with the `content_key` it had when the object was built.
+    pub incbins: Vec<(PathBuf, u64)>,
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, it gives the same key on every platform and Rust
+/// release, so an object stays valid across toolchain updates.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Hash `bytes` preceded by their length, so consecutive fields cannot run together.
+    fn field(&mut self, bytes: &[u8]) {
+        self.bytes(&(bytes.len() as u64).to_le_bytes());
+        self.bytes(bytes);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Cache key for an expanded unit: its text, where each line came from and the target CPU.
+pub fn source_key(source: &[u8], line_map: &[LineOrigin], cpu: CpuKind) -> u64 {
+    let mut hash = Fnv1a::new();
+    hash.field(&OBJECT_VERSION.to_le_bytes());
+    hash.field(source);
+    for origin in line_map {
+        hash.field(origin.file.to_string_lossy().as_bytes());
+        hash.field(&(origin.line as u64).to_le_bytes());
+    }
+    hash.field(cpu.name().as_bytes());
+    hash.0
+}
+
+/// Hash of a file's contents, to notice when a file read by `.incbin` changes.
+pub fn content_key(data: &[u8]) -> u64 {
+    let mut hash = Fnv1a::new();
+    hash.field(data);
+    hash.0
+}
+
+/// Whether every file an object read with `.incbin` still has the contents it was built from.
+pub fn incbins_unchanged(incbins: &[(PathBuf, u64)]) -> bool {
+    incbins
+        .iter()
+        .all(|(path, key)| fs::read(path).is_ok_and(|data| content_key(&data) == *key))
+}
+
+pub fn read_object(path: &Path) -> Result<ObjectFile, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    let body = data
+        .strip_prefix(&OBJECT_MAGIC)
+        .and_then(|rest| rest.split_at_checked(4))
+        .filter(|(version, _)| **version == OBJECT_VERSION.to_le_bytes())
+        .map(|(_, body)| body)
+        .ok_or_else(|| format!("{} is not a CHIPcade object file", path.display()))?;
+    bincode::deserialize(body).map_err(|e| format!("Failed to decode {}: {e}", path.display()))
+}
+
+pub fn write_object(path: &Path, file: &ObjectFile) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {e}", dir.display()))?;
+    }
+    let body = bincode::serialize(file).map_err(|e| format!("Failed to serialize object: {e}"))?;
+    let mut data = Vec::with_capacity(8 + body.len());
+    data.extend_from_slice(&OBJECT_MAGIC);
+    data.extend_from_slice(&OBJECT_VERSION.to_le_bytes());
+    data.extend_from_slice(&body);
+    fs::write(path, data).map_err(|e| format!("Failed to write {}: {e}", path.display()))
+}
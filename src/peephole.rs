@@ -0,0 +1,591 @@
+//! Peephole optimizer for the assembly transpiled from C, enabled with `chipcade build -O`. It
+//! looks at short instruction sequences and removes or rewrites the ones the transpiler emits
+//! naively. Lines are never inserted or deleted: a removed instruction becomes a `; peephole:`
+//! comment, so the listing shows each change next to the C line it came from.
+
+use crate::asm6502::{CpuKind, Mode, opcode_info};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// What the optimizer saved. Cycles are base counts, each instruction counted once.
+#[derive(Default)]
+pub struct PeepholeReport {
+    pub rewrites: usize,
+    pub bytes: usize,
+    pub cycles: usize,
+}
+
+const FLAG_N: u8 = 1;
+const FLAG_Z: u8 = 2;
+const FLAG_C: u8 = 4;
+const FLAG_V: u8 = 8;
+
+const BRANCHES: [(&str, &str); 8] = [
+    ("BCC", "BCS"),
+    ("BCS", "BCC"),
+    ("BEQ", "BNE"),
+    ("BNE", "BEQ"),
+    ("BMI", "BPL"),
+    ("BPL", "BMI"),
+    ("BVC", "BVS"),
+    ("BVS", "BVC"),
+];
+
+enum Line<'a> {
+    /// Blank lines and comments.
+    Skip,
+    Label(&'a str),
+    Instr {
+        mnemonic: String,
+        operand: &'a str,
+    },
+    /// Directives, inline assembly and anything else the optimizer does not look into.
+    Barrier,
+}
+
+/// Optimize `lines` in place until no rule applies. Lines whose index is in `fixed` (inline
+/// assembly) are never changed and stop every rule that would look past them.
+pub fn optimize(
+    lines: &mut [(String, PathBuf, usize)],
+    fixed: &HashSet<usize>,
+    cpu: CpuKind,
+) -> PeepholeReport {
+    let mut opt = Optimizer {
+        lines,
+        fixed,
+        cpu,
+        report: PeepholeReport::default(),
+    };
+    loop {
+        let before = opt.report.rewrites;
+        opt.redundant_loads();
+        opt.jumps();
+        opt.compares_with_zero();
+        opt.add_zero();
+        opt.unreachable();
+        if opt.report.rewrites == before {
+            return opt.report;
+        }
+    }
+}
+
+struct Optimizer<'a> {
+    lines: &'a mut [(String, PathBuf, usize)],
+    fixed: &'a HashSet<usize>,
+    cpu: CpuKind,
+    report: PeepholeReport,
+}
+
+impl Optimizer<'_> {
+    fn line(&self, idx: usize) -> Line<'_> {
+        if self.fixed.contains(&idx) {
+            return Line::Barrier;
+        }
+        let text = self.lines[idx].0.trim();
+        if text.is_empty() || text.starts_with(';') {
+            return Line::Skip;
+        }
+        if let Some(label) = text.strip_suffix(':')
+            && !label.contains(char::is_whitespace)
+        {
+            return Line::Label(label);
+        }
+        if text.starts_with('.') || text.contains(':') {
+            return Line::Barrier;
+        }
+        let (mnemonic, operand) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        if mnemonic.len() != 3 || !mnemonic.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Line::Barrier;
+        }
+        Line::Instr {
+            mnemonic: mnemonic.to_ascii_uppercase(),
+            operand: operand.trim(),
+        }
+    }
+
+    /// The next line at or after `idx` that is not a comment, and its index.
+    fn next_line(&self, mut idx: usize) -> Option<(usize, Line<'_>)> {
+        while idx < self.lines.len() {
+            match self.line(idx) {
+                Line::Skip => idx += 1,
+                line => return Some((idx, line)),
+            }
+        }
+        None
+    }
+
+    /// Replace the instruction at `idx` with a comment saying why, and count what it saved.
+    fn remove(&mut self, idx: usize, why: &str) {
+        let text = self.lines[idx].0.trim().to_string();
+        if let Some((bytes, cycles)) = self.cost(&text) {
+            self.report.bytes += bytes;
+            self.report.cycles += cycles as usize;
+        }
+        self.report.rewrites += 1;
+        self.lines[idx].0 = format!("; peephole: removed {} ({})", text, why);
+    }
+
+    /// Bytes and base cycles of an instruction line.
+    fn cost(&self, text: &str) -> Option<(usize, u8)> {
+        let (mnemonic, operand) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let operand = operand.trim();
+        let upper = operand.to_ascii_uppercase();
+        let (base, index) = match upper.rsplit_once(',') {
+            Some((base, index)) if !upper.starts_with('(') => (base.trim(), Some(index.trim())),
+            _ => (upper.as_str(), None),
+        };
+        let modes: &[Mode] = if operand.is_empty() {
+            &[Mode::Implied, Mode::Accumulator]
+        } else if upper == "A" {
+            &[Mode::Accumulator]
+        } else if operand.starts_with('#') {
+            &[Mode::Immediate]
+        } else if BRANCHES.iter().any(|(b, _)| *b == mnemonic) {
+            &[Mode::Relative]
+        } else if upper.starts_with('(') && upper.ends_with(",Y") {
+            &[Mode::IndirectIndexed]
+        } else if upper.starts_with('(') && upper.ends_with(",X)") {
+            &[Mode::IndexedIndirect]
+        } else if upper.starts_with('(') {
+            &[Mode::Indirect, Mode::ZeroPageIndirect]
+        } else {
+            // Anything but a plain `$XX` may assemble to an absolute address.
+            match (is_zero_page(base), index) {
+                (true, None) => &[Mode::ZeroPage, Mode::Absolute],
+                (true, Some("X")) => &[Mode::ZeroPageX, Mode::AbsoluteX],
+                (true, Some(_)) => &[Mode::ZeroPageY, Mode::AbsoluteY],
+                (false, None) => &[Mode::Absolute],
+                (false, Some("X")) => &[Mode::AbsoluteX],
+                (false, Some(_)) => &[Mode::AbsoluteY],
+            }
+        };
+        modes.iter().find_map(|mode| {
+            (0..=255u8)
+                .filter_map(|op| opcode_info(self.cpu, op))
+                .find(|info| info.mnemonic == mnemonic && info.mode == *mode)
+                .map(|info| (info.size(), info.cycles))
+        })
+    }
+
+    /// Whether none of `flags` is read after the instruction at `idx` before being set again.
+    /// Anything that leaves the straight-line code counts as a read.
+    fn flags_dead_after(&self, idx: usize, mut flags: u8) -> bool {
+        let mut next = idx + 1;
+        while let Some((i, line)) = self.next_line(next) {
+            let Line::Instr { mnemonic, .. } = line else {
+                return false;
+            };
+            let Some((reads, writes)) = flag_use(&mnemonic) else {
+                return false;
+            };
+            if reads & flags != 0 {
+                return false;
+            }
+            flags &= !writes;
+            if flags == 0 {
+                return true;
+            }
+            next = i + 1;
+        }
+        false
+    }
+
+    /// `LDA`/`LDX`/`LDY` of a value the register already holds, and `STA`/`STX`/`STY` of a
+    /// register to the zero-page address it was loaded from.
+    fn redundant_loads(&mut self) {
+        // What each of A, X and Y is known to hold: immediates and zero-page addresses.
+        let mut regs: [Vec<String>; 3] = Default::default();
+        for idx in 0..self.lines.len() {
+            let (mnemonic, operand) = match self.line(idx) {
+                Line::Skip => continue,
+                Line::Instr { mnemonic, operand } => (mnemonic, operand.to_ascii_uppercase()),
+                Line::Label(_) | Line::Barrier => {
+                    regs = Default::default();
+                    continue;
+                }
+            };
+            let reg = |m: &str| "AXY".find(m.chars().nth(2).unwrap_or(' '));
+            match mnemonic.as_str() {
+                "LDA" | "LDX" | "LDY" => {
+                    let r = reg(&mnemonic).unwrap_or(0);
+                    let known = operand.starts_with('#') || is_zero_page(&operand);
+                    if known && regs[r].contains(&operand) {
+                        if self.flags_dead_after(idx, FLAG_N | FLAG_Z) {
+                            self.remove(idx, "the register already holds this value");
+                        }
+                        continue;
+                    }
+                    regs[r] = if known { vec![operand] } else { Vec::new() };
+                }
+                "STA" | "STX" | "STY" => {
+                    let r = reg(&mnemonic).unwrap_or(0);
+                    if is_zero_page(&operand) && regs[r].contains(&operand) {
+                        self.remove(idx, "the address already holds this value");
+                        continue;
+                    }
+                    forget_store(&mut regs, &operand);
+                    if is_zero_page(&operand) {
+                        regs[r].push(operand);
+                    }
+                }
+                "INC" | "DEC" | "ASL" | "LSR" | "ROL" | "ROR"
+                    if !operand.is_empty() && operand != "A" =>
+                {
+                    forget_store(&mut regs, &operand)
+                }
+                "TAX" => regs[1] = regs[0].clone(),
+                "TAY" => regs[2] = regs[0].clone(),
+                "TXA" => regs[0] = regs[1].clone(),
+                "TYA" => regs[0] = regs[2].clone(),
+                "INX" | "DEX" | "TSX" => regs[1].clear(),
+                "INY" | "DEY" => regs[2].clear(),
+                // The memory forms of these were handled above; what is left writes A.
+                "ADC" | "SBC" | "AND" | "ORA" | "EOR" | "PLA" | "INC" | "DEC" | "ASL" | "LSR"
+                | "ROL" | "ROR" => regs[0].clear(),
+                _ if BRANCHES.iter().any(|(b, _)| *b == mnemonic) => {}
+                _ if flag_use(&mnemonic).is_some() => {}
+                _ => regs = Default::default(),
+            }
+        }
+    }
+
+    /// `JMP` to the label right after it, and a branch over a `JMP` (`BNE skip` / `JMP far` /
+    /// `skip:`), which becomes the opposite branch to `far` when it is in range.
+    fn jumps(&mut self) {
+        for idx in 0..self.lines.len() {
+            let Line::Instr { mnemonic, operand } = self.line(idx) else {
+                continue;
+            };
+            if mnemonic == "JMP" && !operand.starts_with('(') {
+                let target = operand.to_string();
+                if self.labels_after(idx + 1).contains(&target) {
+                    self.remove(idx, "jumps to the next line");
+                }
+                continue;
+            }
+            let Some((_, inverse)) = BRANCHES.iter().find(|(b, _)| *b == mnemonic) else {
+                continue;
+            };
+            let skip = operand.to_string();
+            let Some((jmp, Line::Instr { mnemonic, operand })) = self.next_line(idx + 1) else {
+                continue;
+            };
+            if mnemonic != "JMP" || operand.starts_with('(') {
+                continue;
+            }
+            let far = operand.to_string();
+            if !self.labels_after(jmp + 1).contains(&skip) || !self.in_branch_range(idx, jmp, &far)
+            {
+                continue;
+            }
+            let branch = format!("{} {}", inverse, far);
+            self.remove(jmp, &format!("folded into {}", branch));
+            self.lines[idx].0 = branch;
+        }
+    }
+
+    /// The labels between `idx` and the next instruction.
+    fn labels_after(&self, idx: usize) -> Vec<String> {
+        let mut labels = Vec::new();
+        let mut next = idx;
+        while let Some((i, Line::Label(label))) = self.next_line(next) {
+            labels.push(label.to_string());
+            next = i + 1;
+        }
+        labels
+    }
+
+    /// Whether a branch at `branch` could reach `target` once the line `removed` is gone. The
+    /// estimate only gets safer as later rules remove more code.
+    fn in_branch_range(&self, branch: usize, removed: usize, target: &str) -> bool {
+        let Some(label) =
+            (0..self.lines.len()).find(|i| matches!(self.line(*i), Line::Label(l) if l == target))
+        else {
+            return false;
+        };
+        let (range, forward) = if label > branch {
+            (branch + 1..label, true)
+        } else {
+            (label..branch + 1, false)
+        };
+        let mut distance = 0usize;
+        for i in range {
+            if i == removed {
+                continue;
+            }
+            distance += match self.line(i) {
+                Line::Skip | Line::Label(_) => 0,
+                Line::Instr { .. } => match self.cost(self.lines[i].0.trim()) {
+                    Some((bytes, _)) => bytes,
+                    None => return false,
+                },
+                Line::Barrier => return false,
+            };
+        }
+        if forward {
+            distance <= 127
+        } else {
+            distance <= 128
+        }
+    }
+
+    /// `CMP #$00` right after an instruction that already set N and Z from A, when the carry
+    /// it sets is not used; likewise `CPX`/`CPY`.
+    fn compares_with_zero(&mut self) {
+        for idx in 1..self.lines.len() {
+            let Line::Instr { mnemonic, operand } = self.line(idx) else {
+                continue;
+            };
+            let setters: &[&str] = match mnemonic.as_str() {
+                "CMP" => &[
+                    "LDA", "TXA", "TYA", "PLA", "AND", "ORA", "EOR", "ADC", "SBC",
+                ],
+                "CPX" => &["LDX", "TAX", "INX", "DEX"],
+                "CPY" => &["LDY", "TAY", "INY", "DEY"],
+                _ => continue,
+            };
+            if !is_zero(operand) {
+                continue;
+            }
+            let prev = (0..idx)
+                .rev()
+                .find(|i| !matches!(self.line(*i), Line::Skip));
+            let sets_nz = prev.is_some_and(|p| {
+                matches!(self.line(p), Line::Instr { mnemonic, .. } if setters.contains(&mnemonic.as_str()))
+            });
+            if sets_nz && self.flags_dead_after(idx, FLAG_C) {
+                self.remove(idx, "N and Z are already set");
+            }
+        }
+    }
+
+    /// `CLC` / `ADC #0` and `SEC` / `SBC #0` leave A as it was; they go when none of the
+    /// flags they set is used.
+    fn add_zero(&mut self) {
+        for idx in 0..self.lines.len() {
+            let Line::Instr { mnemonic, .. } = self.line(idx) else {
+                continue;
+            };
+            let op = match mnemonic.as_str() {
+                "CLC" => "ADC",
+                "SEC" => "SBC",
+                _ => continue,
+            };
+            let Some((next, Line::Instr { mnemonic, operand })) = self.next_line(idx + 1) else {
+                continue;
+            };
+            if mnemonic == op
+                && is_zero(operand)
+                && self.flags_dead_after(next, FLAG_N | FLAG_Z | FLAG_C | FLAG_V)
+            {
+                self.remove(idx, "adds nothing");
+                self.remove(next, "adds nothing");
+            }
+        }
+    }
+
+    /// Instructions after a `JMP`, `RTS` or `RTI` that no label leads to.
+    fn unreachable(&mut self) {
+        let mut dead = false;
+        for idx in 0..self.lines.len() {
+            match self.line(idx) {
+                Line::Skip => {}
+                Line::Instr { .. } if dead => self.remove(idx, "unreachable"),
+                Line::Instr { mnemonic, .. } => {
+                    dead = matches!(mnemonic.as_str(), "JMP" | "RTS" | "RTI")
+                }
+                Line::Label(_) | Line::Barrier => dead = false,
+            }
+        }
+    }
+}
+
+/// Flags an instruction reads and writes; `None` for ones that leave the straight-line code
+/// or that the optimizer does not know.
+fn flag_use(mnemonic: &str) -> Option<(u8, u8)> {
+    const NZ: u8 = FLAG_N | FLAG_Z;
+    Some(match mnemonic {
+        "LDA" | "LDX" | "LDY" | "TAX" | "TAY" | "TXA" | "TYA" | "TSX" | "PLA" | "INX" | "INY"
+        | "DEX" | "DEY" | "INC" | "DEC" | "AND" | "ORA" | "EOR" => (0, NZ),
+        "ADC" | "SBC" => (FLAG_C, NZ | FLAG_C | FLAG_V),
+        "CMP" | "CPX" | "CPY" | "ASL" | "LSR" => (0, NZ | FLAG_C),
+        "ROL" | "ROR" => (FLAG_C, NZ | FLAG_C),
+        "BIT" => (0, NZ | FLAG_V),
+        "CLC" | "SEC" => (0, FLAG_C),
+        "CLV" => (0, FLAG_V),
+        "STA" | "STX" | "STY" | "PHA" | "TXS" | "NOP" | "CLI" | "SEI" | "CLD" | "SED" => (0, 0),
+        _ => return None,
+    })
+}
+
+/// A store to `operand` changes what the registers are known to hold. Only a plain `$XX`
+/// address is known not to overlap the others.
+fn forget_store(regs: &mut [Vec<String>; 3], operand: &str) {
+    for reg in regs {
+        if is_zero_page(operand) {
+            reg.retain(|known| known != operand);
+        } else {
+            reg.retain(|known| known.starts_with('#'));
+        }
+    }
+}
+
+/// `$XX`: a zero-page address, which is plain RAM.
+fn is_zero_page(operand: &str) -> bool {
+    operand.strip_prefix('$').is_some_and(|hex| {
+        (1..=2).contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+fn is_zero(operand: &str) -> bool {
+    matches!(operand, "#0" | "#$0" | "#$00")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Optimize `asm` (one instruction or label per line) for the 65C02 and return the lines
+    /// that are left, without the comments for removed ones.
+    fn optimized(asm: &str, fixed: &[usize]) -> (Vec<String>, PeepholeReport) {
+        let mut lines: Vec<_> = asm
+            .lines()
+            .map(|line| (line.trim().to_string(), PathBuf::from("main.c"), 1))
+            .collect();
+        let fixed = fixed.iter().copied().collect();
+        let report = optimize(&mut lines, &fixed, CpuKind::Cmos65c02);
+        let kept = lines
+            .into_iter()
+            .map(|(text, _, _)| text)
+            .filter(|text| !text.starts_with("; peephole:"))
+            .collect();
+        (kept, report)
+    }
+
+    fn kept(asm: &str) -> Vec<String> {
+        optimized(asm, &[]).0
+    }
+
+    fn lines(asm: &str) -> Vec<String> {
+        asm.lines().map(|line| line.trim().to_string()).collect()
+    }
+
+    #[test]
+    fn reload_of_known_value_is_removed() {
+        let asm = "LDA #$01\nSTA $10\nLDA #$01\nSTA $11\nLDX #$00";
+        assert_eq!(kept(asm), lines("LDA #$01\nSTA $10\nSTA $11\nLDX #$00"));
+        let asm = "LDA $10\nTAX\nLDX $10\nINX";
+        assert_eq!(kept(asm), lines("LDA $10\nTAX\nINX"));
+    }
+
+    #[test]
+    fn store_back_to_source_is_removed() {
+        let asm = "LDA $10\nSTA $10\nSTA $11";
+        assert_eq!(kept(asm), lines("LDA $10\nSTA $11"));
+    }
+
+    #[test]
+    fn reload_is_kept_when_its_flags_are_used() {
+        let asm = "LDA #$01\nSTA $10\nLDA #$01\nBNE $20";
+        assert_eq!(kept(asm), lines(asm));
+    }
+
+    #[test]
+    fn reload_is_kept_after_a_label() {
+        let asm = "LDA #$01\nSTA $10\nLoop:\nLDA #$01\nLDX #$00";
+        assert_eq!(kept(asm), lines(asm));
+    }
+
+    #[test]
+    fn reload_is_kept_after_a_store_changes_the_value() {
+        let asm = "LDA $10\nLDX #$05\nSTX $10\nLDA $10\nLDY #$00";
+        assert_eq!(kept(asm), lines(asm));
+        let asm = "LDA $10\nINC $10\nLDA $10\nLDY #$00";
+        assert_eq!(kept(asm), lines(asm));
+    }
+
+    #[test]
+    fn reload_is_kept_after_the_accumulator_changes() {
+        for op in ["INC A", "DEC A", "INC", "ASL A", "ADC #$01", "PLA", "TXA"] {
+            let asm = format!("LDA #$01\n{}\nLDA #$01\nLDX #$00", op);
+            assert_eq!(kept(&asm), lines(&asm), "after {}", op);
+        }
+    }
+
+    #[test]
+    fn jump_to_next_line_is_removed() {
+        assert_eq!(kept("JMP Next\nNext:\nRTS"), lines("Next:\nRTS"));
+    }
+
+    #[test]
+    fn branch_over_jump_becomes_opposite_branch() {
+        let asm = "Far:\nLDA $10\nBNE Skip\nJMP Far\nSkip:\nRTS";
+        assert_eq!(kept(asm), lines("Far:\nLDA $10\nBEQ Far\nSkip:\nRTS"));
+    }
+
+    #[test]
+    fn branch_over_jump_stays_when_target_is_out_of_range() {
+        let filler = "STA $1234\n".repeat(50);
+        let asm = format!("Far:\n{}BNE Skip\nJMP Far\nSkip:\nRTS", filler);
+        assert_eq!(kept(&asm), lines(&asm));
+    }
+
+    #[test]
+    fn compare_with_zero_after_load_is_removed() {
+        let asm = "LDA $10\nCMP #$00\nCLC\nRTS";
+        assert_eq!(kept(asm), lines("LDA $10\nCLC\nRTS"));
+        let asm = "DEX\nCPX #0\nSEC\nRTS";
+        assert_eq!(kept(asm), lines("DEX\nSEC\nRTS"));
+    }
+
+    #[test]
+    fn compare_with_zero_is_kept_when_carry_is_used() {
+        let asm = "LDA $10\nCMP #$00\nBCS Done\nDone:\nRTS";
+        assert_eq!(kept(asm), lines(asm));
+        let asm = "LDA $10\nCMP #$00\nADC #$01\nRTS";
+        assert_eq!(kept(asm), lines(asm));
+    }
+
+    #[test]
+    fn compare_with_zero_is_kept_after_a_label() {
+        let asm = "LDA $10\nTop:\nCMP #$00\nCLC\nRTS";
+        assert_eq!(kept(asm), lines(asm));
+    }
+
+    #[test]
+    fn adding_zero_is_removed() {
+        let asm = "LDA $10\nCLC\nADC #$00\nSTA $11\nCMP #$01\nBIT $12\nRTS";
+        assert_eq!(kept(asm), lines("LDA $10\nSTA $11\nCMP #$01\nBIT $12\nRTS"));
+        let asm = "LDA $10\nSEC\nSBC #0\nSTA $11\nCMP #$01\nBIT $12\nRTS";
+        assert_eq!(kept(asm), lines("LDA $10\nSTA $11\nCMP #$01\nBIT $12\nRTS"));
+    }
+
+    #[test]
+    fn adding_zero_is_kept_when_its_flags_are_used() {
+        let asm = "LDA $10\nCLC\nADC #$00\nBEQ Done\nDone:\nRTS";
+        assert_eq!(kept(asm), lines(asm));
+    }
+
+    #[test]
+    fn code_after_jump_is_removed_up_to_a_label() {
+        let asm = "JMP Exit\nLDA #$01\nSTA $10\nDone:\nRTS\nExit:\nBRK";
+        assert_eq!(kept(asm), lines("JMP Exit\nDone:\nRTS\nExit:\nBRK"));
+    }
+
+    #[test]
+    fn inline_assembly_is_left_alone() {
+        let asm = "JMP Done\nLDA #$01\nDone:\nRTS";
+        let (kept, report) = optimized(asm, &[1]);
+        assert_eq!(kept, lines(asm));
+        assert_eq!(report.rewrites, 0);
+    }
+
+    #[test]
+    fn report_counts_savings() {
+        let (_, report) = optimized("LDA #$01\nSTA $10\nLDA #$01\nLDX #$00", &[]);
+        assert_eq!(report.rewrites, 1);
+        assert_eq!(report.bytes, 2);
+        assert_eq!(report.cycles, 2);
+    }
+}
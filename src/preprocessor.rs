@@ -0,0 +1,871 @@
+//! The C preprocessor, run on each C source file before it is transpiled. It removes comments,
+//! joins `\` continuation lines, pulls in `#include`d headers, expands object- and function-like
+//! `#define`s and drops the lines of `#if` branches that are not taken. Every line it returns
+//! keeps the file and line it came from, so errors and the debugger point at the original
+//! source.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A line of preprocessed C and where it came from.
+pub struct SourceLine {
+    /// The file as it was opened, for messages.
+    pub path: PathBuf,
+    /// The canonical path of the file, for the line map.
+    pub canonical: PathBuf,
+    pub line: usize,
+    /// The column `text` starts at, counting from 1.
+    pub col: usize,
+    pub text: String,
+}
+
+struct Macro {
+    /// Parameter names of a function-like macro; `None` for an object-like one.
+    params: Option<Vec<String>>,
+    body: String,
+}
+
+/// An open `#if`/`#ifdef`/`#ifndef` group.
+struct CondGroup {
+    /// Whether the lines around the group are kept.
+    outer: bool,
+    /// Whether the current branch is kept.
+    active: bool,
+    /// Whether an earlier branch was kept, so later ones are not.
+    taken: bool,
+    seen_else: bool,
+}
+
+struct Preprocessor<'a> {
+    include_dirs: &'a [PathBuf],
+    macros: HashMap<String, Macro>,
+    /// Canonical paths of the files being read, innermost last.
+    include_stack: Vec<PathBuf>,
+    /// Files marked with `#pragma once`.
+    once: HashSet<PathBuf>,
+    out: Vec<SourceLine>,
+}
+
+/// Preprocess the C file at `path`. `#include "name"` looks next to the including file first
+/// and then in `include_dirs`; `#include <name>` only looks in `include_dirs`. `predefined`
+/// macros behave as if defined at the top of the file.
+pub fn preprocess(
+    path: &Path,
+    include_dirs: &[PathBuf],
+    predefined: &[(String, String)],
+) -> Result<Vec<SourceLine>, String> {
+    let macros = predefined
+        .iter()
+        .map(|(name, body)| {
+            let body = body.clone();
+            (name.clone(), Macro { params: None, body })
+        })
+        .collect();
+    let mut pp = Preprocessor {
+        include_dirs,
+        macros,
+        include_stack: Vec::new(),
+        once: HashSet::new(),
+        out: Vec::new(),
+    };
+    pp.file(path)?;
+    Ok(pp.out)
+}
+
+impl Preprocessor<'_> {
+    fn file(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read c file {}: {e}", path.display()))?;
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if self.once.contains(&canonical) {
+            return Ok(());
+        }
+        self.include_stack.push(canonical.clone());
+        let err = |line: usize, e: String| {
+            format!("C preprocessor error: {}:{}: {}", path.display(), line, e)
+        };
+
+        let mut groups: Vec<CondGroup> = Vec::new();
+        for (line_no, col, text) in logical_lines(&strip_comments(&content)) {
+            let active = groups.last().is_none_or(|g| g.active);
+            let Some(directive) = text.strip_prefix('#') else {
+                if active && !text.is_empty() {
+                    let text = self
+                        .expand(&text, &mut Vec::new())
+                        .map_err(|e| err(line_no, e))?;
+                    self.out.push(SourceLine {
+                        path: path.to_path_buf(),
+                        canonical: canonical.clone(),
+                        line: line_no,
+                        col,
+                        text: text.trim().to_string(),
+                    });
+                }
+                continue;
+            };
+            let directive = directive.trim_start();
+            let name_len = directive
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(directive.len());
+            let (name, rest) = (&directive[..name_len], directive[name_len..].trim());
+            // Errors inside an included file already carry its location.
+            let include = self
+                .directive(name, rest, active, &mut groups, path)
+                .map_err(|e| err(line_no, e))?;
+            if let Some(target) = include {
+                self.file(&target)?;
+            }
+        }
+        if !groups.is_empty() {
+            return Err(format!(
+                "C preprocessor error: {}: missing #endif",
+                path.display()
+            ));
+        }
+        self.include_stack.pop();
+        Ok(())
+    }
+
+    /// Apply a directive; `#include` returns the file to read.
+    fn directive(
+        &mut self,
+        name: &str,
+        rest: &str,
+        active: bool,
+        groups: &mut Vec<CondGroup>,
+        path: &Path,
+    ) -> Result<Option<PathBuf>, String> {
+        match name {
+            "if" | "ifdef" | "ifndef" => {
+                let holds = active
+                    && match name {
+                        "if" => self.eval_condition(rest)?,
+                        "ifdef" => self.macros.contains_key(macro_name(rest)?),
+                        _ => !self.macros.contains_key(macro_name(rest)?),
+                    };
+                groups.push(CondGroup {
+                    outer: active,
+                    active: holds,
+                    taken: holds,
+                    seen_else: false,
+                });
+            }
+            "elif" | "else" => {
+                let Some(group) = groups.last_mut() else {
+                    return Err(format!("#{} without #if", name));
+                };
+                if group.seen_else {
+                    return Err(format!("#{} after #else", name));
+                }
+                let try_branch = group.outer && !group.taken;
+                let holds = match name {
+                    "elif" => try_branch && self.eval_condition(rest)?,
+                    _ => {
+                        group.seen_else = true;
+                        try_branch
+                    }
+                };
+                group.active = holds;
+                group.taken |= holds;
+            }
+            "endif" => {
+                if groups.pop().is_none() {
+                    return Err("#endif without #if".to_string());
+                }
+            }
+            _ if !active => {}
+            "" => {}
+            "define" => self.define(rest)?,
+            "undef" => {
+                self.macros.remove(macro_name(rest)?);
+            }
+            "include" => {
+                let target = self.find_include(rest, path)?;
+                let canonical = target.canonicalize().unwrap_or_else(|_| target.clone());
+                if self.include_stack.contains(&canonical) {
+                    return Err(format!("'{}' includes itself", target.display()));
+                }
+                return Ok(Some(target));
+            }
+            "pragma" => {
+                if rest == "once" {
+                    self.once.extend(self.include_stack.last().cloned());
+                }
+            }
+            "error" => return Err(format!("#error {}", rest)),
+            _ => return Err(format!("unknown directive '#{}'", name)),
+        }
+        Ok(None)
+    }
+
+    /// `#define NAME body` or `#define NAME(a, b) body`.
+    fn define(&mut self, rest: &str) -> Result<(), String> {
+        let name_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let name = macro_name(&rest[..name_len])?;
+        let after = &rest[name_len..];
+        let (params, body) = match after.strip_prefix('(') {
+            Some(list) => {
+                let Some((list, body)) = list.split_once(')') else {
+                    return Err(format!("expected ')' in parameters of macro '{}'", name));
+                };
+                let params: Vec<String> = if list.trim().is_empty() {
+                    Vec::new()
+                } else {
+                    list.split(',')
+                        .map(|p| macro_name(p).map(str::to_string))
+                        .collect::<Result<_, _>>()?
+                };
+                (Some(params), body)
+            }
+            None => (None, after),
+        };
+        if body.contains('#') {
+            return Err(format!(
+                "'#' and '##' are not supported in macro '{}'",
+                name
+            ));
+        }
+        let body = body.trim().to_string();
+        self.macros.insert(name.to_string(), Macro { params, body });
+        Ok(())
+    }
+
+    fn find_include(&self, rest: &str, path: &Path) -> Result<PathBuf, String> {
+        let (name, local) = if let Some(name) = rest.strip_prefix('"') {
+            (name.strip_suffix('"'), true)
+        } else if let Some(name) = rest.strip_prefix('<') {
+            (name.strip_suffix('>'), false)
+        } else {
+            (None, false)
+        };
+        let Some(name) = name.filter(|n| !n.is_empty()) else {
+            return Err("expected #include \"file\" or #include <file>".to_string());
+        };
+        let here = path.parent().filter(|_| local).map(Path::to_path_buf);
+        here.iter()
+            .chain(self.include_dirs)
+            .map(|dir| dir.join(name))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| format!("cannot find include file '{}'", name))
+    }
+
+    /// Expand the macros in `text`. Macros in `disabled` are being expanded already and are
+    /// left alone, which stops a macro from expanding inside itself.
+    fn expand(&self, text: &str, disabled: &mut Vec<String>) -> Result<String, String> {
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if c == '"' || c == '\'' {
+                let len = literal_len(rest);
+                out.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+            if !(c.is_ascii_alphanumeric() || c == '_') {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            rest = &rest[len..];
+            let Some(mac) = self
+                .macros
+                .get(word)
+                .filter(|_| !c.is_ascii_digit() && !disabled.iter().any(|d| d == word))
+            else {
+                out.push_str(word);
+                continue;
+            };
+            let body = match &mac.params {
+                None => mac.body.clone(),
+                Some(params) => {
+                    // A function-like macro name without arguments is left as it is.
+                    let Some(call) = rest.trim_start().strip_prefix('(') else {
+                        out.push_str(word);
+                        continue;
+                    };
+                    let (args, after) = split_macro_args(call)
+                        .ok_or_else(|| format!("unterminated call of macro '{}'", word))?;
+                    rest = after;
+                    let args = if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+                        Vec::new()
+                    } else {
+                        args
+                    };
+                    if args.len() != params.len() {
+                        return Err(format!(
+                            "macro '{}' takes {} argument(s), got {}",
+                            word,
+                            params.len(),
+                            args.len()
+                        ));
+                    }
+                    let args = args
+                        .iter()
+                        .map(|arg| self.expand(arg, disabled))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    substitute(&mac.body, params, &args)
+                }
+            };
+            disabled.push(word.to_string());
+            let expanded = self.expand(&body, disabled);
+            disabled.pop();
+            out.push_str(&expanded?);
+        }
+        Ok(out)
+    }
+
+    /// Evaluate the expression of `#if` or `#elif`.
+    fn eval_condition(&self, expr: &str) -> Result<bool, String> {
+        // `defined` is resolved before the macros in `expr` are expanded.
+        let mut resolved = String::new();
+        let mut rest = expr;
+        while let Some(at) = rest.find("defined") {
+            let word_start = at == 0 || !is_ident_byte(rest.as_bytes()[at - 1]);
+            let after = &rest[at + "defined".len()..];
+            let word_end = !after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+            resolved.push_str(&rest[..at]);
+            if !(word_start && word_end) {
+                resolved.push_str("defined");
+                rest = after;
+                continue;
+            }
+            let after = after.trim_start();
+            let (name, tail) = match after.strip_prefix('(') {
+                Some(inner) => inner
+                    .split_once(')')
+                    .ok_or_else(|| "expected ')' after defined(".to_string())?,
+                None => {
+                    let len = after
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(after.len());
+                    after.split_at(len)
+                }
+            };
+            let defined = self.macros.contains_key(macro_name(name)?);
+            resolved.push_str(if defined { " 1 " } else { " 0 " });
+            rest = tail;
+        }
+        resolved.push_str(rest);
+        let expanded = self.expand(&resolved, &mut Vec::new())?;
+        let tokens = pp_tokens(&expanded)?;
+        if tokens.is_empty() {
+            return Err("#if needs an expression".to_string());
+        }
+        let mut idx = 0;
+        let value = eval_binary(&tokens, &mut idx, 0)?;
+        if idx != tokens.len() {
+            return Err(format!("unexpected '{}' in #if", tokens[idx]));
+        }
+        Ok(value != 0)
+    }
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+fn macro_name(s: &str) -> Result<&str, String> {
+    let s = s.trim();
+    let valid = s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.bytes().all(is_ident_byte);
+    if valid {
+        Ok(s)
+    } else {
+        Err(format!("invalid macro name '{}'", s))
+    }
+}
+
+/// Replace comments with a space, keeping the newlines of block comments so that line numbers
+/// do not move.
+fn strip_comments(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(c) = rest.chars().next() {
+        if c == '"' || c == '\'' {
+            let len = literal_len(rest);
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if let Some(after) = rest.strip_prefix("//") {
+            rest = &after[after.find('\n').unwrap_or(after.len())..];
+            out.push(' ');
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            let end = after.find("*/").map_or(after.len(), |i| i + 2);
+            out.push(' ');
+            out.extend(after[..end].chars().filter(|&c| c == '\n'));
+            rest = &after[end..];
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// The trimmed lines of `content` with their line and starting column; a line ending in `\`
+/// continues on the next one.
+fn logical_lines(content: &str) -> Vec<(usize, usize, String)> {
+    let mut out: Vec<(usize, usize, String)> = Vec::new();
+    let mut continued = false;
+    for (idx, raw) in content.lines().enumerate() {
+        let (text, continues) = match raw.trim_end().strip_suffix('\\') {
+            Some(text) => (text, true),
+            None => (raw, false),
+        };
+        match out.last_mut() {
+            Some((_, _, joined)) if continued => {
+                joined.push(' ');
+                joined.push_str(text.trim());
+            }
+            _ => {
+                let col = text.len() - text.trim_start().len() + 1;
+                out.push((idx + 1, col, text.trim().to_string()));
+            }
+        }
+        continued = continues;
+    }
+    out
+}
+
+/// Length of the string or character literal at the start of `s`, quotes included.
+fn literal_len(s: &str) -> usize {
+    let quote = s.as_bytes()[0];
+    let mut i = 1;
+    while i < s.len() {
+        match s.as_bytes()[i] {
+            b'\\' => i += 2,
+            b if b == quote => return i + 1,
+            b'\n' => return i,
+            _ => i += 1,
+        }
+    }
+    s.len()
+}
+
+/// Split the arguments of a macro call, starting after its `(`, at top-level commas. Returns
+/// them with the text after the closing `)`.
+fn split_macro_args(s: &str) -> Option<(Vec<String>, &str)> {
+    let mut args = Vec::new();
+    let (mut depth, mut start, mut i) = (0usize, 0usize, 0usize);
+    while i < s.len() {
+        match s.as_bytes()[i] {
+            b'"' | b'\'' => {
+                i += literal_len(&s[i..]);
+                continue;
+            }
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' if depth == 0 => {
+                args.push(s[start..i].trim().to_string());
+                return Some((args, &s[i + 1..]));
+            }
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            b',' if depth == 0 => {
+                args.push(s[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// `body` with each parameter name replaced by its argument.
+fn substitute(body: &str, params: &[String], args: &[String]) -> String {
+    let mut out = String::new();
+    let mut rest = body;
+    while let Some(c) = rest.chars().next() {
+        if c == '"' || c == '\'' {
+            let len = literal_len(rest);
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+        if !(c.is_ascii_alphanumeric() || c == '_') {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        match params.iter().position(|p| p == word) {
+            Some(i) => out.push_str(&args[i]),
+            None => out.push_str(word),
+        }
+        rest = &rest[len..];
+    }
+    out
+}
+
+/// Tokens of an `#if` expression. Names left after macro expansion count as 0.
+fn pp_tokens(expr: &str) -> Result<Vec<String>, String> {
+    const OPS: [&str; 8] = ["||", "&&", "==", "!=", "<=", ">=", "<<", ">>"];
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if let Some(op) = OPS.iter().find(|op| rest.starts_with(*op)) {
+            op.len()
+        } else if c == '\'' {
+            literal_len(rest)
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len())
+        } else if "()!~-+*/%<>&^|".contains(c) {
+            1
+        } else {
+            return Err(format!("unexpected '{}' in #if", c));
+        };
+        let token = &rest[..len];
+        if token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            tokens.push("0".to_string());
+        } else {
+            tokens.push(token.to_string());
+        }
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Binding strength of a binary operator in `#if`, loosest first.
+fn binary_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    })
+}
+
+fn eval_binary(tokens: &[String], idx: &mut usize, min: u8) -> Result<i64, String> {
+    let mut lhs = eval_unary(tokens, idx)?;
+    while let Some(op) = tokens.get(*idx) {
+        let Some(prec) = binary_precedence(op).filter(|&p| p > min) else {
+            break;
+        };
+        *idx += 1;
+        let rhs = eval_binary(tokens, idx, prec)?;
+        lhs = match op.as_str() {
+            "||" => i64::from(lhs != 0 || rhs != 0),
+            "&&" => i64::from(lhs != 0 && rhs != 0),
+            "|" => lhs | rhs,
+            "^" => lhs ^ rhs,
+            "&" => lhs & rhs,
+            "==" => i64::from(lhs == rhs),
+            "!=" => i64::from(lhs != rhs),
+            "<" => i64::from(lhs < rhs),
+            "<=" => i64::from(lhs <= rhs),
+            ">" => i64::from(lhs > rhs),
+            ">=" => i64::from(lhs >= rhs),
+            "<<" => lhs.wrapping_shl(rhs as u32),
+            ">>" => lhs.wrapping_shr(rhs as u32),
+            "+" => lhs.wrapping_add(rhs),
+            "-" => lhs.wrapping_sub(rhs),
+            "*" => lhs.wrapping_mul(rhs),
+            _ if rhs == 0 => return Err("division by zero in #if".to_string()),
+            "/" => lhs.wrapping_div(rhs),
+            _ => lhs.wrapping_rem(rhs),
+        };
+    }
+    Ok(lhs)
+}
+
+fn eval_unary(tokens: &[String], idx: &mut usize) -> Result<i64, String> {
+    let Some(token) = tokens.get(*idx) else {
+        return Err("unexpected end of #if expression".to_string());
+    };
+    *idx += 1;
+    match token.as_str() {
+        "!" => Ok(i64::from(eval_unary(tokens, idx)? == 0)),
+        "~" => Ok(!eval_unary(tokens, idx)?),
+        "-" => Ok(eval_unary(tokens, idx)?.wrapping_neg()),
+        "+" => eval_unary(tokens, idx),
+        "(" => {
+            let value = eval_binary(tokens, idx, 0)?;
+            if tokens.get(*idx).map(String::as_str) != Some(")") {
+                return Err("expected ')' in #if".to_string());
+            }
+            *idx += 1;
+            Ok(value)
+        }
+        _ => parse_pp_number(token),
+    }
+}
+
+/// A C integer or character constant.
+fn parse_pp_number(token: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid number '{}' in #if", token);
+    if let Some(c) = token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c as i64),
+            _ => Err(invalid()),
+        };
+    }
+    let digits = token.trim_end_matches(['u', 'U', 'l', 'L']);
+    let (radix, digits) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (16, hex)
+    } else if let Some(bin) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (2, bin)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory holding `files`, removed when dropped.
+    struct Project(PathBuf);
+
+    impl Project {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("chipcade-pp-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            for (file, content) in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            Project(dir)
+        }
+
+        fn run(&self, include_dirs: &[&str]) -> Result<Vec<SourceLine>, String> {
+            let dirs: Vec<_> = include_dirs.iter().map(|d| self.0.join(d)).collect();
+            preprocess(&self.0.join("main.c"), &dirs, &[])
+        }
+
+        fn texts(&self) -> Vec<String> {
+            let lines = self.run(&[]).unwrap_or_else(|e| panic!("{}", e));
+            lines.into_iter().map(|line| line.text).collect()
+        }
+    }
+
+    impl Drop for Project {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn texts(name: &str, source: &str) -> Vec<String> {
+        Project::new(name, &[("main.c", source)]).texts()
+    }
+
+    fn error(name: &str, source: &str) -> String {
+        match Project::new(name, &[("main.c", source)]).run(&[]) {
+            Ok(_) => panic!("expected an error for {:?}", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn object_and_function_macros_expand() {
+        let source = "#define W 320\n\
+                      #define AT(x, y) ((y) * W + (x))\n\
+                      #define TWICE(v) ((v) + (v))\n\
+                      a = AT(1, 2);\n\
+                      b = TWICE(AT(0, 1));\n";
+        assert_eq!(
+            texts("expand", source),
+            [
+                "a = ((2) * 320 + (1));",
+                "b = ((((1) * 320 + (0))) + (((1) * 320 + (0))));"
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_leave_strings_and_longer_names_alone() {
+        let source = "#define N 4\nputs(\"N\"); c = 'N'; NN = N;\n";
+        assert_eq!(texts("literals", source), ["puts(\"N\"); c = 'N'; NN = 4;"]);
+    }
+
+    #[test]
+    fn macro_does_not_expand_inside_itself() {
+        let source = "#define X X + 1\n#define F(a) F(a)\nx = X; f = F(2);\n";
+        assert_eq!(texts("recursive", source), ["x = X + 1; f = F(2);"]);
+    }
+
+    #[test]
+    fn function_macro_without_call_is_kept() {
+        let source = "#define F(a) a\nf = F;\n";
+        assert_eq!(texts("nocall", source), ["f = F;"]);
+    }
+
+    #[test]
+    fn macro_argument_count_is_checked() {
+        let e = error("argcount", "#define F(a, b) a\nx = F(1);\n");
+        assert!(
+            e.contains("main.c:2: macro 'F' takes 2 argument(s), got 1"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn undef_removes_a_macro() {
+        let source = "#define N 1\na = N;\n#undef N\nb = N;\n";
+        assert_eq!(texts("undef", source), ["a = 1;", "b = N;"]);
+    }
+
+    #[test]
+    fn nested_conditionals_keep_only_taken_branches() {
+        let source = "#define A 1\n\
+                      #if A\n\
+                      one;\n\
+                      #if 0\n\
+                      two;\n\
+                      #elif A + 1 == 2\n\
+                      three;\n\
+                      #else\n\
+                      four;\n\
+                      #endif\n\
+                      #else\n\
+                      #if 1\n\
+                      five;\n\
+                      #endif\n\
+                      #endif\n\
+                      six;\n";
+        assert_eq!(texts("nested", source), ["one;", "three;", "six;"]);
+    }
+
+    #[test]
+    fn only_the_first_true_branch_is_taken() {
+        let source = "#if 0\na;\n#elif 1\nb;\n#elif 1\nc;\n#else\nd;\n#endif\n";
+        assert_eq!(texts("first", source), ["b;"]);
+    }
+
+    #[test]
+    fn ifdef_ifndef_and_defined() {
+        let source = "#define A\n\
+                      #ifdef A\na;\n#endif\n\
+                      #ifndef B\nb;\n#endif\n\
+                      #if defined(A) && !defined B\nc;\n#endif\n\
+                      #if UNKNOWN\nd;\n#endif\n";
+        assert_eq!(texts("defined", source), ["a;", "b;", "c;"]);
+    }
+
+    #[test]
+    fn inactive_branches_ignore_directives() {
+        let source = "#if 0\n#define A 1\n#error never\n#bogus\n#endif\nx = A;\n";
+        assert_eq!(texts("inactive", source), ["x = A;"]);
+    }
+
+    #[test]
+    fn if_expressions_follow_c_precedence() {
+        let source = "#if 1 + 2 * 3 == 7 && (8 >> 1) == 4 && -1 < 0 && 0x10 == 16 && 'A' == 65\n\
+                      ok;\n\
+                      #endif\n";
+        assert_eq!(texts("precedence", source), ["ok;"]);
+        let e = error("divzero", "#if 1 / 0\n#endif\n");
+        assert!(e.contains("division by zero in #if"), "{}", e);
+    }
+
+    #[test]
+    fn unbalanced_conditionals_are_errors() {
+        let e = error("noendif", "#if 1\nx;\n");
+        assert!(e.contains("missing #endif"), "{}", e);
+        let e = error("noif", "#endif\n");
+        assert!(e.contains("main.c:1: #endif without #if"), "{}", e);
+        let e = error("elifelse", "#if 0\n#else\n#elif 1\n#endif\n");
+        assert!(e.contains("main.c:3: #elif after #else"), "{}", e);
+        let e = error("twoelse", "#if 0\n#else\n#else\n#endif\n");
+        assert!(e.contains("main.c:3: #else after #else"), "{}", e);
+    }
+
+    #[test]
+    fn error_directive_reports_its_message() {
+        let e = error("error", "#ifndef CPU\n#error CPU is not set\n#endif\n");
+        assert!(e.contains("main.c:2: #error CPU is not set"), "{}", e);
+    }
+
+    #[test]
+    fn include_searches_next_to_the_file_then_include_dirs() {
+        let project = Project::new(
+            "include",
+            &[
+                ("main.c", "#include \"local.h\"\n#include <lib.h>\nmain;\n"),
+                ("local.h", "#define LOCAL 1\nlocal = LOCAL;\n"),
+                ("inc/lib.h", "lib;\n"),
+            ],
+        );
+        let lines = project.run(&["inc"]).unwrap();
+        let texts: Vec<_> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["local = 1;", "lib;", "main;"]);
+        assert!(lines[0].path.ends_with("local.h"));
+        assert_eq!(lines[0].line, 2);
+        assert!(lines[2].path.ends_with("main.c"));
+        assert_eq!(lines[2].line, 3);
+
+        let e = project.run(&[]).err().unwrap();
+        assert!(
+            e.contains("main.c:2: cannot find include file 'lib.h'"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let project = Project::new(
+            "cycle",
+            &[
+                ("main.c", "#include \"a.h\"\n"),
+                ("a.h", "#include \"b.h\"\n"),
+                ("b.h", "#include \"a.h\"\n"),
+            ],
+        );
+        let e = project.run(&[]).err().unwrap();
+        assert!(e.contains("b.h:1: "), "{}", e);
+        assert!(e.contains("a.h' includes itself"), "{}", e);
+    }
+
+    #[test]
+    fn pragma_once_includes_a_header_once() {
+        let project = Project::new(
+            "once",
+            &[
+                ("main.c", "#include \"a.h\"\n#include \"a.h\"\nmain;\n"),
+                ("a.h", "#pragma once\na;\n"),
+            ],
+        );
+        assert_eq!(project.texts(), ["a;", "main;"]);
+    }
+
+    #[test]
+    fn comments_and_continuations_keep_line_numbers() {
+        let source = "/* one\n   two */\na;\n  b = 1 + \\\n      2; // done\nc;\n";
+        let project = Project::new("lines", &[("main.c", source)]);
+        let lines = project.run(&[]).unwrap();
+        let got: Vec<_> = lines
+            .iter()
+            .map(|l| (l.line, l.col, l.text.as_str()))
+            .collect();
+        assert_eq!(got, [(3, 1, "a;"), (4, 3, "b = 1 + 2;"), (6, 1, "c;")]);
+    }
+}
@@ -0,0 +1,451 @@
+use crate::asm6502::{ANON_LABEL_PREFIX, DEFAULT_SEGMENT};
+use crate::machine::{BuildArtifacts, LineOrigin, relative_path};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A named label or `.const` value from the build.
+struct Symbol<'a> {
+    name: &'a str,
+    value: u16,
+    constant: bool,
+}
+
+/// One block of assembled bytes at its load address.
+struct Block<'a> {
+    name: &'a str,
+    start: u16,
+    bytes: &'a [u8],
+    pc_line_map: &'a [LineOrigin],
+    pc_asm_line_map: &'a [usize],
+}
+
+/// Bytes emitted by one source line: an offset into its block and a length.
+struct LineSpan<'a> {
+    block: usize,
+    offset: usize,
+    size: usize,
+    origin: &'a LineOrigin,
+}
+
+/// Named symbols sorted by value then name; anonymous labels are left out.
+fn symbols(artifacts: &BuildArtifacts) -> Vec<Symbol<'_>> {
+    let mut out: Vec<Symbol> = artifacts
+        .labels
+        .iter()
+        .filter(|(name, _)| !name.starts_with(ANON_LABEL_PREFIX))
+        .map(|(name, value)| Symbol {
+            name,
+            value: *value,
+            constant: artifacts.constants.contains(name),
+        })
+        .collect();
+    out.sort_by(|a, b| a.value.cmp(&b.value).then(a.name.cmp(b.name)));
+    out
+}
+
+fn blocks(artifacts: &BuildArtifacts) -> Vec<Block<'_>> {
+    std::iter::once(Block {
+        name: DEFAULT_SEGMENT,
+        start: artifacts.load_addr,
+        bytes: &artifacts.program,
+        pc_line_map: &artifacts.pc_line_map,
+        pc_asm_line_map: &artifacts.pc_asm_line_map,
+    })
+    .chain(artifacts.segments.iter().map(|seg| Block {
+        name: &seg.name,
+        start: seg.start,
+        bytes: &seg.bytes,
+        pc_line_map: &seg.pc_line_map,
+        pc_asm_line_map: &seg.pc_asm_line_map,
+    }))
+    .collect()
+}
+
+/// Group each block's bytes into runs emitted by the same assembler line.
+fn line_spans<'a>(blocks: &[Block<'a>]) -> Vec<LineSpan<'a>> {
+    let mut spans: Vec<LineSpan> = Vec::new();
+    for (idx, block) in blocks.iter().enumerate() {
+        let mut previous = None;
+        for (offset, (asm_line, origin)) in block
+            .pc_asm_line_map
+            .iter()
+            .zip(block.pc_line_map)
+            .enumerate()
+        {
+            match spans.last_mut() {
+                Some(span) if previous == Some(asm_line) => span.size += 1,
+                _ => spans.push(LineSpan {
+                    block: idx,
+                    offset,
+                    size: 1,
+                    origin,
+                }),
+            }
+            previous = Some(asm_line);
+        }
+    }
+    spans
+}
+
+/// Index of the block holding `addr`, if any.
+fn block_at(blocks: &[Block], addr: u16) -> Option<usize> {
+    blocks.iter().position(|block| {
+        let start = block.start as usize;
+        (start..start + block.bytes.len()).contains(&(addr as usize))
+    })
+}
+
+/// VICE monitor label file, loaded with `ll "program.vs"`. Constants are included like ld65's
+/// `-Ln` output, since zero-page variables are usually declared with `.const`.
+///
+/// VICE label names only take letters, digits and `_`, so a local label (`Parent.name`) is
+/// written as `Parent__name`, and left out if another symbol already has that name.
+pub fn render_vice_labels(artifacts: &BuildArtifacts) -> String {
+    let symbols = symbols(artifacts);
+    let names: HashSet<&str> = symbols.iter().map(|sym| sym.name).collect();
+    let mut out = String::new();
+    for sym in &symbols {
+        let name = sym.name.replace('.', "__");
+        if name != sym.name && names.contains(name.as_str()) {
+            continue;
+        }
+        out.push_str(&format!("al C:{:04X} .{}\n", sym.value, name));
+    }
+    out
+}
+
+/// Plain `NAME = $ADDR` map covering labels and constants.
+pub fn render_symbol_map(artifacts: &BuildArtifacts) -> String {
+    let symbols = symbols(artifacts);
+    let width = symbols.iter().map(|sym| sym.name.len()).max().unwrap_or(0);
+    let mut out = String::from("; CHIPcade symbols\n");
+    for sym in &symbols {
+        out.push_str(&format!("{:<width$} = ${:04X}\n", sym.name, sym.value));
+    }
+    out
+}
+
+/// Debug info in the ca65/ld65 `.dbg` text format: source files, segments, the byte span of
+/// every source line and the symbols. The image is a flat 64 KB file, so segment offsets in
+/// `image_name` equal their load addresses.
+pub fn render_debug_info(
+    artifacts: &BuildArtifacts,
+    project_root: &Path,
+    image_name: &str,
+) -> String {
+    let blocks = blocks(artifacts);
+    let spans = line_spans(&blocks);
+    let symbols = symbols(artifacts);
+
+    let mut files: Vec<&Path> = Vec::new();
+    let mut file_ids: HashMap<&Path, usize> = HashMap::new();
+    for span in &spans {
+        let file = span.origin.file.as_path();
+        file_ids.entry(file).or_insert_with(|| {
+            files.push(file);
+            files.len() - 1
+        });
+    }
+
+    let mut out = String::from("version\tmajor=2,minor=0\n");
+    out.push_str(&format!(
+        "info\tcsym=0,file={},lib=0,line={},mod=1,scope=1,seg={},span={},sym={},type=0\n",
+        files.len(),
+        spans.len(),
+        blocks.len(),
+        spans.len(),
+        symbols.len()
+    ));
+    for (id, file) in files.iter().enumerate() {
+        let size = fs::metadata(file).map(|m| m.len()).unwrap_or(0);
+        out.push_str(&format!(
+            "file\tid={},name=\"{}\",size={},mtime=0x00000000,mod=0\n",
+            id,
+            relative_path(project_root, file).display(),
+            size
+        ));
+    }
+    for (id, span) in spans.iter().enumerate() {
+        out.push_str(&format!(
+            "line\tid={},file={},line={},span={}\n",
+            id,
+            file_ids[span.origin.file.as_path()],
+            span.origin.line,
+            id
+        ));
+    }
+    out.push_str("mod\tid=0,name=\"program\",file=0\n");
+    for (id, block) in blocks.iter().enumerate() {
+        out.push_str(&format!(
+            "seg\tid={},name=\"{}\",start=0x{:06X},size=0x{:04X},addrsize=absolute,type=rw,oname=\"{}\",ooffs={}\n",
+            id,
+            block.name,
+            block.start,
+            block.bytes.len(),
+            image_name,
+            block.start
+        ));
+    }
+    for (id, span) in spans.iter().enumerate() {
+        out.push_str(&format!(
+            "span\tid={},seg={},start={},size={}\n",
+            id, span.block, span.offset, span.size
+        ));
+    }
+    let code_size: usize = blocks.iter().map(|block| block.bytes.len()).sum();
+    out.push_str(&format!("scope\tid=0,name=\"\",mod=0,size={}\n", code_size));
+    for (id, sym) in symbols.iter().enumerate() {
+        let kind = if sym.constant { "equ" } else { "lab" };
+        let seg = (!sym.constant)
+            .then(|| block_at(&blocks, sym.value))
+            .flatten()
+            .map(|seg| format!(",seg={}", seg))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "sym\tid={},name=\"{}\",addrsize=absolute,scope=0,val=0x{:X}{},type={}\n",
+            id, sym.name, sym.value, seg, kind
+        ));
+    }
+    out
+}
+
+#[derive(Serialize)]
+struct SymbolTable {
+    symbols: Vec<SymbolEntry>,
+    lines: Vec<LineEntry>,
+    segments: Vec<SegmentEntry>,
+}
+
+#[derive(Serialize)]
+struct SymbolEntry {
+    name: String,
+    value: u16,
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+struct LineEntry {
+    address: u16,
+    size: usize,
+    file: PathBuf,
+    line: usize,
+}
+
+#[derive(Serialize)]
+struct SegmentEntry {
+    name: String,
+    start: u16,
+    size: usize,
+}
+
+/// JSON with every symbol, the address range and source line of each emitted line, and the
+/// placed blocks.
+pub fn render_symbol_json(
+    artifacts: &BuildArtifacts,
+    project_root: &Path,
+) -> Result<String, String> {
+    let blocks = blocks(artifacts);
+    let table = SymbolTable {
+        symbols: symbols(artifacts)
+            .iter()
+            .map(|sym| SymbolEntry {
+                name: sym.name.to_string(),
+                value: sym.value,
+                kind: if sym.constant { "constant" } else { "label" },
+            })
+            .collect(),
+        lines: line_spans(&blocks)
+            .iter()
+            .map(|span| LineEntry {
+                address: blocks[span.block].start.wrapping_add(span.offset as u16),
+                size: span.size,
+                file: relative_path(project_root, &span.origin.file),
+                line: span.origin.line,
+            })
+            .collect(),
+        segments: blocks
+            .iter()
+            .map(|block| SegmentEntry {
+                name: block.name.to_string(),
+                start: block.start,
+                size: block.bytes.len(),
+            })
+            .collect(),
+    };
+    serde_json::to_string_pretty(&table)
+        .map(|json| json + "\n")
+        .map_err(|e| format!("Failed to encode symbol table: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::ProgramSegment;
+    use crate::sprites::SpritePack;
+
+    /// The build of a small project: a constant, a routine with a local label, a macro
+    /// expansion with its own local label, and a `DATA` segment filled from an include file.
+    ///
+    /// ```text
+    /// main.asm  1  .const SCREEN_W $A0
+    ///           3  Init: LDX #$03
+    ///           4  .loop: DEX
+    ///           5        BNE .loop
+    ///           6        Wait          ; .spin: DEY / BNE .spin
+    ///           7        RTS
+    /// data.inc  1  Table: .byte 1, 2, 3
+    /// ```
+    fn artifacts() -> BuildArtifacts {
+        let origin = |file: &str, line| LineOrigin {
+            file: PathBuf::from("/game/src").join(file),
+            line,
+        };
+        let code_lines = [
+            (0, 3),
+            (0, 3),
+            (1, 4),
+            (2, 5),
+            (2, 5),
+            (3, 6),
+            (4, 6),
+            (4, 6),
+            (5, 7),
+        ];
+        let labels = [
+            ("SCREEN_W", 0x00A0),
+            ("Init", 0x0200),
+            ("Init.loop", 0x0202),
+            ("Wait.1.spin", 0x0205),
+            ("__anon_0", 0x0205),
+            ("Table", 0x0300),
+        ];
+        BuildArtifacts {
+            program: vec![0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x88, 0xD0, 0xFD, 0x60],
+            sprites: SpritePack::default(),
+            entry_point: Some(0x0200),
+            labels: labels
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+            constants: HashSet::from(["SCREEN_W".to_string()]),
+            load_addr: 0x0200,
+            line_map: Vec::new(),
+            pc_line_map: code_lines
+                .iter()
+                .map(|(_, line)| origin("main.asm", *line))
+                .collect(),
+            asm_lines: Vec::new(),
+            pc_asm_line_map: code_lines.iter().map(|(asm_line, _)| *asm_line).collect(),
+            segments: vec![ProgramSegment {
+                name: "DATA".to_string(),
+                start: 0x0300,
+                bytes: vec![1, 2, 3],
+                pc_line_map: vec![origin("data.inc", 1); 3],
+                pc_asm_line_map: vec![7; 3],
+            }],
+        }
+    }
+
+    #[test]
+    fn vice_labels() {
+        assert_eq!(
+            render_vice_labels(&artifacts()),
+            "al C:00A0 .SCREEN_W\n\
+             al C:0200 .Init\n\
+             al C:0202 .Init__loop\n\
+             al C:0205 .Wait__1__spin\n\
+             al C:0300 .Table\n"
+        );
+    }
+
+    #[test]
+    fn vice_labels_skip_locals_that_clash_with_a_symbol() {
+        let mut artifacts = artifacts();
+        artifacts.labels.insert("Init__loop".to_string(), 0x0208);
+        let labels = render_vice_labels(&artifacts);
+        assert!(labels.contains("al C:0208 .Init__loop\n"));
+        assert!(!labels.contains("al C:0202"));
+    }
+
+    #[test]
+    fn symbol_map() {
+        assert_eq!(
+            render_symbol_map(&artifacts()),
+            "; CHIPcade symbols\n\
+             SCREEN_W    = $00A0\n\
+             Init        = $0200\n\
+             Init.loop   = $0202\n\
+             Wait.1.spin = $0205\n\
+             Table       = $0300\n"
+        );
+    }
+
+    #[test]
+    fn debug_info() {
+        assert_eq!(
+            render_debug_info(&artifacts(), Path::new("/game"), "program.bin"),
+            "version\tmajor=2,minor=0\n\
+             info\tcsym=0,file=2,lib=0,line=7,mod=1,scope=1,seg=2,span=7,sym=5,type=0\n\
+             file\tid=0,name=\"src/main.asm\",size=0,mtime=0x00000000,mod=0\n\
+             file\tid=1,name=\"src/data.inc\",size=0,mtime=0x00000000,mod=0\n\
+             line\tid=0,file=0,line=3,span=0\n\
+             line\tid=1,file=0,line=4,span=1\n\
+             line\tid=2,file=0,line=5,span=2\n\
+             line\tid=3,file=0,line=6,span=3\n\
+             line\tid=4,file=0,line=6,span=4\n\
+             line\tid=5,file=0,line=7,span=5\n\
+             line\tid=6,file=1,line=1,span=6\n\
+             mod\tid=0,name=\"program\",file=0\n\
+             seg\tid=0,name=\"CODE\",start=0x000200,size=0x0009,addrsize=absolute,type=rw,oname=\"program.bin\",ooffs=512\n\
+             seg\tid=1,name=\"DATA\",start=0x000300,size=0x0003,addrsize=absolute,type=rw,oname=\"program.bin\",ooffs=768\n\
+             span\tid=0,seg=0,start=0,size=2\n\
+             span\tid=1,seg=0,start=2,size=1\n\
+             span\tid=2,seg=0,start=3,size=2\n\
+             span\tid=3,seg=0,start=5,size=1\n\
+             span\tid=4,seg=0,start=6,size=2\n\
+             span\tid=5,seg=0,start=8,size=1\n\
+             span\tid=6,seg=1,start=0,size=3\n\
+             scope\tid=0,name=\"\",mod=0,size=12\n\
+             sym\tid=0,name=\"SCREEN_W\",addrsize=absolute,scope=0,val=0xA0,type=equ\n\
+             sym\tid=1,name=\"Init\",addrsize=absolute,scope=0,val=0x200,seg=0,type=lab\n\
+             sym\tid=2,name=\"Init.loop\",addrsize=absolute,scope=0,val=0x202,seg=0,type=lab\n\
+             sym\tid=3,name=\"Wait.1.spin\",addrsize=absolute,scope=0,val=0x205,seg=0,type=lab\n\
+             sym\tid=4,name=\"Table\",addrsize=absolute,scope=0,val=0x300,seg=1,type=lab\n"
+        );
+    }
+
+    #[test]
+    fn symbol_json() {
+        let json = render_symbol_json(&artifacts(), Path::new("/game")).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let line = |address, size, file, line| serde_json::json!({ "address": address, "size": size, "file": file, "line": line });
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "symbols": [
+                    { "name": "SCREEN_W", "value": 0xA0, "kind": "constant" },
+                    { "name": "Init", "value": 0x200, "kind": "label" },
+                    { "name": "Init.loop", "value": 0x202, "kind": "label" },
+                    { "name": "Wait.1.spin", "value": 0x205, "kind": "label" },
+                    { "name": "Table", "value": 0x300, "kind": "label" },
+                ],
+                "lines": [
+                    line(0x200, 2, "src/main.asm", 3),
+                    line(0x202, 1, "src/main.asm", 4),
+                    line(0x203, 2, "src/main.asm", 5),
+                    line(0x205, 1, "src/main.asm", 6),
+                    line(0x206, 2, "src/main.asm", 6),
+                    line(0x208, 1, "src/main.asm", 7),
+                    line(0x300, 3, "src/data.inc", 1),
+                ],
+                "segments": [
+                    { "name": "CODE", "start": 0x200, "size": 9 },
+                    { "name": "DATA", "start": 0x300, "size": 3 },
+                ],
+            })
+        );
+        assert!(json.ends_with("}\n"));
+    }
+}